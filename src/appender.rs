@@ -0,0 +1,201 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::storage::{hold_open_file, OpenFile};
+use log::error;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::JoinHandle;
+use tokio::sync::oneshot;
+
+enum Op {
+    Line(Vec<u8>),
+    Sync(oneshot::Sender<io::Result<()>>),
+    Truncate(oneshot::Sender<io::Result<()>>),
+}
+
+/// Appends whole lines to a file from anywhere at once. Every line goes over a
+/// channel to one thread that owns the file, so lines from different tasks
+/// never interleave, and land in the order they were sent; dropping the
+/// appender waits for them all to be written. Waiting on the writer, to sync
+/// or truncate, leaves the task's thread free for others meanwhile.
+pub struct Appender {
+    ops: Option<Sender<Op>>,
+    writer: Option<JoinHandle<()>>,
+    _open: Option<OpenFile<'static>>,
+}
+
+impl Appender {
+    /// Takes over `file`, which should be open for appending, as `path`. It
+    /// counts against --max-open-files until the appender is dropped.
+    pub fn new(file: File, path: &Path) -> io::Result<Appender> {
+        let open = hold_open_file()?;
+        let (ops, received) = channel();
+        let path = path.to_owned();
+        let writer = std::thread::spawn(move || write_lines(file, path, received));
+        Ok(Appender {
+            ops: Some(ops),
+            writer: Some(writer),
+            _open: open,
+        })
+    }
+
+    /// Queues `line` to be written. It should end in a newline.
+    pub fn append(&self, line: Vec<u8>) {
+        self.send(Op::Line(line));
+    }
+
+    /// Waits for everything queued so far to reach the disk, and reports the
+    /// first write that failed since the last time.
+    pub async fn sync(&self) -> io::Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.send(Op::Sync(reply));
+        result.await.unwrap_or_else(|_| Err(stopped()))
+    }
+
+    /// Empties the file, once everything queued so far is written.
+    pub async fn truncate(&self) -> io::Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.send(Op::Truncate(reply));
+        result.await.unwrap_or_else(|_| Err(stopped()))
+    }
+
+    fn send(&self, op: Op) {
+        // The writer only stops once the sender is dropped, below.
+        let _ = self.ops.as_ref().unwrap().send(op);
+    }
+}
+
+impl Drop for Appender {
+    fn drop(&mut self) {
+        drop(self.ops.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the writer has stopped")
+}
+
+fn write_lines(mut file: File, path: PathBuf, ops: std::sync::mpsc::Receiver<Op>) {
+    let mut failed = None;
+    for op in ops {
+        match op {
+            Op::Line(line) => {
+                if let Err(e) = file.write_all(&line) {
+                    error!("Could not write to {:?}: {}", path, e);
+                    failed.get_or_insert(e);
+                }
+            }
+            Op::Sync(reply) => {
+                let result = match failed.take() {
+                    Some(e) => Err(e),
+                    None => file.sync_data(),
+                };
+                let _ = reply.send(result);
+            }
+            Op::Truncate(reply) => {
+                let _ = reply.send(file.set_len(0));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::Scratch;
+    use std::collections::HashMap;
+    use std::fs::OpenOptions;
+    use std::sync::Arc;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn keeps_lines_whole_from_many_tasks() {
+        const TASKS: usize = 32;
+        const LINES: usize = 200;
+        let scratch = Scratch::new("appender");
+        let path = scratch.path().join("lines.txt");
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let appender = Arc::new(Appender::new(file, &path).unwrap());
+
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|task| {
+                let appender = appender.clone();
+                tokio::spawn(async move {
+                    for line in 0..LINES {
+                        // Long enough that a torn write would show.
+                        let padding = "x".repeat(line % 50 * 100);
+                        appender.append(format!("{} {} {}\n", task, line, padding).into_bytes());
+                        if line % 50 == 0 {
+                            appender.sync().await.unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        appender.sync().await.unwrap();
+        drop(appender);
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let mut next: HashMap<usize, usize> = HashMap::new();
+        for line in written.lines() {
+            let fields: Vec<_> = line.split(' ').collect();
+            let task: usize = fields[0].parse().unwrap();
+            let number: usize = fields[1].parse().unwrap();
+            assert_eq!(fields[2], "x".repeat(number % 50 * 100));
+            // Each task's lines in the order it sent them.
+            let expected = next.entry(task).or_default();
+            assert_eq!(number, *expected);
+            *expected += 1;
+        }
+        assert_eq!(next.len(), TASKS);
+        assert!(next.values().all(|&count| count == LINES));
+    }
+
+    #[tokio::test]
+    async fn truncates_after_whats_queued() {
+        let scratch = Scratch::new("appender");
+        let path = scratch.path().join("lines.txt");
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let appender = Appender::new(file, &path).unwrap();
+        appender.append(b"before\n".to_vec());
+        appender.truncate().await.unwrap();
+        appender.append(b"after\n".to_vec());
+        drop(appender);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "after\n");
+    }
+}
@@ -0,0 +1,79 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::layout::NOT_ARTISTS;
+use crate::{Post, Tags};
+use clap::ValueEnum;
+use std::collections::{BTreeMap, HashSet};
+
+/// A category of tags, to balance a sample across.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TagCategory {
+    General,
+    Species,
+    Character,
+    Copyright,
+    Artist,
+    Lore,
+    Meta,
+}
+
+impl TagCategory {
+    fn tags(self, tags: &Tags) -> &[String] {
+        match self {
+            TagCategory::General => &tags.general,
+            TagCategory::Species => &tags.species,
+            TagCategory::Character => &tags.character,
+            TagCategory::Copyright => &tags.copyright,
+            TagCategory::Artist => &tags.artist,
+            TagCategory::Lore => &tags.lore,
+            TagCategory::Meta => &tags.meta,
+        }
+    }
+}
+
+/// Picks up to `per_class` posts for each tag in `category`, in the order
+/// they're given. A post with several tags in the category counts towards
+/// whichever of them has the fewest so far. Posts with none aren't picked.
+/// Returns the ids picked, and how many each tag ended up with.
+pub fn sample<'a>(
+    posts: impl IntoIterator<Item = &'a Post>,
+    category: TagCategory,
+    per_class: usize,
+) -> (HashSet<u64>, BTreeMap<String, usize>) {
+    let mut picked = HashSet::new();
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for post in posts {
+        let class = category
+            .tags(&post.tags)
+            .iter()
+            .filter(|tag| category != TagCategory::Artist || !NOT_ARTISTS.contains(&tag.as_str()))
+            .map(|tag| (counts.get(tag).copied().unwrap_or(0), tag))
+            .filter(|&(count, _)| count < per_class)
+            .min();
+        if let Some((_, tag)) = class {
+            *counts.entry(tag.clone()).or_default() += 1;
+            picked.insert(post.id);
+        }
+    }
+    (picked, counts)
+}
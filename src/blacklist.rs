@@ -0,0 +1,251 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use crate::client::Client;
+use crate::error::MonosodiumError;
+use crate::Post;
+use log::{info, warn};
+use serde::Deserialize;
+
+/// Posts to leave out, written the way e621 takes a blacklist: one rule per
+/// line, which a post matches if it has every plain tag on the line, none of
+/// those starting with `-`, and at least one of those starting with `~`, if
+/// there are any. `*` in a tag stands for anything, and `rating:`, `type:`
+/// and `id:` work as on the site; lines with other metatags are left out,
+/// since they can't be checked here.
+#[derive(Debug, Default)]
+pub struct Blacklist {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    required: Vec<Term>,
+    excluded: Vec<Term>,
+    optional: Vec<Term>,
+}
+
+#[derive(Debug)]
+enum Term {
+    Tag(String),
+    Rating(char),
+    Type(String),
+    Id(u64),
+}
+
+impl Blacklist {
+    /// Reads rules, one per line; blank lines and lines starting with `#`
+    /// are skipped.
+    pub fn parse(lines: &str) -> Blacklist {
+        let rules = lines
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let rule = Rule::parse(line);
+                if rule.is_none() {
+                    warn!(
+                        "Leaving out the blacklist line {:?}, which has a metatag that can only be checked on e621",
+                        line
+                    );
+                }
+                rule
+            })
+            .collect();
+        Blacklist { rules }
+    }
+
+    pub fn extend(&mut self, other: Blacklist) {
+        self.rules.extend(other.rules);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn matches(&self, post: &Post) -> bool {
+        self.rules.iter().any(|rule| rule.matches(post))
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Rule> {
+        let mut rule = Rule {
+            required: Vec::new(),
+            excluded: Vec::new(),
+            optional: Vec::new(),
+        };
+        for word in line.split_whitespace() {
+            let word = word.to_lowercase();
+            let (list, word) = match (word.strip_prefix('-'), word.strip_prefix('~')) {
+                (Some(word), _) => (&mut rule.excluded, word),
+                (_, Some(word)) => (&mut rule.optional, word),
+                _ => (&mut rule.required, word.as_str()),
+            };
+            list.push(Term::parse(word)?);
+        }
+        Some(rule)
+    }
+
+    fn matches(&self, post: &Post) -> bool {
+        self.required.iter().all(|term| term.matches(post))
+            && !self.excluded.iter().any(|term| term.matches(post))
+            && (self.optional.is_empty() || self.optional.iter().any(|term| term.matches(post)))
+    }
+}
+
+impl Term {
+    fn parse(word: &str) -> Option<Term> {
+        let Some((name, value)) = word.split_once(':') else {
+            return Some(Term::Tag(word.to_owned()));
+        };
+        match name {
+            "rating" => value.chars().next().map(Term::Rating),
+            "type" => Some(Term::Type(value.to_owned())),
+            "id" => value.parse().ok().map(Term::Id),
+            // A tag with a colon in it, like ":3".
+            "" => Some(Term::Tag(word.to_owned())),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, post: &Post) -> bool {
+        match self {
+            Term::Tag(tag) => post.tags.all().any(|have| glob(tag, have)),
+            Term::Rating(rating) => post.rating.starts_with(*rating),
+            Term::Type(ext) => post.file.ext.eq_ignore_ascii_case(ext),
+            Term::Id(id) => post.id == *id,
+        }
+    }
+}
+
+// Whether `text` matches `pattern`, where `*` stands for any run of
+// characters.
+fn glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+// The part of https://e621.net/users/<id>.json that only the user themself
+// gets to see.
+#[derive(Deserialize)]
+struct Settings {
+    blacklisted_tags: Option<String>,
+}
+
+/// Fetches the blacklist of the account the client is logged in as, whose
+/// id is `user_id`.
+pub async fn from_account(client: &Client, user_id: u32) -> Result<Blacklist, MonosodiumError> {
+    info!("Fetching the account's blacklist");
+    let url = format!("https://e621.net/users/{}.json", user_id);
+    let settings = client
+        .get(&url)
+        .await?
+        .error_for_status()?
+        .json::<Settings>()
+        .await?;
+    match settings.blacklisted_tags {
+        Some(lines) => Ok(Blacklist::parse(&lines)),
+        None => Err(MonosodiumError::InvalidOptions(
+            "e621 didn't give the account's blacklist; check --username and --api-key".to_owned(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::post;
+
+    fn tagged(general: &[&str]) -> Post {
+        let mut post = post(b"contents");
+        post.tags.general = general.iter().map(|&tag| tag.to_owned()).collect();
+        post
+    }
+
+    #[test]
+    fn globs_match_any_run_of_characters() {
+        assert!(glob("fox", "fox"));
+        assert!(!glob("fox", "foxes"));
+        assert!(glob("fox*", "foxes"));
+        assert!(glob("*_tail", "fluffy_tail"));
+        assert!(glob("f*x*s", "foxes"));
+        assert!(glob("*", ""));
+        assert!(!glob("a*a", "a"));
+        assert!(!glob("*_tail", "tail"));
+    }
+
+    #[test]
+    fn skips_comments_blank_lines_and_unknown_metatags() {
+        let blacklist = Blacklist::parse("# not a rule\n\n   \nfox\nscore:<0\n  wolf tail  \n");
+        assert_eq!(blacklist.len(), 2);
+        assert!(blacklist.matches(&tagged(&["fox"])));
+        assert!(!blacklist.matches(&tagged(&["wolf"])));
+        assert!(blacklist.matches(&tagged(&["tail", "wolf"])));
+    }
+
+    #[test]
+    fn matches_every_plain_tag_on_a_line() {
+        let blacklist = Blacklist::parse("Fox Tail*");
+        assert!(blacklist.matches(&tagged(&["fox", "tail_wag"])));
+        assert!(!blacklist.matches(&tagged(&["fox"])));
+        assert!(!blacklist.matches(&tagged(&["wolf", "tail"])));
+    }
+
+    #[test]
+    fn negated_and_optional_tags() {
+        let blacklist = Blacklist::parse("fox -solo ~red ~blue");
+        assert!(blacklist.matches(&tagged(&["fox", "red"])));
+        assert!(blacklist.matches(&tagged(&["fox", "blue", "duo"])));
+        assert!(!blacklist.matches(&tagged(&["fox", "red", "solo"])));
+        assert!(!blacklist.matches(&tagged(&["fox", "green"])));
+    }
+
+    #[test]
+    fn metatags_check_the_post_itself() {
+        let post = tagged(&[]);
+        assert!(Blacklist::parse("rating:safe").matches(&post));
+        assert!(!Blacklist::parse("rating:e").matches(&post));
+        assert!(Blacklist::parse("type:PNG").matches(&post));
+        assert!(Blacklist::parse("id:1234").matches(&post));
+        assert!(!Blacklist::parse("id:1234 -type:png").matches(&post));
+        assert!(!Blacklist::parse("id:12345").matches(&post));
+        assert!(Blacklist::parse(":3").matches(&tagged(&[":3"])));
+    }
+}
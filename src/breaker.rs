@@ -0,0 +1,102 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use log::{info, warn};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, sleep_until, Instant};
+
+// How often a request waiting on someone else's probe checks back.
+const PROBE_POLL: Duration = Duration::from_millis(250);
+
+/// Shared by every task that talks to the server. After `threshold` failures
+/// in a row, all new requests wait out the cooldown, then a single probe
+/// request is let through. If it succeeds, everyone resumes; if not, the
+/// cooldown starts over.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    consecutive_failures: u32,
+    open_until: Instant,
+    probing: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Mutex::new(State {
+                consecutive_failures: 0,
+                open_until: Instant::now(),
+                probing: false,
+            }),
+        }
+    }
+
+    /// Waits until a request may be sent.
+    pub async fn admit(&self) {
+        loop {
+            let mut state = self.state.lock().await;
+            if state.consecutive_failures < self.threshold {
+                return;
+            }
+            if state.probing {
+                drop(state);
+                sleep(PROBE_POLL).await;
+            } else if Instant::now() < state.open_until {
+                let open_until = state.open_until;
+                drop(state);
+                sleep_until(open_until).await;
+            } else {
+                info!("Cooldown over, probing the server");
+                state.probing = true;
+                return;
+            }
+        }
+    }
+
+    pub async fn record(&self, success: bool) {
+        let mut state = self.state.lock().await;
+        state.probing = false;
+        if success {
+            if state.consecutive_failures >= self.threshold {
+                info!("Server is responding again, resuming requests");
+            }
+            state.consecutive_failures = 0;
+        } else {
+            state.consecutive_failures += 1;
+            if state.consecutive_failures >= self.threshold {
+                warn!(
+                    "{} failed requests in a row, pausing all requests for {}",
+                    state.consecutive_failures,
+                    humantime::format_duration(self.cooldown)
+                );
+                state.open_until = Instant::now() + self.cooldown;
+            }
+        }
+    }
+}
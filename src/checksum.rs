@@ -0,0 +1,108 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::manifest::{self, WriteStrategy};
+use crate::storage::with_open_file;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{metadata, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Where the cache is kept, in the output directory.
+pub const CACHE_FILE: &str = ".monosodium-checksums.json";
+
+/// Remembers the MD5 of files already hashed, keyed by path, so that files
+/// which haven't changed since (same size, same modification time) don't
+/// have to be read again.
+#[derive(Default)]
+pub struct ChecksumCache {
+    path: PathBuf,
+    entries: BTreeMap<PathBuf, CachedChecksum>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct CachedChecksum {
+    size: u64,
+    modified: SystemTime,
+    md5: String,
+    #[serde(default)]
+    verified_at: Option<SystemTime>,
+}
+
+impl ChecksumCache {
+    pub fn load(path: &Path) -> std::io::Result<ChecksumCache> {
+        let entries = manifest::load(path)?.unwrap_or_default();
+        Ok(ChecksumCache {
+            path: path.to_owned(),
+            entries,
+        })
+    }
+
+    pub fn save(&self, strategy: WriteStrategy) -> std::io::Result<()> {
+        manifest::save(&self.path, &self.entries, strategy)
+    }
+
+    /// The cached MD5 of `file`, if the file hasn't changed since it was
+    /// hashed.
+    pub fn get(&self, file: &Path) -> Option<String> {
+        let entry = self.entries.get(file)?;
+        let (size, modified) = stamp(file).ok()?;
+        (entry.size == size && entry.modified == modified).then(|| entry.md5.clone())
+    }
+
+    pub fn insert(&mut self, file: &Path, md5: &str) {
+        if let Ok((size, modified)) = stamp(file) {
+            self.entries.insert(
+                file.to_owned(),
+                CachedChecksum {
+                    size,
+                    modified,
+                    md5: md5.to_owned(),
+                    verified_at: Some(SystemTime::now()),
+                },
+            );
+        }
+    }
+}
+
+fn stamp(file: &Path) -> std::io::Result<(u64, SystemTime)> {
+    let metadata = metadata(file)?;
+    Ok((metadata.len(), metadata.modified()?))
+}
+
+/// Hashes a file without reading it all into memory at once.
+pub fn md5_file(file: &Path) -> std::io::Result<String> {
+    with_open_file(|| {
+        let mut reader = BufReader::new(File::open(file)?);
+        let mut context = md5::Context::new();
+        let mut buffer = vec![0; 1 << 16];
+        loop {
+            match reader.read(&mut buffer)? {
+                0 => break,
+                n => context.consume(&buffer[..n]),
+            }
+        }
+        Ok(format!("{:x}", context.compute()))
+    })
+}
@@ -0,0 +1,165 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::breaker::CircuitBreaker;
+use crate::profile::{Phase, Profile};
+use crate::ratelimit::RateLimiter;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Error, Response, StatusCode, Url};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The only host that's told who we're logged in as; files come from the CDN,
+// and notifications go elsewhere entirely.
+const API_HOST: &str = "e621.net";
+
+/// A cheaply cloneable HTTP client. All requests, whether for pages or for
+/// files, go through the shared circuit breaker and rate limiter.
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    limiter: Arc<RateLimiter>,
+    breaker: Arc<CircuitBreaker>,
+    login: Option<Arc<(String, String)>>,
+    profile: Arc<Profile>,
+}
+
+impl Client {
+    pub fn new(http: reqwest::Client, limiter: RateLimiter, breaker: CircuitBreaker) -> Client {
+        Client {
+            http,
+            limiter: Arc::new(limiter),
+            breaker: Arc::new(breaker),
+            login: None,
+            profile: Arc::default(),
+        }
+    }
+
+    /// Keeps track of where the time goes, for --profile.
+    pub fn profiled(mut self) -> Client {
+        self.profile = Arc::new(Profile::new(true));
+        self
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    /// Whether requests to the API say who they're from.
+    pub fn is_logged_in(&self) -> bool {
+        self.login.is_some()
+    }
+
+    /// Who requests to the API say they're from, if anyone.
+    pub fn username(&self) -> Option<&str> {
+        self.login.as_ref().map(|login| login.0.as_str())
+    }
+
+    /// Sends `username` and `api_key` with every request to the API.
+    pub fn log_in(mut self, username: String, api_key: String) -> Client {
+        self.login = Some(Arc::new((username, api_key)));
+        self
+    }
+
+    pub async fn get(&self, url: &str) -> Result<Response, Error> {
+        self.wait_turn().await;
+        self.send(url).await
+    }
+
+    /// Waits until the breaker and the rate limiter let another request
+    /// through.
+    pub async fn wait_turn(&self) {
+        self.breaker.admit().await;
+        self.profile
+            .time(Phase::RateLimit, self.limiter.wait())
+            .await;
+    }
+
+    /// Makes a request straight away; only once `wait_turn` has been waited
+    /// on, which `get` does for you.
+    pub async fn send(&self, url: &str) -> Result<Response, Error> {
+        let mut request = self.http.get(url);
+        if let Some(login) = &self.login {
+            if is_api(url) {
+                request = request.basic_auth(&login.0, Some(&login.1));
+            }
+        }
+        let response = request.send().await;
+        if let Ok(response) = &response {
+            if let Some((remaining, window)) = quota(response) {
+                self.limiter.observe(remaining, window);
+            }
+        }
+        let up = match &response {
+            Ok(response) => !is_outage(response.status()),
+            Err(_) => false,
+        };
+        self.breaker.record(up).await;
+        response
+    }
+
+    /// Sends `body` as JSON to somewhere other than e621, so without the
+    /// breaker or rate limiter.
+    pub async fn post_json(&self, url: &str, body: &impl Serialize) -> Result<Response, Error> {
+        self.http.post(url).json(body).send().await
+    }
+}
+
+// What the response says is left of the server's quota: how many requests,
+// and how long until it's topped up again. Without the headers, requests
+// are just paced by --api-delay.
+fn quota(response: &Response) -> Option<(u64, Duration)> {
+    let headers = response.headers();
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        if let Some(wait) = header_number(headers, RETRY_AFTER.as_str()) {
+            return Some((0, Duration::from_secs(wait)));
+        }
+    }
+    let remaining = header_number(headers, "x-ratelimit-remaining")?;
+    let reset = header_number(headers, "x-ratelimit-reset")?;
+    // A reset is either how many seconds from now, or a time in seconds since
+    // 1970.
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    let seconds = if reset > now / 2 {
+        reset.saturating_sub(now)
+    } else {
+        reset
+    };
+    Some((remaining, Duration::from_secs(seconds)))
+}
+
+fn header_number(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+fn is_api(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| url.host_str() == Some(API_HOST))
+}
+
+/// Statuses that mean the server can't serve anyone right now, as opposed to
+/// a problem with one particular request.
+pub fn is_outage(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
@@ -0,0 +1,93 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use clap::ValueEnum;
+use env_logger::WriteStyle;
+use std::fmt::Display;
+use std::io::{stderr, stdout, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// When to color what's printed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// When printing to a terminal, unless NO_COLOR is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// What a bit of colored text is saying.
+#[derive(Clone, Copy, Debug)]
+pub enum Style {
+    Good,
+    Bad,
+    Notice,
+}
+
+// Decided once, before anything is printed.
+static STDOUT: AtomicBool = AtomicBool::new(false);
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Settles whether standard output and standard error are colored, and sets
+/// up logging to match.
+pub fn init(choice: ColorChoice) {
+    // https://no-color.org: set to anything but empty, it means no color.
+    let allowed = std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+    let (out, err, log) = match choice {
+        ColorChoice::Always => (true, true, WriteStyle::Always),
+        ColorChoice::Never => (false, false, WriteStyle::Never),
+        ColorChoice::Auto if !allowed => (false, false, WriteStyle::Never),
+        ColorChoice::Auto => (
+            stdout().is_terminal(),
+            stderr().is_terminal(),
+            WriteStyle::Auto,
+        ),
+    };
+    STDOUT.store(out, Ordering::Relaxed);
+    STDERR.store(err, Ordering::Relaxed);
+    env_logger::Builder::from_default_env()
+        .write_style(log)
+        .init();
+}
+
+/// `text` in `style`, for printing to standard output.
+pub fn out(style: Style, text: impl Display) -> String {
+    paint(STDOUT.load(Ordering::Relaxed), style, text)
+}
+
+/// `text` in `style`, for printing to standard error.
+pub fn err(style: Style, text: impl Display) -> String {
+    paint(STDERR.load(Ordering::Relaxed), style, text)
+}
+
+fn paint(enabled: bool, style: Style, text: impl Display) -> String {
+    if !enabled {
+        return text.to_string();
+    }
+    let code = match style {
+        Style::Good => "32",
+        Style::Bad => "1;31",
+        Style::Notice => "33",
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
@@ -0,0 +1,152 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::checksum::md5_file;
+use serde::{Deserialize, Serialize};
+use std::fs::{metadata, remove_file, rename};
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// A program that makes an image smaller without changing a pixel of it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Optimizer {
+    Oxipng,
+    Jpegtran,
+}
+
+impl Optimizer {
+    fn program(&self) -> &'static str {
+        match self {
+            Optimizer::Oxipng => "oxipng",
+            Optimizer::Jpegtran => "jpegtran",
+        }
+    }
+
+    // Both keep everything in the file besides the pixels, like the color
+    // profile, so that only how it's stored changes.
+    fn command(&self, input: &Path, output: &Path) -> Command {
+        let mut command = Command::new(self.program());
+        match self {
+            Optimizer::Oxipng => {
+                command
+                    .args(["--opt", "2", "--quiet", "--out"])
+                    .arg(output)
+                    .arg(input);
+            }
+            Optimizer::Jpegtran => {
+                command
+                    .args(["-copy", "all", "-optimize", "-progressive", "-outfile"])
+                    .arg(output)
+                    .arg(input);
+            }
+        }
+        command
+    }
+}
+
+/// The optimizers that are on the PATH, for --compress-images-lossless.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Optimizers {
+    png: bool,
+    jpeg: bool,
+}
+
+impl Optimizers {
+    pub async fn find() -> Optimizers {
+        Optimizers {
+            png: available(Optimizer::Oxipng).await,
+            jpeg: available(Optimizer::Jpegtran).await,
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        self.png || self.jpeg
+    }
+
+    /// The optimizer for a file with this extension, if there's one for it
+    /// and it's on the PATH.
+    pub fn for_ext(&self, ext: &str) -> Option<Optimizer> {
+        match ext {
+            "png" if self.png => Some(Optimizer::Oxipng),
+            "jpg" | "jpeg" if self.jpeg => Some(Optimizer::Jpegtran),
+            _ => None,
+        }
+    }
+}
+
+// Whether the program can be run at all; how it exits when only asked for
+// its version differs from one build to the next.
+async fn available(optimizer: Optimizer) -> bool {
+    Command::new(optimizer.program())
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .is_ok()
+}
+
+/// How a file was made smaller, for --compress-images-lossless. The post's
+/// own MD5 is still the file's as it is on e621; this one is the file as it's
+/// saved.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Compressed {
+    pub optimizer: String,
+    pub original_size: u64,
+    pub size: u64,
+    pub md5: String,
+}
+
+/// Optimizes the image at `path` in place, if that makes it any smaller.
+pub async fn compress(path: &Path, optimizer: Optimizer) -> std::io::Result<Option<Compressed>> {
+    let original_size = metadata(path)?.len();
+    let ext = path.extension().unwrap_or_default();
+    let optimized = path.with_extension(Path::new("compressing").with_extension(ext));
+    let output = optimizer
+        .command(path, &optimized)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    if !output.status.success() {
+        let _ = remove_file(&optimized);
+        return Err(std::io::Error::other(format!(
+            "{} failed: {}",
+            optimizer.program(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let size = metadata(&optimized)?.len();
+    if size == 0 || size >= original_size {
+        remove_file(&optimized)?;
+        return Ok(None);
+    }
+    let md5 = md5_file(&optimized)?;
+    rename(&optimized, path)?;
+    Ok(Some(Compressed {
+        optimizer: optimizer.program().to_owned(),
+        original_size,
+        size,
+        md5,
+    }))
+}
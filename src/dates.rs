@@ -0,0 +1,152 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::time::{Duration, SystemTime};
+
+/// Parses `--since`: a date like "2024-01-01" (midnight UTC), a timestamp like
+/// "2024-01-01 18:30:00", or a duration back from now like "7d".
+pub fn parse_since(s: &str) -> Result<SystemTime, String> {
+    if let Ok(ago) = humantime::parse_duration(s) {
+        return SystemTime::now()
+            .checked_sub(ago)
+            .ok_or_else(|| format!("{:?} is too far back", s));
+    }
+    let timestamp = if s.len() == 10 {
+        humantime::parse_rfc3339_weak(&format!("{} 00:00:00", s))
+    } else {
+        humantime::parse_rfc3339_weak(s)
+    };
+    timestamp.map_err(|_| {
+        format!(
+            "expected a date like 2024-01-01 or a duration like 7d, got {:?}",
+            s
+        )
+    })
+}
+
+/// Parses a timestamp from the API, like "2023-05-01T12:34:56.789-04:00".
+pub fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    if let Some(utc) = s.strip_suffix('Z') {
+        return humantime::parse_rfc3339_weak(utc).ok();
+    }
+    let split = s.len().checked_sub(6)?;
+    let (local, offset) = (s.get(..split)?, s.get(split..)?);
+    let east = match offset.as_bytes()[0] {
+        b'+' => true,
+        b'-' => false,
+        _ => return humantime::parse_rfc3339_weak(s).ok(),
+    };
+    let (hours, minutes) = offset[1..].split_once(':')?;
+    let offset =
+        Duration::from_secs(hours.parse::<u64>().ok()? * 3600 + minutes.parse::<u64>().ok()? * 60);
+    let local = humantime::parse_rfc3339_weak(local).ok()?;
+    if east {
+        local.checked_sub(offset)
+    } else {
+        local.checked_add(offset)
+    }
+}
+
+/// The `date:` metatag for a search between `since` and `until`, so that the
+/// server leaves out posts from outside them. Dates are whole days in UTC, so
+/// this can take in a little more than asked for, but never less.
+pub fn date_tag(since: Option<SystemTime>, until: Option<SystemTime>) -> Option<String> {
+    let day =
+        |time: SystemTime| humantime::format_rfc3339_seconds(time).to_string()[..10].to_owned();
+    match (since.map(day), until.map(day)) {
+        (Some(since), Some(until)) => Some(format!("date:{}..{}", since, until)),
+        (Some(since), None) => Some(format!("date:>={}", since)),
+        (None, Some(until)) => Some(format!("date:<={}", until)),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    // 2024-01-01 00:00:00 UTC.
+    fn new_year() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_704_067_200)
+    }
+
+    #[test]
+    fn since_takes_a_duration_back_from_now() {
+        let since = parse_since("7d").unwrap();
+        let ago = SystemTime::now().duration_since(since).unwrap();
+        let week = Duration::from_secs(7 * 24 * 3600);
+        assert!(
+            ago >= week && ago < week + Duration::from_secs(60),
+            "{:?}",
+            ago
+        );
+    }
+
+    #[test]
+    fn since_takes_dates_and_timestamps() {
+        let half_past_six = new_year() + Duration::from_secs(18 * 3600 + 30 * 60);
+        assert_eq!(parse_since("2024-01-01"), Ok(new_year()));
+        assert_eq!(parse_since("2024-01-01 18:30:00"), Ok(half_past_six));
+        assert_eq!(parse_since("2024-01-01T18:30:00Z"), Ok(half_past_six));
+    }
+
+    #[test]
+    fn since_says_what_it_expected() {
+        assert_eq!(
+            parse_since("last tuesday"),
+            Err(
+                "expected a date like 2024-01-01 or a duration like 7d, got \"last tuesday\""
+                    .to_owned()
+            )
+        );
+        assert!(parse_since("2024-13-01").is_err());
+    }
+
+    #[test]
+    fn timestamps_from_the_api_keep_their_offset() {
+        let noon = new_year() + Duration::from_secs(12 * 3600);
+        assert_eq!(parse_timestamp("2024-01-01T08:00:00.000-04:00"), Some(noon));
+        assert_eq!(parse_timestamp("2024-01-01T13:30:00.000+01:30"), Some(noon));
+        assert_eq!(parse_timestamp("2024-01-01T12:00:00Z"), Some(noon));
+        assert_eq!(parse_timestamp("recently"), None);
+        assert_eq!(parse_timestamp(""), None);
+    }
+
+    #[test]
+    fn date_tags_cover_whole_days() {
+        let later = new_year() + Duration::from_secs(40 * 24 * 3600 + 3600);
+        assert_eq!(
+            date_tag(Some(new_year()), Some(later)).as_deref(),
+            Some("date:2024-01-01..2024-02-10")
+        );
+        assert_eq!(
+            date_tag(Some(later), None).as_deref(),
+            Some("date:>=2024-02-10")
+        );
+        assert_eq!(
+            date_tag(None, Some(new_year())).as_deref(),
+            Some("date:<=2024-01-01")
+        );
+        assert_eq!(date_tag(None, None), None);
+    }
+}
@@ -0,0 +1,129 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::Post;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DELETED_FILE: &str = ".monosodium-deleted.json";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    /// When the post was first seen without a file, in seconds since 1970.
+    first_seen: u64,
+    /// When --retry-deleted last looked it up, likewise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_checked: Option<u64>,
+}
+
+/// The posts that would have been archived, but had been deleted, so that
+/// --retry-deleted can look for them again later without reading every page.
+pub struct DeletedPosts {
+    path: PathBuf,
+    entries: BTreeMap<u64, Entry>,
+    changed: bool,
+}
+
+impl DeletedPosts {
+    /// Loads the list at `path`, or starts an empty one if there isn't one yet.
+    pub fn load(path: &Path) -> std::io::Result<DeletedPosts> {
+        let entries = match File::open(path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(DeletedPosts {
+            path: path.to_owned(),
+            entries,
+            changed: false,
+        })
+    }
+
+    /// Notes `post` as deleted, if it is and isn't noted already.
+    pub fn record(&mut self, post: &Post) {
+        if post.file.url.is_some() || !post.flags.deleted || self.entries.contains_key(&post.id) {
+            return;
+        }
+        self.entries.insert(
+            post.id,
+            Entry {
+                first_seen: seconds(SystemTime::now()),
+                last_checked: None,
+            },
+        );
+        self.changed = true;
+    }
+
+    /// The posts that haven't been looked up in the last `interval`, those
+    /// looked up longest ago first, and at most `limit` of them.
+    pub fn due(&self, interval: Duration, limit: Option<usize>) -> Vec<u64> {
+        let cutoff = seconds(SystemTime::now()).saturating_sub(interval.as_secs());
+        let mut due: Vec<(u64, u64)> = self
+            .entries
+            .iter()
+            .map(|(&id, entry)| (entry.last_checked.unwrap_or(0), id))
+            .filter(|&(checked, _)| checked <= cutoff)
+            .collect();
+        due.sort_unstable();
+        due.into_iter()
+            .map(|(_, id)| id)
+            .take(limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    /// Notes that `id` was just looked up, and is still deleted.
+    pub fn checked(&mut self, id: u64) {
+        if let Some(entry) = self.entries.get_mut(&id) {
+            entry.last_checked = Some(seconds(SystemTime::now()));
+            self.changed = true;
+        }
+    }
+
+    /// Forgets `id`, once it's archived or gone for good.
+    pub fn remove(&mut self, id: u64) {
+        self.changed |= self.entries.remove(&id).is_some();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Saves the list, if anything changed since it was loaded.
+    pub fn save(&mut self) -> std::io::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let file = BufWriter::new(File::create(&self.path)?);
+        serde_json::to_writer(file, &self.entries)?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
@@ -0,0 +1,163 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::shutdown::{Shutdown, StopReason};
+use crate::units::format_size;
+use clap::ValueEnum;
+use log::{info, warn};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+
+// How often free space is checked while downloading, and how often a paused
+// run looks to see whether it's been freed up.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const PAUSE_POLL: Duration = Duration::from_secs(1);
+
+/// What to do when the disk is close to full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LowSpace {
+    /// Wait, without starting new downloads, until space is freed up
+    #[default]
+    Pause,
+    /// Stop the run as if interrupted, keeping its progress
+    Abort,
+}
+
+/// Keeps an eye on free space where the archive is written, for
+/// --min-free-space.
+#[derive(Clone)]
+pub struct Watchdog {
+    low: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    /// Checks `dirs` now, and then every so often in the background. When any
+    /// of them has less than `min` bytes free, downloads are held up in
+    /// [`Watchdog::wait_for_space`], or with `LowSpace::Abort`, the run is
+    /// stopped. Fails if free space can't be found out at all.
+    pub async fn start(
+        dirs: Vec<PathBuf>,
+        min: u64,
+        action: LowSpace,
+        shutdown: &Shutdown,
+    ) -> io::Result<Watchdog> {
+        let watchdog = Watchdog {
+            low: Arc::new(AtomicBool::new(false)),
+        };
+        watchdog.update(&dirs, min, action, shutdown).await?;
+        let (background, shutdown) = (watchdog.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            while shutdown.reason().is_none() {
+                tokio::time::sleep(CHECK_INTERVAL).await;
+                if let Err(e) = background.update(&dirs, min, action, &shutdown).await {
+                    warn!("Could not check free space: {}", e);
+                }
+            }
+        });
+        Ok(watchdog)
+    }
+
+    async fn update(
+        &self,
+        dirs: &[PathBuf],
+        min: u64,
+        action: LowSpace,
+        shutdown: &Shutdown,
+    ) -> io::Result<()> {
+        let mut lowest: Option<(u64, &Path)> = None;
+        for dir in dirs {
+            let free = available(dir).await?;
+            if lowest.is_none_or(|(least, _)| free < least) {
+                lowest = Some((free, dir));
+            }
+        }
+        let Some((free, dir)) = lowest.filter(|(free, _)| *free < min) else {
+            if self.low.swap(false, Ordering::Relaxed) {
+                info!("There's enough free space again, so downloads carry on");
+            }
+            return Ok(());
+        };
+        if self.low.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        match action {
+            LowSpace::Pause => warn!(
+                "Only {} free on {}, less than --min-free-space; pausing downloads until there's more",
+                format_size(free),
+                dir.display()
+            ),
+            LowSpace::Abort => {
+                warn!(
+                    "Only {} free on {}, less than --min-free-space; finishing in-flight work",
+                    format_size(free),
+                    dir.display()
+                );
+                shutdown.trigger(StopReason::LowDiskSpace);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns once there's enough free space, or the run is stopping.
+    pub async fn wait_for_space(&self, shutdown: &Shutdown) {
+        while self.low.load(Ordering::Relaxed) && shutdown.reason().is_none() {
+            tokio::time::sleep(PAUSE_POLL).await;
+        }
+    }
+}
+
+/// The bytes free to an ordinary user on the filesystem holding `dir`, as
+/// `df` reports them; its portable output is the same on every Unix.
+pub async fn available(dir: &Path) -> io::Result<u64> {
+    let output = Command::new("df").arg("-Pk").arg(dir).output().await?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "df failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    // The filesystem's name can have spaces in it, but the available space
+    // always comes just before the capacity, which ends in "%".
+    let printed = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = printed
+        .lines()
+        .nth(1)
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect();
+    fields
+        .iter()
+        .position(|field| field.ends_with('%'))
+        .and_then(|capacity| fields.get(capacity.checked_sub(1)?))
+        .and_then(|kib| kib.parse::<u64>().ok())
+        .map(|kib| kib * 1024)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("couldn't read df's output: {:?}", printed.trim()),
+            )
+        })
+}
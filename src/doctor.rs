@@ -0,0 +1,247 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::checksum::{md5_file, ChecksumCache};
+use crate::progress::Progress;
+use crate::Post;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+// Workers take this many posts at a time from the shared queue.
+const CHUNK: usize = 8;
+
+pub enum Problem {
+    Missing,
+    Mismatch { actual: String },
+    Unreadable(std::io::Error),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "missing"),
+            Problem::Mismatch { actual } => write!(f, "MD5 mismatch (file hashes to {})", actual),
+            Problem::Unreadable(e) => write!(f, "unreadable: {}", e),
+        }
+    }
+}
+
+pub struct Finding {
+    pub id: u64,
+    pub path: PathBuf,
+    pub problem: Problem,
+}
+
+#[derive(Default)]
+pub struct Diagnosis {
+    pub checked: usize,
+    pub cached: usize,
+    pub findings: Vec<Finding>,
+}
+
+enum Outcome {
+    Healthy { md5: String, cached: bool },
+    Sick(Problem),
+}
+
+/// Checks that every post's file is where its sidecar says, and that its MD5
+/// matches, with `concurrency` threads hashing at once. Unless `force` is set,
+/// files whose checksum is cached and which haven't changed since aren't read
+/// again; the cache is updated with anything newly hashed.
+pub fn diagnose(
+    posts: &[&Post],
+    cache: &mut ChecksumCache,
+    concurrency: usize,
+    force: bool,
+) -> Diagnosis {
+    let mut diagnosis = Diagnosis::default();
+    let mut progress = Progress::new(posts.len());
+    let mut hashed = Vec::new();
+
+    let queue = Mutex::new(posts.chunks(CHUNK));
+    let (results, outcomes) = mpsc::channel();
+    let cache_ref = &*cache;
+    thread::scope(|scope| {
+        for _ in 0..concurrency.max(1) {
+            let results = results.clone();
+            let queue = &queue;
+            scope.spawn(move || loop {
+                let Some(chunk) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                for post in chunk {
+                    let _ = results.send((post, examine(post, cache_ref, force)));
+                }
+            });
+        }
+        drop(results);
+
+        for (post, outcome) in outcomes {
+            progress.tick();
+            diagnosis.checked += 1;
+            let path = post.file_path.clone().unwrap_or_default();
+            match outcome {
+                Outcome::Healthy { md5, cached } => {
+                    if cached {
+                        diagnosis.cached += 1;
+                    } else {
+                        hashed.push((path, md5));
+                    }
+                }
+                Outcome::Sick(problem) => diagnosis.findings.push(Finding {
+                    id: post.id,
+                    path,
+                    problem,
+                }),
+            }
+        }
+    });
+    progress.finish();
+
+    for (path, md5) in hashed {
+        cache.insert(&path, &md5);
+    }
+    diagnosis.findings.sort_by_key(|finding| finding.id);
+    diagnosis
+}
+
+fn examine(post: &Post, cache: &ChecksumCache, force: bool) -> Outcome {
+    let Some(path) = &post.file_path else {
+        return Outcome::Sick(Problem::Missing);
+    };
+    if !path.exists() {
+        return Outcome::Sick(Problem::Missing);
+    }
+    let cached = if force { None } else { cache.get(path) };
+    let (actual, cached) = match cached {
+        Some(md5) => (md5, true),
+        None => match md5_file(path) {
+            Ok(md5) => (md5, false),
+            Err(e) => return Outcome::Sick(Problem::Unreadable(e)),
+        },
+    };
+    // A variant, or a file changed after it was downloaded, has an MD5 of its
+    // own. One changed by a run from before that was noted has nothing to be
+    // checked against, the way --strict-md5-in-filename leaves it alone.
+    let unrecorded = post.is_altered() && post.saved_md5.lock().unwrap().is_none();
+    if post.is_saved_md5(&actual) || unrecorded {
+        Outcome::Healthy {
+            md5: actual,
+            cached,
+        }
+    } else {
+        Outcome::Sick(Problem::Mismatch { actual })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downscale::Resized;
+    use crate::testutil::{post, Scratch};
+
+    // The problems found with a post whose file as saved is `saved`, though
+    // e621's is `original`, after `change` has said what else is known.
+    fn problems(original: &[u8], saved: &[u8], change: impl FnOnce(&mut Post)) -> Vec<Problem> {
+        let scratch = Scratch::new("doctor");
+        let path = scratch.path().join("1234.png");
+        std::fs::write(&path, saved).unwrap();
+        let mut post = post(original);
+        post.file_path = Some(path);
+        change(&mut post);
+        let mut cache = ChecksumCache::load(&scratch.path().join("checksums.json")).unwrap();
+        let diagnosis = diagnose(&[&post], &mut cache, 1, true);
+        diagnosis
+            .findings
+            .into_iter()
+            .map(|finding| finding.problem)
+            .collect()
+    }
+
+    fn md5(contents: &[u8]) -> String {
+        format!("{:x}", md5::compute(contents))
+    }
+
+    #[test]
+    fn passes_e621s_file() {
+        assert!(problems(b"a picture", b"a picture", |_| {}).is_empty());
+    }
+
+    #[test]
+    fn finds_a_file_that_isnt_e621s() {
+        let found = problems(b"a picture", b"a pixture", |_| {});
+        assert!(matches!(found[..], [Problem::Mismatch { .. }]));
+    }
+
+    #[test]
+    fn passes_a_file_with_the_md5_it_was_saved_with() {
+        let found = problems(b"a picture", b"a smaller picture", |post| {
+            post.resized = Some(Resized {
+                width: 1,
+                height: 1,
+                original_width: 2,
+                original_height: 2,
+            });
+            *post.saved_md5.lock().unwrap() = Some(md5(b"a smaller picture"));
+        });
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn finds_a_changed_file_that_changed_again() {
+        let found = problems(b"a picture", b"a smaller pixture", |post| {
+            *post.saved_md5.lock().unwrap() = Some(md5(b"a smaller picture"));
+        });
+        assert!(matches!(found[..], [Problem::Mismatch { .. }]));
+    }
+
+    #[test]
+    fn passes_a_changed_file_from_before_md5s_were_noted() {
+        let found = problems(b"a picture", b"a smaller picture", |post| {
+            post.resized = Some(Resized {
+                width: 1,
+                height: 1,
+                original_width: 2,
+                original_height: 2,
+            });
+        });
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn finds_a_missing_file() {
+        let scratch = Scratch::new("doctor");
+        let mut post = post(b"a picture");
+        post.file_path = Some(scratch.path().join("1234.png"));
+        let mut cache = ChecksumCache::load(&scratch.path().join("checksums.json")).unwrap();
+        let diagnosis = diagnose(&[&post], &mut cache, 1, true);
+        assert!(matches!(
+            diagnosis.findings[..],
+            [Finding {
+                problem: Problem::Missing,
+                ..
+            }]
+        ));
+    }
+}
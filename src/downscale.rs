@@ -0,0 +1,84 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use serde::{Deserialize, Serialize};
+use std::fs::rename;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Command;
+
+// What ffmpeg can scale and save in the same format. GIFs are left alone,
+// since scaling loses their palette and often their animation.
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp"];
+
+/// The size an image was scaled down to, for --max-dimension, and the size it
+/// was on e621.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resized {
+    pub width: u32,
+    pub height: u32,
+    pub original_width: u32,
+    pub original_height: u32,
+}
+
+/// The size to scale an image of this extension down to so that neither
+/// side is longer than `max`, keeping its shape; None if it fits already or
+/// isn't an image.
+pub fn fit(ext: &str, width: u32, height: u32, max: u32) -> Option<Resized> {
+    let longest = width.max(height);
+    if longest <= max || !IMAGE_EXTENSIONS.contains(&ext) {
+        return None;
+    }
+    let scale =
+        |side: u32| ((side as u64 * max as u64 + longest as u64 / 2) / longest as u64).max(1);
+    Some(Resized {
+        width: scale(width) as u32,
+        height: scale(height) as u32,
+        original_width: width,
+        original_height: height,
+    })
+}
+
+/// Scales the image at `path` down to the size in `resized`, in place.
+pub async fn downscale(path: &Path, resized: &Resized) -> std::io::Result<()> {
+    // ffmpeg picks the format from the extension, so it has to stay last.
+    let ext = path.extension().unwrap_or_default();
+    let scaled = path.with_extension(Path::new("resized").with_extension(ext));
+    let output = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-i"])
+        .arg(path)
+        .arg("-vf")
+        .arg(format!("scale={}:{}", resized.width, resized.height))
+        .args(["-q:v", "2"])
+        .arg(&scaled)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&scaled);
+        return Err(std::io::Error::other(format!(
+            "ffmpeg failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    rename(&scaled, path)
+}
@@ -0,0 +1,128 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::checksum::{md5_file, ChecksumCache};
+use crate::md5names::named_md5;
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{read_dir, Metadata};
+use std::path::{Path, PathBuf};
+
+/// Files under the archive that have the same contents, for --dedupe-report.
+pub struct Group {
+    pub md5: String,
+    pub size: u64,
+    pub paths: Vec<PathBuf>,
+}
+
+/// Finds every file under `root` that has the same contents as another,
+/// leaving out anything under `skip` and hidden files, like monosodium's own.
+/// Files named after their MD5, as e621 names them or with a place in a pool
+/// in front, are taken at their word so long as the sizes agree too, which keeps a video's poster apart from
+/// the video; any others are hashed, with `cache` saving the work next time.
+/// Hard links to a file already seen aren't counted again, since removing
+/// them wouldn't free anything.
+pub fn find(root: &Path, skip: &[&Path], cache: &mut ChecksumCache) -> std::io::Result<Vec<Group>> {
+    let mut files: BTreeMap<(String, u64), Vec<PathBuf>> = BTreeMap::new();
+    let mut seen = BTreeSet::new();
+    let mut pending = vec![root.to_owned()];
+    while let Some(dir) = pending.pop() {
+        for entry in read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if hidden || skip.contains(&path.as_path()) {
+                continue;
+            }
+            let kind = entry.file_type()?;
+            if kind.is_dir() {
+                pending.push(path);
+                continue;
+            } else if !kind.is_file() {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if file_id(&metadata).is_some_and(|id| !seen.insert(id)) {
+                continue;
+            }
+            let md5 = match named_md5(&path).or_else(|| cache.get(&path)) {
+                Some(md5) => md5,
+                None => match md5_file(&path) {
+                    Ok(md5) => {
+                        cache.insert(&path, &md5);
+                        md5
+                    }
+                    Err(e) => {
+                        warn!("Skipping {:?}: {}", path, e);
+                        continue;
+                    }
+                },
+            };
+            files.entry((md5, metadata.len())).or_default().push(path);
+        }
+    }
+    Ok(files
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|((md5, size), mut paths)| {
+            paths.sort();
+            Group { md5, size, paths }
+        })
+        .collect())
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::Scratch;
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    fn finds_a_pool_copy_by_its_name() {
+        let scratch = Scratch::new("duplicates");
+        let root = scratch.path();
+        let md5 = format!("{:x}", md5::compute(b"a picture"));
+        create_dir_all(root.join("pools/comic")).unwrap();
+        write(root.join(format!("{}.png", md5)), b"a picture").unwrap();
+        write(
+            root.join(format!("pools/comic/007_{}.png", md5)),
+            b"a picture",
+        )
+        .unwrap();
+        let mut cache = ChecksumCache::load(&root.join(".checksums.json")).unwrap();
+        let groups = find(root, &[], &mut cache).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].md5, md5);
+        assert_eq!(groups[0].paths.len(), 2);
+    }
+}
@@ -0,0 +1,175 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum MonosodiumError {
+    Http(reqwest::Error),
+    Io(std::io::Error),
+    InvalidQuery(String),
+    InvalidOptions(String),
+    NotWritable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Locked {
+        path: PathBuf,
+        holder: Option<u32>,
+    },
+    TooManyFailures {
+        failed: usize,
+        attempted: usize,
+    },
+    Incomplete {
+        expected: u64,
+        received: u64,
+    },
+    // A whole file, but not the one e621 says it is, for --verify-on-download
+    WrongMd5 {
+        expected: String,
+        actual: String,
+    },
+    SelfTestFailed {
+        failed: usize,
+    },
+    // Posts that aren't deleted, but came without a file to download, for
+    // --fail-on-missing-url
+    MissingUrls {
+        count: usize,
+        logged_in: bool,
+    },
+    // e621 answered, but with a reason instead of what was asked for
+    Refused {
+        status: u16,
+        reason: String,
+    },
+}
+
+impl fmt::Display for MonosodiumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MonosodiumError::Http(e) => write!(f, "{}", e),
+            MonosodiumError::Io(e) => write!(f, "{}", e),
+            MonosodiumError::InvalidQuery(reason) => write!(f, "{}", reason),
+            MonosodiumError::InvalidOptions(reason) => write!(f, "{}", reason),
+            MonosodiumError::NotWritable { path, source } => {
+                write!(
+                    f,
+                    "directory {} is not writable: {}",
+                    path.display(),
+                    source
+                )
+            }
+            MonosodiumError::Locked { path, holder } => {
+                write!(f, "another monosodium")?;
+                if let Some(pid) = holder {
+                    write!(f, " (process {})", pid)?;
+                }
+                write!(
+                    f,
+                    " is already using {}; if it isn't, pass --force-unlock",
+                    path.display()
+                )
+            }
+            MonosodiumError::Incomplete { expected, received } if received > expected => {
+                write!(
+                    f,
+                    "the server sent {} bytes for a file of {}",
+                    received, expected
+                )
+            }
+            MonosodiumError::Incomplete { expected, received } => {
+                write!(
+                    f,
+                    "the server sent {} of the file's {} bytes",
+                    received, expected
+                )
+            }
+            MonosodiumError::WrongMd5 { expected, actual } => {
+                write!(
+                    f,
+                    "the server sent a file with MD5 {}, not {}",
+                    actual, expected
+                )
+            }
+            MonosodiumError::TooManyFailures { failed, attempted } => {
+                write!(
+                    f,
+                    "{} of {} downloads failed, more than --max-file-failures allows",
+                    failed, attempted
+                )
+            }
+            MonosodiumError::Refused { status, reason } => {
+                write!(f, "e621 turned down the request ({}): {}", status, reason)
+            }
+            MonosodiumError::SelfTestFailed { failed } => {
+                write!(f, "{} self-test checks failed", failed)
+            }
+            MonosodiumError::MissingUrls { count, logged_in } => {
+                write!(
+                    f,
+                    "{} posts that aren't deleted had no file to download",
+                    count
+                )?;
+                if !logged_in {
+                    write!(
+                        f,
+                        "; e621 only gives some files to users who are logged in, \
+                         so try --username and --api-key"
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl MonosodiumError {
+    /// A rough class of error, for logs meant for machines.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MonosodiumError::Http(e) if e.status().is_some() => "http",
+            MonosodiumError::Http(_) => "network",
+            MonosodiumError::Io(_) | MonosodiumError::NotWritable { .. } => "io",
+            MonosodiumError::Incomplete { .. } => "incomplete",
+            MonosodiumError::WrongMd5 { .. } => "corrupt",
+            MonosodiumError::Refused { .. } => "http",
+            _ => "other",
+        }
+    }
+}
+
+impl std::error::Error for MonosodiumError {}
+
+impl From<reqwest::Error> for MonosodiumError {
+    fn from(e: reqwest::Error) -> Self {
+        MonosodiumError::Http(e)
+    }
+}
+
+impl From<std::io::Error> for MonosodiumError {
+    fn from(e: std::io::Error) -> Self {
+        MonosodiumError::Io(e)
+    }
+}
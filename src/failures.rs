@@ -0,0 +1,92 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::appender::Appender;
+use crate::error::MonosodiumError;
+use crate::Post;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::time::SystemTime;
+
+pub const FAILURE_LOG: &str = "failures.jsonl";
+
+/// One line of the failure log.
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    post_id: u64,
+    url: Option<&'a str>,
+    /// What was being written: "file", or a kind of metadata like "sources".
+    what: &'a str,
+    /// A rough class of error, like "http", "network" or "io".
+    kind: &'static str,
+    /// The HTTP status, for "http" errors.
+    status: Option<u16>,
+    error: String,
+    /// How many times it was retried before giving up.
+    retries: u32,
+}
+
+/// A record of everything that failed, one JSON object per line, kept in the
+/// output directory across runs.
+pub struct FailureLog {
+    appender: Appender,
+}
+
+impl FailureLog {
+    /// Opens the log in `directory`, adding to it, or with `truncate`,
+    /// starting it over.
+    pub fn open(directory: &Path, truncate: bool) -> std::io::Result<FailureLog> {
+        let mut options = OpenOptions::new();
+        options.create(true);
+        if truncate {
+            options.write(true).truncate(true);
+        } else {
+            options.append(true);
+        }
+        let path = directory.join(FAILURE_LOG);
+        Ok(FailureLog {
+            appender: Appender::new(options.open(&path)?, &path)?,
+        })
+    }
+
+    pub fn record(&self, post: &Post, what: &str, e: &MonosodiumError, retries: u32) {
+        let status = match e {
+            MonosodiumError::Http(e) => e.status().map(|status| status.as_u16()),
+            _ => None,
+        };
+        let record = Record {
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            post_id: post.id,
+            url: post.file.url.as_deref(),
+            what,
+            kind: e.kind(),
+            status,
+            error: e.to_string(),
+            retries,
+        };
+        let mut line = serde_json::to_vec(&record).unwrap();
+        line.push(b'\n');
+        self.appender.append(line);
+    }
+}
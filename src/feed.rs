@@ -0,0 +1,380 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use crate::client::Client;
+use crate::error::MonosodiumError;
+use crate::ApiResponse;
+use serde_json::{json, Value};
+
+/// An Atom feed of posts, read in place of the JSON API when that can't be
+/// reached. `{page}` in the URL is replaced with the page number; a URL
+/// without it is a single page.
+///
+/// A feed entry says much less than the API does, so posts read from one
+/// have only an id, dates, an image, and whatever tags are given as
+/// categories, all of them filed as general tags. Sizes and dimensions are
+/// unknown, and the image is whichever one the entry links to, which is often
+/// a sample rather than the original.
+pub struct Feed {
+    url: String,
+}
+
+impl Feed {
+    pub fn new(url: String) -> Feed {
+        Feed { url }
+    }
+
+    pub async fn fetch_page(
+        &self,
+        client: &Client,
+        page: usize,
+    ) -> Result<ApiResponse, MonosodiumError> {
+        let posts: Vec<Value> = if self.url.contains("{page}") || page == 1 {
+            let url = self.url.replace("{page}", &page.to_string());
+            let body = client.get(&url).await?.error_for_status()?.text().await?;
+            elements(&body, "entry")
+                .into_iter()
+                .filter_map(entry_to_post)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        // Through text, since posts keep the JSON they were read from.
+        let json = json!({ "posts": posts }).to_string();
+        Ok(serde_json::from_str(&json).map_err(std::io::Error::from)?)
+    }
+}
+
+// A post as the API would have given it, as far as the entry says.
+fn entry_to_post(entry: &str) -> Option<Value> {
+    let id = links(entry)
+        .into_iter()
+        .chain(elements(entry, "id").into_iter().map(text))
+        .find_map(|link| post_id(&link))?;
+    let updated = elements(entry, "updated")
+        .first()
+        .map(|updated| text(updated));
+    let created = elements(entry, "published")
+        .first()
+        .map(|published| text(published))
+        .or_else(|| updated.clone())
+        .unwrap_or_default();
+    let updated = updated.unwrap_or_else(|| created.clone());
+
+    let mut rating = String::new();
+    let mut general = Vec::new();
+    for term in tags(entry, "category")
+        .iter()
+        .filter_map(|tag| attribute(tag, "term"))
+    {
+        match term.strip_prefix("rating:") {
+            Some(r) => rating = r.to_owned(),
+            None => general.push(term),
+        }
+    }
+
+    let url = image_url(entry);
+    let name = url
+        .as_deref()
+        .and_then(|url| url.rsplit('/').next())
+        .map(|name| name.split(['?', '#']).next().unwrap_or(name))
+        .unwrap_or_default();
+    let (md5, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+
+    Some(json!({
+        "id": id,
+        "created_at": created,
+        "updated_at": updated,
+        "file": {
+            "width": 0,
+            "height": 0,
+            "ext": ext.to_lowercase(),
+            "size": 0,
+            "md5": md5,
+            "url": url,
+        },
+        "tags": {
+            "general": general,
+            "species": [],
+            "character": [],
+            "copyright": [],
+            "artist": [],
+            "invalid": [],
+            "lore": [],
+            "meta": [],
+        },
+        "rating": rating,
+        "flags": { "pending": false, "flagged": false, "deleted": false },
+    }))
+}
+
+// The number after "/posts/", in a link or an id like
+// "tag:e621.net,2005:/posts/12345".
+fn post_id(link: &str) -> Option<u64> {
+    let (_, rest) = link.rsplit_once("/posts/")?;
+    let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+    digits.parse().ok()
+}
+
+fn links(entry: &str) -> Vec<String> {
+    tags(entry, "link")
+        .iter()
+        .filter_map(|tag| attribute(tag, "href"))
+        .collect()
+}
+
+// Media elements and enclosures first, then the first image in the
+// entry's HTML.
+fn image_url(entry: &str) -> Option<String> {
+    let media = tags(entry, "media:content")
+        .iter()
+        .chain(&tags(entry, "media:thumbnail"))
+        .find_map(|tag| attribute(tag, "url"));
+    let enclosure = || {
+        tags(entry, "link")
+            .iter()
+            .filter(|tag| attribute(tag, "rel").as_deref() == Some("enclosure"))
+            .find_map(|tag| attribute(tag, "href"))
+    };
+    let in_content = || {
+        let html = text(elements(entry, "content").first()?);
+        let (_, rest) = html.split_once("<img")?;
+        let tag = &rest[..rest.find('>')?];
+        attribute(tag, "src")
+    };
+    media.or_else(enclosure).or_else(in_content)
+}
+
+// The inside of each `<name ...>...</name>` in `xml`.
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let close = format!("</{}>", name);
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = find_tag(rest, name) {
+        let after = &rest[start..];
+        let Some(open_end) = after.find('>') else {
+            break;
+        };
+        if after[..open_end].ends_with('/') {
+            found.push("");
+            rest = &after[open_end + 1..];
+            continue;
+        }
+        let inside = &after[open_end + 1..];
+        let Some(end) = inside.find(&close) else {
+            break;
+        };
+        found.push(&inside[..end]);
+        rest = &inside[end + close.len()..];
+    }
+    found
+}
+
+// The opening tags `<name ...>`, attributes and all, without the brackets.
+fn tags<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = find_tag(rest, name) {
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('>') else {
+            break;
+        };
+        found.push(after[..end].trim_end_matches('/'));
+        rest = &after[end..];
+    }
+    found
+}
+
+// Where the next tag called exactly `name` starts.
+fn find_tag(xml: &str, name: &str) -> Option<usize> {
+    let open = format!("<{}", name);
+    let mut offset = 0;
+    while let Some(i) = xml[offset..].find(&open) {
+        let start = offset + i;
+        let next = xml[start + open.len()..].chars().next();
+        if matches!(next, Some(c) if c.is_whitespace() || c == '>' || c == '/') {
+            return Some(start);
+        }
+        offset = start + open.len();
+    }
+    None
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    loop {
+        let i = rest.find(name)?;
+        let before = rest[..i].chars().next_back();
+        let after = rest[i + name.len()..].trim_start();
+        rest = &rest[i + name.len()..];
+        if !matches!(before, Some(c) if c.is_whitespace()) {
+            continue;
+        }
+        let Some(value) = after.strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let quote = value.chars().next()?;
+        if quote != '"' && quote != '\'' {
+            return None;
+        }
+        let value = &value[1..];
+        return Some(unescape(&value[..value.find(quote)?]));
+    }
+}
+
+// An element's text, which may be escaped or in a CDATA section.
+fn text(inside: &str) -> String {
+    let inside = inside.trim();
+    match inside
+        .strip_prefix("<![CDATA[")
+        .and_then(|cdata| cdata.strip_suffix("]]>"))
+    {
+        Some(cdata) => cdata.to_owned(),
+        None => unescape(inside),
+    }
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        unescaped.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{client, serve};
+
+    const FEED: &[u8] = br#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:media="http://search.yahoo.com/mrss/">
+  <title>e621 / fox</title>
+  <entry>
+    <title>fox &amp; wolf</title>
+    <id>tag:e621.net,2005:/posts/12345</id>
+    <link rel="alternate" href="https://e621.net/posts/12345?q=fox&amp;page=2"/>
+    <published>2023-05-01T12:34:56-04:00</published>
+    <updated>2023-05-02T00:00:00-04:00</updated>
+    <category term="rating:e"/>
+    <category term="fox"/>
+    <category term='black_&amp;_white'/>
+    <media:content url="https://static1.e621.net/data/ab/cd/0123456789abcdef0123456789abcdef.PNG?x=1&amp;y=2"/>
+  </entry>
+  <entry>
+    <title>just a link</title>
+    <link href="https://e621.net/posts/678"/>
+    <updated>2023-05-03T00:00:00Z</updated>
+    <content type="html">&lt;p&gt;&lt;img src=&quot;https://static1.e621.net/data/sample/fedcba9876543210fedcba9876543210.jpg&quot;&gt;&lt;/p&gt;</content>
+  </entry>
+  <entry>
+    <title>not a post</title>
+    <link href="https://e621.net/wiki_pages/fox"/>
+  </entry>
+</feed>
+"#;
+
+    #[tokio::test]
+    async fn reads_the_posts_in_a_feed() {
+        let feed = Feed::new(serve(FEED).await);
+        let posts = feed.fetch_page(&client(), 1).await.unwrap().posts;
+        assert_eq!(posts.len(), 2);
+
+        let post = &posts[0];
+        assert_eq!(post.id, 12345);
+        assert_eq!(post.created_at, "2023-05-01T12:34:56-04:00");
+        assert_eq!(post.updated_at, "2023-05-02T00:00:00-04:00");
+        assert_eq!(post.rating, "e");
+        assert_eq!(post.tags.general, ["fox", "black_&_white"]);
+        assert_eq!(post.file.md5, "0123456789abcdef0123456789abcdef");
+        assert_eq!(post.file.ext, "png");
+        assert_eq!(
+            post.file.url.as_deref(),
+            Some(
+                "https://static1.e621.net/data/ab/cd/0123456789abcdef0123456789abcdef.PNG?x=1&y=2"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn fills_in_what_an_entry_leaves_out() {
+        let feed = Feed::new(serve(FEED).await);
+        let posts = feed.fetch_page(&client(), 1).await.unwrap().posts;
+        let post = &posts[1];
+        assert_eq!(post.id, 678);
+        assert_eq!(post.created_at, "2023-05-03T00:00:00Z");
+        assert_eq!(post.updated_at, post.created_at);
+        assert_eq!(post.rating, "");
+        assert!(post.tags.general.is_empty());
+        assert_eq!(post.file.md5, "fedcba9876543210fedcba9876543210");
+        assert_eq!(post.file.ext, "jpg");
+    }
+
+    #[tokio::test]
+    async fn a_url_without_a_page_is_a_single_page() {
+        let feed = Feed::new(serve(FEED).await);
+        assert!(feed
+            .fetch_page(&client(), 2)
+            .await
+            .unwrap()
+            .posts
+            .is_empty());
+    }
+
+    #[test]
+    fn unescapes_entities() {
+        assert_eq!(unescape("a &lt;b&gt; &amp;&quot;c&apos;"), "a <b> &\"c'");
+        assert_eq!(unescape("&#233;t&#xE9;"), "été");
+        assert_eq!(unescape("fish &chips; & more"), "fish &chips; & more");
+        assert_eq!(text("<![CDATA[a &amp; b]]>"), "a &amp; b");
+    }
+}
@@ -0,0 +1,395 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::blacklist::Blacklist;
+use crate::search::Query;
+use crate::{Opts, Post};
+use clap::ValueEnum;
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::task::JoinSet;
+
+// How far from 1:1 an image can be and still count as square.
+const SQUARE_TOLERANCE: f64 = 1.05;
+
+/// Which shapes of image to keep, by width divided by height.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aspect {
+    Landscape,
+    Portrait,
+    Square,
+    Range { min: Option<f64>, max: Option<f64> },
+}
+
+impl Aspect {
+    fn matches(&self, width: u32, height: u32) -> bool {
+        if width == 0 || height == 0 {
+            return false;
+        }
+        let ratio = width as f64 / height as f64;
+        match *self {
+            Aspect::Landscape => ratio > SQUARE_TOLERANCE,
+            Aspect::Portrait => ratio < 1.0 / SQUARE_TOLERANCE,
+            Aspect::Square => (1.0 / SQUARE_TOLERANCE..=SQUARE_TOLERANCE).contains(&ratio),
+            Aspect::Range { min, max } => {
+                min.is_none_or(|min| ratio >= min) && max.is_none_or(|max| ratio <= max)
+            }
+        }
+    }
+}
+
+impl FromStr for Aspect {
+    type Err = String;
+
+    /// Either a named shape, or a `MIN-MAX` range of ratios where either end
+    /// may be left off, like `1.5-2.5` or `-0.8`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "landscape" => return Ok(Aspect::Landscape),
+            "portrait" => return Ok(Aspect::Portrait),
+            "square" => return Ok(Aspect::Square),
+            _ => {}
+        }
+        let expected = || {
+            format!(
+                "expected landscape, portrait, square or a MIN-MAX ratio range, got {:?}",
+                s
+            )
+        };
+        let (min, max) = s.split_once('-').ok_or_else(expected)?;
+        let bound = |b: &str| match b.trim() {
+            "" => Ok(None),
+            b => b
+                .parse::<f64>()
+                .ok()
+                .filter(|ratio| ratio.is_finite() && *ratio > 0.0)
+                .map(Some)
+                .ok_or_else(expected),
+        };
+        let (min, max) = (bound(min)?, bound(max)?);
+        match (min, max) {
+            (None, None) => Err(expected()),
+            (Some(min), Some(max)) if min > max => Err(format!(
+                "the range {:?} has its minimum above its maximum",
+                s
+            )),
+            _ => Ok(Aspect::Range { min, max }),
+        }
+    }
+}
+
+/// Whether a post needs every one of the --include-tags, or just one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TagsMatch {
+    /// Keep posts with every one of the tags
+    #[default]
+    All,
+    /// Keep posts with at least one of the tags
+    Any,
+}
+
+/// Why a video longer than --max-duration-seconds was skipped, whether that
+/// was known before it was downloaded or only after.
+pub const TOO_LONG: &str = "longer than --max-duration-seconds";
+
+/// The client-side checks a post has to pass before it's downloaded.
+pub struct Filters {
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    min_pixels: Option<u64>,
+    min_fav_count: Option<u32>,
+    min_comment_count: Option<u32>,
+    max_duration: Option<u32>,
+    aspect: Option<Aspect>,
+    include_tags: Vec<String>,
+    tags_match: TagsMatch,
+    query: Option<Query>,
+    sample: Option<HashSet<u64>>,
+    listed: Option<HashSet<u64>>,
+    excluded_md5s: HashSet<String>,
+    blacklist: Blacklist,
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+    script: Option<Script>,
+}
+
+impl Filters {
+    pub fn new(opts: &Opts, query: Option<&Query>) -> Filters {
+        Filters {
+            min_width: opts.min_width,
+            min_height: opts.min_height,
+            min_pixels: opts.min_pixels,
+            min_fav_count: opts.min_fav_count,
+            min_comment_count: opts.min_comment_count,
+            max_duration: opts.max_duration_seconds,
+            aspect: opts.aspect,
+            include_tags: opts
+                .include_tags
+                .iter()
+                .flat_map(|tags| tags.split_whitespace())
+                .map(str::to_lowercase)
+                .collect(),
+            tags_match: opts.tags_match,
+            query: query.cloned(),
+            sample: None,
+            listed: None,
+            excluded_md5s: HashSet::new(),
+            blacklist: Blacklist::default(),
+            since: opts.since,
+            until: opts.until,
+            script: opts
+                .filter_script
+                .as_ref()
+                .map(|path| Script::new(path, opts.filter_script_concurrency)),
+        }
+    }
+
+    /// Never keeps posts whose file has one of these MD5s.
+    pub fn exclude_md5s(&mut self, md5s: HashSet<String>) {
+        self.excluded_md5s = md5s;
+    }
+
+    /// Never keeps posts that match any of `blacklist`'s rules.
+    pub fn blacklist(&mut self, blacklist: Blacklist) {
+        self.blacklist = blacklist;
+    }
+
+    /// Keeps only the posts in --download-order-file.
+    pub fn only_listed(&mut self, ids: HashSet<u64>) {
+        self.listed = Some(ids);
+    }
+
+    /// Keeps only the posts with these ids, from here on.
+    pub fn restrict_to(&mut self, sample: HashSet<u64>) {
+        self.sample = Some(sample);
+    }
+
+    /// Asks the --filter-script about each of `posts` that passes the other
+    /// checks and hasn't been asked about already, so that `reject` knows
+    /// what it said.
+    pub async fn screen(&self, posts: &[Post]) -> std::io::Result<()> {
+        match &self.script {
+            Some(script) => {
+                let undecided = posts
+                    .iter()
+                    .filter(|post| self.check(post).is_none() && !script.has_decided(post.id));
+                script.decide(undecided).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Why the post should be skipped, or `None` if it should be kept.
+    pub fn reject(&self, post: &Post) -> Option<&'static str> {
+        self.check(post).or_else(|| {
+            // Posts that haven't been screened are given the benefit of the
+            // doubt.
+            self.script
+                .as_ref()
+                .filter(|script| script.decision(post.id) == Some(false))
+                .map(|_| "rejected by --filter-script")
+        })
+    }
+
+    fn check(&self, post: &Post) -> Option<&'static str> {
+        let file = &post.file;
+        if self.excluded_md5s.contains(&file.md5.to_ascii_lowercase()) {
+            return Some("listed in --exclude-md5-file");
+        }
+        if self.since.is_some_and(|since| post.is_older_than(since)) {
+            return Some("uploaded before --since");
+        }
+        if self.until.is_some_and(|until| post.is_newer_than(until)) {
+            return Some("uploaded after --until");
+        }
+        if self.min_width.is_some_and(|min| file.width < min) {
+            return Some("narrower than --min-width");
+        }
+        if self.min_height.is_some_and(|min| file.height < min) {
+            return Some("shorter than --min-height");
+        }
+        if self
+            .min_pixels
+            .is_some_and(|min| (file.width as u64) * (file.height as u64) < min)
+        {
+            return Some("fewer pixels than --min-pixels");
+        }
+        // Posts whose count is missing are given the benefit of the doubt.
+        if let (Some(min), Some(favorites)) = (self.min_fav_count, post.fav_count) {
+            if favorites < min {
+                return Some("fewer favorites than --min-fav-count");
+            }
+        }
+        if let (Some(min), Some(comments)) = (self.min_comment_count, post.comment_count) {
+            if comments < min {
+                return Some("fewer comments than --min-comment-count");
+            }
+        }
+        // Videos e621 doesn't know the length of are checked once they're
+        // downloaded instead.
+        if let (Some(max), Some(duration)) = (self.max_duration, post.duration) {
+            if duration > max as f64 {
+                return Some(TOO_LONG);
+            }
+        }
+        if self
+            .aspect
+            .is_some_and(|aspect| !aspect.matches(file.width, file.height))
+        {
+            return Some("outside the --aspect range");
+        }
+        if !self.include_tags.is_empty() {
+            let has = |wanted: &String| post.tags.all().any(|tag| tag == wanted);
+            match self.tags_match {
+                TagsMatch::All if !self.include_tags.iter().all(has) => {
+                    return Some("missing some of --include-tags");
+                }
+                TagsMatch::Any if !self.include_tags.iter().any(has) => {
+                    return Some("has none of --include-tags");
+                }
+                _ => {}
+            }
+        }
+        if self
+            .query
+            .as_ref()
+            .is_some_and(|query| !query.matches_locally(post))
+        {
+            return Some("doesn't match the --tags beyond the tag limit");
+        }
+        if self.blacklist.matches(post) {
+            return Some("blacklisted");
+        }
+        if self
+            .sample
+            .as_ref()
+            .is_some_and(|sample| !sample.contains(&post.id))
+        {
+            return Some("not in the --balance-tag sample");
+        }
+        if self
+            .listed
+            .as_ref()
+            .is_some_and(|listed| !listed.contains(&post.id))
+        {
+            return Some("not in --download-order-file");
+        }
+        None
+    }
+}
+
+/// A program that decides whether to keep each post, for --filter-script. It's
+/// given the post's JSON on standard input, and the post is kept if it exits
+/// successfully. What it decided is remembered for the rest of the run.
+struct Script {
+    path: PathBuf,
+    concurrency: usize,
+    decisions: Mutex<HashMap<u64, bool>>,
+}
+
+impl Script {
+    fn new(path: &Path, concurrency: usize) -> Script {
+        Script {
+            path: path.to_owned(),
+            concurrency: concurrency.max(1),
+            decisions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn decision(&self, id: u64) -> Option<bool> {
+        self.decisions.lock().unwrap().get(&id).copied()
+    }
+
+    fn has_decided(&self, id: u64) -> bool {
+        self.decision(id).is_some()
+    }
+
+    // Runs the script for each post, with up to `concurrency` running at once.
+    async fn decide(&self, posts: impl Iterator<Item = &Post>) -> std::io::Result<()> {
+        let mut running = JoinSet::new();
+        for post in posts {
+            if running.len() >= self.concurrency {
+                self.settle(running.join_next().await)?;
+            }
+            let (path, id, json) = (self.path.clone(), post.id, serde_json::to_vec(post)?);
+            running.spawn(async move { (id, run(&path, &json).await) });
+        }
+        while let Some(joined) = running.join_next().await {
+            self.settle(Some(joined))?;
+        }
+        Ok(())
+    }
+
+    fn settle(
+        &self,
+        joined: Option<Result<(u64, std::io::Result<bool>), tokio::task::JoinError>>,
+    ) -> std::io::Result<()> {
+        let (id, kept) = joined
+            .expect("only waited on while something is running")
+            .expect("running the filter script doesn't panic");
+        let kept = kept.map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("could not run --filter-script {:?}: {}", self.path, e),
+            )
+        })?;
+        self.decisions.lock().unwrap().insert(id, kept);
+        Ok(())
+    }
+}
+
+// Whether the script keeps the post. Its own output is left out of ours, but
+// what it says on standard error is passed through.
+async fn run(path: &Path, json: &[u8]) -> std::io::Result<bool> {
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin was piped");
+    match stdin.write_all(json).await {
+        // A script that decides without reading it all is fine.
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => {}
+        written => written?,
+    }
+    drop(stdin);
+    Ok(child.wait().await?.success())
+}
+
+/// Reads a list of MD5s, one per line. Blank lines and lines starting with
+/// `#` are skipped, and anything after the hash is ignored, so the output of
+/// `md5sum` works as it is.
+pub fn read_md5_list(path: &Path) -> std::io::Result<HashSet<String>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_ascii_lowercase)
+        .collect())
+}
@@ -0,0 +1,173 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::client::Client;
+use crate::error::MonosodiumError;
+use log::{info, warn};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where the implications looked up for --expand-implications are kept,
+/// in the output directory.
+pub const CACHE_FILE: &str = ".monosodium-implications.json";
+
+// Rules change now and then, so they're looked up again after this long.
+const CACHE_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// The most implications e621 lists at once. A tag with more than this many
+// narrower tags is rare, and the rest are left out, with a warning.
+const PER_REQUEST: usize = 320;
+
+// Chains of implications longer than this are taken to be a loop.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Deserialize)]
+struct Implication {
+    antecedent_name: String,
+}
+
+// A search with no results comes back as an object rather than a list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Found {
+    Implications(Vec<Implication>),
+    None {},
+}
+
+// The tags that imply a tag directly, and when e621 was asked.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    implied_by: Vec<String>,
+    fetched: u64,
+}
+
+/// For each of `tags`, every tag that implies it, directly or through others:
+/// the narrower tags a post might have instead. The rules are e621's active
+/// implications, looked up once and kept in `cache_path` for a while.
+pub async fn narrower(
+    client: &Client,
+    tags: &[String],
+    cache_path: &Path,
+) -> Result<HashMap<String, HashSet<String>>, MonosodiumError> {
+    let mut cache = load(cache_path)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut changed = false;
+    let mut expanded = HashMap::new();
+    for tag in tags {
+        let mut found = HashSet::new();
+        let mut level = vec![tag.clone()];
+        for _ in 0..MAX_DEPTH {
+            let mut next = Vec::new();
+            for broader in &level {
+                let fresh = cache
+                    .get(broader)
+                    .is_some_and(|entry| now.saturating_sub(entry.fetched) < CACHE_TTL.as_secs());
+                if !fresh {
+                    let implied_by = fetch(client, broader).await?;
+                    cache.insert(
+                        broader.clone(),
+                        Entry {
+                            implied_by,
+                            fetched: now,
+                        },
+                    );
+                    changed = true;
+                }
+                for narrower in &cache[broader].implied_by {
+                    if narrower != tag && found.insert(narrower.clone()) {
+                        next.push(narrower.clone());
+                    }
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            level = next;
+        }
+        info!("{} tags imply {}", found.len(), tag);
+        expanded.insert(tag.clone(), found);
+    }
+
+    if changed {
+        if let Err(e) = save(cache_path, &cache) {
+            warn!("Could not save implication cache {:?}: {}", cache_path, e);
+        }
+    }
+    Ok(expanded)
+}
+
+// The tags that imply `tag` directly.
+async fn fetch(client: &Client, tag: &str) -> Result<Vec<String>, MonosodiumError> {
+    info!("Looking up the tags that imply {}", tag);
+    let limit = PER_REQUEST.to_string();
+    let url = Url::parse_with_params(
+        "https://e621.net/tag_implications.json",
+        &[
+            ("search[consequent_name]", tag),
+            ("search[status]", "active"),
+            ("limit", limit.as_str()),
+        ],
+    )
+    .expect("the implications URL is always valid");
+    let found = client
+        .get(url.as_str())
+        .await?
+        .error_for_status()?
+        .json::<Found>()
+        .await?;
+    let implications = match found {
+        Found::Implications(implications) => implications,
+        Found::None {} => Vec::new(),
+    };
+    if implications.len() >= PER_REQUEST {
+        warn!(
+            "{} is implied by more than {} tags; only the first {} are used",
+            tag, PER_REQUEST, PER_REQUEST
+        );
+    }
+    let names: BTreeSet<String> = implications
+        .into_iter()
+        .map(|implication| implication.antecedent_name)
+        .collect();
+    Ok(names.into_iter().collect())
+}
+
+fn load(cache_path: &Path) -> std::io::Result<BTreeMap<String, Entry>> {
+    match File::open(cache_path) {
+        Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn save(cache_path: &Path, cache: &BTreeMap<String, Entry>) -> std::io::Result<()> {
+    serde_json::to_writer_pretty(File::create(cache_path)?, cache)?;
+    Ok(())
+}
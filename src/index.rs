@@ -0,0 +1,177 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::manifest::{self, WriteStrategy};
+use crate::{load_sidecars, Post};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::canonicalize;
+use std::path::{Path, PathBuf};
+
+/// A single file describing everything in the archive, so it can be searched
+/// without opening every sidecar.
+pub struct Index {
+    path: PathBuf,
+    entries: BTreeMap<u64, IndexEntry>,
+    // Which posts have each MD5, to find copies of a file.
+    by_md5: HashMap<String, Vec<u64>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexEntry {
+    pub md5: String,
+    /// Where the post's file is.
+    pub path: Option<PathBuf>,
+    /// Where the real file is, if `path` is only linked to it, by --dedupe.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canonical: Option<PathBuf>,
+    /// Other places it's linked from, by --route-mode all or --dedupe.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<PathBuf>,
+    pub rating: String,
+    pub tags: Vec<String>,
+}
+
+impl Index {
+    /// Loads the index at `path`, or starts an empty one if there isn't one
+    /// yet.
+    pub fn load(path: &Path) -> std::io::Result<Index> {
+        let entries: BTreeMap<u64, IndexEntry> = manifest::load(path)?.unwrap_or_default();
+        let mut by_md5: HashMap<String, Vec<u64>> = HashMap::new();
+        for (id, entry) in &entries {
+            by_md5.entry(entry.md5.clone()).or_default().push(*id);
+        }
+        Ok(Index {
+            path: path.to_owned(),
+            entries,
+            by_md5,
+        })
+    }
+
+    /// Builds a fresh index from the JSON sidecars in `metadata_dir`.
+    pub fn rebuild(path: &Path, metadata_dir: &Path) -> std::io::Result<Index> {
+        let mut index = Index {
+            path: path.to_owned(),
+            entries: BTreeMap::new(),
+            by_md5: HashMap::new(),
+        };
+        for post in load_sidecars(metadata_dir)? {
+            index.insert(&post, None);
+        }
+        Ok(index)
+    }
+
+    /// Adds `post`, or replaces what was there for it. `original` is the file
+    /// it was linked to, if it was; otherwise a symlink is followed to find
+    /// the real file, but a hard link can't be told from the real thing.
+    pub fn insert(&mut self, post: &Post, original: Option<&Path>) {
+        let canonical = match (original, &post.file_path) {
+            (Some(original), _) => Some(original.to_owned()),
+            (None, Some(path)) if path.is_symlink() => canonicalize(path).ok(),
+            (None, _) => None,
+        };
+        self.entries.insert(
+            post.id,
+            IndexEntry {
+                md5: post.file.md5.clone(),
+                path: post.file_path.clone(),
+                canonical: canonical.filter(|canonical| Some(canonical) != post.file_path.as_ref()),
+                links: post.link_paths.clone(),
+                rating: post.rating.clone(),
+                tags: post.tags.all().cloned().collect(),
+            },
+        );
+        let ids = self.by_md5.entry(post.file.md5.clone()).or_default();
+        if !ids.contains(&post.id) {
+            ids.push(post.id);
+        }
+    }
+
+    /// An existing file with the same contents as the post's, somewhere other
+    /// than where the post's file goes: the real file, rather than a link to
+    /// it.
+    pub fn copy_of(&self, post: &Post) -> Option<&Path> {
+        let destination = post.file_path.as_deref()?;
+        self.by_md5
+            .get(&post.file.md5)?
+            .iter()
+            .filter_map(|id| self.entries.get(id))
+            .filter(|entry| entry.md5 == post.file.md5)
+            .filter_map(|entry| entry.canonical.as_deref().or(entry.path.as_deref()))
+            .find(|path| *path != destination && path.is_file())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn save(&self, strategy: WriteStrategy) -> std::io::Result<()> {
+        manifest::save(&self.path, &self.entries, strategy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{post, Scratch};
+    use std::fs::write;
+
+    fn post_at(id: u64, path: PathBuf) -> Post {
+        let mut post = post(b"a picture");
+        post.id = id;
+        post.file_path = Some(path);
+        post
+    }
+
+    #[test]
+    fn links_to_the_real_file() {
+        let scratch = Scratch::new("index-original");
+        let real = scratch.path().join("real.png");
+        let link = scratch.path().join("link.png");
+        write(&real, b"a picture").unwrap();
+        write(&link, b"a picture").unwrap();
+        let mut index = Index::load(&scratch.path().join("index.json")).unwrap();
+        index.insert(&post_at(1, link.clone()), Some(&real));
+        assert_eq!(index.entries[&1].canonical.as_deref(), Some(real.as_path()));
+
+        let elsewhere = post_at(2, scratch.path().join("elsewhere.png"));
+        assert_eq!(index.copy_of(&elsewhere), Some(real.as_path()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_a_symlink_to_the_real_file() {
+        let scratch = Scratch::new("index-symlink");
+        let real = scratch.path().join("real.png");
+        let link = scratch.path().join("link.png");
+        write(&real, b"a picture").unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        let mut index = Index::load(&scratch.path().join("index.json")).unwrap();
+        index.insert(&post_at(1, real.clone()), None);
+        index.insert(&post_at(2, link), None);
+        assert_eq!(index.entries[&1].canonical, None);
+        assert_eq!(
+            index.entries[&2].canonical,
+            Some(canonicalize(&real).unwrap())
+        );
+    }
+}
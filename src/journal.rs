@@ -0,0 +1,212 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::appender::Appender;
+use crate::Post;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+pub const JOURNAL: &str = ".monosodium-journal.jsonl";
+
+/// One line of the journal.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Entry {
+    /// Written, and synced, before anything of the post's is.
+    Begin { post_id: u64, paths: Vec<PathBuf> },
+    /// Once the post is complete, or has been cleaned up after failing.
+    End { post_id: u64 },
+}
+
+/// Makes archiving each post all or nothing. Before a post's file and
+/// metadata are written, the paths they'll go to are noted here, and once
+/// they're all written, that's noted too; a post begun but never finished,
+/// because monosodium crashed or was killed partway, has whatever it wrote
+/// removed by [`recover`] on the next run, so it's archived again from
+/// scratch rather than left half done.
+pub struct Journal {
+    appender: Appender,
+}
+
+impl Journal {
+    /// Starts a new journal in `directory`. Anything left in the old one
+    /// should have been recovered first.
+    pub fn create(directory: &Path) -> io::Result<Journal> {
+        // Appending, so that writes after a checkpoint start from the top.
+        let path = directory.join(JOURNAL);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.set_len(0)?;
+        Ok(Journal {
+            appender: Appender::new(file, &path)?,
+        })
+    }
+
+    /// Notes that `post` is about to be written. This reaches the disk before
+    /// returning, or the note could be lost along with the files it's about.
+    pub async fn begin(&self, post: &Post) -> io::Result<()> {
+        let paths = written_paths(post);
+        self.append(&Entry::Begin {
+            post_id: post.id,
+            paths,
+        });
+        self.appender.sync().await
+    }
+
+    /// Notes that `post` is completely written.
+    pub fn commit(&self, post: &Post) {
+        self.append(&Entry::End { post_id: post.id });
+    }
+
+    /// Removes whatever was written for `post`, after it failed, so that it
+    /// isn't mistaken for archived next time.
+    pub fn roll_back(&self, post: &Post) {
+        remove_all(post.id, &written_paths(post));
+        self.commit(post);
+    }
+
+    /// Empties the journal, once everything in it is finished with.
+    pub async fn checkpoint(&self) -> io::Result<()> {
+        self.appender.truncate().await
+    }
+
+    fn append(&self, entry: &Entry) {
+        let mut line = serde_json::to_vec(entry).unwrap();
+        line.push(b'\n');
+        self.appender.append(line);
+    }
+}
+
+// Everything for a post that would mark it as archived if it were left behind
+// half written. Other metadata is simply written again along with these.
+fn written_paths(post: &Post) -> Vec<PathBuf> {
+    [&post.file_path, &post.poster_path, &post.tags_path]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect()
+}
+
+/// Undoes every post left unfinished in the journal in `directory` by an
+/// earlier run, and returns their ids. A line cut short by the crash is
+/// ignored: it can only be the last, and a `begin` is synced before anything
+/// is written, so nothing will have been.
+pub fn recover(directory: &Path) -> io::Result<Vec<u64>> {
+    let file = match File::open(directory.join(JOURNAL)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut unfinished = BTreeMap::new();
+    for line in BufReader::new(file).lines() {
+        match serde_json::from_str(&line?) {
+            Ok(Entry::Begin { post_id, paths }) => {
+                unfinished.insert(post_id, paths);
+            }
+            Ok(Entry::End { post_id }) => {
+                unfinished.remove(&post_id);
+            }
+            Err(_) => break,
+        }
+    }
+    for (post_id, paths) in &unfinished {
+        warn!(
+            "Post {} was left half archived by an earlier run; removing it to archive again",
+            post_id
+        );
+        remove_all(*post_id, paths);
+    }
+    Ok(unfinished.into_keys().collect())
+}
+
+fn remove_all(post_id: u64, paths: &[PathBuf]) {
+    for path in paths {
+        match fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => error!("Could not remove {:?} for post {}: {}", path, post_id, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{post, Scratch};
+
+    fn post_in(directory: &Path) -> Post {
+        let mut post = post(b"a picture");
+        post.file_path = Some(directory.join("1234.png"));
+        post.tags_path = Some(directory.join("1234.json"));
+        post
+    }
+
+    #[tokio::test]
+    async fn rolls_back_a_post_cut_short() {
+        let scratch = Scratch::new("journal");
+        let post = post_in(scratch.path());
+        let journal = Journal::create(scratch.path()).unwrap();
+        journal.begin(&post).await.unwrap();
+        // The file, but only some of it, and no sidecar: then the crash.
+        fs::write(post.file_path.as_ref().unwrap(), b"a pic").unwrap();
+        drop(journal);
+
+        assert_eq!(recover(scratch.path()).unwrap(), vec![1234]);
+        assert!(!post.file_path.as_ref().unwrap().exists());
+        assert!(!post.tags_path.as_ref().unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn keeps_a_post_committed() {
+        let scratch = Scratch::new("journal");
+        let post = post_in(scratch.path());
+        let journal = Journal::create(scratch.path()).unwrap();
+        journal.begin(&post).await.unwrap();
+        fs::write(post.file_path.as_ref().unwrap(), b"a picture").unwrap();
+        fs::write(post.tags_path.as_ref().unwrap(), b"{}").unwrap();
+        journal.commit(&post);
+        drop(journal);
+
+        assert!(recover(scratch.path()).unwrap().is_empty());
+        assert!(post.file_path.as_ref().unwrap().exists());
+        assert!(post.tags_path.as_ref().unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn ignores_a_line_cut_short() {
+        let scratch = Scratch::new("journal");
+        let post = post_in(scratch.path());
+        let journal = Journal::create(scratch.path()).unwrap();
+        journal.begin(&post).await.unwrap();
+        drop(journal);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(scratch.path().join(JOURNAL))
+            .unwrap();
+        io::Write::write_all(&mut file, b"{\"event\":\"begin\",\"post_id\":56").unwrap();
+
+        assert_eq!(recover(scratch.path()).unwrap(), vec![1234]);
+    }
+}
@@ -0,0 +1,121 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::notify::status;
+use crate::summary::{Outcome, Summary};
+use crate::Opts;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Goes up by one whenever a field is renamed or removed, or changes meaning,
+/// so that scripts can tell. New fields may be added without changing it.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Writes everything `summary` knows about the run, and how it ended, to
+/// `path` as one JSON object.
+pub fn write(
+    path: &Path,
+    opts: &Opts,
+    summary: &Summary,
+    error: Option<&str>,
+) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut file, &document(opts, summary, error))?;
+    writeln!(file)?;
+    file.flush()
+}
+
+fn document(opts: &Opts, summary: &Summary, error: Option<&str>) -> Value {
+    let elapsed = summary.elapsed();
+    let started = SystemTime::now() - elapsed;
+    json!({
+        "schema_version": SCHEMA_VERSION,
+        "monosodium_version": env!("CARGO_PKG_VERSION"),
+        "config": {
+            "arguments": arguments(),
+            "directory": opts.directory,
+        },
+        "status": status(summary, error),
+        "error": error,
+        "stopped": summary.stopped.map(|reason| reason.to_string()),
+        "timing": {
+            "started_at": humantime::format_rfc3339_seconds(started).to_string(),
+            "finished_at": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            "elapsed_seconds": elapsed.as_secs_f64(),
+        },
+        "counts": {
+            "pages": summary.pages,
+            "posts_seen": summary.posts_seen,
+            "excluded": summary.excluded_total(),
+            "already_present": summary.already_present,
+            "downloaded": summary.downloaded,
+            "deduplicated": summary.deduplicated,
+            "linked_from_library": summary.linked_from_library,
+            "metadata_refreshed": summary.metadata_refreshed,
+            "failed": summary.failures.len(),
+            "bytes": summary.bytes,
+        },
+        "excluded": summary.excluded,
+        "ratings": summary.ratings,
+        "sample": summary.sample,
+        "missing_urls": summary.missing_urls,
+        "failures": summary.failures.iter().map(|failure| json!({
+            "id": failure.id,
+            "url": failure.url,
+            "reason": failure.reason,
+        })).collect::<Vec<_>>(),
+        "posts": summary.outcomes.iter().flatten().map(|(id, outcome)| post(*id, outcome)).collect::<Vec<_>>(),
+    })
+}
+
+fn post(id: u64, outcome: &Outcome) -> Value {
+    let (outcome, reason) = match outcome {
+        Outcome::Excluded(reason) => ("excluded", Some(*reason)),
+        Outcome::AlreadyPresent => ("already_present", None),
+        Outcome::Unavailable => ("unavailable", None),
+        Outcome::Downloaded => ("downloaded", None),
+        Outcome::Deduplicated => ("deduplicated", None),
+        Outcome::LinkedFromLibrary => ("linked_from_library", None),
+        Outcome::Failed(reason) => ("failed", Some(reason.as_str())),
+    };
+    json!({ "id": id, "outcome": outcome, "reason": reason })
+}
+
+// The command line the run was started with, with the API key left out.
+fn arguments() -> Vec<String> {
+    let mut hide_next = false;
+    std::env::args()
+        .map(|arg| {
+            if std::mem::take(&mut hide_next) {
+                "<hidden>".to_owned()
+            } else if arg.starts_with("--api-key=") {
+                "--api-key=<hidden>".to_owned()
+            } else {
+                hide_next = arg == "--api-key";
+                arg
+            }
+        })
+        .collect()
+}
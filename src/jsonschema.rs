@@ -0,0 +1,375 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::compress::Compressed;
+use crate::downscale::Resized;
+use crate::pools::PoolPlace;
+use crate::reencode::Reencoded;
+use crate::sets::SetPlace;
+use crate::tagdb::ResolvedTags;
+use crate::variant::Variant;
+use crate::{FileData, Flags, Post, Preview, Score, Tags};
+use serde_json::{json, Map, Value};
+use std::path::PathBuf;
+
+/// The version of the sidecar format. It goes up whenever a field is taken
+/// away or changes meaning; fields that are only added, and optional, leave
+/// it as it is.
+pub const VERSION: u32 = 1;
+
+/// Types that can say what they look like as JSON, in JSON Schema. Objects
+/// list their fields by hand, but each field's schema comes from its type.
+pub trait Described {
+    fn schema() -> Value;
+}
+
+impl Described for u32 {
+    fn schema() -> Value {
+        json!({ "type": "integer", "minimum": 0, "maximum": u32::MAX })
+    }
+}
+
+impl Described for u64 {
+    fn schema() -> Value {
+        json!({ "type": "integer", "minimum": 0 })
+    }
+}
+
+impl Described for usize {
+    fn schema() -> Value {
+        u64::schema()
+    }
+}
+
+impl Described for i64 {
+    fn schema() -> Value {
+        json!({ "type": "integer" })
+    }
+}
+
+impl Described for f64 {
+    fn schema() -> Value {
+        json!({ "type": "number" })
+    }
+}
+
+impl Described for bool {
+    fn schema() -> Value {
+        json!({ "type": "boolean" })
+    }
+}
+
+impl Described for String {
+    fn schema() -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+impl Described for PathBuf {
+    fn schema() -> Value {
+        String::schema()
+    }
+}
+
+// Kept as the API gave it, whatever that is.
+impl Described for Value {
+    fn schema() -> Value {
+        json!({})
+    }
+}
+
+impl<T: Described> Described for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+impl<T: Described> Described for Option<T> {
+    fn schema() -> Value {
+        json!({ "anyOf": [T::schema(), { "type": "null" }] })
+    }
+}
+
+// One field of an object.
+struct Field {
+    name: &'static str,
+    schema: Value,
+    // Whether every sidecar this version writes has it.
+    required: bool,
+}
+
+// A field that's always written, though maybe as null.
+fn field<T: Described>(name: &'static str, description: &str) -> Field {
+    Field {
+        name,
+        schema: with_description(T::schema(), description),
+        required: true,
+    }
+}
+
+// A field that's left out when there's nothing to say.
+fn optional<T: Described>(name: &'static str, description: &str) -> Field {
+    Field {
+        name,
+        schema: with_description(T::schema(), description),
+        required: false,
+    }
+}
+
+fn with_description(mut schema: Value, description: &str) -> Value {
+    if let Value::Object(map) = &mut schema {
+        map.insert("description".to_owned(), description.into());
+    }
+    schema
+}
+
+fn object(fields: Vec<Field>) -> Value {
+    let required: Vec<&str> = fields
+        .iter()
+        .filter(|field| field.required)
+        .map(|field| field.name)
+        .collect();
+    let properties: Map<String, Value> = fields
+        .into_iter()
+        .map(|field| (field.name.to_owned(), field.schema))
+        .collect();
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+impl Described for Post {
+    fn schema() -> Value {
+        object(vec![
+            field::<u64>("id", "The post's id on e621"),
+            field::<String>(
+                "created_at",
+                "When it was uploaded, as an RFC 3339 timestamp",
+            ),
+            field::<String>("updated_at", "When it was last changed on e621, likewise"),
+            field::<FileData>("file", "The post's file, as e621 has it"),
+            field::<Tags>(
+                "tags",
+                "Its tags, by category, each sorted and in Unicode NFC",
+            ),
+            field::<String>("rating", "\"s\", \"q\" or \"e\""),
+            field::<Flags>("flags", "Its state on e621"),
+            field::<Score>("score", "Its votes"),
+            optional::<f64>("duration", "How long a video runs, in seconds"),
+            optional::<u32>("fav_count", "How many users have favorited it"),
+            optional::<u32>("comment_count", "How many comments it has"),
+            field::<Vec<u64>>("pools", "The ids of the pools it's in"),
+            optional::<PoolPlace>("pool", "The pool it's filed under, for --layout by-pool"),
+            optional::<SetPlace>("set", "Where it comes in the set archived with --set"),
+            field::<Vec<String>>("sources", "Where the art was originally posted"),
+            field::<Option<PathBuf>>("file_path", "Where its file was saved"),
+            field::<Option<PathBuf>>("tags_path", "Where this sidecar was saved"),
+            optional::<Vec<PathBuf>>(
+                "link_paths",
+                "Links to its file, under the other routes it matched",
+            ),
+            optional::<PathBuf>("poster_path", "A still from a video, for browsing"),
+            optional::<PathBuf>(
+                "thumbnail_path",
+                "Where the site's thumbnail of it was saved, for --download-thumbnails-too",
+            ),
+            optional::<ResolvedTags>("resolved_tags", "Its tags as --tag-db sees them"),
+            optional::<Preview>("preview", "The thumbnail the site shows in listings"),
+            optional::<Value>(
+                "sample",
+                "Smaller or differently encoded versions of the file, as the API gave them",
+            ),
+            optional::<Variant>("variant", "The version saved instead of the file"),
+            optional::<Resized>("resized", "The size the file was scaled down to"),
+            optional::<Reencoded>("reencoded", "How the video was transcoded"),
+            optional::<Compressed>("compressed", "How the image was made smaller, losslessly"),
+            optional::<String>(
+                "saved_md5",
+                "The MD5 of the file as saved, when that isn't the file e621 has",
+            ),
+            optional::<String>(
+                "supersedes",
+                "The MD5 of the file it had when last archived, which has since been replaced",
+            ),
+        ])
+    }
+}
+
+impl Described for FileData {
+    fn schema() -> Value {
+        object(vec![
+            field::<u32>("width", "In pixels"),
+            field::<u32>("height", "In pixels"),
+            field::<String>("ext", "The extension, such as \"png\" or \"webm\""),
+            field::<u32>("size", "In bytes"),
+            field::<String>("md5", "The MD5 of the file, in lower-case hex"),
+            field::<Option<String>>(
+                "url",
+                "Where it was downloaded from; null for deleted posts and some others",
+            ),
+        ])
+    }
+}
+
+impl Described for Preview {
+    fn schema() -> Value {
+        object(vec![
+            field::<u32>("width", "In pixels"),
+            field::<u32>("height", "In pixels"),
+            field::<Option<String>>("url", "Where to get it; null where the file's URL is"),
+        ])
+    }
+}
+
+impl Described for Tags {
+    fn schema() -> Value {
+        let category = |name| field::<Vec<String>>(name, "Tags in this category");
+        object(vec![
+            category("general"),
+            category("species"),
+            category("character"),
+            category("copyright"),
+            category("artist"),
+            category("invalid"),
+            category("lore"),
+            category("meta"),
+        ])
+    }
+}
+
+impl Described for Flags {
+    fn schema() -> Value {
+        object(vec![
+            field::<bool>("pending", "Waiting for approval"),
+            field::<bool>("flagged", "Flagged for deletion"),
+            field::<bool>("deleted", "Deleted"),
+        ])
+    }
+}
+
+impl Described for Score {
+    fn schema() -> Value {
+        object(vec![
+            field::<i64>("up", "Up votes"),
+            field::<i64>("down", "Down votes, as a negative number"),
+            field::<i64>("total", "The sum of the two"),
+        ])
+    }
+}
+
+impl Described for PoolPlace {
+    fn schema() -> Value {
+        object(vec![
+            field::<u64>("id", "The pool's id on e621"),
+            field::<String>("name", "The pool's name"),
+            field::<usize>(
+                "position",
+                "Where the post comes in the pool, counting from 1",
+            ),
+            field::<usize>("length", "How many posts the pool has"),
+        ])
+    }
+}
+
+impl Described for SetPlace {
+    fn schema() -> Value {
+        object(vec![
+            field::<u64>("id", "The set's id on e621"),
+            field::<String>("name", "The set's name"),
+            field::<usize>(
+                "position",
+                "Where the post comes in the set, counting from 1",
+            ),
+            field::<usize>("length", "How many posts the set has"),
+        ])
+    }
+}
+
+impl Described for ResolvedTags {
+    fn schema() -> Value {
+        object(vec![
+            field::<Vec<String>>("canonical", "Every tag, with aliases replaced"),
+            field::<Vec<String>>("implied", "Tags implied by those that it didn't have"),
+        ])
+    }
+}
+
+impl Described for Variant {
+    fn schema() -> Value {
+        object(vec![
+            field::<String>("url", "Where it was downloaded from"),
+            field::<String>("ext", "Its extension"),
+        ])
+    }
+}
+
+impl Described for Resized {
+    fn schema() -> Value {
+        object(vec![
+            field::<u32>("width", "As saved, in pixels"),
+            field::<u32>("height", "As saved, in pixels"),
+            field::<u32>("original_width", "On e621, in pixels"),
+            field::<u32>("original_height", "On e621, in pixels"),
+        ])
+    }
+}
+
+impl Described for Reencoded {
+    fn schema() -> Value {
+        object(vec![
+            field::<String>("preset", "\"h264\", \"h265\" or \"vp9\""),
+            field::<String>("ext", "Its extension, as saved"),
+            field::<String>("video_codec", "The ffmpeg encoder it was transcoded with"),
+            field::<String>(
+                "audio_codec",
+                "The ffmpeg encoder its sound was transcoded with",
+            ),
+            field::<String>("original_ext", "Its extension on e621"),
+            field::<u64>("original_size", "Its size on e621, in bytes"),
+            optional::<PathBuf>("original_path", "Where the video as downloaded was kept"),
+        ])
+    }
+}
+
+impl Described for Compressed {
+    fn schema() -> Value {
+        object(vec![
+            field::<String>("optimizer", "\"oxipng\" or \"jpegtran\""),
+            field::<u64>("original_size", "Its size as downloaded, in bytes"),
+            field::<u64>("size", "Its size as saved, in bytes"),
+            field::<String>("md5", "The MD5 of the file as saved"),
+        ])
+    }
+}
+
+/// The JSON Schema of a sidecar, for --output-json-schema.
+pub fn sidecar() -> Value {
+    let mut schema = Post::schema();
+    schema["$schema"] = "https://json-schema.org/draft/2020-12/schema".into();
+    schema["$id"] = format!("urn:monosodium:sidecar:{}", VERSION).into();
+    schema["title"] = format!("monosodium sidecar, version {}", VERSION).into();
+    schema["description"] =
+        "The metadata saved for each archived post, as metadata/<MD5>.json".into();
+    schema
+}
@@ -0,0 +1,67 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::{load_sidecars, Post};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Where the archive's sidecars say each file is, for --skip-existing-by-id,
+/// so a file archived under an old layout is still recognized.
+#[derive(Default)]
+pub struct KnownFiles {
+    by_id: HashMap<u64, (String, PathBuf)>,
+    by_md5: HashMap<String, PathBuf>,
+}
+
+impl KnownFiles {
+    pub fn load(metadata_dir: &Path) -> std::io::Result<KnownFiles> {
+        let posts = match load_sidecars(metadata_dir) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            posts => posts?,
+        };
+        let mut known = KnownFiles::default();
+        for post in posts {
+            let Some(path) = post.file_path.filter(|path| path.is_file()) else {
+                continue;
+            };
+            let md5 = post.file.md5.to_ascii_lowercase();
+            known.by_md5.entry(md5.clone()).or_insert(path.clone());
+            known.by_id.insert(post.id, (md5, path));
+        }
+        Ok(known)
+    }
+
+    /// Where the post's file already is: where this post's sidecar says it
+    /// is, if the post still has the same file, or else wherever another
+    /// sidecar says a file with the same MD5 is.
+    pub fn find(&self, post: &Post) -> Option<&Path> {
+        let md5 = post.file.md5.to_ascii_lowercase();
+        self.by_id
+            .get(&post.id)
+            .filter(|(known, _)| *known == md5)
+            .map(|(_, path)| path)
+            .or_else(|| self.by_md5.get(&md5))
+            .map(PathBuf::as_path)
+            .filter(|path| path.is_file())
+    }
+}
@@ -0,0 +1,164 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::template::PathTemplate;
+use crate::Post;
+use clap::ValueEnum;
+use std::path::PathBuf;
+
+// Entries in the artist category that aren't actually artists.
+pub const NOT_ARTISTS: &[&str] = &[
+    "avoid_posting",
+    "conditional_dnp",
+    "epilepsy_warning",
+    "sound_warning",
+];
+
+/// How downloaded files are arranged under the output directory. There is
+/// exactly one layout per run, so there's never any question about which of
+/// several options decides a post's path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputLayout {
+    /// Every file directly in the output directory
+    #[default]
+    Flat,
+    /// One subdirectory per artist
+    ByArtist,
+    /// One subdirectory per year and month the post was uploaded
+    ByDate,
+    /// Posts in a pool under pools/<name>, numbered in reading order; the
+    /// rest directly in the output directory
+    ByPool,
+}
+
+impl OutputLayout {
+    /// The --output-layout-template the layout is short for, with
+    /// `by_rating` putting it all under the rating's directory. Posts in a
+    /// pool go under pools/ and the rest don't, which a template can't say,
+    /// so by-pool has none.
+    pub fn template(&self, by_rating: bool) -> Option<PathTemplate> {
+        let template = match self {
+            OutputLayout::Flat => "{md5}.{ext}",
+            OutputLayout::ByArtist => "{artist}/{md5}.{ext}",
+            OutputLayout::ByDate => "{year}/{month}/{md5}.{ext}",
+            OutputLayout::ByPool => return None,
+        };
+        let template = match by_rating {
+            true => format!("{{rating}}/{}", template),
+            false => template.to_owned(),
+        };
+        Some(template.parse().expect("the layouts are valid templates"))
+    }
+}
+
+/// How a run decides where each post's file goes.
+#[derive(Clone, Debug)]
+pub enum Placement {
+    Template(PathTemplate),
+    /// Posts in a pool under pools/<name>, numbered in reading order; the rest
+    /// directly in the output directory, or with `by_rating`, the rating's.
+    ByPool {
+        by_rating: bool,
+    },
+}
+
+impl Placement {
+    /// The file's path relative to the output directory.
+    pub fn path(&self, post: &Post) -> PathBuf {
+        let by_rating = match self {
+            Placement::Template(template) => return template.expand(post),
+            Placement::ByPool { by_rating } => *by_rating,
+        };
+        let mut path = PathBuf::new();
+        if by_rating {
+            path.push(rating_directory(&post.rating));
+        }
+        match &post.pool {
+            // Files in a pool lead with their place in it, padded so that they
+            // sort in order.
+            Some(pool) => {
+                let width = pool.length.to_string().len().max(3);
+                path.push("pools");
+                path.push(sanitize(&pool.name));
+                path.push(format!(
+                    "{:0width$}_{}.{}",
+                    pool.position,
+                    post.file.md5,
+                    post.ext(),
+                    width = width
+                ));
+            }
+            None => path.push(format!("{}.{}", post.file.md5, post.ext())),
+        }
+        path
+    }
+
+    /// Whether posts need to be looked up in their pools to be placed.
+    pub fn uses_pools(&self) -> bool {
+        match self {
+            Placement::Template(template) => template.uses_pools(),
+            Placement::ByPool { .. } => true,
+        }
+    }
+}
+
+/// The directory for a rating, for --by-rating.
+pub fn rating_directory(rating: &str) -> &'static str {
+    match rating {
+        "s" => "safe",
+        "q" => "questionable",
+        "e" => "explicit",
+        _ => "unknown_rating",
+    }
+}
+
+pub fn primary_artist(post: &Post) -> &str {
+    post.tags
+        .artist
+        .iter()
+        .find(|artist| !NOT_ARTISTS.contains(&artist.as_str()))
+        .map_or("unknown_artist", |artist| artist.as_str())
+}
+
+// Timestamps look like "2023-01-05T12:34:56.789-05:00".
+pub fn year_and_month(created_at: &str) -> Option<(&str, &str)> {
+    let year = created_at.get(0..4)?;
+    let month = created_at.get(5..7)?;
+    let digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    (digits(year) && digits(month)).then_some((year, month))
+}
+
+/// Makes a tag safe to use as a single path segment.
+pub fn sanitize(segment: &str) -> String {
+    let cleaned: String = segment
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    match cleaned.trim_matches('.') {
+        "" => "_".to_string(),
+        _ => cleaned,
+    }
+}
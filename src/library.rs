@@ -0,0 +1,214 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::checksum::md5_file;
+use crate::error::MonosodiumError;
+use crate::jsonschema::VERSION;
+use crate::load_sidecars;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs::read_dir;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Files in an existing collection, by MD5 and extension, for
+/// --hardlink-from.
+pub struct Library {
+    by_md5: HashMap<(String, String), PathBuf>,
+}
+
+// Sidecars and notes are named after the post's MD5 too, but aren't the
+// post's file.
+fn is_metadata(path: &Path) -> bool {
+    path.parent().and_then(Path::file_name) == Some("metadata".as_ref())
+        || matches!(extension(path).as_str(), "json" | "txt")
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+impl Library {
+    /// Finds every file under `root`. Files named like e621's, after their
+    /// MD5, are taken at their word; any others are hashed, which takes a
+    /// while for a big collection. Sidecars, and anything else in a
+    /// `metadata` directory, are left out.
+    pub fn scan(root: &Path) -> std::io::Result<Library> {
+        let mut library = Library {
+            by_md5: HashMap::new(),
+        };
+        let mut pending = vec![root.to_owned()];
+        while let Some(dir) = pending.pop() {
+            for entry in read_dir(&dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                let kind = entry.file_type()?;
+                if kind.is_dir() {
+                    pending.push(path);
+                } else if kind.is_file() {
+                    library.add(path);
+                }
+            }
+        }
+        info!(
+            "Found {} files in the library at {:?}",
+            library.by_md5.len(),
+            root
+        );
+        Ok(library)
+    }
+
+    /// Finds the files of another monosodium archive from its sidecars,
+    /// without looking at every file, for --resume-from-manifest-of. Its
+    /// sidecars have to be readable as this version's; files the sidecars
+    /// mention that aren't there any more are left out.
+    pub fn from_archive(root: &Path) -> Result<Library, MonosodiumError> {
+        let metadata_dir = root.join("metadata");
+        let posts = match load_sidecars(&metadata_dir) {
+            Ok(posts) => posts,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(MonosodiumError::InvalidOptions(format!(
+                    "{:?} has no metadata directory, so it isn't a monosodium archive",
+                    root
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if posts.is_empty() {
+            return Err(MonosodiumError::InvalidOptions(format!(
+                "none of the sidecars in {:?} could be read as version {} sidecars",
+                metadata_dir, VERSION
+            )));
+        }
+        let mut library = Library {
+            by_md5: HashMap::new(),
+        };
+        for post in &posts {
+            let Some(path) = &post.file_path else {
+                continue;
+            };
+            // Sidecars record paths as they were given to that run, which
+            // may have been relative to somewhere else; the sidecar's own
+            // path says where the archive was then.
+            let then = post
+                .tags_path
+                .as_deref()
+                .and_then(Path::parent)
+                .and_then(Path::parent);
+            let path = match then.and_then(|then| path.strip_prefix(then).ok()) {
+                Some(relative) if !path.is_file() => root.join(relative),
+                _ => path.clone(),
+            };
+            if path.is_file() {
+                library
+                    .by_md5
+                    .entry((post.file.md5.to_ascii_lowercase(), extension(&path)))
+                    .or_insert(path);
+            }
+        }
+        info!(
+            "Found {} files in the archive at {:?}",
+            library.by_md5.len(),
+            root
+        );
+        Ok(library)
+    }
+
+    /// Adds the files in `other` that this doesn't have.
+    pub fn merge(&mut self, other: Library) {
+        for (md5, path) in other.by_md5 {
+            self.by_md5.entry(md5).or_insert(path);
+        }
+    }
+
+    fn add(&mut self, path: PathBuf) {
+        if is_metadata(&path) {
+            return;
+        }
+        let named = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .filter(|stem| stem.len() == 32 && stem.bytes().all(|b| b.is_ascii_hexdigit()))
+            .map(str::to_ascii_lowercase);
+        let md5 = match named {
+            Some(md5) => md5,
+            None => match md5_file(&path) {
+                Ok(md5) => md5,
+                Err(e) => {
+                    warn!("Skipping {:?} in the library: {}", path, e);
+                    return;
+                }
+            },
+        };
+        let ext = extension(&path);
+        self.by_md5.entry((md5, ext)).or_insert(path);
+    }
+
+    pub fn get(&self, md5: &str, ext: &str) -> Option<&Path> {
+        self.by_md5
+            .get(&(md5.to_ascii_lowercase(), ext.to_ascii_lowercase()))
+            .map(PathBuf::as_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::Scratch;
+    use std::fs::{create_dir_all, write};
+
+    const MD5: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn finds_a_file_by_md5_and_extension() {
+        let scratch = Scratch::new("library-ext");
+        let root = scratch.path();
+        create_dir_all(root.join("metadata")).unwrap();
+        write(root.join("metadata").join(format!("{}.json", MD5)), b"{}").unwrap();
+        write(root.join(format!("{}.txt", MD5)), b"notes").unwrap();
+        write(root.join(format!("{}.jpg", MD5)), b"poster").unwrap();
+        write(root.join(format!("{}.webm", MD5)), b"video").unwrap();
+        let library = Library::scan(root).unwrap();
+        assert_eq!(
+            library.get(&MD5.to_ascii_uppercase(), "webm"),
+            Some(root.join(format!("{}.webm", MD5)).as_path())
+        );
+        assert_eq!(library.get(MD5, "png"), None);
+        assert_eq!(library.by_md5.len(), 2);
+    }
+
+    #[test]
+    fn hashes_files_named_otherwise() {
+        let scratch = Scratch::new("library-hash");
+        let root = scratch.path();
+        write(root.join("cat.png"), b"meow").unwrap();
+        let library = Library::scan(root).unwrap();
+        let md5 = md5_file(&root.join("cat.png")).unwrap();
+        assert_eq!(
+            library.get(&md5, "PNG"),
+            Some(root.join("cat.png").as_path())
+        );
+    }
+}
@@ -0,0 +1,135 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::error::MonosodiumError;
+use log::warn;
+use std::fs::{read_to_string, remove_file, File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+const LOCK_FILE: &str = ".monosodium.lock";
+
+/// Keeps other runs out of a directory for as long as it's held. The lock is
+/// the operating system's, so it goes away with the process however that
+/// ends, and the file only says who holds it. The file is made once and then
+/// left in place: removing it while another run has it open would let that
+/// run lock a file no one else can find, alongside whoever makes a new one.
+pub struct DirectoryLock {
+    file: File,
+}
+
+impl DirectoryLock {
+    /// Takes the lock on `directory`, failing if another run has it. With
+    /// `force`, the lock file is replaced first, so whoever holds the old one
+    /// no longer counts; for network filesystems whose locks outlive their
+    /// owners.
+    pub fn acquire(directory: &Path, force: bool) -> Result<DirectoryLock, MonosodiumError> {
+        let path = directory.join(LOCK_FILE);
+        if force {
+            match remove_file(&path) {
+                Ok(()) => warn!("Removed the lock on {:?}", directory),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let created = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path);
+        let mut file = match created {
+            Ok(file) => file,
+            // Whoever made it after it was forced out got there first, and
+            // holds it or is about to.
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && force => {
+                return Err(locked(directory, &path));
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                OpenOptions::new().read(true).write(true).open(&path)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(locked(directory, &path)),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+
+        Ok(DirectoryLock { file })
+    }
+}
+
+// Who holds the lock, as far as the file says.
+fn locked(directory: &Path, path: &Path) -> MonosodiumError {
+    let holder = read_to_string(path).unwrap_or_default();
+    MonosodiumError::Locked {
+        path: directory.to_owned(),
+        holder: holder.trim().parse().ok(),
+    }
+}
+
+impl Drop for DirectoryLock {
+    // The OS lock goes with the file; the process id is cleared so that
+    // nothing points at this run once it's gone.
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::Scratch;
+
+    #[test]
+    fn keeps_a_second_run_out() {
+        let scratch = Scratch::new("lock");
+        let lock = DirectoryLock::acquire(scratch.path(), false).unwrap();
+        match DirectoryLock::acquire(scratch.path(), false) {
+            Err(MonosodiumError::Locked { holder, .. }) => {
+                assert_eq!(holder, Some(std::process::id()))
+            }
+            _ => panic!("the directory should be locked"),
+        }
+        drop(lock);
+        let path = scratch.path().join(LOCK_FILE);
+        assert_eq!(read_to_string(&path).unwrap(), "");
+        DirectoryLock::acquire(scratch.path(), false).unwrap();
+    }
+
+    #[test]
+    fn takes_over_a_lock_left_behind() {
+        let scratch = Scratch::new("lock-stale");
+        std::fs::write(scratch.path().join(LOCK_FILE), "4000000").unwrap();
+        DirectoryLock::acquire(scratch.path(), false).unwrap();
+    }
+
+    #[test]
+    fn forces_a_lock_that_is_held() {
+        let scratch = Scratch::new("lock-force");
+        let _held = DirectoryLock::acquire(scratch.path(), false).unwrap();
+        DirectoryLock::acquire(scratch.path(), true).unwrap();
+    }
+}
@@ -24,33 +24,820 @@
 extern crate env_logger;
 extern crate log;
 
-use clap::Parser;
-use log::{debug, error, info};
-use reqwest::Error;
+mod appender;
+mod balance;
+mod blacklist;
+mod breaker;
+mod checksum;
+mod client;
+mod color;
+mod compress;
+mod dates;
+mod deleted;
+mod diskspace;
+mod doctor;
+mod downscale;
+mod duplicates;
+mod error;
+mod failures;
+mod feed;
+mod filter;
+mod implications;
+mod index;
+mod journal;
+mod jsonoutput;
+mod jsonschema;
+mod known;
+mod layout;
+mod library;
+mod lock;
+mod manifest;
+mod md5names;
+mod metadata;
+mod notify;
+mod order;
+mod pages;
+mod permissions;
+mod pools;
+mod profile;
+mod progress;
+mod projection;
+mod quarantine;
+mod ratelimit;
+mod reencode;
+mod report;
+mod route;
+#[cfg(feature = "s3")]
+mod s3;
+mod schema;
+mod search;
+mod selftest;
+mod sets;
+mod shutdown;
+mod state;
+mod storage;
+mod strip;
+mod summary;
+mod tagdb;
+mod tarzst;
+mod template;
+#[cfg(test)]
+mod testutil;
+mod units;
+mod users;
+mod variant;
+mod video;
+mod zip;
+
+use balance::TagCategory;
+use blacklist::Blacklist;
+use breaker::CircuitBreaker;
+use checksum::{md5_file, ChecksumCache, CACHE_FILE};
+use clap::{Parser, ValueEnum};
+use client::{is_outage, Client};
+use color::{ColorChoice, Style};
+use compress::{Compressed, Optimizers};
+use deleted::{DeletedPosts, DELETED_FILE};
+use diskspace::{LowSpace, Watchdog};
+use doctor::{diagnose, Finding};
+use downscale::Resized;
+use error::MonosodiumError;
+use failures::FailureLog;
+use feed::Feed;
+use filter::{read_md5_list, Aspect, Filters, TagsMatch, TOO_LONG};
+use index::Index;
+use journal::Journal;
+use known::KnownFiles;
+use layout::{OutputLayout, Placement, NOT_ARTISTS};
+use library::Library;
+use lock::DirectoryLock;
+use log::{debug, error, info, warn};
+use manifest::WriteStrategy;
+use md5names::NameFix;
+use metadata::{MetadataWriter, Sidecar, TagCase, TagSpace, EXTRA_SUFFIXES, RAW_SUFFIX};
+use notify::NotifyOn;
+use order::read_order_file;
+use pages::{
+    fetch_page, Page, PageCache, PageDrift, Pages, Paging, Source, DEFAULT_PREFETCH_PAGES,
+    MAX_PER_PAGE, MAX_PREFETCH_PAGES,
+};
+use permissions::{parse_mode, Modes};
+use pools::{PoolPlace, Pools};
+use profile::Phase;
+use projection::Projection;
+use ratelimit::RateLimiter;
+use reencode::{Reencoded, VideoPreset};
+use report::write_report;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT_LANGUAGE};
+use reqwest::Url;
+use route::{LinkKind, Route, RouteMode, Router};
+use search::{Query, DEFAULT_TAG_LIMIT, LOGGED_IN_TAG_LIMIT};
 use serde::{Deserialize, Serialize};
-use std::fs::{create_dir_all, File};
-use std::io::Write;
+use serde_json::value::RawValue;
+use sets::{PostSet, SetPlace};
+use shutdown::{Shutdown, StopReason};
+use state::{CheckpointInterval, Checkpoints, PostsDone, RunState, SinceRun};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, write, File};
+use std::io::{BufReader, ErrorKind, IsTerminal, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use storage::{Filesystem, StorageBackend};
+use summary::{FailureLimit, Outcome, Summary};
+use tagdb::{ResolvedTags, TagDb};
+use template::PathTemplate;
 use tokio_stream::StreamExt;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+use units::{format_size, parse_size};
+use variant::Variant;
+use video::{extract_poster, ffmpeg_available, ffprobe_available, is_video};
 
 const USER_AGENT: &str = "monosodium/1.0 (https://github.com/tiltonraccoon/monosodium)";
 
+// e621 allows at most two requests a second.
+const MIN_API_DELAY: Duration = Duration::from_millis(500);
+const DEFAULT_API_DELAY: Duration = Duration::from_millis(1500);
+// More threads than this only fight over the disk.
+const MAX_VERIFY_CONCURRENCY: u64 = 64;
+// Set to "yes" to run --unthrottled without being asked.
+const UNTHROTTLED_ENV: &str = "MONOSODIUM_ALLOW_UNTHROTTLED";
+
+// How long an unused connection is kept open at least; longer if requests
+// are further apart than that, so that it's reused rather than opened afresh.
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(90);
+
+// The wait before the first retry of a download, doubled for each one after.
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+// Files are served from subdomains of this, and this one is expected to stay.
+const CDN_DOMAIN: &str = "e621.net";
+const CDN_HOST: &str = "static1.e621.net";
+
+// Where --rebuild-metadata keeps the last post it finished, and how often.
+const REBUILD_FILE: &str = ".monosodium-rebuild";
+const REBUILD_CHECKPOINT: usize = 100;
+
+// Where --download-thumbnails-too saves thumbnails, under the directory.
+const THUMBNAILS_DIR: &str = "thumbnails";
+
+/// What --list-only prints for each post.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ListFormat {
+    /// Just the post id
+    Ids,
+    /// The post id and its file URL, separated by a tab
+    Urls,
+}
+
+/// The order each page's downloads are made in, for --sort-downloads.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DownloadOrder {
+    /// Smallest files first
+    SizeAsc,
+    /// Largest files first
+    SizeDesc,
+    /// Highest scoring posts first
+    ScoreDesc,
+    /// Oldest posts first
+    IdAsc,
+}
+
+/// Which kind of address to connect to the server over, for --ip-version.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum IpVersion {
+    /// Whichever the server's name resolves to first, falling back to the other
+    #[default]
+    Any,
+    /// IPv4 only
+    V4,
+    /// IPv6 only
+    V6,
+}
+
 #[derive(Parser, Debug)]
 #[clap(version = "1.0", author = "Tilton Raccoon <tilton@tiltonraccoon.com>")]
 struct Opts {
-    #[clap(short, long)]
-    user_id: u32,
-    #[clap(short, long)]
-    directory: String,
+    #[clap(
+        short,
+        long,
+        required_unless_present_any = [
+            "rebuild_index",
+            "rebuild_metadata",
+            "doctor",
+            "dedupe_report",
+            "strict_md5_in_filename",
+            "rename_existing",
+            "resume_partial_verify",
+            "clear_quarantine",
+            "retry_deleted",
+            "tags",
+            "set",
+            "username_lookup",
+            "my_favorites",
+            "selftest",
+            "output_json_schema"
+        ]
+    )]
+    user_id: Option<u32>,
+    /// Archive the favorites of the user with this name, looking up their id
+    #[clap(long, value_name = "NAME", conflicts_with_all = ["user_id", "tags"])]
+    username_lookup: Option<String>,
+    /// Archive the favorites of the user given by --username and --api-key
+    #[clap(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["user_id", "username_lookup", "tags"]
+    )]
+    my_favorites: bool,
+    /// Log in to e621 as this user; needs --api-key
+    #[clap(long, value_name = "NAME", requires = "api_key")]
+    username: Option<String>,
+    /// The API key for --username, from the account settings on e621
+    #[clap(long, value_name = "KEY", requires = "username")]
+    api_key: Option<String>,
+    /// Archive the results of this e621 search instead of a user's favorites
+    #[clap(long, conflicts_with = "user_id")]
+    tags: Option<String>,
+    /// Also save the user's public profile as user.json, and their avatar, in the output directory
+    #[clap(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["tags", "set", "list_only", "analyze", "estimate_only"]
+    )]
+    archive_avatar_and_profile: bool,
+    /// Archive the posts in the e621 set with this id instead
+    #[clap(
+        long,
+        value_name = "ID",
+        conflicts_with_all = ["user_id", "username_lookup", "my_favorites", "tags"]
+    )]
+    set: Option<u64>,
+    /// Count a post as having a --tags tag that's checked locally if it has a tag that implies it
+    #[clap(long, default_value_t = false, requires = "tags")]
+    expand_implications: bool,
+    /// The most tags e621 accepts in one search, 40, or 50 logged in; any others are checked locally
+    #[clap(long)]
+    tag_limit: Option<usize>,
+    #[clap(
+        short,
+        long,
+        required_unless_present_any = ["selftest", "output_json_schema"]
+    )]
+    directory: Option<String>,
+    /// Check that downloading works, with one small post in a temporary directory, then exit
+    #[clap(long, default_value_t = false)]
+    selftest: bool,
+    /// Print a JSON Schema of the metadata sidecars, then exit
+    #[clap(long, default_value_t = false)]
+    output_json_schema: bool,
+    /// Count what would be downloaded, and how big it is, then exit
     #[clap(short, long, default_value_t = false)]
     analyze: bool,
+    /// Roughly estimate what would be downloaded from a count and the first page, then exit
+    #[clap(long, default_value_t = false, conflicts_with_all = ["analyze", "list_only"])]
+    estimate_only: bool,
+    /// Print the posts that would be archived, one per line, then exit
+    #[clap(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "ids",
+        conflicts_with = "analyze"
+    )]
+    list_only: Option<ListFormat>,
+    /// Don't ask before starting a large download
+    #[clap(short = 'y', long, default_value_t = false)]
+    yes: bool,
+    /// Ask before downloading more than this many files
+    #[clap(long, default_value_t = 1000)]
+    confirm_files: usize,
+    /// Ask before downloading more than this much, e.g. "10G"
+    #[clap(long, default_value = "10G", value_parser = parse_size)]
+    confirm_size: u64,
+    /// Write a Markdown report of the run to this file
+    #[clap(long)]
+    report: Option<PathBuf>,
+    /// Write everything the run did, post by post, to this file as JSON when it ends
+    #[clap(long, value_name = "FILE")]
+    json_output: Option<PathBuf>,
+    /// Run this shell command when the run ends, with the outcome in its environment
+    #[clap(long, value_name = "COMMAND")]
+    notify: Option<String>,
+    /// POST a JSON summary to this URL when the run ends
+    #[clap(long, value_name = "URL")]
+    webhook_url: Option<Url>,
+    /// Whether to run --notify and --webhook-url after every run, or only failed ones
+    #[clap(long, value_enum, default_value_t = NotifyOn::Always)]
+    notify_on: NotifyOn,
+    /// Stop starting new downloads after this long, e.g. "30m" or "2h"
+    #[clap(long, value_parser = humantime::parse_duration)]
+    max_duration: Option<Duration>,
+    /// Hold off downloading while the disk has less than this free, e.g. "5G"
+    #[clap(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "s3")]
+    min_free_space: Option<u64>,
+    /// What to do when free space drops below --min-free-space
+    #[clap(long, value_enum, default_value_t = LowSpace::Pause, requires = "min_free_space")]
+    on_low_space: LowSpace,
+    /// Save fetched pages here, and reuse them on later runs while they're fresh
+    #[clap(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+    /// How long saved pages stay fresh, e.g. "30m" or "1d"
+    #[clap(long, default_value = "1h", value_parser = humantime::parse_duration)]
+    cache_ttl: Duration,
+    /// Fetch every page again, replacing what's saved in --cache-dir
+    #[clap(long, default_value_t = false, requires = "cache_dir")]
+    refresh_cache: bool,
+    /// How many posts to fetch on each page, up to 320
+    #[clap(
+        long,
+        default_value_t = MAX_PER_PAGE,
+        value_parser = clap::value_parser!(u32).range(1..=MAX_PER_PAGE as i64)
+    )]
+    per_page: u32,
+    /// How many pages to fetch ahead of the downloads, so the next is ready when a page is done
+    #[clap(
+        long,
+        value_name = "PAGES",
+        default_value_t = DEFAULT_PREFETCH_PAGES,
+        value_parser = clap::value_parser!(u32).range(1..=MAX_PREFETCH_PAGES as i64)
+    )]
+    parallel_pages: u32,
+    /// Fetch each page again before the next, to catch posts that moved back onto it as others were removed
+    #[clap(long, value_enum, default_value_t = PageDrift::Ignore)]
+    page_drift: PageDrift,
+    /// Rewrite the metadata of posts already archived that changed on e621 since this date or this long ago
+    #[clap(long, value_name = "DATE_OR_DURATION", value_parser = dates::parse_since)]
+    only_updated_since: Option<SystemTime>,
+    /// Note in the metadata when a post's file has been replaced since it was archived
+    #[clap(long, default_value_t = false, conflicts_with_all = ["s3", "zip", "output_archive"])]
+    if_newer_remote: bool,
+    /// Stop after this many pages of posts
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_pages: Option<u32>,
+    /// Only archive posts uploaded since this date or this long ago, e.g. "2024-01-01" or "7d"
+    #[clap(long, value_name = "DATE_OR_DURATION", value_parser = dates::parse_since)]
+    since: Option<SystemTime>,
+    /// Only fetch favorites added since the last finished run started, going by the newest favorites it saved in FILE
+    #[clap(long, value_name = "FILE", conflicts_with_all = ["tags", "set"])]
+    since_run: Option<PathBuf>,
+    /// Only archive posts uploaded before this date or this long ago, e.g. "2024-01-01" or "30d"
+    #[clap(long, value_name = "DATE_OR_DURATION", value_parser = dates::parse_since)]
+    until: Option<SystemTime>,
+    /// Also fetch each post's comments, into <md5>.comments.json in the metadata directory
+    #[clap(long, default_value_t = false)]
+    with_comments: bool,
+    /// Also fetch each post's notes, with their text and positions, into <md5>.notes.json
+    #[clap(long, default_value_t = false)]
+    with_notes: bool,
+    /// Also save the wiki page of every tag on a downloaded post in this directory, once per tag
+    #[clap(long, value_name = "DIR")]
+    write_tag_wiki: Option<PathBuf>,
+    /// Also write each post's tags, separated by commas, to a .txt file next to its file
+    #[clap(long, default_value_t = false)]
+    write_tags_txt: bool,
+    /// How tags are cased in --write-tags-txt files
+    #[clap(long, value_enum, default_value_t = TagCase::Original)]
+    tag_case: TagCase,
+    /// Whether words in --write-tags-txt tags are joined by underscores or spaces
+    #[clap(long, value_enum, default_value_t = TagSpace::Underscore)]
+    tag_space: TagSpace,
+    /// Also write each post's source URLs to <md5>.source in the metadata directory
+    #[clap(long, default_value_t = false)]
+    write_sources: bool,
+    /// Also write each post exactly as the API gave it to <md5>.raw.json in the metadata directory
+    #[clap(long, default_value_t = false)]
+    preserve_raw: bool,
+    /// Add canonical and implied tags to each sidecar, from alias and implication rules in this JSON file
+    #[clap(long, value_name = "FILE")]
+    tag_db: Option<PathBuf>,
+    /// Download each page's files in this order, rather than the order the API lists them in
+    #[clap(long, value_enum, value_name = "ORDER")]
+    sort_downloads: Option<DownloadOrder>,
+    /// Download only the posts listed in this file, one id per line, in that order
+    #[clap(long, value_name = "FILE", conflicts_with_all = ["sort_downloads", "resume"])]
+    download_order_file: Option<PathBuf>,
+    /// Give up once this many downloads have failed, or this share of them, e.g. "50" or "10%"
+    #[clap(long, value_name = "COUNT_OR_PERCENT")]
+    max_file_failures: Option<FailureLimit>,
+    /// Exit with an error if any post that isn't deleted had no file to download, rather than skipping it quietly
+    #[clap(long, default_value_t = false)]
+    fail_on_missing_url: bool,
+    /// How often to save progress, the index and the archive: every so many pages, like "5", or so often, like "10m"
+    #[clap(long, value_name = "PAGES_OR_DURATION", default_value = "1")]
+    checkpoint_interval: CheckpointInterval,
+    /// Start failures.jsonl afresh, rather than adding this run's failures to it
+    #[clap(long, default_value_t = false)]
+    truncate_failure_log: bool,
+    /// How many times to retry a download that failed because of the network or the server
+    #[clap(long, default_value_t = 3)]
+    retries: u32,
+    /// Remove EXIF and other embedded metadata from downloaded JPEGs and PNGs
+    #[clap(long, default_value_t = false, conflicts_with = "verify")]
+    strip_metadata: bool,
+    /// Retry downloads that aren't exactly the size e621 gives, not only ones that come up short
+    #[clap(long, default_value_t = false)]
+    retry_on_parse_mismatch: bool,
+    /// Check each file's MD5 against e621's before it's saved, and download it again if it doesn't match
+    #[clap(long, default_value_t = false)]
+    verify_on_download: bool,
+    /// Save a sample or alternate version of each file instead, if one has the first of these extensions, e.g. "webm,png"
+    #[clap(
+        long,
+        value_name = "EXTS",
+        value_delimiter = ',',
+        conflicts_with = "verify"
+    )]
+    prefer_extension: Vec<String>,
+    /// Scale down images wider or taller than this many pixels after downloading; needs ffmpeg
+    #[clap(
+        long,
+        value_name = "PX",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["verify", "prefer_extension", "s3", "zip", "output_archive"]
+    )]
+    max_dimension: Option<u32>,
+    /// Transcode videos once they're downloaded, to H.264, H.265 or VP9; needs ffmpeg
+    #[clap(
+        long,
+        value_enum,
+        value_name = "PRESET",
+        conflicts_with_all = ["verify", "s3", "zip", "output_archive"]
+    )]
+    reencode_video: Option<VideoPreset>,
+    /// Make downloaded PNGs and JPEGs smaller without changing a pixel, with oxipng and jpegtran
+    #[clap(long, default_value_t = false, conflicts_with_all = ["s3", "zip", "output_archive"])]
+    compress_images_lossless: bool,
+    /// With --reencode-video, keep each video as it was downloaded too, as <md5>.original.<ext>
+    #[clap(long, default_value_t = false, requires = "reencode_video")]
+    keep_original_video: bool,
+    /// Download files from this CDN host instead of the one in each post's URL
+    #[clap(long, value_name = "HOST")]
+    cdn_host: Option<String>,
+    /// Pause all requests after this many failures in a row
+    #[clap(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    breaker_threshold: u32,
+    /// How long to pause all requests for once the breaker trips
+    #[clap(long, default_value = "1m", value_parser = humantime::parse_duration)]
+    breaker_cooldown: Duration,
+    /// How to arrange downloaded files under the directory
+    #[clap(long, value_enum, default_value_t = OutputLayout::Flat)]
+    layout: OutputLayout,
+    /// Put every file directly in the directory; the same as --layout flat
+    #[clap(long, default_value_t = false, conflicts_with = "layout")]
+    flatten_output: bool,
+    /// File posts in a pool under pools/<name>, in reading order; the same as --layout by-pool
+    #[clap(long, default_value_t = false, conflicts_with_all = ["layout", "flatten_output"])]
+    group_by_pool: bool,
+    /// Put files under safe/, questionable/ and explicit/ by rating, arranged within those by --layout
+    #[clap(long, default_value_t = false, conflicts_with = "flatten_output")]
+    by_rating: bool,
+    /// Arrange files by a path like "{rating}/{artist}/{md5}.{ext}" instead of --layout
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        conflicts_with_all = ["layout", "flatten_output", "group_by_pool", "by_rating"]
+    )]
+    output_layout_template: Option<PathTemplate>,
+    /// Save posts tagged TAG under DIRECTORY instead, as TAG=DIRECTORY; can be given more than once
+    #[clap(long = "route", value_name = "TAG=DIRECTORY")]
+    routes: Vec<Route>,
+    /// Whether a post goes under only its first matching --route, or all of them
+    #[clap(long, value_enum, default_value_t = RouteMode::First)]
+    route_mode: RouteMode,
+    /// Link to a file already in the --index with the same MD5 instead of downloading it again, and link routes this way too
+    #[clap(long, value_enum, value_name = "KIND", requires = "index", conflicts_with_all = ["s3", "zip", "output_archive"])]
+    dedupe: Option<LinkKind>,
+    /// Hard link files already in this collection, found by MD5, instead of downloading them
+    #[clap(long, value_name = "DIR", conflicts_with_all = ["s3", "zip", "output_archive"])]
+    hardlink_from: Option<PathBuf>,
+    /// Hard link files already in this other monosodium archive, found from its metadata, instead of downloading them
+    #[clap(long, value_name = "DIR", conflicts_with_all = ["s3", "zip", "output_archive"])]
+    resume_from_manifest_of: Option<PathBuf>,
+    /// Count a post as archived if its sidecar, or another with the same MD5, says where its file is, even if that's not where the layout would put it
+    #[clap(long, default_value_t = false, conflicts_with_all = ["s3", "zip", "output_archive"])]
+    skip_existing_by_id: bool,
+    /// Skip posts narrower than this many pixels
+    #[clap(long)]
+    min_width: Option<u32>,
+    /// Skip posts shorter than this many pixels
+    #[clap(long)]
+    min_height: Option<u32>,
+    /// Skip posts with fewer pixels than this in total (width times height)
+    #[clap(long)]
+    min_pixels: Option<u64>,
+    /// Skip posts favorited by fewer users than this
+    #[clap(long, value_name = "N")]
+    min_fav_count: Option<u32>,
+    /// Skip posts with fewer comments than this
+    #[clap(long, value_name = "N")]
+    min_comment_count: Option<u32>,
+    /// Download at most this many posts by any one artist in a run, skipping the rest
+    #[clap(long, value_name = "N")]
+    limit_per_artist: Option<usize>,
+    /// Skip videos that run for longer than this; those e621 doesn't give the length of are checked with ffprobe
+    #[clap(long, value_name = "SECONDS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_duration_seconds: Option<u32>,
+    /// Keep only landscape, portrait or square posts, or a width/height ratio range like 1.5-2.5
+    #[clap(long, allow_hyphen_values = true)]
+    aspect: Option<Aspect>,
+    /// Keep only posts with these tags, separated by spaces, checked here rather than by e621
+    #[clap(long, value_name = "TAGS")]
+    include_tags: Option<String>,
+    /// Whether --include-tags keeps posts with all of its tags, or with any of them
+    #[clap(long, value_enum, default_value_t = TagsMatch::All)]
+    tags_match: TagsMatch,
+    /// Never download files whose MD5 is listed in this file, one per line
+    #[clap(long, value_name = "FILE")]
+    exclude_md5_file: Option<PathBuf>,
+    /// Run this program for each post, with the post's JSON on standard input, and keep the post only if it succeeds
+    #[clap(long, value_name = "PATH")]
+    filter_script: Option<PathBuf>,
+    /// How many copies of --filter-script to run at once
+    #[clap(long, default_value_t = 4, requires = "filter_script")]
+    filter_script_concurrency: usize,
+    /// Also download the thumbnail e621 shows for each post, to thumbnails/ under the directory
+    #[clap(long, default_value_t = false)]
+    download_thumbnails_too: bool,
+    /// Skip posts matching these tags, like a line of e621's blacklist; can be given more than once
+    #[clap(long, value_name = "TAGS")]
+    blacklist: Vec<String>,
+    /// Skip posts on the blacklist of the account given by --username and --api-key, as well as any --blacklist
+    #[clap(long, default_value_t = false)]
+    tag_blacklist_from_account: bool,
+    /// Download a sample with up to --per-class posts for each tag in this category
+    #[clap(long, value_enum, value_name = "CATEGORY", requires = "per_class")]
+    balance_tag: Option<TagCategory>,
+    /// How many posts to sample for each tag, with --balance-tag
+    #[clap(long, value_name = "N", requires = "balance_tag")]
+    per_class: Option<usize>,
+    /// Keep a single JSON index of every archived post in this file
+    #[clap(long)]
+    index: Option<PathBuf>,
+    /// Rebuild the --index file from the metadata sidecars, then exit
+    #[clap(long, default_value_t = false, requires = "index")]
+    rebuild_index: bool,
+    /// Write metadata sidecars as compact JSON, which is about half the size
+    #[clap(long, default_value_t = false)]
+    json_compact: bool,
+    /// Write the index, checksum cache and saved progress safely, keeping a backup of each
+    #[clap(long, default_value_t = false)]
+    atomic_manifest: bool,
+    /// Permissions for files written into the archive, in octal, like 644 (Unix only)
+    #[clap(long, value_name = "MODE", value_parser = parse_mode)]
+    file_mode: Option<u32>,
+    /// Permissions for directories created in the archive, in octal, like 2775 (Unix only)
+    #[clap(long, value_name = "MODE", value_parser = parse_mode)]
+    dir_mode: Option<u32>,
+    /// Write metadata sidecars as indented JSON (the default)
+    #[clap(long, default_value_t = false, conflicts_with = "json_compact")]
+    json_pretty: bool,
+    /// Send this Accept-Language header with every request, e.g. "en-US"
+    #[clap(long)]
+    accept_language: Option<HeaderValue>,
+    /// Don't check that the API's responses look as expected before starting
+    #[clap(long, default_value_t = false)]
+    skip_schema_check: bool,
+    /// Send every request through this HTTP or HTTPS proxy, e.g. "http://localhost:8080"
+    #[clap(long, value_name = "URL")]
+    proxy: Option<Url>,
+    /// Talk to the server over HTTP/1.1 only, even where HTTP/2 is offered
+    #[clap(long, default_value_t = false)]
+    http1_only: bool,
+    /// Connect over IPv4 only, IPv6 only, or either
+    #[clap(long, value_enum, value_name = "VERSION", default_value_t = IpVersion::Any)]
+    ip_version: IpVersion,
+    /// If the API keeps failing, read the rest of the posts from this Atom feed instead; "{page}" is replaced with the page number
+    #[clap(long, value_name = "URL")]
+    fallback_feed: Option<String>,
+    /// At the end, show how long was spent fetching pages, downloading, writing and waiting on the rate limit
+    #[clap(long, default_value_t = false)]
+    profile: bool,
+    /// Write every sidecar again from the JSON kept by --preserve-raw, without the network, then exit
+    #[clap(long, default_value_t = false, conflicts_with_all = ["s3", "zip", "output_archive", "doctor", "rebuild_index"])]
+    rebuild_metadata: bool,
+    /// Check every archived file against its metadata's MD5, then exit
+    #[clap(long, default_value_t = false)]
+    doctor: bool,
+    /// List files in the directory that have the same contents as another, and how much space they take, then exit
+    #[clap(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["s3", "zip", "output_archive", "doctor", "rebuild_index"]
+    )]
+    dedupe_report: bool,
+    /// With --dedupe-report, delete all but one file of each group, after asking
+    #[clap(long, default_value_t = false, requires = "dedupe_report")]
+    dedupe_apply: bool,
+    /// Hash every file in the directory that's named after an MD5, list those whose contents don't match, then exit
+    #[clap(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["s3", "zip", "output_archive", "doctor", "rebuild_index", "dedupe_report"]
+    )]
+    strict_md5_in_filename: bool,
+    /// With --strict-md5-in-filename, rename the files that don't match after their real MD5, or quarantine them
+    #[clap(
+        long,
+        value_enum,
+        value_name = "ACTION",
+        requires = "strict_md5_in_filename",
+        requires_if("quarantine", "quarantine_dir")
+    )]
+    fix_md5_names: Option<NameFix>,
+    /// How many files to hash at once, up to 64; keep this low on spinning disks
+    #[clap(
+        long,
+        default_value_t = 2,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=MAX_VERIFY_CONCURRENCY)
+    )]
+    verify_concurrency: usize,
+    /// The most files to have open at once, to write, hash or keep open all run; lower it if runs fail with "too many open files"
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_open_files: Option<u32>,
+    /// Check files already archived against their MD5, and download any that don't match again
+    #[clap(long, default_value_t = false, conflicts_with_all = ["s3", "zip", "output_archive"])]
+    verify: bool,
+    /// Hash every file when checking, even ones that haven't changed since they last passed
+    #[clap(long, default_value_t = false)]
+    force_verify: bool,
+    /// Carry on from where the last, unfinished run stopped
+    #[clap(long, default_value_t = false)]
+    resume: bool,
+    /// Save after each post that it's done, so that --resume needn't check a page's posts again after a crash
+    #[clap(long, default_value_t = false, conflicts_with_all = ["s3", "zip", "output_archive"])]
+    partial_page_recovery: bool,
+    /// Save the first frame of each video as <md5>.jpg next to it; needs ffmpeg
+    #[clap(long, default_value_t = false)]
+    flatten_video_thumbnails: bool,
+    /// How long to wait between requests, 1500ms unless --unthrottled; don't pound the server!
+    #[clap(long, value_parser = humantime::parse_duration)]
+    api_delay: Option<Duration>,
+    /// Wait up to this much more or less than --api-delay each time, chosen at random
+    #[clap(long, default_value = "0s", value_parser = humantime::parse_duration)]
+    api_delay_jitter: Duration,
+    /// Don't wait between requests at all, only as the server's rate limit headers say; only where that's allowed
+    #[clap(long, default_value_t = false)]
+    unthrottled: bool,
+    /// Archive to an S3-compatible bucket instead, as BUCKET/PREFIX; needs the s3 feature
+    #[clap(
+        long,
+        value_name = "BUCKET/PREFIX",
+        conflicts_with_all = ["routes", "flatten_video_thumbnails", "doctor", "rebuild_index"]
+    )]
+    s3: Option<String>,
+    /// Archive into this zip file instead, adding to it if it already exists
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["s3", "routes", "flatten_video_thumbnails", "doctor", "rebuild_index"]
+    )]
+    zip: Option<PathBuf>,
+    /// Stream everything into this zstd-compressed tar file instead, adding to it if it already exists
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["s3", "zip", "routes", "flatten_video_thumbnails", "doctor", "rebuild_index"]
+    )]
+    output_archive: Option<PathBuf>,
+    /// When to color output: always, never, or only on a terminal and without NO_COLOR set
+    #[clap(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// Never color output; the same as --color never
+    #[clap(long, default_value_t = false, conflicts_with = "color")]
+    no_color: bool,
+    /// Start even if the directory looks like it's in use by another run
+    #[clap(long, default_value_t = false)]
+    force_unlock: bool,
+    /// Move archived files to where --layout and --route now put them, then exit
+    #[clap(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["s3", "zip", "output_archive", "doctor", "rebuild_index"]
+    )]
+    rename_existing: bool,
+    /// Check every archived file against its MD5, download the damaged and missing ones again, then exit
+    #[clap(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["s3", "zip", "output_archive", "doctor", "rebuild_index", "rename_existing"]
+    )]
+    resume_partial_verify: bool,
+    /// Look up posts that were deleted when last seen, archive any that are back, then exit
+    #[clap(long, default_value_t = false)]
+    retry_deleted: bool,
+    /// With --retry-deleted, how long to leave each post before looking it up again
+    #[clap(long, default_value = "7d", value_parser = humantime::parse_duration)]
+    retry_deleted_interval: Duration,
+    /// With --retry-deleted, the most posts to look up in one run
+    #[clap(long, value_name = "COUNT", requires = "retry_deleted")]
+    retry_deleted_limit: Option<usize>,
+    /// Move files that fail their MD5 check here, with a note of what was wrong, rather than overwriting them
+    #[clap(long, value_name = "DIR")]
+    quarantine_dir: Option<PathBuf>,
+    /// Delete everything in --quarantine-dir, then exit
+    #[clap(long, default_value_t = false, requires = "quarantine_dir")]
+    clear_quarantine: bool,
+    /// With --rename-existing, only list what would be moved
+    #[clap(long, default_value_t = false, requires = "rename_existing")]
+    dry_run: bool,
+}
+
+impl Opts {
+    fn color(&self) -> ColorChoice {
+        if self.no_color {
+            ColorChoice::Never
+        } else {
+            self.color
+        }
+    }
+
+    fn layout(&self) -> OutputLayout {
+        if self.group_by_pool {
+            OutputLayout::ByPool
+        } else {
+            self.layout
+        }
+    }
+
+    // The other layouts are all short for a template, except by-pool.
+    fn placement(&self) -> Placement {
+        let template = self
+            .output_layout_template
+            .clone()
+            .or_else(|| self.layout().template(self.by_rating));
+        match template {
+            Some(template) => Placement::Template(template),
+            None => Placement::ByPool {
+                by_rating: self.by_rating,
+            },
+        }
+    }
+
+    fn modes(&self) -> Modes {
+        Modes {
+            file: self.file_mode,
+            dir: self.dir_mode,
+        }
+    }
+
+    fn tag_limit(&self) -> usize {
+        let logged_in = self.username.is_some() && self.api_key.is_some();
+        self.tag_limit.unwrap_or(match logged_in {
+            true => LOGGED_IN_TAG_LIMIT,
+            false => DEFAULT_TAG_LIMIT,
+        })
+    }
+
+    // --unthrottled only lifts the floor; a delay asked for is still kept.
+    fn api_delay(&self) -> Duration {
+        match (self.api_delay, self.unthrottled) {
+            (Some(delay), _) => delay,
+            (None, true) => Duration::ZERO,
+            (None, false) => DEFAULT_API_DELAY,
+        }
+    }
+
+    fn write_strategy(&self) -> WriteStrategy {
+        if self.atomic_manifest {
+            WriteStrategy::Atomic
+        } else {
+            WriteStrategy::InPlace
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct ApiResponse {
+    #[serde(deserialize_with = "posts_with_raw")]
     posts: Vec<Post>,
 }
 
+// Keeps each post's JSON exactly as it came, as well as parsing it.
+fn posts_with_raw<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Post>, D::Error> {
+    let raw_posts = Vec::<Box<RawValue>>::deserialize(deserializer)?;
+    raw_posts
+        .into_iter()
+        .map(|raw| {
+            let mut post: Post =
+                serde_json::from_str(raw.get()).map_err(serde::de::Error::custom)?;
+            post.raw = Some(raw);
+            Ok(post)
+        })
+        .collect()
+}
+
+// What https://e621.net/posts/<id>.json returns.
+#[derive(Deserialize)]
+struct PostResponse {
+    post: Post,
+}
+
+// Fields written to the sidecar belong in jsonschema.rs too.
 #[derive(Serialize, Deserialize, Debug)]
 struct Post {
     id: u64,
@@ -60,9 +847,74 @@ struct Post {
     tags: Tags,
     rating: String,
     flags: Flags,
+    #[serde(default)]
+    score: Score,
+    // How long a video runs, in seconds, if the API said
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+    // How many users have favorited it, if the API said
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fav_count: Option<u32>,
+    // How many comments it has, if the API said
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment_count: Option<u32>,
+    // The ids of the pools it's in
+    #[serde(default)]
+    pools: Vec<u64>,
+    // The pool it's filed under, for --layout by-pool
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pool: Option<PoolPlace>,
+    // Where it comes in the set it was archived from, for --set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    set: Option<SetPlace>,
+    // Where the art was originally posted, when the uploader said
+    #[serde(default)]
+    sources: Vec<String>,
     // Hydrated after fetch
     file_path: Option<PathBuf>,
     tags_path: Option<PathBuf>,
+    // Links to file_path, for posts matching more than one --route
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    link_paths: Vec<PathBuf>,
+    // A still from a video, for browsing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    poster_path: Option<PathBuf>,
+    // The site's thumbnail of it, for --download-thumbnails-too
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thumbnail_path: Option<PathBuf>,
+    // Canonical and implied tags from --tag-db
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resolved_tags: Option<ResolvedTags>,
+    // The thumbnail the site shows in listings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    preview: Option<Preview>,
+    // Smaller or differently encoded versions of the file, as the API gave them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sample: Option<serde_json::Value>,
+    // The version saved instead of the file, for --prefer-extension
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    variant: Option<Variant>,
+    // The size the file was scaled down to, for --max-dimension
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resized: Option<Resized>,
+    // How the video was transcoded, for --reencode-video
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reencoded: Option<Reencoded>,
+    // How the image was made smaller, for --compress-images-lossless, which
+    // is only known once it's been downloaded
+    #[serde(default, skip_serializing_if = "is_unset")]
+    compressed: Mutex<Option<Compressed>>,
+    // The MD5 of the file as it was saved, when that isn't e621's file byte
+    // for byte, which is only known once it's been downloaded
+    #[serde(default, skip_serializing_if = "is_unset")]
+    saved_md5: Mutex<Option<String>>,
+    // The MD5 of the file this post had when it was last archived, when it's
+    // been replaced since; the old file is kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    supersedes: Option<String>,
+    // The post as the API gave it, for --preserve-raw
+    #[serde(skip)]
+    raw: Option<Box<RawValue>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -75,6 +927,13 @@ struct FileData {
     url: Option<String>, // May not be present if the file is deleted
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct Preview {
+    width: u32,
+    height: u32,
+    url: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct Tags {
     general: Vec<String>,
@@ -87,6 +946,63 @@ struct Tags {
     meta: Vec<String>,
 }
 
+fn is_unprintable(c: char) -> bool {
+    c.is_control() || c == char::REPLACEMENT_CHARACTER
+}
+
+impl Tags {
+    /// Puts every tag in Unicode Normalization Form C, so that tags which look
+    /// the same are the same string, and sorts each category, so that the same
+    /// tags are always written out the same way. Control characters, and the
+    /// replacement characters left where the response wasn't valid UTF-8, are
+    /// taken out, so they never end up in a file or its name; returns how many
+    /// tags that changed.
+    pub fn normalize(&mut self) -> usize {
+        let mut sanitized = 0;
+        for tags in [
+            &mut self.general,
+            &mut self.species,
+            &mut self.character,
+            &mut self.copyright,
+            &mut self.artist,
+            &mut self.invalid,
+            &mut self.lore,
+            &mut self.meta,
+        ] {
+            for tag in tags.iter_mut().filter(|tag| tag.contains(is_unprintable)) {
+                tag.retain(|c| !is_unprintable(c));
+                sanitized += 1;
+            }
+            tags.retain(|tag| !tag.is_empty());
+            for tag in tags.iter_mut().filter(|tag| !is_nfc(tag)) {
+                *tag = tag.nfc().collect();
+            }
+            tags.sort_unstable();
+            tags.dedup();
+        }
+        sanitized
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &String> {
+        self.general
+            .iter()
+            .chain(&self.species)
+            .chain(&self.character)
+            .chain(&self.copyright)
+            .chain(&self.artist)
+            .chain(&self.invalid)
+            .chain(&self.lore)
+            .chain(&self.meta)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Score {
+    up: i64,
+    down: i64,
+    total: i64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct Flags {
     pending: bool,
@@ -95,127 +1011,3054 @@ struct Flags {
 }
 
 impl ApiResponse {
-    pub fn hydrate(&mut self, output: &Path, metadata_dir: &Path) {
-        for mut post in &mut self.posts {
-            let image_file = format!("{}.{}", post.file.md5, post.file.ext);
-            let image_path = output.join(image_file);
-            let tags_file = format!("{}.json", post.file.md5);
-            let tags_path = metadata_dir.join(tags_file);
-            debug!(
-                "Hydrated output path {:?}, tags path {:?}",
-                image_path, tags_path
+    /// Finds out about the pools the posts are in, if the layout needs them,
+    /// before they're hydrated.
+    pub async fn look_up_pools(&self, context: &Context<'_>) -> Result<(), MonosodiumError> {
+        match &context.pools {
+            Some(pools) => pools.fetch(&context.client, &self.posts).await,
+            None => Ok(()),
+        }
+    }
+
+    pub fn hydrate(&mut self, context: &Context<'_>) {
+        for post in &mut self.posts {
+            post.normalize_tags();
+            if let Some(tag_db) = &context.tag_db {
+                post.resolved_tags = Some(tag_db.resolve(&post.tags));
+            }
+            if let Some(md5) = context
+                .archived_md5s
+                .as_ref()
+                .and_then(|archived| archived.get(&post.id))
+                .filter(|&md5| *md5 != post.file.md5)
+            {
+                post.supersedes = Some(md5.clone());
+            }
+            if !context.opts.prefer_extension.is_empty() {
+                post.variant = variant::choose(post, &context.opts.prefer_extension);
+            }
+            if let Some(max) = context.opts.max_dimension {
+                post.resized = downscale::fit(post.ext(), post.file.width, post.file.height, max);
+            }
+            if let Some(pools) = &context.pools {
+                post.pool = pools.place(post);
+            }
+            if let Some(preset) = context.reencode.filter(|_| is_video(post.downloaded_ext())) {
+                post.reencoded = Some(preset.apply(post.downloaded_ext(), post.file.size as u64));
+            }
+            if let Some(set) = &context.post_set {
+                post.set = set.place(post);
+            }
+            post.place(
+                &context.placement,
+                &context.router,
+                &context.metadata_dir,
+                context.posters,
             );
-            post.file_path = Some(image_path);
-            post.tags_path = Some(tags_path);
+            if let Some(dir) = &context.thumbnails_dir {
+                post.thumbnail_path = post.thumbnail_name().map(|name| dir.join(name));
+            }
+            // The file stays where it is, and the post goes by that.
+            if let Some(known) = &context.known_files {
+                if !post.file_path.as_ref().is_some_and(|path| path.is_file()) {
+                    if let Some(path) = known.find(post) {
+                        post.file_path = Some(path.to_owned());
+                    }
+                }
+            }
+            if let Some(reencoded) = post
+                .reencoded
+                .as_mut()
+                .filter(|_| context.opts.keep_original_video)
+            {
+                reencoded.original_path = post.file_path.as_ref().map(|path| {
+                    path.with_extension(format!("original.{}", reencoded.original_ext))
+                });
+            }
         }
     }
 }
 
-fn archive_metadata(post: &Post) {
-    let path = &post.tags_path;
-    if let Ok(mut tags_file) = File::create(path.as_ref().unwrap()) {
-        let _ = tags_file.write_all(serde_json::to_string_pretty(&post).unwrap().as_bytes());
+impl Post {
+    /// What the post's thumbnail is saved as, if it has one: its MD5, and the
+    /// extension of the thumbnail, which isn't always the file's.
+    fn thumbnail_name(&self) -> Option<String> {
+        let url = self.preview.as_ref()?.url.as_deref()?;
+        let ext = Path::new(url.split('?').next().unwrap_or(url)).extension()?;
+        Some(format!("{}.{}", self.file.md5, ext.to_string_lossy()))
+    }
+
+    /// The extension of the file that's saved, which is the variant's if there
+    /// is one.
+    fn ext(&self) -> &str {
+        match &self.reencoded {
+            Some(reencoded) => &reencoded.ext,
+            None => self.downloaded_ext(),
+        }
+    }
+
+    // The extension of what's downloaded, before any --reencode-video.
+    fn downloaded_ext(&self) -> &str {
+        self.variant
+            .as_ref()
+            .map_or(&self.file.ext, |variant| &variant.ext)
+    }
+
+    /// Takes from the sidecar an earlier run saved what only that run knew
+    /// about the file: which version it saved, and what it did to it.
+    fn keep_saved(&mut self, saved: Post) {
+        self.variant = saved.variant;
+        self.resized = saved.resized;
+        self.reencoded = saved.reencoded;
+        self.compressed = saved.compressed;
+        self.saved_md5 = saved.saved_md5;
+    }
+
+    /// Whether the file saved is something other than e621's, whether a
+    /// variant or changed after it was downloaded, and so has an MD5 of its
+    /// own.
+    fn is_altered(&self) -> bool {
+        self.variant.is_some()
+            || self.resized.is_some()
+            || self.reencoded.is_some()
+            || !is_unset(&self.compressed)
+            || !is_unset(&self.saved_md5)
+    }
+
+    /// Whether `md5` is what the saved file should hash to: e621's MD5, or
+    /// the one noted when it was saved.
+    fn is_saved_md5(&self, md5: &str) -> bool {
+        let matches = |known: &String| known.eq_ignore_ascii_case(md5);
+        matches(&self.file.md5)
+            || self
+                .compressed
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|compressed| matches(&compressed.md5))
+            || self.saved_md5.lock().unwrap().as_ref().is_some_and(matches)
+    }
+
+    fn normalize_tags(&mut self) {
+        match self.tags.normalize() {
+            0 => {}
+            1 => warn!(
+                "Post {} had a tag with unprintable characters; they were removed",
+                self.id
+            ),
+            n => warn!(
+                "Post {} had {} tags with unprintable characters; they were removed",
+                self.id, n
+            ),
+        }
+    }
+
+    /// Whether the post was uploaded before `cutoff`. Posts whose date can't
+    /// be read are never counted as older.
+    fn is_older_than(&self, cutoff: SystemTime) -> bool {
+        dates::parse_timestamp(&self.created_at).is_some_and(|created| created < cutoff)
+    }
+
+    /// Whether the post was uploaded after `cutoff`, likewise.
+    fn is_newer_than(&self, cutoff: SystemTime) -> bool {
+        dates::parse_timestamp(&self.created_at).is_some_and(|created| created > cutoff)
+    }
+
+    /// Whether the post was changed on e621 at or after `cutoff`. Posts whose
+    /// date can't be read are counted as changed.
+    fn is_updated_since(&self, cutoff: SystemTime) -> bool {
+        dates::parse_timestamp(&self.updated_at).is_none_or(|updated| updated >= cutoff)
+    }
+
+    /// Works out where the post's file, links, poster and metadata go, with
+    /// the file at the same place under each of its routes.
+    fn place(
+        &mut self,
+        placement: &Placement,
+        router: &Router,
+        metadata_dir: &Path,
+        posters: bool,
+    ) {
+        let relative = placement.path(self);
+        let mut paths = router
+            .roots(self)
+            .into_iter()
+            .map(|root| root.join(&relative));
+        let image_path = paths.next().unwrap();
+        self.link_paths = paths.collect();
+        self.poster_path =
+            (posters && is_video(self.ext())).then(|| image_path.with_extension("jpg"));
+        let tags_file = format!("{}.json", self.file.md5);
+        let tags_path = metadata_dir.join(tags_file);
+        debug!(
+            "Hydrated output path {:?}, tags path {:?}",
+            image_path, tags_path
+        );
+        self.file_path = Some(image_path);
+        self.tags_path = Some(tags_path);
     }
 }
 
-async fn archive_post(post: &Post) -> Result<(), Error> {
-    // This isn't really async, we block and download only one image
-    // at a time.
-    let path = &post.file_path;
-    if let Some(url) = &post.file.url {
-        match File::create(path.as_ref().unwrap()) {
-            Ok(mut output) => {
-                // Since this uses async code, and we don't want this function
-                // to be async itself, we must spawn an async closure.
-                let url = url.to_owned();
-                info!("downloading {}", &url);
-                match reqwest::get(&url).await {
-                    Ok(response) => {
-                        if let Ok(bytes) = response.bytes().await {
-                            let _ = output.write_all(&bytes);
-                        }
-                    }
-                    Err(e) => {
-                        error!("Could not fetch url {}: {:?}", &url, e)
-                    }
-                }
-                // Force a sleep, don't pound the server!
-                std::thread::sleep(std::time::Duration::from_millis(1500));
+// Which MD5 each archived post has now. A post whose file was replaced has a
+// sidecar for each, and it's the one that isn't superseded.
+fn archived_md5s(metadata_dir: &Path) -> std::io::Result<HashMap<u64, String>> {
+    let posts = load_sidecars(metadata_dir)?;
+    let superseded: HashSet<&str> = posts
+        .iter()
+        .filter_map(|post| post.supersedes.as_deref())
+        .collect();
+    Ok(posts
+        .iter()
+        .filter(|post| !superseded.contains(post.file.md5.as_str()))
+        .map(|post| (post.id, post.file.md5.clone()))
+        .collect())
+}
+
+/// Reads every metadata sidecar in `metadata_dir`, skipping (with a warning)
+/// any that can't be read.
+fn load_sidecars(metadata_dir: &Path) -> std::io::Result<Vec<Post>> {
+    let mut posts = Vec::new();
+    for entry in read_dir(metadata_dir)? {
+        let sidecar = entry?.path();
+        let name = sidecar.to_string_lossy();
+        if sidecar.extension().is_none_or(|ext| ext != "json")
+            || EXTRA_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+        {
+            continue;
+        }
+        let post = File::open(&sidecar).and_then(|file| {
+            serde_json::from_reader::<_, Post>(BufReader::new(file)).map_err(Into::into)
+        });
+        match post {
+            Ok(post) => posts.push(post),
+            Err(e) => warn!("Skipping unreadable sidecar {:?}: {}", sidecar, e),
+        }
+    }
+    Ok(posts)
+}
+
+// Downloads the post's file. With `cdn_host`, the file is always fetched from
+// that host; otherwise the URL is used as given, and if that fails and it
+// points at some other CDN host, the canonical one is tried as well.
+async fn archive_post(
+    client: &Client,
+    storage: &dyn StorageBackend,
+    post: &Post,
+    opts: &Opts,
+) -> Result<(), MonosodiumError> {
+    let variant = post.variant.as_ref().map(|variant| &variant.url);
+    let Some(url) = variant.or(post.file.url.as_ref()) else {
+        return Ok(());
+    };
+    if let Some(pinned) = opts
+        .cdn_host
+        .as_deref()
+        .and_then(|host| on_cdn_host(url, host))
+    {
+        return download_with_retries(client, storage, post, &pinned, opts).await;
+    }
+    match download_with_retries(client, storage, post, url, opts).await {
+        Err(MonosodiumError::Http(e)) => match on_cdn_host(url, CDN_HOST).filter(|c| c != url) {
+            Some(canonical) => {
+                warn!(
+                    "Download of post {} failed ({}), trying {} instead",
+                    post.id, e, CDN_HOST
+                );
+                download_with_retries(client, storage, post, &canonical, opts).await
             }
-            Err(e) => {
-                error!("{:?}", e);
+            None => Err(e.into()),
+        },
+        result => result,
+    }
+}
+
+// Moves a file URL on one of the site's CDN hosts to `host`. Other URLs are
+// left alone.
+fn on_cdn_host(url: &str, host: &str) -> Option<String> {
+    let mut url = Url::parse(url).ok()?;
+    let current = url.host_str()?;
+    if current != CDN_DOMAIN && !current.ends_with(&format!(".{}", CDN_DOMAIN)) {
+        return None;
+    }
+    url.set_host(Some(host)).ok()?;
+    Some(url.into())
+}
+
+async fn download_with_retries(
+    client: &Client,
+    storage: &dyn StorageBackend,
+    post: &Post,
+    url: &str,
+    opts: &Opts,
+) -> Result<(), MonosodiumError> {
+    let retries = opts.retries;
+    let mut attempt = 0;
+    loop {
+        match download_post(client, storage, post, url, opts).await {
+            Err(e) if attempt < retries && is_retryable(&e) => {
+                let backoff = RETRY_BACKOFF * 2u32.pow(attempt);
+                attempt += 1;
+                warn!(
+                    "Download of post {} failed ({}), retry {}/{} in {}",
+                    post.id,
+                    e,
+                    attempt,
+                    retries,
+                    humantime::format_duration(backoff)
+                );
+                tokio::time::sleep(backoff).await;
             }
+            result => return result,
+        }
+    }
+}
+
+async fn download_post(
+    client: &Client,
+    storage: &dyn StorageBackend,
+    post: &Post,
+    url: &str,
+    opts: &Opts,
+) -> Result<(), MonosodiumError> {
+    info!("downloading {}", url);
+    client.wait_turn().await;
+    let started = Instant::now();
+    let fetched = async {
+        let response = client.send(url).await?.error_for_status()?;
+        let status = response.status();
+        response.bytes().await.map(|bytes| (status, bytes))
+    };
+    let fetched = client.profile().time(Phase::Downloads, fetched).await;
+    let elapsed = started.elapsed();
+    let mut bytes: Vec<u8> = match fetched {
+        Ok((status, bytes)) => {
+            debug!(
+                "{} {}: {} bytes in {:.3}s",
+                status.as_u16(),
+                url,
+                bytes.len(),
+                elapsed.as_secs_f64()
+            );
+            client
+                .profile()
+                .record_download(url, bytes.len() as u64, elapsed);
+            bytes.into()
+        }
+        Err(e) => {
+            debug!(
+                "{} {}: failed after {:.3}s: {}",
+                e.status()
+                    .map_or("---".to_owned(), |status| status.as_u16().to_string()),
+                url,
+                elapsed.as_secs_f64(),
+                e
+            );
+            return Err(e.into());
+        }
+    };
+    // A hiccup can come back as a success with a short or empty body. Saving
+    // that would leave a file that looks archived from then on.
+    // A variant's size isn't known ahead of time.
+    let expected = match post.variant {
+        Some(_) => 0,
+        None => post.file.size as u64,
+    };
+    let received = bytes.len() as u64;
+    let mismatched = opts.retry_on_parse_mismatch && expected > 0 && received != expected;
+    if bytes.is_empty() || received < expected || mismatched {
+        return Err(MonosodiumError::Incomplete { expected, received });
+    }
+    // Nor is a variant's MD5.
+    if opts.verify_on_download && post.variant.is_none() {
+        let actual = format!("{:x}", md5::compute(&bytes));
+        if !actual.eq_ignore_ascii_case(&post.file.md5) {
+            return Err(MonosodiumError::WrongMd5 {
+                expected: post.file.md5.clone(),
+                actual,
+            });
+        }
+    }
+    let mut stripped = false;
+    if opts.strip_metadata {
+        if let Some((without, removed)) = strip::strip(post.ext(), &bytes) {
+            info!("Stripped {} from post {}", removed.join(", "), post.id);
+            bytes = without;
+            stripped = true;
         }
     }
+    // Not e621's file, so its MD5 is only known now.
+    if post.variant.is_some() || stripped {
+        *post.saved_md5.lock().unwrap() = Some(format!("{:x}", md5::compute(&bytes)));
+    }
+    let written = storage.write(post.file_path.as_ref().unwrap(), bytes);
+    client.profile().time(Phase::Writes, written).await?;
 
     Ok(())
 }
 
-fn favorites_url(user_id: u32, page: usize) -> String {
-    format!(
-        "https://e621.net/favorites.json?user_id={}&page={}",
-        user_id, page
-    )
+// Network trouble and server outages may clear up on their own; anything else,
+// like a missing file or a full disk, won't. A short body is most likely a
+// hiccup too.
+fn is_retryable(e: &MonosodiumError) -> bool {
+    match e {
+        MonosodiumError::Http(e) => e.status().is_none_or(is_outage),
+        MonosodiumError::Incomplete { .. } | MonosodiumError::WrongMd5 { .. } => true,
+        _ => false,
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    env_logger::init();
-
-    let opts: Opts = Opts::parse();
-
-    let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+// Downloads the post's thumbnail, for --download-thumbnails-too, unless it's
+// already there.
+async fn archive_thumbnail(
+    client: &Client,
+    storage: &dyn StorageBackend,
+    post: &Post,
+) -> Result<(), MonosodiumError> {
+    let (Some(path), Some(url)) = (
+        &post.thumbnail_path,
+        post.preview
+            .as_ref()
+            .and_then(|preview| preview.url.as_ref()),
+    ) else {
+        return Ok(());
+    };
+    if storage.exists(path).await? {
+        return Ok(());
+    }
+    info!("downloading {}", url);
+    let bytes = client.get(url).await?.error_for_status()?.bytes().await?;
+    // Saving nothing would keep it from being downloaded again.
+    if bytes.is_empty() {
+        return Err(std::io::Error::other("the server sent an empty thumbnail").into());
+    }
+    storage.write(path, bytes.into()).await
+}
 
-    let directory = Path::new(&opts.directory);
-    let metadata_dir = directory.join("metadata");
-    create_dir_all(&metadata_dir).expect("Could not create metadata directory");
+// Gives a post's file its place under every other route it matched.
+fn link_post(post: &Post, kind: LinkKind, modes: Modes) -> std::io::Result<()> {
+    let file_path = post.file_path.as_ref().unwrap();
+    for link_path in &post.link_paths {
+        if link_path.exists() {
+            continue;
+        }
+        link(file_path, link_path, kind, modes)?;
+        if let Some(poster_path) = post.poster_path.as_ref().filter(|path| path.exists()) {
+            link(poster_path, &link_path.with_extension("jpg"), kind, modes)?;
+        }
+    }
+    Ok(())
+}
 
-    let mut page: usize = 1;
+fn link(original: &Path, link: &Path, kind: LinkKind, modes: Modes) -> std::io::Result<()> {
+    if let Some(parent) = link.parent() {
+        modes.create_dir_all(parent)?;
+    }
+    match kind {
+        #[cfg(unix)]
+        LinkKind::Symlink => std::os::unix::fs::symlink(original.canonicalize()?, link)?,
+        #[cfg(not(unix))]
+        LinkKind::Symlink => {
+            std::fs::copy(original, link)?;
+            modes.apply_to_file(link)?;
+        }
+        // Across filesystems, a copy is the next best thing.
+        LinkKind::Hardlink => match std::fs::hard_link(original, link) {
+            Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+                std::fs::copy(original, link)?;
+                modes.apply_to_file(link)?;
+            }
+            result => result?,
+        },
+    }
+    Ok(())
+}
 
-    loop {
-        info!("Checking favorites page {:2}", page);
+// Descriptors held whatever the settings: standard streams, the lock, the
+// journal, logs and a few connections to e621.
+const BASELINE_OPEN_FILES: u64 = 32;
 
-        let url = favorites_url(opts.user_id, page);
+// Warns when the settings could hold more files open at once than the soft
+// limit allows, which otherwise shows up as failures partway through a run.
+fn check_open_file_limit(opts: &Opts) {
+    let Some(limit) = storage::open_file_limit() else {
+        return;
+    };
+    // Each copy of --filter-script holds three pipes.
+    let scripts = match opts.filter_script {
+        Some(_) => opts.filter_script_concurrency as u64 * 3,
+        None => 0,
+    };
+    // --max-open-files covers files being hashed, as well as written.
+    let files = opts
+        .max_open_files
+        .map_or(opts.verify_concurrency as u64 + 1, u64::from);
+    let needed = BASELINE_OPEN_FILES + scripts + files;
+    if needed > limit {
+        warn!(
+            "these settings could have {} files open at once, more than the limit of {}; \
+             raise it with `ulimit -n`, or lower --verify-concurrency, --filter-script-concurrency or --max-open-files",
+            needed, limit
+        );
+    }
+}
 
-        let mut response = client.get(&url).send().await?.json::<ApiResponse>().await?;
+// Creates the directory if need be, and makes sure files can be written in it,
+// so that a bad --directory is reported before any time is spent fetching.
+fn ensure_writable(dir: &Path, modes: Modes) -> Result<(), MonosodiumError> {
+    let not_writable = |source| MonosodiumError::NotWritable {
+        path: dir.to_owned(),
+        source,
+    };
+    modes.create_dir_all(dir).map_err(not_writable)?;
+    let probe = dir.join(".monosodium-write-test");
+    File::create(&probe).map_err(not_writable)?;
+    remove_file(&probe).map_err(not_writable)
+}
 
-        if response.posts.is_empty() {
-            break;
+// Whether the post's file is already in the archive.
+// The API knows nothing of what an earlier run did to the files it saved, so
+// for posts already archived, that's taken from their sidecars, for checking
+// the files or writing the sidecars again.
+fn keep_saved_fields(posts: &mut [Post]) {
+    for post in posts {
+        let Some(sidecar) = &post.tags_path else {
+            continue;
+        };
+        let saved = File::open(sidecar)
+            .map(|file| serde_json::from_reader::<_, Post>(BufReader::new(file)));
+        match saved {
+            // Saved somewhere else, it isn't the file this run would check.
+            Ok(Ok(saved)) if saved.file_path == post.file_path => post.keep_saved(saved),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("Skipping unreadable sidecar {:?}: {}", sidecar, e),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!("Skipping unreadable sidecar {:?}: {}", sidecar, e),
         }
+    }
+}
 
-        response.hydrate(directory, &metadata_dir);
+async fn is_archived(storage: &dyn StorageBackend, post: &Post) -> Result<bool, MonosodiumError> {
+    match &post.file_path {
+        Some(path) => storage.exists(path).await,
+        None => Ok(false),
+    }
+}
 
-        page += 1;
+fn is_downloadable(post: &Post, archived: bool) -> bool {
+    post.file.url.is_some() && post.file_path.is_some() && !archived
+}
 
-        let downloadable_posts: Vec<&Post> = response
-            .posts
-            .iter()
-            .filter(|x| {
-                x.file.url.is_some()
-                    && x.file_path.is_some()
-                    && !x.file_path.as_ref().unwrap().exists()
-            })
-            .collect();
+// What a run would download, worked out ahead of time from the metadata.
+#[derive(Default)]
+struct Estimate {
+    posts: usize,
+    files: usize,
+    bytes: u64,
+}
 
-        let count = downloadable_posts.len();
-        match count {
-            0 => info!("No images to download"),
-            1 => info!("1 image to download"),
-            n => info!("{n} images to download"),
-        };
+impl Estimate {
+    fn add(&mut self, more: Estimate) {
+        self.posts += more.posts;
+        self.files += more.files;
+        self.bytes += more.bytes;
+    }
 
-        let mut stream = tokio_stream::iter(downloadable_posts);
+    // Whether it's worth asking before downloading this much.
+    fn is_large(&self, opts: &Opts) -> bool {
+        self.files > opts.confirm_files || self.bytes > opts.confirm_size
+    }
+}
 
-        while let Some(post) = stream.next().await {
-            archive_post(post).await?;
-            archive_metadata(post);
+async fn estimate(context: &Context<'_>, pages: &mut [Page]) -> Result<Estimate, MonosodiumError> {
+    let mut estimate = Estimate::default();
+    for Page { response: page, .. } in pages {
+        estimate.posts += page.posts.len();
+        for post in &page.posts {
+            if context.filters.reject(post).is_some() {
+                continue;
+            }
+            if is_downloadable(post, is_archived(context.storage.as_ref(), post).await?) {
+                estimate.files += 1;
+                estimate.bytes += post.file.size as u64;
+            }
         }
-
-        // Force a sleep between page fetches, don't pound the server!
-        std::thread::sleep(std::time::Duration::from_millis(1500));
     }
+    Ok(estimate)
+}
 
-    println!("Done! Enjoy that offline archive!");
+// Works out roughly what a run would download from a count of its posts and
+// one page of them, for --estimate-only, rather than reading every page.
+async fn run_estimate(context: &Context<'_>, source: &Source) -> Result<(), MonosodiumError> {
+    let mut response = fetch_page(&context.client, source, 1, MAX_PER_PAGE, None).await?;
+    response.look_up_pools(context).await?;
+    response.hydrate(context);
+    context.filters.screen(&response.posts).await?;
+    let count = projection::count(&context.client, source, response.posts.len()).await?;
+    let mut sample = Vec::with_capacity(response.posts.len());
+    for post in &response.posts {
+        let wanted = context.filters.reject(post).is_none()
+            && is_downloadable(post, is_archived(context.storage.as_ref(), post).await?);
+        sample.push(wanted.then_some(post.file.size as u64));
+    }
+    let Projection { files, bytes } = projection::project(&sample, count.posts());
+    // Every request waits its turn, files included, so this is the least it
+    // could take.
+    let pages = count.posts().div_ceil(context.opts.per_page as u64);
+    let delay = context.opts.api_delay();
+    let time = delay.mul_f64((pages + files.estimate) as f64);
 
+    println!(
+        "Estimated from the first {} posts, not counted:",
+        sample.len()
+    );
+    println!("  Posts: {}", count);
+    println!(
+        "  Files to download: about {} ({} to {})",
+        files.estimate, files.low, files.high
+    );
+    println!(
+        "  Size: about {} ({} to {})",
+        format_size(bytes.estimate),
+        format_size(bytes.low),
+        format_size(bytes.high)
+    );
+    println!(
+        "  Time: at least {}",
+        humantime::format_duration(Duration::from_secs(time.as_secs()))
+    );
+    println!(
+        "The ranges are 95% confidence intervals, if the first posts are like the rest; \
+         --analyze gives exact numbers."
+    );
     Ok(())
 }
+
+// With `whole`, the estimate is of every page; otherwise only of those it
+// took to get over the limits, so there's at least that much.
+fn confirm(estimate: &Estimate, whole: bool) -> std::io::Result<bool> {
+    ask(&format!(
+        "About to download {}{} files ({}). Continue?",
+        if whole { "" } else { "at least " },
+        estimate.files,
+        format_size(estimate.bytes)
+    ))
+}
+
+fn ask(question: &str) -> std::io::Result<bool> {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+// Everything a run needs that stays the same from start to finish.
+struct Context<'a> {
+    opts: &'a Opts,
+    client: Client,
+    storage: Box<dyn StorageBackend>,
+    router: Router,
+    metadata_dir: PathBuf,
+    filters: Filters,
+    tag_db: Option<TagDb>,
+    library: Option<Library>,
+    failure_log: FailureLog,
+    // Only for local storage, where a crash can leave files half written.
+    journal: Option<Journal>,
+    shutdown: Shutdown,
+    source_key: String,
+    state_path: PathBuf,
+    // Where --partial-page-recovery keeps the posts done since the last
+    // checkpoint.
+    done_path: PathBuf,
+    posters: bool,
+    // Whether downloaded videos of unknown length are measured with ffprobe,
+    // for --max-duration-seconds.
+    probe_durations: bool,
+    // The MD5 of each archived post's current file, for --if-newer-remote.
+    archived_md5s: Option<HashMap<u64, String>>,
+    placement: Placement,
+    // Only for --layout by-pool, or an --output-layout-template with a pool
+    // in it.
+    pools: Option<Pools>,
+    // Only for --set.
+    post_set: Option<PostSet>,
+    // Only for --reencode-video, and only with ffmpeg.
+    reencode: Option<VideoPreset>,
+    // Only for --compress-images-lossless, and only with an optimizer.
+    optimizers: Option<Optimizers>,
+    // Only for --skip-existing-by-id.
+    known_files: Option<KnownFiles>,
+    // Only for --min-free-space.
+    watchdog: Option<Watchdog>,
+    // Only for --download-thumbnails-too.
+    thumbnails_dir: Option<PathBuf>,
+}
+
+async fn archive_posts(
+    context: &Context<'_>,
+    mut pages: Pages,
+    summary: &mut Summary,
+    mut index: Option<&mut Index>,
+    mut checksums: Option<&mut ChecksumCache>,
+    deleted: &mut DeletedPosts,
+) -> Result<(), MonosodiumError> {
+    let Context {
+        opts,
+        client,
+        storage,
+        filters,
+        library,
+        failure_log,
+        journal,
+        shutdown,
+        source_key,
+        probe_durations,
+        watchdog,
+        ..
+    } = context;
+    let storage = storage.as_ref();
+    let writers = metadata::writers(opts, client);
+    let mut checkpoints = Checkpoints::new(opts.checkpoint_interval);
+    let mut finished = None;
+    // Once a post has failed, progress stays before its page, so resuming
+    // tries it again.
+    let mut failed = false;
+    let mut done = opts.partial_page_recovery.then(|| load_posts_done(context));
+
+    // Run to the end, or to the first error, and then wherever it stopped,
+    // save whatever hasn't been yet.
+    let result = async {
+        while let Some(page) = pages.next().await {
+            let Page {
+                number,
+                mut response,
+            } = page?;
+
+            response.look_up_pools(context).await?;
+            response.hydrate(context);
+            filters.screen(&response.posts).await?;
+            if (checksums.is_some() || opts.only_updated_since.is_some()) && storage.is_local() {
+                keep_saved_fields(&mut response.posts);
+            }
+
+            summary.pages += 1;
+            summary.posts_seen += response.posts.len();
+
+            let excluded_before = summary.excluded_total();
+            let wanted_posts: Vec<&Post> = response
+                .posts
+                .iter()
+                .filter(|x| match filters.reject(x) {
+                    Some(reason) => {
+                        summary.record_exclusion(x, reason);
+                        false
+                    }
+                    None => true,
+                })
+                .collect();
+            match summary.excluded_total() - excluded_before {
+                0 => {}
+                1 => info!("1 post excluded by filters"),
+                n => info!("{n} posts excluded by filters"),
+            };
+            for post in &wanted_posts {
+                deleted.record(post);
+            }
+
+            let mut archived = Vec::with_capacity(wanted_posts.len());
+            // The files posts were linked to by --dedupe, for the index.
+            let mut originals = HashMap::new();
+            for post in &wanted_posts {
+                let trusted = done.as_ref().is_some_and(|done| done.contains(post.id));
+                archived.push(trusted || is_archived(storage, post).await?);
+            }
+
+            if opts.fail_on_missing_url {
+                for (post, &archived) in wanted_posts.iter().zip(&archived) {
+                    if post.file.url.is_none() && !post.flags.deleted && !archived {
+                        warn!(
+                            "Post {} isn't deleted, but has no file to download",
+                            post.id
+                        );
+                        summary.missing_urls.push(post.id);
+                    }
+                }
+            }
+
+            if let Some(cache) = checksums.as_deref_mut() {
+                let present: Vec<&Post> = wanted_posts
+                    .iter()
+                    .zip(&archived)
+                    .filter_map(|(post, &archived)| archived.then_some(*post))
+                    .collect();
+                let diagnosis = tokio::task::block_in_place(|| {
+                    diagnose(&present, cache, opts.verify_concurrency, opts.force_verify)
+                });
+                for finding in diagnosis.findings {
+                    warn!(
+                        "Post {} failed verification ({}), downloading it again",
+                        finding.id, finding.problem
+                    );
+                    if let Some(i) = wanted_posts.iter().position(|post| post.id == finding.id) {
+                        archived[i] = false;
+                        if let Some(dir) = &opts.quarantine_dir {
+                            keep_in_quarantine(dir, wanted_posts[i], &finding);
+                        }
+                    }
+                }
+            }
+
+            let mut downloadable_posts: Vec<usize> = (0..wanted_posts.len())
+                .filter(|&i| is_downloadable(wanted_posts[i], archived[i]))
+                .collect();
+            if let Some(order) = opts.sort_downloads {
+                let post = |i: &usize| wanted_posts[*i];
+                match order {
+                    DownloadOrder::SizeAsc => downloadable_posts.sort_by_key(|i| post(i).file.size),
+                    DownloadOrder::SizeDesc => {
+                        downloadable_posts.sort_by_key(|i| std::cmp::Reverse(post(i).file.size))
+                    }
+                    DownloadOrder::ScoreDesc => {
+                        downloadable_posts.sort_by_key(|i| std::cmp::Reverse(post(i).score.total))
+                    }
+                    DownloadOrder::IdAsc => downloadable_posts.sort_by_key(|i| post(i).id),
+                }
+            }
+
+            summary.already_present += archived.iter().filter(|&&archived| archived).count();
+            for (post, &archived) in wanted_posts.iter().zip(&archived) {
+                if archived {
+                    summary.record_outcome(post, Outcome::AlreadyPresent);
+                } else if !is_downloadable(post, archived) {
+                    summary.record_outcome(post, Outcome::Unavailable);
+                }
+            }
+
+            // Posts already archived are otherwise left as they are, sidecar and
+            // all, however much they've changed on e621 since.
+            if let Some(cutoff) = opts.only_updated_since {
+                let sidecar = Sidecar {
+                    compact: opts.json_compact,
+                };
+                let updated = wanted_posts
+                    .iter()
+                    .zip(&archived)
+                    .filter(|(post, &archived)| archived && post.is_updated_since(cutoff));
+                for (post, _) in updated {
+                    match sidecar.write(storage, post).await {
+                        Ok(()) => summary.metadata_refreshed += 1,
+                        Err(e) => {
+                            error!("Could not refresh metadata for post {}: {}", post.id, e);
+                            failure_log.record(post, sidecar.name(), &e, 0);
+                        }
+                    }
+                }
+            }
+
+            let count = downloadable_posts.len();
+            match count {
+                0 => info!("No images to download"),
+                1 => info!("1 image to download"),
+                n => info!("{n} images to download"),
+            };
+
+            if let Some(done) = done.as_mut() {
+                let present = wanted_posts
+                    .iter()
+                    .zip(&archived)
+                    .filter_map(|(post, &archived)| archived.then_some(post.id));
+                if let Err(e) = done.record(present, &context.done_path, opts.write_strategy()) {
+                    error!("Could not save the posts done so far: {}", e);
+                }
+            }
+
+            let mut stream = tokio_stream::iter(downloadable_posts);
+            let mut interrupted = false;
+            let mut too_many_failures = false;
+
+            while let Some(i) = stream.next().await {
+                if let Some(watchdog) = watchdog {
+                    watchdog.wait_for_space(shutdown).await;
+                }
+                if shutdown.reason().is_some() {
+                    interrupted = true;
+                    break;
+                }
+
+                let post = wanted_posts[i];
+                if let Some(limit) = opts.limit_per_artist {
+                    let reached = post
+                        .tags
+                        .artist
+                        .iter()
+                        .filter(|artist| !NOT_ARTISTS.contains(&artist.as_str()))
+                        .any(|artist| summary.artists.get(artist).is_some_and(|&n| n >= limit));
+                    if reached {
+                        summary.record_exclusion(post, "over --limit-per-artist");
+                        continue;
+                    }
+                }
+                let in_archive = opts
+                    .dedupe
+                    .zip(index.as_deref().and_then(|index| index.copy_of(post)));
+                let in_library = library
+                    .as_ref()
+                    .and_then(|library| library.get(&post.file.md5, post.ext()))
+                    .map(|original| (LinkKind::Hardlink, original));
+                let copy = in_archive.or(in_library);
+                if let Some(journal) = journal {
+                    journal.begin(post).await?;
+                }
+                let result = match copy {
+                    Some((kind, original)) => {
+                        info!("Linking post {} to its copy at {:?}", post.id, original);
+                        link(
+                            original,
+                            post.file_path.as_ref().unwrap(),
+                            kind,
+                            opts.modes(),
+                        )
+                        .map_err(Into::into)
+                    }
+                    None => archive_post(client, storage, post, opts).await,
+                };
+                if result.is_ok()
+                    && copy.is_none()
+                    && *probe_durations
+                    && is_too_long(post, opts).await
+                {
+                    info!(
+                        "Post {} runs longer than --max-duration-seconds, so it was removed",
+                        post.id
+                    );
+                    if let Err(e) = remove_file(post.file_path.as_ref().unwrap()) {
+                        error!("Could not remove post {}: {}", post.id, e);
+                    }
+                    if let Some(journal) = journal {
+                        journal.commit(post);
+                    }
+                    summary.record_exclusion(post, TOO_LONG);
+                    continue;
+                }
+                let result = match result {
+                    Ok(()) => {
+                        let completed = complete_post(
+                            storage,
+                            post,
+                            &writers,
+                            failure_log,
+                            context.optimizers,
+                            opts.modes(),
+                        );
+                        client.profile().time(Phase::Writes, completed).await
+                    }
+                    Err(e) => {
+                        // Errors worth retrying are only given up on once the
+                        // retries have run out.
+                        let retries = if is_retryable(&e) { opts.retries } else { 0 };
+                        failure_log.record(post, "file", &e, retries);
+                        Err(e)
+                    }
+                };
+                match result {
+                    Ok(()) => {
+                        if let Some(journal) = journal {
+                            journal.commit(post);
+                        }
+                        if let Some(old) = &post.supersedes {
+                            warn!(
+                                "Post {} has a new file; archived it next to the old one, {}",
+                                post.id, old
+                            );
+                        }
+                        archived[i] = true;
+                        if let Some((_, original)) = in_archive {
+                            originals.insert(post.id, original.to_owned());
+                        }
+                        if let Some(done) = done.as_mut() {
+                            let path = &context.done_path;
+                            if let Err(e) = done.record([post.id], path, opts.write_strategy()) {
+                                error!("Could not save the posts done so far: {}", e);
+                            }
+                        }
+                        if in_archive.is_some() {
+                            summary.record_outcome(post, Outcome::Deduplicated);
+                            summary.deduplicated += 1;
+                        } else if in_library.is_some() {
+                            summary.record_outcome(post, Outcome::LinkedFromLibrary);
+                            summary.linked_from_library += 1;
+                        } else {
+                            summary.record_download(post);
+                        }
+                    }
+                    Err(e) => {
+                        if let Some(journal) = journal {
+                            journal.roll_back(post);
+                        }
+                        error!("Could not archive post {}: {}", post.id, e);
+                        summary.record_failure(post, e.to_string());
+                        failed = true;
+                        if opts
+                            .max_file_failures
+                            .is_some_and(|limit| summary.exceeds(limit))
+                        {
+                            too_many_failures = true;
+                            interrupted = true;
+                            break;
+                        }
+                    }
+                }
+            }
+
+            let stored_posts: Vec<&Post> = wanted_posts
+                .iter()
+                .zip(&archived)
+                .filter_map(|(post, &archived)| archived.then_some(*post))
+                .collect();
+
+            // Posts archived before thumbnails were asked for get theirs too.
+            for post in &stored_posts {
+                if shutdown.reason().is_some() {
+                    break;
+                }
+                if let Err(e) = archive_thumbnail(client, storage, post).await {
+                    warn!(
+                        "Could not download the thumbnail of post {}: {}",
+                        post.id, e
+                    );
+                    failure_log.record(post, "thumbnail", &e, 0);
+                }
+            }
+
+            for post in &stored_posts {
+                if let Err(e) = link_post(post, opts.dedupe.unwrap_or_default(), opts.modes()) {
+                    error!(
+                        "Could not link post {} under its other routes: {}",
+                        post.id, e
+                    );
+                }
+            }
+
+            if let Some(index) = index.as_deref_mut() {
+                for post in &stored_posts {
+                    index.insert(post, originals.get(&post.id).map(PathBuf::as_path));
+                }
+            }
+
+            // The page isn't finished, so progress stays at the one before it.
+            if !interrupted && !failed {
+                finished = Some(RunState::new(
+                    source_key.clone(),
+                    number,
+                    opts.per_page,
+                    &response,
+                ));
+            }
+            if checkpoints.due() || interrupted {
+                checkpoints.saved();
+                let state = finished.take();
+                save_checkpoint(
+                    context,
+                    index.as_deref(),
+                    checksums.as_deref(),
+                    deleted,
+                    state.as_ref(),
+                    done.as_mut(),
+                )
+                .await;
+            }
+
+            if too_many_failures {
+                summary.stopped = shutdown.reason();
+                return Err(MonosodiumError::TooManyFailures {
+                    failed: summary.failures.len(),
+                    attempted: summary.attempted(),
+                });
+            }
+
+            if opts
+                .max_pages
+                .is_some_and(|max| summary.pages >= max as usize)
+            {
+                warn!(
+                    "Stopping after {} pages, as --max-pages asked",
+                    summary.pages
+                );
+                shutdown.trigger(StopReason::PageLimit);
+            }
+
+            if shutdown.reason().is_some() {
+                break;
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    // Whatever pages have been finished since the last checkpoint.
+    if checkpoints.pending() {
+        let state = finished.take();
+        save_checkpoint(
+            context,
+            index.as_deref(),
+            checksums.as_deref(),
+            deleted,
+            state.as_ref(),
+            done.as_mut(),
+        )
+        .await;
+    }
+
+    summary.stopped = shutdown.reason();
+
+    result
+}
+
+// Makes what's been done so far durable: the archive itself, the files kept
+// alongside it, and, given the state of the last finished page, progress.
+async fn save_checkpoint(
+    context: &Context<'_>,
+    index: Option<&Index>,
+    checksums: Option<&ChecksumCache>,
+    deleted: &mut DeletedPosts,
+    state: Option<&RunState>,
+    done: Option<&mut PostsDone>,
+) {
+    let opts = context.opts;
+    if let Some(index) = index {
+        if let Err(e) = index.save(opts.write_strategy()) {
+            error!("Could not save index: {}", e);
+        }
+    }
+    if let Some(cache) = checksums {
+        if let Err(e) = cache.save(opts.write_strategy()) {
+            error!("Could not save checksum cache: {}", e);
+        }
+    }
+    if let Err(e) = deleted.save() {
+        error!("Could not save the list of deleted posts: {}", e);
+    }
+
+    if let Err(e) = context.storage.flush() {
+        error!("Could not save the archive: {}", e);
+    }
+
+    // Every post begun so far is committed or rolled back by now.
+    if let Some(journal) = &context.journal {
+        if let Err(e) = journal.checkpoint().await {
+            error!("Could not clear the journal: {}", e);
+        }
+    }
+
+    if let Some(state) = state {
+        match state.save(&context.state_path, opts.write_strategy()) {
+            Ok(()) => {
+                // Resuming starts after this page now, so the posts done
+                // on the way to it aren't needed any more.
+                if let Some(done) = done {
+                    if let Err(e) = done.clear(&context.done_path) {
+                        error!("Could not clear the posts done so far: {}", e);
+                    }
+                }
+            }
+            Err(e) => error!("Could not save progress: {}", e),
+        }
+    }
+}
+
+// The posts a crashed run got done since its last checkpoint, for
+// --partial-page-recovery. Only a resumed run trusts them; any other starts
+// afresh.
+fn load_posts_done(context: &Context<'_>) -> PostsDone {
+    let path = &context.done_path;
+    let source = &context.source_key;
+    if !context.opts.resume {
+        if let Err(e) = RunState::clear(path) {
+            warn!("Could not clear the posts done by the last run: {}", e);
+        }
+        return PostsDone::new(source.clone());
+    }
+    match PostsDone::load(path, source) {
+        Ok(done) => {
+            if !done.posts.is_empty() {
+                info!(
+                    "{} posts were done before the last run stopped; not checking them again",
+                    done.posts.len()
+                );
+            }
+            done
+        }
+        Err(e) => {
+            warn!(
+                "Could not read the posts done by the last run ({}), so checking them all",
+                e
+            );
+            PostsDone::new(source.clone())
+        }
+    }
+}
+
+// Whether a video that e621 didn't give the length of turns out to be longer
+// than --max-duration-seconds, now that it's downloaded. One that can't be
+// measured is kept.
+async fn is_too_long(post: &Post, opts: &Opts) -> bool {
+    let Some(max) = opts.max_duration_seconds else {
+        return false;
+    };
+    if post.duration.is_some() || !is_video(post.ext()) {
+        return false;
+    }
+    match video::duration(post.file_path.as_ref().unwrap()).await {
+        Ok(duration) => duration > max as f64,
+        Err(e) => {
+            warn!("Could not measure post {}: {}", post.id, e);
+            false
+        }
+    }
+}
+
+// The optimizers there are for --compress-images-lossless, if it was given.
+async fn find_optimizers(opts: &Opts) -> Option<Optimizers> {
+    if !opts.compress_images_lossless {
+        return None;
+    }
+    let found = Optimizers::find().await;
+    if !found.any() {
+        warn!("Neither oxipng nor jpegtran is on the PATH, so images will be kept as they're downloaded");
+        return None;
+    }
+    Some(found)
+}
+
+fn is_unset<T>(field: &Mutex<Option<T>>) -> bool {
+    field.lock().unwrap().is_none()
+}
+
+// Writes everything that goes with a post once its file is in place. Only a
+// failure of a required writer fails the post; the rest are logged.
+async fn complete_post(
+    storage: &dyn StorageBackend,
+    post: &Post,
+    writers: &[Box<dyn MetadataWriter>],
+    failure_log: &FailureLog,
+    optimizers: Option<Optimizers>,
+    modes: Modes,
+) -> Result<(), MonosodiumError> {
+    alter_file(post, failure_log, optimizers, modes).await?;
+    if let Some(poster_path) = &post.poster_path {
+        let video = post.file_path.as_ref().unwrap();
+        let made = extract_poster(video, poster_path)
+            .await
+            .and_then(|()| modes.apply_to_file(poster_path));
+        if let Err(e) = made {
+            warn!("Could not make a poster for post {}: {}", post.id, e);
+        }
+    }
+    let mut result = Ok(());
+    for writer in writers {
+        if let Err(e) = writer.write(storage, post).await {
+            error!(
+                "Could not write {} for post {}: {}",
+                writer.name(),
+                post.id,
+                e
+            );
+            failure_log.record(post, writer.name(), &e, 0);
+            if writer.required() && result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
+
+// Does to the post's file, once it's downloaded, whatever the run asked for:
+// scaling it down, reencoding it and compressing it.
+async fn alter_file(
+    post: &Post,
+    failure_log: &FailureLog,
+    optimizers: Option<Optimizers>,
+    modes: Modes,
+) -> Result<(), MonosodiumError> {
+    if let Some(resized) = &post.resized {
+        let file_path = post.file_path.as_ref().unwrap();
+        let scaled = match downscale::downscale(file_path, resized)
+            .await
+            .and_then(|()| modes.apply_to_file(file_path))
+        {
+            Ok(()) => note_saved_md5(post).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = scaled {
+            error!("Could not scale down post {}: {}", post.id, e);
+            let e = e.into();
+            failure_log.record(post, "downscale", &e, 0);
+            return Err(e);
+        }
+    }
+    if let Some(reencoded) = &post.reencoded {
+        let file_path = post.file_path.as_ref().unwrap();
+        let transcoded = match reencode::reencode(file_path, reencoded)
+            .await
+            .and_then(|()| modes.apply_to_file(file_path))
+        {
+            Ok(()) => note_saved_md5(post).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = transcoded {
+            error!("Could not reencode post {}: {}", post.id, e);
+            // Left behind, the download would pass for the transcoded file
+            // next time.
+            let _ = remove_file(file_path);
+            let e = e.into();
+            failure_log.record(post, "reencode", &e, 0);
+            return Err(e);
+        }
+    }
+    // Just a smaller file, so if it can't be had, the one downloaded will do.
+    if let Some(optimizer) = optimizers.and_then(|found| found.for_ext(post.ext())) {
+        let file_path = post.file_path.as_ref().unwrap();
+        let compressed = compress::compress(file_path, optimizer).await;
+        match compressed.and_then(|compressed| {
+            modes.apply_to_file(file_path)?;
+            Ok(compressed)
+        }) {
+            Ok(Some(compressed)) => {
+                debug!(
+                    "Compressed post {} from {} to {} bytes",
+                    post.id, compressed.original_size, compressed.size
+                );
+                *post.saved_md5.lock().unwrap() = Some(compressed.md5.clone());
+                *post.compressed.lock().unwrap() = Some(compressed);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Could not compress post {}: {}", post.id, e),
+        }
+    }
+    Ok(())
+}
+
+// Notes the MD5 of the post's file as it is now, after changing it.
+async fn note_saved_md5(post: &Post) -> std::io::Result<()> {
+    let path = post.file_path.clone().unwrap();
+    let md5 = tokio::task::spawn_blocking(move || md5_file(&path))
+        .await
+        .expect("hashing panicked")?;
+    *post.saved_md5.lock().unwrap() = Some(md5);
+    Ok(())
+}
+
+// Prints every post that passes the filters, one per line. Posts without a
+// file URL still get their id printed, with nothing after the tab.
+fn list_posts(filters: &Filters, pages: &[Page], format: ListFormat) {
+    let posts = pages
+        .iter()
+        .flat_map(|page| &page.response.posts)
+        .filter(|post| filters.reject(post).is_none());
+    for post in posts {
+        match format {
+            ListFormat::Ids => println!("{}", post.id),
+            ListFormat::Urls => println!("{}\t{}", post.id, post.file.url.as_deref().unwrap_or("")),
+        }
+    }
+}
+
+// Runs the doctor's check, then downloads every file that failed it again. The
+// sidecar's URL is used if it has one; otherwise the post is looked up by id.
+async fn run_repair(
+    opts: &Opts,
+    client: &Client,
+    directory: &Path,
+    metadata_dir: &Path,
+) -> Result<(), MonosodiumError> {
+    let posts = load_sidecars(metadata_dir)?;
+    let mut cache = ChecksumCache::load(&directory.join(CACHE_FILE))?;
+    let diagnosis = {
+        let posts: Vec<&Post> = posts.iter().collect();
+        diagnose(
+            &posts,
+            &mut cache,
+            opts.verify_concurrency,
+            opts.force_verify,
+        )
+    };
+    if let Err(e) = cache.save(opts.write_strategy()) {
+        error!("Could not save checksum cache: {}", e);
+    }
+
+    let mut posts: HashMap<u64, Post> = posts.into_iter().map(|post| (post.id, post)).collect();
+    // A file that was changed after it was downloaded is changed again once
+    // it's repaired, rather than left as e621's, and gets its sidecar again.
+    let failure_log = FailureLog::open(directory, false)?;
+    let optimizers = match posts.values().any(|post| !is_unset(&post.compressed)) {
+        true => Some(Optimizers::find().await),
+        false => None,
+    };
+    let sidecar = Sidecar {
+        compact: opts.json_compact,
+    };
+    let mut repaired = 0;
+    for finding in &diagnosis.findings {
+        let Some(post) = posts.get_mut(&finding.id) else {
+            continue;
+        };
+        info!("Repairing post {} ({})", post.id, finding.problem);
+        if let Some(dir) = &opts.quarantine_dir {
+            keep_in_quarantine(dir, post, finding);
+        }
+        if post.file.url.is_none() {
+            match lookup_post(client, post.id).await {
+                Ok(current) => post.file.url = current.file.url,
+                Err(e) => {
+                    error!("Could not look up post {}: {}", post.id, e);
+                    continue;
+                }
+            }
+        }
+        if post.file.url.is_none() {
+            error!(
+                "Post {} has no file to download; it may have been deleted",
+                post.id
+            );
+            continue;
+        }
+        let storage = Filesystem {
+            modes: opts.modes(),
+        };
+        let altered = post.is_altered();
+        let compressed = post.compressed.lock().unwrap().take().is_some();
+        *post.saved_md5.lock().unwrap() = None;
+        let repair = async {
+            archive_post(client, &storage, post, opts).await?;
+            if altered {
+                let optimizers = optimizers.filter(|_| compressed);
+                alter_file(post, &failure_log, optimizers, opts.modes()).await?;
+                sidecar.write(&storage, post).await?;
+            }
+            Ok::<_, MonosodiumError>(())
+        };
+        match repair.await {
+            Ok(()) => repaired += 1,
+            Err(e) => error!("Could not repair post {}: {}", post.id, e),
+        }
+    }
+
+    println!(
+        "Checked {} files, repaired {} of {} problems.",
+        diagnosis.checked,
+        repaired,
+        diagnosis.findings.len()
+    );
+    Ok(())
+}
+
+// Looks up, for --retry-deleted, posts that had been deleted when they were
+// last seen, and archives any whose file is back. Those still deleted aren't
+// looked up again until --retry-deleted-interval has passed.
+async fn run_retry_deleted(
+    opts: &Opts,
+    client: &Client,
+    storage: &dyn StorageBackend,
+    router: &Router,
+    directory: &Path,
+    metadata_dir: &Path,
+) -> Result<(), MonosodiumError> {
+    let mut deleted = DeletedPosts::load(&directory.join(DELETED_FILE))?;
+    let due = deleted.due(opts.retry_deleted_interval, opts.retry_deleted_limit);
+    let writers = metadata::writers(opts, client);
+    let failure_log = FailureLog::open(directory, opts.truncate_failure_log)?;
+    let mut index = opts.index.as_deref().map(Index::load).transpose()?;
+    let pools = opts.placement().uses_pools().then(Pools::default);
+    let optimizers = find_optimizers(opts).await;
+
+    let mut restored = 0;
+    for &id in &due {
+        let mut post = match lookup_post(client, id).await {
+            Ok(post) => post,
+            Err(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+                info!(
+                    "Post {} no longer exists, so it won't be looked up again",
+                    id
+                );
+                deleted.remove(id);
+                continue;
+            }
+            Err(e) => {
+                error!("Could not look up post {}: {}", id, e);
+                continue;
+            }
+        };
+        if post.file.url.is_none() {
+            deleted.checked(id);
+            continue;
+        }
+        info!("Post {} was deleted, but its file is available again", id);
+        post.normalize_tags();
+        if let Some(pools) = &pools {
+            pools.fetch(client, std::slice::from_ref(&post)).await?;
+            post.pool = pools.place(&post);
+        }
+        post.place(&opts.placement(), router, metadata_dir, false);
+        let result = match archive_post(client, storage, &post, opts).await {
+            Ok(()) => {
+                let (log, modes) = (&failure_log, opts.modes());
+                complete_post(storage, &post, &writers, log, optimizers, modes).await
+            }
+            Err(e) => {
+                failure_log.record(&post, "file", &e, 0);
+                Err(e)
+            }
+        };
+        // A post that fails stays due, to be tried again next time.
+        match result {
+            Ok(()) => {
+                restored += 1;
+                deleted.remove(id);
+                if let Some(index) = index.as_mut() {
+                    index.insert(&post, None);
+                }
+            }
+            Err(e) => error!("Could not archive post {}: {}", id, e),
+        }
+    }
+
+    deleted.save()?;
+    if let Some(index) = &index {
+        index.save(opts.write_strategy())?;
+    }
+    storage.flush()?;
+    println!(
+        "Looked up {} deleted posts, and archived {} that are back; {} are still listed as deleted.",
+        due.len(),
+        restored,
+        deleted.len()
+    );
+    Ok(())
+}
+
+// Moves a damaged file out of the way of its replacement, for --quarantine-dir.
+fn keep_in_quarantine(dir: &Path, post: &Post, finding: &Finding) {
+    match quarantine::quarantine(dir, post, finding) {
+        Ok(Some(path)) => warn!("Moved the damaged file of post {} to {:?}", post.id, path),
+        Ok(None) => {}
+        Err(e) => error!("Could not quarantine post {}: {}", post.id, e),
+    }
+}
+
+async fn lookup_post(client: &Client, id: u64) -> Result<Post, reqwest::Error> {
+    let url = format!("https://e621.net/posts/{}.json", id);
+    let response = client.get(&url).await?.error_for_status()?;
+    Ok(response.json::<PostResponse>().await?.post)
+}
+
+// Says why a post in --download-order-file won't be downloaded, when it
+// wasn't among the posts fetched.
+async fn check_unlisted(client: &Client, id: u64) {
+    match lookup_post(client, id).await {
+        Ok(_) => info!(
+            "Post {} from --download-order-file isn't in the results, so it's skipped",
+            id
+        ),
+        Err(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+            warn!("Post {} from --download-order-file doesn't exist on e621", id)
+        }
+        Err(e) => warn!(
+            "Post {} from --download-order-file isn't in the results, and couldn't be looked up: {}",
+            id, e
+        ),
+    }
+}
+
+fn run_doctor(opts: &Opts, directory: &Path, metadata_dir: &Path) -> Result<(), MonosodiumError> {
+    let posts = load_sidecars(metadata_dir)?;
+    let mut cache = ChecksumCache::load(&directory.join(CACHE_FILE))?;
+
+    let posts: Vec<&Post> = posts.iter().collect();
+    let diagnosis = diagnose(
+        &posts,
+        &mut cache,
+        opts.verify_concurrency,
+        opts.force_verify,
+    );
+    if let Err(e) = cache.save(opts.write_strategy()) {
+        error!("Could not save checksum cache: {}", e);
+    }
+
+    for finding in &diagnosis.findings {
+        println!(
+            "post {}: {}: {}",
+            finding.id,
+            finding.path.display(),
+            color::out(Style::Bad, &finding.problem)
+        );
+    }
+    println!(
+        "Checked {} files ({} unchanged since last checked), {} problems.",
+        diagnosis.checked,
+        diagnosis.cached,
+        diagnosis.findings.len()
+    );
+
+    Ok(())
+}
+
+// Lists the files in the archive that have the same contents as another, and
+// with --dedupe-apply, deletes the spares. Of each group, a file some post's
+// metadata points to is the one kept, as is any other file metadata points to,
+// so nothing the sidecars know about goes missing; without any, the first by
+// path is kept.
+fn run_dedupe_report(
+    opts: &Opts,
+    directory: &Path,
+    metadata_dir: &Path,
+) -> Result<(), MonosodiumError> {
+    // An archive gathered from elsewhere may have no metadata at all.
+    let posts = match load_sidecars(metadata_dir) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        posts => posts?,
+    };
+    let referenced: BTreeSet<&Path> = posts
+        .iter()
+        .flat_map(|post| {
+            post.file_path
+                .iter()
+                .chain(&post.link_paths)
+                .chain(&post.poster_path)
+                .chain(&post.thumbnail_path)
+                .chain(post.reencoded.iter().flat_map(|r| &r.original_path))
+        })
+        .map(PathBuf::as_path)
+        .collect();
+    let mut cache = ChecksumCache::load(&directory.join(CACHE_FILE))?;
+    let thumbnails_dir = directory.join(THUMBNAILS_DIR);
+    let mut skip = vec![metadata_dir, thumbnails_dir.as_path()];
+    skip.extend(opts.quarantine_dir.as_deref());
+    let groups = duplicates::find(directory, &skip, &mut cache)?;
+    if let Err(e) = cache.save(opts.write_strategy()) {
+        error!("Could not save checksum cache: {}", e);
+    }
+
+    let mut spares = Vec::new();
+    for group in &groups {
+        let keep = group
+            .paths
+            .iter()
+            .find(|path| referenced.contains(path.as_path()))
+            .unwrap_or(&group.paths[0]);
+        println!("{} ({}):", group.md5, format_size(group.size));
+        for path in &group.paths {
+            if path == keep {
+                println!("  keep    {}", path.display());
+            } else if referenced.contains(path.as_path()) {
+                println!("  keep    {} (in metadata)", path.display());
+            } else {
+                println!("  {}  {}", color::out(Style::Bad, "remove"), path.display());
+                spares.push((path, group.size));
+            }
+        }
+    }
+    let reclaimable: u64 = spares.iter().map(|(_, size)| size).sum();
+    println!(
+        "Found {} groups of duplicates; removing {} files would free {}.",
+        groups.len(),
+        spares.len(),
+        format_size(reclaimable)
+    );
+
+    if spares.is_empty() {
+        return Ok(());
+    }
+    if !opts.dedupe_apply {
+        println!("Nothing was deleted; run again with --dedupe-apply to remove them.");
+        return Ok(());
+    }
+    let question = format!(
+        "About to delete {} files ({}). Continue?",
+        spares.len(),
+        format_size(reclaimable)
+    );
+    if !opts.yes && !ask(&question)? {
+        println!("Nothing was deleted.");
+        return Ok(());
+    }
+    let (mut removed, mut freed) = (0, 0);
+    for (path, size) in spares {
+        match std::fs::remove_file(path) {
+            Ok(()) => {
+                removed += 1;
+                freed += size;
+            }
+            Err(e) => warn!("Could not delete {:?}: {}", path, e),
+        }
+    }
+    println!("Deleted {} files, freeing {}.", removed, format_size(freed));
+    Ok(())
+}
+
+// Checks that every file named after an MD5 really has it, for
+// --strict-md5-in-filename, and renames or quarantines those that don't with
+// --fix-md5-names. Files that are changed on purpose, like scaled images and
+// video posters, are left out, going by their sidecars.
+fn run_md5_names(
+    opts: &Opts,
+    directory: &Path,
+    metadata_dir: &Path,
+) -> Result<(), MonosodiumError> {
+    let posts = match load_sidecars(metadata_dir) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        posts => posts?,
+    };
+    let mut owners = HashMap::new();
+    let mut exempt = HashSet::new();
+    for post in &posts {
+        if let Some(path) = &post.file_path {
+            owners.insert(path.as_path(), post);
+            if post.is_altered() {
+                exempt.insert(path.as_path());
+            }
+        }
+        exempt.extend(post.poster_path.as_deref());
+    }
+    let mut cache = ChecksumCache::load(&directory.join(CACHE_FILE))?;
+    let thumbnails_dir = directory.join(THUMBNAILS_DIR);
+    let mut skip = vec![metadata_dir, thumbnails_dir.as_path()];
+    skip.extend(opts.quarantine_dir.as_deref());
+    let check = md5names::check(directory, &skip, &exempt, &mut cache, opts.force_verify)?;
+    if let Err(e) = cache.save(opts.write_strategy()) {
+        error!("Could not save checksum cache: {}", e);
+    }
+
+    for (path, e) in &check.unreadable {
+        println!(
+            "{}: {}",
+            path.display(),
+            color::out(Style::Bad, format!("unreadable: {}", e))
+        );
+    }
+    let mut fixed = 0;
+    for mismatch in &check.mismatches {
+        println!(
+            "{}: {}",
+            mismatch.path.display(),
+            color::out(
+                Style::Bad,
+                format!(
+                    "named {}, but hashes to {}",
+                    mismatch.named, mismatch.actual
+                )
+            )
+        );
+        let moved = match opts.fix_md5_names {
+            None => continue,
+            Some(NameFix::Rename) => {
+                let renamed = mismatch.renamed();
+                if renamed.exists() {
+                    warn!(
+                        "{:?} is already there, so {:?} was left as it is",
+                        renamed, mismatch.path
+                    );
+                    continue;
+                }
+                move_file(&mismatch.path, &renamed).map(|()| renamed)
+            }
+            Some(NameFix::Quarantine) => {
+                let dir = opts
+                    .quarantine_dir
+                    .as_deref()
+                    .expect("clap requires --quarantine-dir");
+                let owner = owners.get(mismatch.path.as_path());
+                let ext = mismatch
+                    .path
+                    .extension()
+                    .unwrap_or_default()
+                    .to_string_lossy();
+                let expected = quarantine::Expected {
+                    post_id: owner.map(|post| post.id),
+                    md5: &mismatch.named,
+                    ext: owner.map_or(&ext, |post| &post.file.ext),
+                };
+                quarantine::quarantine_file(dir, &mismatch.path, expected, &mismatch.actual)
+            }
+        };
+        match moved {
+            Ok(path) => {
+                println!("  moved to {}", path.display());
+                fixed += 1;
+            }
+            Err(e) => error!("Could not move {:?}: {}", mismatch.path, e),
+        }
+    }
+    println!(
+        "Checked {} files named after an MD5 ({} unchanged since last checked), {} don't match.",
+        check.checked,
+        check.cached,
+        check.mismatches.len()
+    );
+    if fixed > 0 {
+        println!("Moved {} of them.", fixed);
+    }
+    Ok(())
+}
+
+// Writes every post's sidecar, and whatever else --write-sources and
+// --write-tags-txt ask for, again from the API's own JSON kept by
+// --preserve-raw, the way this version and the current options would have
+// written them. Where the archive put the post's files is kept from the old
+// sidecar; a post whose sidecar is missing or unreadable is placed as the
+// current layout would place it. The raw files are worked through in order of
+// name, and the last one done is saved as it goes, so a rebuild that's
+// interrupted picks up where it left off.
+async fn run_rebuild_metadata(
+    opts: &Opts,
+    directory: &Path,
+    metadata_dir: &Path,
+) -> Result<(), MonosodiumError> {
+    let progress_path = directory.join(REBUILD_FILE);
+    let done_through = match read_to_string(&progress_path) {
+        Ok(md5) => Some(md5.trim().to_owned()),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let mut raw_files: Vec<(String, PathBuf)> = Vec::new();
+    for entry in read_dir(metadata_dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if let Some(md5) = name.strip_suffix(RAW_SUFFIX) {
+            raw_files.push((md5.to_owned(), path.clone()));
+        }
+    }
+    raw_files.sort();
+
+    let router = Router::new(directory, &opts.routes, opts.route_mode);
+    let tag_db = opts.tag_db.as_deref().map(TagDb::load).transpose()?;
+    let storage = Filesystem {
+        modes: opts.modes(),
+    };
+    let writers = metadata::offline_writers(opts);
+    let (mut rebuilt, mut skipped, mut earlier) = (0, 0, 0);
+    for (md5, raw_path) in &raw_files {
+        if done_through.as_ref().is_some_and(|done| md5 <= done) {
+            earlier += 1;
+            continue;
+        }
+        let raw = File::open(raw_path).and_then(|file| {
+            serde_json::from_reader::<_, Post>(BufReader::new(file)).map_err(Into::into)
+        });
+        let mut post = match raw {
+            Ok(post) => post,
+            Err(e) => {
+                warn!("Skipping unreadable raw JSON {:?}: {}", raw_path, e);
+                skipped += 1;
+                continue;
+            }
+        };
+        post.normalize_tags();
+        if let Some(tag_db) = &tag_db {
+            post.resolved_tags = Some(tag_db.resolve(&post.tags));
+        }
+        let sidecar_path = metadata_dir.join(format!("{}.json", md5));
+        let old = File::open(&sidecar_path).and_then(|file| {
+            serde_json::from_reader::<_, Post>(BufReader::new(file)).map_err(Into::into)
+        });
+        match old {
+            Ok(mut old) => {
+                post.file_path = old.file_path.take();
+                post.tags_path = old.tags_path.take();
+                post.link_paths = std::mem::take(&mut old.link_paths);
+                post.poster_path = old.poster_path.take();
+                post.thumbnail_path = old.thumbnail_path.take();
+                post.pool = old.pool.take();
+                post.set = old.set.take();
+                post.supersedes = old.supersedes.take();
+                post.keep_saved(old);
+            }
+            Err(_) => post.place(&opts.placement(), &router, metadata_dir, false),
+        }
+        // The raw JSON is named after the file the post had when it was
+        // archived, which is what everything else goes by.
+        post.tags_path = Some(sidecar_path);
+
+        let mut failed = false;
+        for writer in &writers {
+            if let Err(e) = writer.write(&storage, &post).await {
+                error!(
+                    "Could not write {} for post {}: {}",
+                    writer.name(),
+                    post.id,
+                    e
+                );
+                failed = true;
+            }
+        }
+        if failed {
+            skipped += 1;
+        } else {
+            rebuilt += 1;
+        }
+        if (rebuilt + skipped) % REBUILD_CHECKPOINT == 0 {
+            write(&progress_path, md5)?;
+        }
+    }
+    if let Err(e) = remove_file(&progress_path) {
+        if e.kind() != ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+
+    print!("Rebuilt {} sidecars from raw JSON", rebuilt);
+    if earlier > 0 {
+        print!(", after {} rebuilt by an earlier run", earlier);
+    }
+    println!("; skipped {}.", skipped);
+    Ok(())
+}
+
+// Moves archived files to wherever the current layout and routes put them, so
+// that switching layouts doesn't mean downloading everything again. Sidecars
+// and the index are updated to match. Posts whose new place is taken are
+// left alone.
+async fn run_rename(
+    opts: &Opts,
+    directory: &Path,
+    metadata_dir: &Path,
+) -> Result<(), MonosodiumError> {
+    let router = Router::new(directory, &opts.routes, opts.route_mode);
+    let mut index = opts.index.as_deref().map(Index::load).transpose()?;
+    let (mut renamed, mut skipped) = (0, 0);
+    let placement = opts.placement();
+
+    for mut post in load_sidecars(metadata_dir)? {
+        let Some(old_path) = post.file_path.take() else {
+            continue;
+        };
+        let old_poster = post.poster_path.take();
+        let old_links = std::mem::take(&mut post.link_paths);
+        post.place(&placement, &router, metadata_dir, old_poster.is_some());
+        let new_path = post.file_path.clone().unwrap();
+        if new_path == old_path {
+            continue;
+        }
+        if !old_path.exists() {
+            warn!("Skipping post {}: {:?} is missing", post.id, old_path);
+            skipped += 1;
+            continue;
+        }
+        if new_path.exists() {
+            warn!("Skipping post {}: {:?} is already taken", post.id, new_path);
+            skipped += 1;
+            continue;
+        }
+
+        println!("{} -> {}", old_path.display(), new_path.display());
+        renamed += 1;
+        if opts.dry_run {
+            continue;
+        }
+
+        move_file(&old_path, &new_path)?;
+        if let (Some(old_poster), Some(new_poster)) = (&old_poster, &post.poster_path) {
+            if old_poster.exists() {
+                move_file(old_poster, new_poster)?;
+            }
+        }
+        let old_tag_text = old_path.with_extension("txt");
+        if old_tag_text.exists() {
+            move_file(&old_tag_text, &new_path.with_extension("txt"))?;
+        }
+        // Links to the old place are broken now.
+        for link in &old_links {
+            if link.symlink_metadata().is_ok() {
+                remove_file(link)?;
+            }
+        }
+        link_post(&post, opts.dedupe.unwrap_or_default(), opts.modes())?;
+        let sidecar = Sidecar {
+            compact: opts.json_compact,
+        };
+        sidecar
+            .write(
+                &Filesystem {
+                    modes: opts.modes(),
+                },
+                &post,
+            )
+            .await?;
+        if let Some(index) = index.as_mut() {
+            index.insert(&post, None);
+        }
+    }
+
+    if let Some(index) = &index {
+        if !opts.dry_run {
+            index.save(opts.write_strategy())?;
+        }
+    }
+    if opts.dry_run {
+        println!("Would move {} files, skipping {}.", renamed, skipped);
+    } else {
+        println!("Moved {} files, skipped {}.", renamed, skipped);
+    }
+    Ok(())
+}
+
+// Renames, or copies and deletes when moving to another filesystem.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        create_dir_all(parent)?;
+    }
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            std::fs::copy(from, to)?;
+            remove_file(from)
+        }
+        result => result,
+    }
+}
+
+// The newest favorites when the last finished run with --since-run started,
+// if one has.
+fn load_since_run(path: &Path) -> Option<Vec<u64>> {
+    match SinceRun::load(path) {
+        Ok(Some(newest)) => {
+            info!("Looking for favorites added since post {} was", newest[0]);
+            Some(newest)
+        }
+        Ok(None) => {
+            info!(
+                "No run has finished with --since-run {:?} yet, so checking every favorite",
+                path
+            );
+            None
+        }
+        Err(e) => {
+            warn!(
+                "Could not read --since-run {:?} ({}), so checking every favorite",
+                path, e
+            );
+            None
+        }
+    }
+}
+
+// Works out which page to start from when resuming. The last finished page is
+// fetched again to check that it hasn't changed, because if it has, pages
+// after it have too, and starting from the next one could skip posts.
+async fn resume_page(
+    client: &Client,
+    source: &Source,
+    state_path: &Path,
+    per_page: u32,
+) -> Result<usize, MonosodiumError> {
+    let state = match RunState::load(state_path) {
+        Ok(Some(state)) => state,
+        Ok(None) => {
+            info!("No unfinished run to resume, starting from the beginning");
+            return Ok(1);
+        }
+        Err(e) => {
+            warn!(
+                "Could not read saved progress ({}), starting from the beginning",
+                e
+            );
+            return Ok(1);
+        }
+    };
+    if state.source != source.key() {
+        warn!(
+            "Saved progress is for {:?}, not {:?}; starting from the beginning",
+            state.source,
+            source.key()
+        );
+        return Ok(1);
+    }
+    if !state.is_per_page(per_page) {
+        warn!(
+            "Saved progress is for pages of {} posts, not {}; starting from the beginning",
+            state.per_page, per_page
+        );
+        return Ok(1);
+    }
+
+    // Straight from e621, as a cached page would pass however much has moved.
+    let response = fetch_page(client, source, state.page, per_page, None).await?;
+    if state.still_matches(&response) {
+        info!("Resuming after page {}", state.page);
+        Ok(state.page + 1)
+    } else {
+        warn!(
+            "The {} have changed since page {} was finished; falling back to a full scan",
+            source.describe(),
+            state.page
+        );
+        Ok(1)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let opts: Opts = Opts::parse();
+    color::init(opts.color());
+
+    if let Err(e) = run(opts).await {
+        eprintln!("{} {}", color::err(Style::Bad, "Error:"), e);
+        std::process::exit(1);
+    }
+}
+
+// Makes sure whoever asked for --unthrottled means it: e621's own rules don't
+// allow it, so it's only for mirrors and accounts with a higher limit.
+fn allow_unthrottled() -> Result<(), MonosodiumError> {
+    eprintln!(
+        "{} --unthrottled sends requests as fast as the server answers them. e621 doesn't \
+         allow that; only use it with a mirror of your own, or an account allowed more.",
+        color::err(Style::Bad, "Warning:")
+    );
+    if std::env::var(UNTHROTTLED_ENV).is_ok_and(|allowed| allowed == "yes") {
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(MonosodiumError::InvalidOptions(format!(
+            "--unthrottled has to be confirmed, at a terminal or by setting {}=yes",
+            UNTHROTTLED_ENV
+        )));
+    }
+    match ask("Run without waiting between requests?")? {
+        true => Ok(()),
+        false => Err(MonosodiumError::InvalidOptions(
+            "--unthrottled wasn't confirmed".to_owned(),
+        )),
+    }
+}
+
+// The client every request goes through, paced and set up as the options say.
+fn build_client(opts: &Opts) -> Result<Client, MonosodiumError> {
+    let (delay, jitter) = (opts.api_delay(), opts.api_delay_jitter);
+    if opts.unthrottled {
+        allow_unthrottled()?;
+    } else if delay.saturating_sub(jitter) < MIN_API_DELAY {
+        return Err(MonosodiumError::InvalidOptions(format!(
+            "--api-delay minus --api-delay-jitter must be at least {}, the most e621 allows",
+            humantime::format_duration(MIN_API_DELAY)
+        )));
+    }
+
+    let mut headers = HeaderMap::new();
+    if let Some(language) = &opts.accept_language {
+        headers.insert(ACCEPT_LANGUAGE, language.clone());
+    }
+    // HTTP/2 is used when the server offers it, over one connection that's
+    // kept open between requests.
+    let mut http = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .default_headers(headers)
+        .pool_idle_timeout(IDLE_CONNECTION_TIMEOUT.max(2 * delay));
+    if opts.http1_only {
+        http = http.http1_only();
+    }
+    // Connecting from an unspecified address of one kind rules out the
+    // server's addresses of the other.
+    match opts.ip_version {
+        IpVersion::Any => {}
+        IpVersion::V4 => http = http.local_address(IpAddr::from(Ipv4Addr::UNSPECIFIED)),
+        IpVersion::V6 => http = http.local_address(IpAddr::from(Ipv6Addr::UNSPECIFIED)),
+    }
+    if let Some(proxy) = &opts.proxy {
+        // reqwest only speaks SOCKS when built with its "socks" feature, which
+        // this build doesn't have, and it would fail later and less clearly.
+        if proxy.scheme().starts_with("socks") {
+            return Err(MonosodiumError::InvalidOptions(format!(
+                "monosodium was built without SOCKS support, so can't use the proxy {}; \
+                 use an HTTP proxy instead",
+                proxy
+            )));
+        }
+        http = http.proxy(reqwest::Proxy::all(proxy.clone())?);
+    }
+    let http = http.build()?;
+    let mut client = Client::new(
+        http,
+        RateLimiter::new(delay, jitter),
+        CircuitBreaker::new(opts.breaker_threshold, opts.breaker_cooldown),
+    );
+    if let (Some(username), Some(api_key)) = (&opts.username, &opts.api_key) {
+        client = client.log_in(username.clone(), api_key.clone());
+    }
+    if opts.profile {
+        client = client.profiled();
+    }
+    Ok(client)
+}
+
+// The newest favorites as the run starts, for the next run with --since-run
+// to stop at, straight from the API. If they can't be had, the last run's are
+// left as they are.
+async fn newest_favorites(client: &Client, source: &Source) -> Option<Vec<u64>> {
+    match pages::fetch_page(client, source, 1, SinceRun::KEPT as u32, None).await {
+        Ok(response) => Some(response.posts.iter().map(|post| post.id).collect()),
+        Err(e) => {
+            warn!(
+                "Could not look up the newest favorites for the next --since-run: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+async fn run(opts: Opts) -> Result<(), MonosodiumError> {
+    if opts.output_json_schema {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&jsonschema::sidecar()).unwrap()
+        );
+        return Ok(());
+    }
+    if opts.selftest {
+        let client = build_client(&opts)?;
+        return selftest::run(&opts, &client).await;
+    }
+    let directory = Path::new(
+        opts.directory
+            .as_deref()
+            .expect("clap requires --directory unless --selftest or --output-json-schema"),
+    );
+    let metadata_dir = directory.join("metadata");
+
+    #[cfg(not(unix))]
+    if opts.file_mode.is_some() || opts.dir_mode.is_some() {
+        warn!("--file-mode and --dir-mode only work on Unix, so they're ignored");
+    }
+    if let Some(max) = opts.max_open_files {
+        storage::limit_open_files(max as usize);
+    }
+    check_open_file_limit(&opts);
+    ensure_writable(directory, opts.modes())?;
+    let _lock = DirectoryLock::acquire(directory, opts.force_unlock)?;
+
+    // Before anything looks at the archive, so that nothing sees a post an
+    // earlier run didn't finish.
+    let unfinished = journal::recover(directory)?;
+    if !unfinished.is_empty() {
+        println!(
+            "Removed {} posts left half archived by an earlier run.",
+            unfinished.len()
+        );
+    }
+
+    if opts.rebuild_index {
+        let index = Index::rebuild(opts.index.as_ref().unwrap(), &metadata_dir)?;
+        index.save(opts.write_strategy())?;
+        println!("Rebuilt the index with {} posts.", index.len());
+        return Ok(());
+    }
+
+    if let Some(dir) = &opts.quarantine_dir {
+        if opts.clear_quarantine {
+            let cleared = quarantine::clear(dir)?;
+            println!("Cleared {} files from the quarantine.", cleared);
+            return Ok(());
+        }
+        ensure_writable(dir, opts.modes())?;
+    }
+    if let Some(dir) = &opts.write_tag_wiki {
+        ensure_writable(dir, opts.modes())?;
+    }
+
+    if opts.doctor {
+        return run_doctor(&opts, directory, &metadata_dir);
+    }
+
+    if opts.dedupe_report {
+        return run_dedupe_report(&opts, directory, &metadata_dir);
+    }
+
+    if opts.strict_md5_in_filename {
+        return run_md5_names(&opts, directory, &metadata_dir);
+    }
+
+    if opts.rebuild_metadata {
+        return run_rebuild_metadata(&opts, directory, &metadata_dir).await;
+    }
+
+    if opts.rename_existing {
+        return run_rename(&opts, directory, &metadata_dir).await;
+    }
+
+    // Date bounds go to the server too, where there's a search to add them to,
+    // so that it doesn't send pages of posts that would only be filtered out.
+    let mut query = match &opts.tags {
+        Some(tags) => {
+            let tags = match dates::date_tag(opts.since, opts.until) {
+                Some(date) => format!("{} {}", tags, date),
+                None => tags.clone(),
+            };
+            Some(Query::plan(&tags, opts.tag_limit())?)
+        }
+        None => None,
+    };
+    if opts.tag_blacklist_from_account && opts.username.is_none() {
+        return Err(MonosodiumError::InvalidOptions(
+            "--tag-blacklist-from-account needs --username and --api-key, to know whose \
+             blacklist to use"
+                .to_string(),
+        ));
+    }
+    if opts.my_favorites && opts.username.is_none() {
+        return Err(MonosodiumError::InvalidOptions(
+            "--my-favorites needs --username and --api-key: e621 only knows whose \
+             favorites to give when you log in"
+                .to_string(),
+        ));
+    }
+
+    let storage = storage::open(
+        opts.s3.as_deref(),
+        opts.zip.as_deref(),
+        opts.output_archive.as_deref(),
+        directory,
+        opts.modes(),
+    )?;
+    let router = Router::new(directory, &opts.routes, opts.route_mode);
+    if storage.is_local() {
+        for directory in router.directories() {
+            ensure_writable(directory, opts.modes())?;
+        }
+        ensure_writable(&metadata_dir, opts.modes())?;
+    }
+
+    let client = build_client(&opts)?;
+
+    // A change to the API would otherwise only show up as a confusing failure
+    // partway through.
+    if !opts.skip_schema_check {
+        let problems = schema::check(&client).await;
+        if !problems.is_empty() {
+            eprintln!(
+                "{} the e621 API may have changed, and this run may fail:",
+                color::err(Style::Notice, "Warning:")
+            );
+            for problem in &problems {
+                eprintln!("  - {}", problem);
+            }
+        }
+    }
+
+    if opts.resume_partial_verify {
+        return run_repair(&opts, &client, directory, &metadata_dir).await;
+    }
+
+    if opts.retry_deleted {
+        return run_retry_deleted(
+            &opts,
+            &client,
+            storage.as_ref(),
+            &router,
+            directory,
+            &metadata_dir,
+        )
+        .await;
+    }
+
+    // e621 applies implications to the tags it searches on itself.
+    if let Some(query) = query.as_mut().filter(|_| opts.expand_implications) {
+        let local: Vec<String> = query
+            .local_include
+            .iter()
+            .chain(&query.local_exclude)
+            .cloned()
+            .collect();
+        if !local.is_empty() {
+            let cache_path = directory.join(implications::CACHE_FILE);
+            query.narrower = implications::narrower(&client, &local, &cache_path).await?;
+        }
+    }
+
+    let post_set = match opts.set {
+        Some(id) => Some(PostSet::fetch(&client, id).await?),
+        None => None,
+    };
+    let source = match (&post_set, &query, &opts.username_lookup) {
+        (Some(set), _, _) => {
+            info!("Archiving set {} ({} posts)", set.name, set.len());
+            Source::Set {
+                id: set.id,
+                shortname: set.shortname.clone(),
+            }
+        }
+        (None, Some(query), _) => Source::Search(query.server.clone()),
+        (None, None, None) if opts.my_favorites => Source::MyFavorites(
+            opts.username
+                .clone()
+                .expect("--my-favorites is checked for credentials above"),
+        ),
+        (None, None, Some(name)) => {
+            let cache_path = directory.join(".monosodium-users.json");
+            Source::Favorites(users::lookup(&client, name, &cache_path).await?)
+        }
+        (None, None, None) => Source::Favorites(
+            opts.user_id
+                .expect("clap requires --user-id, --username-lookup, --my-favorites or --tags"),
+        ),
+    };
+
+    let shutdown = Shutdown::new();
+    shutdown.listen(opts.max_duration);
+    let watchdog = match opts.min_free_space {
+        Some(min) => {
+            // An archive file is all in one place; otherwise files can go
+            // under any of the routes, which may be on different disks.
+            let dirs = match opts.zip.as_ref().or(opts.output_archive.as_ref()) {
+                Some(archive) => vec![archive
+                    .parent()
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                    .unwrap_or(Path::new("."))
+                    .to_owned()],
+                None => router.directories().map(Path::to_owned).collect(),
+            };
+            match Watchdog::start(dirs, min, opts.on_low_space, &shutdown).await {
+                Ok(watchdog) => Some(watchdog),
+                Err(e) => {
+                    warn!(
+                        "Can't check free space, so --min-free-space is ignored: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut index = opts.index.as_deref().map(Index::load).transpose()?;
+    let mut checksums = opts
+        .verify
+        .then(|| ChecksumCache::load(&directory.join(CACHE_FILE)))
+        .transpose()?;
+
+    let posters = opts.flatten_video_thumbnails && ffmpeg_available().await;
+    let known_files = opts
+        .skip_existing_by_id
+        .then(|| KnownFiles::load(&metadata_dir))
+        .transpose()?;
+    let archived_md5s = opts
+        .if_newer_remote
+        .then(|| archived_md5s(&metadata_dir))
+        .transpose()?;
+    if opts.flatten_video_thumbnails && !posters {
+        warn!("ffmpeg isn't on the PATH, so videos won't get posters");
+    }
+    let probe_durations = opts.max_duration_seconds.is_some()
+        && opts.s3.is_none()
+        && opts.zip.is_none()
+        && opts.output_archive.is_none()
+        && ffprobe_available().await;
+    if opts.max_duration_seconds.is_some() && !probe_durations {
+        warn!("Videos e621 doesn't give the length of can only be measured in local files with ffprobe, so they're kept whatever their length");
+    }
+    let optimizers = find_optimizers(&opts).await;
+    let reencode = match opts.reencode_video {
+        Some(_) if !ffmpeg_available().await => {
+            warn!("ffmpeg isn't on the PATH, so videos will be kept as they're downloaded");
+            None
+        }
+        preset => preset,
+    };
+    if opts.max_dimension.is_some() && !ffmpeg_available().await {
+        return Err(MonosodiumError::InvalidOptions(
+            "--max-dimension needs ffmpeg, which isn't on the PATH".to_owned(),
+        ));
+    }
+
+    let mut filters = Filters::new(&opts, query.as_ref());
+    if let Some(path) = &opts.exclude_md5_file {
+        filters.exclude_md5s(read_md5_list(path)?);
+    }
+    let mut blacklist = Blacklist::parse(&opts.blacklist.join("\n"));
+    if opts.tag_blacklist_from_account {
+        let name = opts
+            .username
+            .as_deref()
+            .expect("checked for credentials above");
+        let cache_path = directory.join(".monosodium-users.json");
+        let user_id = users::lookup(&client, name, &cache_path).await?;
+        let account = blacklist::from_account(&client, user_id).await?;
+        info!("The account's blacklist has {} rules", account.len());
+        blacklist.extend(account);
+    }
+    if !blacklist.is_empty() {
+        filters.blacklist(blacklist);
+    }
+    let order = opts
+        .download_order_file
+        .as_deref()
+        .map(read_order_file)
+        .transpose()?;
+    if let Some(order) = &order {
+        filters.only_listed(order.iter().copied().collect());
+    }
+
+    let tag_db = opts.tag_db.as_deref().map(TagDb::load).transpose()?;
+    let mut library = opts
+        .hardlink_from
+        .as_deref()
+        .map(Library::scan)
+        .transpose()?;
+    if let Some(other) = &opts.resume_from_manifest_of {
+        let archive = Library::from_archive(other)?;
+        library = Some(match library {
+            Some(mut library) => {
+                library.merge(archive);
+                library
+            }
+            None => archive,
+        });
+    }
+    let journal = storage
+        .is_local()
+        .then(|| Journal::create(directory))
+        .transpose()?;
+
+    let placement = opts.placement();
+    let mut context = Context {
+        opts: &opts,
+        client,
+        storage,
+        router,
+        metadata_dir,
+        filters,
+        tag_db,
+        library,
+        failure_log: FailureLog::open(directory, opts.truncate_failure_log)?,
+        journal,
+        shutdown,
+        source_key: source.key(),
+        state_path: directory.join(".monosodium-state.json"),
+        done_path: directory.join(".monosodium-done.json"),
+        posters,
+        probe_durations,
+        archived_md5s,
+        pools: placement.uses_pools().then(Pools::default),
+        placement,
+        post_set,
+        reencode,
+        optimizers,
+        known_files,
+        watchdog,
+        thumbnails_dir: opts
+            .download_thumbnails_too
+            .then(|| directory.join(THUMBNAILS_DIR)),
+    };
+
+    if opts.estimate_only {
+        return run_estimate(&context, &source).await;
+    }
+
+    if opts.archive_avatar_and_profile {
+        let user_id = match &source {
+            Source::Favorites(user_id) => Some(*user_id),
+            Source::MyFavorites(username) => {
+                let cache_path = directory.join(".monosodium-users.json");
+                Some(users::lookup(&context.client, username, &cache_path).await?)
+            }
+            Source::Search(_) | Source::Set { .. } => None,
+        };
+        if let Some(user_id) = user_id {
+            let storage = context.storage.as_ref();
+            if let Err(e) =
+                users::archive_profile(&context.client, storage, directory, user_id).await
+            {
+                error!("Could not save the profile of user {}: {}", user_id, e);
+            }
+        }
+    }
+
+    let cache = opts
+        .cache_dir
+        .clone()
+        .map(|dir| {
+            let login = context.client.username().map(str::to_owned);
+            PageCache::new(dir, opts.cache_ttl, opts.refresh_cache, login).map(Arc::new)
+        })
+        .transpose()?;
+
+    let first_page = if opts.resume {
+        resume_page(&context.client, &source, &context.state_path, opts.per_page).await?
+    } else {
+        1
+    };
+    let paging = Paging {
+        per_page: opts.per_page,
+        max_pages: opts.max_pages.map(|max| max as usize),
+        since: opts.since,
+        favorited_since: opts.since_run.as_deref().and_then(load_since_run),
+        ahead: opts.parallel_pages,
+        drift: opts.page_drift,
+    };
+    // Favorites added from here on are new to the next run.
+    let newest = match &opts.since_run {
+        Some(_) => newest_favorites(&context.client, &source).await,
+        None => None,
+    };
+    let mut pages = Pages::fetch(
+        &context.client,
+        source,
+        first_page,
+        paging,
+        cache,
+        opts.fallback_feed.clone().map(Feed::new),
+        &context.shutdown,
+    );
+
+    // Look before leaping: when analyzing, read all of the metadata first to
+    // see how much there is to download. When someone is around to ask, only
+    // as much is read ahead as it takes to know the run is a big one.
+    let interactive = !opts.yes && std::io::stdin().is_terminal();
+    let mut sample = BTreeMap::new();
+    let listing = opts.list_only.is_some();
+    // An order across pages can only be kept once all of them are in.
+    let buffering = opts.analyze || listing || order.is_some();
+    if buffering || opts.balance_tag.is_some() {
+        let mut buffered = pages.buffer().await?;
+        for page in &mut buffered {
+            page.response.look_up_pools(&context).await?;
+            page.response.hydrate(&context);
+            context.filters.screen(&page.response.posts).await?;
+        }
+        if let (Some(category), Some(per_class)) = (opts.balance_tag, opts.per_class) {
+            let wanted = buffered
+                .iter()
+                .flat_map(|page| &page.response.posts)
+                .filter(|post| context.filters.reject(post).is_none());
+            let (picked, counts) = balance::sample(wanted, category, per_class);
+            // Keep the list clean for whatever it's piped into.
+            if !listing {
+                println!(
+                    "Sampled {} posts across {} tags.",
+                    picked.len(),
+                    counts.len()
+                );
+            }
+            for (tag, count) in &counts {
+                info!("Sampled {} posts tagged {}", count, tag);
+            }
+            context.filters.restrict_to(picked);
+            sample = counts;
+        }
+        if let Some(order) = &order {
+            let (page, missing) = order::reorder(buffered, order);
+            buffered = page.into_iter().collect();
+            for id in missing {
+                check_unlisted(&context.client, id).await;
+            }
+        }
+        if let Some(format) = opts.list_only {
+            list_posts(&context.filters, &buffered, format);
+            return Ok(());
+        }
+        let estimate = estimate(&context, &mut buffered).await?;
+        if opts.analyze {
+            println!(
+                "{} posts, {} files to download ({}).",
+                estimate.posts,
+                estimate.files,
+                format_size(estimate.bytes)
+            );
+            return Ok(());
+        }
+        if interactive && estimate.is_large(&opts) && !confirm(&estimate, true)? {
+            println!("Nothing downloaded.");
+            return Ok(());
+        }
+        pages = Pages::Buffered(buffered.into_iter());
+    } else if interactive {
+        let mut read = Vec::new();
+        let mut so_far = Estimate::default();
+        while let Some(page) = pages.next().await {
+            let mut page = page?;
+            page.response.look_up_pools(&context).await?;
+            page.response.hydrate(&context);
+            context.filters.screen(&page.response.posts).await?;
+            so_far.add(estimate(&context, std::slice::from_mut(&mut page)).await?);
+            read.push(page);
+            if so_far.is_large(&opts) {
+                break;
+            }
+        }
+        if so_far.is_large(&opts) && !confirm(&so_far, false)? {
+            println!("Nothing downloaded.");
+            return Ok(());
+        }
+        pages = pages.unread(read);
+    }
+
+    let mut summary = Summary::new();
+    summary.sample = sample;
+    if opts.json_output.is_some() {
+        summary.track_outcomes();
+    }
+    let mut deleted = DeletedPosts::load(&directory.join(DELETED_FILE))?;
+    let result = archive_posts(
+        &context,
+        pages,
+        &mut summary,
+        index.as_mut(),
+        checksums.as_mut(),
+        &mut deleted,
+    )
+    .await;
+
+    for (reason, count) in &summary.excluded {
+        info!("Excluded {} posts: {}", count, reason);
+    }
+
+    if let Some(report) = &opts.report {
+        if let Err(e) = write_report(report, &summary) {
+            error!("Could not write report {:?}: {}", report, e);
+        }
+    }
+
+    let error = result.as_ref().err().map(ToString::to_string);
+    if let Some(path) = &opts.json_output {
+        if let Err(e) = jsonoutput::write(path, &opts, &summary, error.as_deref()) {
+            error!("Could not write {:?}: {}", path, e);
+        }
+    }
+    if notify::wanted(opts.notify_on, &summary, error.as_deref()) {
+        if let Some(command) = &opts.notify {
+            notify::run_command(command, &summary, error.as_deref()).await;
+        }
+        if let Some(url) = &opts.webhook_url {
+            notify::post_webhook(&context.client, url, &summary, error.as_deref()).await;
+        }
+    }
+
+    result?;
+
+    // A finished run leaves nothing to resume.
+    if summary.stopped.is_none() {
+        if let Err(e) = RunState::clear(&context.state_path) {
+            error!("Could not clear saved progress: {}", e);
+        }
+        if let Err(e) = RunState::clear(&context.done_path) {
+            error!("Could not clear the posts done so far: {}", e);
+        }
+        if let (Some(path), Some(newest)) = (&opts.since_run, &newest) {
+            if let Err(e) = SinceRun::save(path, newest) {
+                error!("Could not save the newest favorites in {:?}: {}", path, e);
+            }
+        }
+    }
+
+    match summary.stopped {
+        Some(reason) => println!(
+            "{} ({}) after {} downloads. Run again to pick up the rest.",
+            color::out(Style::Notice, "Stopped early"),
+            reason,
+            summary.downloaded
+        ),
+        None => println!(
+            "{}",
+            color::out(Style::Good, "Done! Enjoy that offline archive!")
+        ),
+    }
+    if summary.linked_from_library > 0 {
+        println!(
+            "Linked {} files from the library, and downloaded {}.",
+            summary.linked_from_library, summary.downloaded
+        );
+    }
+    if summary.metadata_refreshed > 0 {
+        println!(
+            "Refreshed the metadata of {} posts.",
+            summary.metadata_refreshed
+        );
+    }
+    if opts.profile {
+        print!("{}", context.client.profile().report(summary.elapsed()));
+    }
+
+    if !summary.missing_urls.is_empty() {
+        let ids: Vec<String> = summary.missing_urls.iter().map(u64::to_string).collect();
+        println!(
+            "{} {}",
+            color::out(Style::Bad, "Posts with no file to download:"),
+            ids.join(", ")
+        );
+        return Err(MonosodiumError::MissingUrls {
+            count: ids.len(),
+            logged_in: context.client.is_logged_in(),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, post, Memory};
+
+    fn try_opts(args: &[&str]) -> Result<Opts, clap::Error> {
+        let required = ["monosodium", "--directory", "out", "--user-id", "1"];
+        Opts::try_parse_from(required.iter().chain(args))
+    }
+
+    fn opts(args: &[&str]) -> Opts {
+        try_opts(args).unwrap()
+    }
+
+    #[tokio::test]
+    async fn writes_posts_through_the_backend() {
+        let url = testutil::serve(b"a picture").await;
+        let storage = Memory::default();
+        let post = post(b"a picture");
+        download_post(&testutil::client(), &storage, &post, &url, &opts(&[]))
+            .await
+            .unwrap();
+        let path = post.file_path.as_ref().unwrap();
+        assert!(storage.exists(path).await.unwrap());
+        assert_eq!(storage.read(path).unwrap(), b"a picture");
+    }
+
+    #[tokio::test]
+    async fn notes_the_md5_of_a_changed_file() {
+        let scratch = testutil::Scratch::new("saved-md5");
+        let mut post = post(b"a picture");
+        let path = scratch.path().join("1234.png");
+        std::fs::write(&path, b"a smaller picture").unwrap();
+        post.file_path = Some(path);
+        note_saved_md5(&post).await.unwrap();
+        let md5 = format!("{:x}", md5::compute(b"a smaller picture"));
+        assert_eq!(
+            post.saved_md5.lock().unwrap().as_deref(),
+            Some(md5.as_str())
+        );
+        assert!(post.is_saved_md5(&md5));
+        assert!(post.is_saved_md5(&post.file.md5));
+        assert!(!post.is_saved_md5(&format!("{:x}", md5::compute(b"nothing"))));
+    }
+
+    #[tokio::test]
+    async fn notes_the_md5_of_a_stripped_file() {
+        const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+        const TEXT: &[u8] = b"\0\0\0\x04tEXta\0b!\0\0\0\0";
+        const END: &[u8] = b"\0\0\0\0IEND\xae\x42\x60\x82";
+        const PNG: &[u8] =
+            b"\x89PNG\r\n\x1a\n\0\0\0\x04tEXta\0b!\0\0\0\0\0\0\0\0IEND\xae\x42\x60\x82";
+        assert_eq!(PNG, [SIGNATURE, TEXT, END].concat());
+        let url = testutil::serve(PNG).await;
+        let storage = Memory::default();
+        let post = post(PNG);
+        let opts = opts(&["--strip-metadata"]);
+        download_post(&testutil::client(), &storage, &post, &url, &opts)
+            .await
+            .unwrap();
+        let saved = storage.read(post.file_path.as_ref().unwrap()).unwrap();
+        assert_eq!(saved, [SIGNATURE, END].concat());
+        let md5 = format!("{:x}", md5::compute(&saved));
+        assert_eq!(
+            post.saved_md5.lock().unwrap().as_deref(),
+            Some(md5.as_str())
+        );
+        assert!(post.is_altered());
+    }
+
+    #[test]
+    fn keeps_what_the_sidecar_says_was_done_to_the_file() {
+        let scratch = testutil::Scratch::new("keep-saved");
+        let file_path = scratch.path().join("1234.png");
+        let tags_path = scratch.path().join("1234.json");
+        std::fs::write(&file_path, b"a smaller picture").unwrap();
+        let md5 = format!("{:x}", md5::compute(b"a smaller picture"));
+
+        let mut saved = post(b"a picture");
+        *saved.compressed.lock().unwrap() = Some(Compressed {
+            optimizer: "oxipng".to_owned(),
+            original_size: 9,
+            size: 17,
+            md5: md5.clone(),
+        });
+        *saved.saved_md5.lock().unwrap() = Some(md5.clone());
+        saved.file_path = Some(file_path.clone());
+        std::fs::write(&tags_path, serde_json::to_vec(&saved).unwrap()).unwrap();
+
+        // As the API gives it, on the next run.
+        let mut again = post(b"a picture");
+        again.file_path = Some(file_path.clone());
+        again.tags_path = Some(tags_path);
+        let mut posts = vec![again];
+        keep_saved_fields(&mut posts);
+        assert!(posts[0].is_saved_md5(&md5));
+
+        let mut cache = ChecksumCache::load(&scratch.path().join("checksums.json")).unwrap();
+        let diagnosis = diagnose(&[&posts[0]], &mut cache, 1, true);
+        assert!(diagnosis.findings.is_empty());
+        // And refreshing the sidecar keeps it.
+        let json: serde_json::Value = serde_json::to_value(&posts[0]).unwrap();
+        assert_eq!(json["compressed"]["md5"], md5.as_str());
+        assert_eq!(json["saved_md5"], md5.as_str());
+    }
+
+    #[test]
+    fn leaves_posts_saved_somewhere_else() {
+        let scratch = testutil::Scratch::new("keep-saved");
+        let tags_path = scratch.path().join("1234.json");
+        let mut saved = post(b"a picture");
+        saved.file_path = Some(scratch.path().join("1234.mp4"));
+        *saved.saved_md5.lock().unwrap() = Some("0".repeat(32));
+        std::fs::write(&tags_path, serde_json::to_vec(&saved).unwrap()).unwrap();
+
+        let mut again = post(b"a picture");
+        again.file_path = Some(scratch.path().join("1234.png"));
+        again.tags_path = Some(tags_path);
+        let mut posts = vec![again];
+        keep_saved_fields(&mut posts);
+        assert!(!posts[0].is_altered());
+    }
+
+    #[tokio::test]
+    async fn refuses_an_empty_200() {
+        let url = testutil::serve(b"").await;
+        let storage = Memory::default();
+        let post = post(b"a picture");
+        let result = download_post(&testutil::client(), &storage, &post, &url, &opts(&[])).await;
+        match result {
+            Err(e @ MonosodiumError::Incomplete { received: 0, .. }) => assert!(is_retryable(&e)),
+            result => panic!("expected an incomplete download, got {:?}", result),
+        }
+        assert!(storage.read(post.file_path.as_ref().unwrap()).is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_an_empty_thumbnail() {
+        let url = testutil::serve(b"").await;
+        let storage = Memory::default();
+        let mut post = post(b"a picture");
+        post.thumbnail_path = Some(PathBuf::from("out/thumbnails/1234.jpg"));
+        post.preview = Some(Preview {
+            width: 1,
+            height: 1,
+            url: Some(url),
+        });
+        assert!(archive_thumbnail(&testutil::client(), &storage, &post)
+            .await
+            .is_err());
+        assert!(storage
+            .read(post.thumbnail_path.as_ref().unwrap())
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn retries_a_short_body() {
+        let url = testutil::serve(b"a pic").await;
+        let storage = Memory::default();
+        let post = post(b"a picture");
+        let opts = opts(&["--retry-on-parse-mismatch"]);
+        let result = download_post(&testutil::client(), &storage, &post, &url, &opts).await;
+        match result {
+            Err(
+                e @ MonosodiumError::Incomplete {
+                    expected: 9,
+                    received: 5,
+                },
+            ) => assert!(is_retryable(&e)),
+            result => panic!("expected an incomplete download, got {:?}", result),
+        }
+        assert!(storage.read(post.file_path.as_ref().unwrap()).is_none());
+    }
+
+    #[tokio::test]
+    async fn keeps_a_long_body_unless_asked() {
+        let url = testutil::serve(b"a picture, and then some").await;
+        let post = post(b"a picture");
+
+        let storage = Memory::default();
+        let strict = opts(&["--retry-on-parse-mismatch"]);
+        let result = download_post(&testutil::client(), &storage, &post, &url, &strict).await;
+        assert!(matches!(result, Err(MonosodiumError::Incomplete { .. })));
+
+        let storage = Memory::default();
+        download_post(&testutil::client(), &storage, &post, &url, &opts(&[]))
+            .await
+            .unwrap();
+        assert!(storage.read(post.file_path.as_ref().unwrap()).is_some());
+    }
+
+    #[tokio::test]
+    async fn verify_on_download_keeps_a_file_with_the_right_md5() {
+        let url = testutil::serve(b"a picture").await;
+        let storage = Memory::default();
+        let post = post(b"a picture");
+        let opts = opts(&["--verify-on-download"]);
+        download_post(&testutil::client(), &storage, &post, &url, &opts)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.read(post.file_path.as_ref().unwrap()).as_deref(),
+            Some(&b"a picture"[..])
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_on_download_refuses_a_file_of_the_right_size_with_the_wrong_md5() {
+        let url = testutil::serve(b"a mixture").await;
+        let post = post(b"a picture");
+
+        let storage = Memory::default();
+        let verifying = opts(&["--verify-on-download"]);
+        let result = download_post(&testutil::client(), &storage, &post, &url, &verifying).await;
+        match result {
+            Err(e @ MonosodiumError::WrongMd5 { .. }) => assert!(is_retryable(&e)),
+            result => panic!("expected the wrong MD5, got {:?}", result),
+        }
+        assert!(storage.read(post.file_path.as_ref().unwrap()).is_none());
+
+        // Only the size is checked otherwise, even asking for it exactly.
+        let storage = Memory::default();
+        let strict = opts(&["--retry-on-parse-mismatch"]);
+        download_post(&testutil::client(), &storage, &post, &url, &strict)
+            .await
+            .unwrap();
+        assert!(storage.read(post.file_path.as_ref().unwrap()).is_some());
+    }
+
+    #[test]
+    fn unthrottled_keeps_an_api_delay_given() {
+        assert_eq!(opts(&[]).api_delay(), DEFAULT_API_DELAY);
+        assert_eq!(opts(&["--unthrottled"]).api_delay(), Duration::ZERO);
+        let given = opts(&["--unthrottled", "--api-delay", "100ms"]);
+        assert_eq!(given.api_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn refuses_a_breaker_threshold_of_zero() {
+        assert!(try_opts(&["--breaker-threshold", "0"]).is_err());
+    }
+
+    #[test]
+    fn allows_more_tags_logged_in() {
+        assert_eq!(opts(&[]).tag_limit(), DEFAULT_TAG_LIMIT);
+        let logged_in = opts(&["--username", "someone", "--api-key", "key"]);
+        assert_eq!(logged_in.tag_limit(), LOGGED_IN_TAG_LIMIT);
+        assert_eq!(opts(&["--tag-limit", "12"]).tag_limit(), 12);
+    }
+
+    #[test]
+    fn keeps_verify_concurrency_in_range() {
+        assert!(try_opts(&["--verify-concurrency", "0"]).is_err());
+        assert!(try_opts(&["--verify-concurrency", "65"]).is_err());
+        assert_eq!(opts(&["--verify-concurrency", "64"]).verify_concurrency, 64);
+    }
+
+    #[test]
+    fn takes_an_aspect_range_without_a_minimum() {
+        let range = |min, max| Some(Aspect::Range { min, max });
+        assert_eq!(opts(&["--aspect", "-0.8"]).aspect, range(None, Some(0.8)));
+        assert_eq!(opts(&["--aspect", "1.5-"]).aspect, range(Some(1.5), None));
+        assert!(try_opts(&["--aspect", "--min-score"]).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::storage::with_open_file;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::fs::{remove_file, rename, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// How the files that keep track of the archive, like the index and saved
+/// progress, are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WriteStrategy {
+    /// Straight over the old file. A crash partway through leaves it cut short.
+    #[default]
+    InPlace,
+    /// To a temporary file that's then renamed over the old one, which is kept
+    /// as a backup, so there's always a whole copy to go back to.
+    Atomic,
+}
+
+/// Writes `value` to `path` as JSON.
+pub fn save<T: Serialize + ?Sized>(
+    path: &Path,
+    value: &T,
+    strategy: WriteStrategy,
+) -> io::Result<()> {
+    match strategy {
+        WriteStrategy::InPlace => with_open_file(|| write_json(path, value).map(drop)),
+        WriteStrategy::Atomic => {
+            let temporary = with_suffix(path, ".tmp");
+            with_open_file(|| write_json(&temporary, value)?.sync_all())?;
+            match rename(path, with_suffix(path, ".bak")) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            rename(&temporary, path)
+        }
+    }
+}
+
+fn write_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> io::Result<File> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.into_inner().map_err(|e| e.into_error())
+}
+
+/// Reads the JSON at `path`, or `None` if there isn't any. If it's missing or
+/// can't be read as JSON, but a backup left by [`WriteStrategy::Atomic`] can
+/// be, the backup is used instead.
+pub fn load<T: DeserializeOwned>(path: &Path) -> io::Result<Option<T>> {
+    let error = match read_json(path) {
+        Ok(value) => return Ok(Some(value)),
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::NotFound | ErrorKind::InvalidData | ErrorKind::UnexpectedEof
+            ) =>
+        {
+            e
+        }
+        Err(e) => return Err(e),
+    };
+    let backup = with_suffix(path, ".bak");
+    match read_json(&backup) {
+        Ok(value) => {
+            warn!("Could not read {:?} ({}), so using its backup", path, error);
+            Ok(Some(value))
+        }
+        Err(_) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(_) => Err(error),
+    }
+}
+
+/// Removes the file at `path`, if there is one, along with its backup, which
+/// [`load`] would otherwise go back to.
+pub fn remove(path: &Path) -> io::Result<()> {
+    for path in [path.to_owned(), with_suffix(path, ".bak")] {
+        match remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> io::Result<T> {
+    with_open_file(|| {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    })
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::Scratch;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn goes_back_to_the_backup_when_cut_short() {
+        let scratch = Scratch::new("manifest");
+        let path = scratch.path().join("state.json");
+        save(&path, &vec![1, 2, 3], WriteStrategy::Atomic).unwrap();
+        save(&path, &vec![4, 5, 6], WriteStrategy::Atomic).unwrap();
+        // A crash partway through writing it in place.
+        let length = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(length / 2)
+            .unwrap();
+        assert_eq!(load::<Vec<u32>>(&path).unwrap(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn goes_back_to_the_backup_when_missing() {
+        let scratch = Scratch::new("manifest");
+        let path = scratch.path().join("state.json");
+        save(&path, &vec![1], WriteStrategy::Atomic).unwrap();
+        save(&path, &vec![2], WriteStrategy::Atomic).unwrap();
+        remove_file(&path).unwrap();
+        assert_eq!(load::<Vec<u32>>(&path).unwrap(), Some(vec![1]));
+    }
+
+    #[test]
+    fn removes_the_backup_too() {
+        let scratch = Scratch::new("manifest");
+        let path = scratch.path().join("state.json");
+        save(&path, &vec![1], WriteStrategy::Atomic).unwrap();
+        save(&path, &vec![2], WriteStrategy::Atomic).unwrap();
+        remove(&path).unwrap();
+        assert_eq!(load::<Vec<u32>>(&path).unwrap(), None);
+        // And there's nothing to remove the second time.
+        remove(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_cut_short_file_without_a_backup() {
+        let scratch = Scratch::new("manifest");
+        let path = scratch.path().join("state.json");
+        std::fs::write(&path, b"[1, 2").unwrap();
+        assert!(load::<Vec<u32>>(&path).is_err());
+    }
+}
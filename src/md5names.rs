@@ -0,0 +1,182 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::checksum::{md5_file, ChecksumCache};
+use crate::progress::Progress;
+use clap::ValueEnum;
+use std::collections::HashSet;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+/// What to do with files whose contents don't match the MD5 they're named
+/// after, for --fix-md5-names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum NameFix {
+    /// Rename the file after the MD5 it really has
+    Rename,
+    /// Move the file into --quarantine-dir, with a note of what was wrong
+    Quarantine,
+}
+
+/// A file that doesn't hash to the MD5 in its name.
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub named: String,
+    pub actual: String,
+}
+
+#[derive(Default)]
+pub struct Check {
+    pub checked: usize,
+    pub cached: usize,
+    pub unreadable: Vec<(PathBuf, std::io::Error)>,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Hashes every file under `root` that's named after an MD5, leaving out
+/// anything under `skip`, hidden files and the files in `exempt`, and lists
+/// those whose contents don't match. Files that haven't changed since they
+/// were last hashed are taken from `cache`, unless `force` is set.
+pub fn check(
+    root: &Path,
+    skip: &[&Path],
+    exempt: &HashSet<&Path>,
+    cache: &mut ChecksumCache,
+    force: bool,
+) -> std::io::Result<Check> {
+    let mut named = Vec::new();
+    let mut pending = vec![root.to_owned()];
+    while let Some(dir) = pending.pop() {
+        for entry in read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if hidden || skip.contains(&path.as_path()) {
+                continue;
+            }
+            let kind = entry.file_type()?;
+            if kind.is_dir() {
+                pending.push(path);
+            } else if kind.is_file() && !exempt.contains(path.as_path()) {
+                if let Some(md5) = named_md5(&path) {
+                    named.push((path, md5));
+                }
+            }
+        }
+    }
+    named.sort();
+
+    let mut check = Check::default();
+    let mut progress = Progress::new(named.len());
+    for (path, named) in named {
+        progress.tick();
+        let actual = match cache.get(&path).filter(|_| !force) {
+            Some(md5) => {
+                check.cached += 1;
+                md5
+            }
+            None => match md5_file(&path) {
+                Ok(md5) => {
+                    cache.insert(&path, &md5);
+                    md5
+                }
+                Err(e) => {
+                    check.unreadable.push((path, e));
+                    continue;
+                }
+            },
+        };
+        check.checked += 1;
+        if actual != named {
+            check.mismatches.push(Mismatch {
+                path,
+                named,
+                actual,
+            });
+        }
+    }
+    progress.finish();
+    Ok(check)
+}
+
+impl Mismatch {
+    /// Where the file would be if it were named after the MD5 it really has.
+    pub fn renamed(&self) -> PathBuf {
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let (start, end) = md5_span(&name).expect("named after an MD5");
+        let name = format!("{}{}{}", &name[..start], self.actual, &name[end..]);
+        self.path.with_file_name(name)
+    }
+}
+
+/// The MD5 a file is named after: e621's names are the MD5 and an extension,
+/// with a place in a pool in front for --layout by-pool, and things like
+/// ".original" added after by some options.
+pub fn named_md5(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let (start, end) = md5_span(name)?;
+    Some(name[start..end].to_ascii_lowercase())
+}
+
+fn md5_span(name: &str) -> Option<(usize, usize)> {
+    let base = name.split('.').next()?;
+    let start = base.rfind('_').map_or(0, |i| i + 1);
+    let md5 = &base[start..];
+    (md5.len() == 32 && md5.bytes().all(|b| b.is_ascii_hexdigit())).then_some((start, base.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MD5: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn finds_the_md5_in_a_name() {
+        for name in [
+            format!("{}.png", MD5),
+            format!("007_{}.png", MD5),
+            format!("{}.original.png", MD5.to_ascii_uppercase()),
+        ] {
+            assert_eq!(
+                named_md5(Path::new(&name)).as_deref(),
+                Some(MD5),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(named_md5(Path::new("007_fox.png")), None);
+    }
+
+    #[test]
+    fn renames_only_the_md5() {
+        let mismatch = Mismatch {
+            path: PathBuf::from(format!("pools/comic/007_{}.png", MD5)),
+            named: MD5.to_owned(),
+            actual: "f".repeat(32),
+        };
+        assert_eq!(
+            mismatch.renamed(),
+            PathBuf::from(format!("pools/comic/007_{}.png", "f".repeat(32)))
+        );
+    }
+}
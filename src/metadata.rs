@@ -0,0 +1,475 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::client::Client;
+use crate::layout::sanitize;
+use crate::storage::{Filesystem, Pending, StorageBackend};
+use crate::{Opts, Post};
+use clap::ValueEnum;
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::PathBuf;
+
+// Raw API objects are saved as <md5>.raw.json, next to the sidecars.
+pub const RAW_SUFFIX: &str = ".raw.json";
+const COMMENTS_SUFFIX: &str = ".comments.json";
+const NOTES_SUFFIX: &str = ".notes.json";
+
+/// The other JSON files kept next to the sidecars, that aren't sidecars.
+// As many as the API sends in one page of a list.
+const LIST_LIMIT: usize = 320;
+pub const EXTRA_SUFFIXES: &[&str] = &[RAW_SUFFIX, COMMENTS_SUFFIX, NOTES_SUFFIX];
+
+/// How tags are cased in the `.txt` tag files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TagCase {
+    /// Just as e621 gives them
+    #[default]
+    Original,
+    /// All lower case
+    Lower,
+}
+
+/// What separates the words of a tag in the `.txt` tag files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TagSpace {
+    /// Underscores, as on e621: `long_hair`
+    #[default]
+    Underscore,
+    /// Spaces: `long hair`
+    Space,
+}
+
+/// One kind of file written alongside each downloaded post. Every writer that
+/// was asked for gets the same post, and one failing doesn't stop the others.
+pub trait MetadataWriter: Send + Sync {
+    /// What's written, for error messages.
+    fn name(&self) -> &'static str;
+
+    /// Whether a post without this isn't archived at all, so that failing to
+    /// write it fails the post.
+    fn required(&self) -> bool {
+        false
+    }
+
+    fn write<'a>(&'a self, storage: &'a dyn StorageBackend, post: &'a Post) -> Pending<'a, ()>;
+}
+
+/// The writers asked for on the command line. The JSON sidecar is always
+/// written, since the index and --rename-existing rely on it.
+pub fn writers(opts: &Opts, client: &Client) -> Vec<Box<dyn MetadataWriter>> {
+    let mut writers = offline_writers(opts);
+    if opts.preserve_raw {
+        writers.push(Box::new(Raw));
+    }
+    if opts.with_comments {
+        writers.push(Box::new(Comments {
+            client: client.clone(),
+            compact: opts.json_compact,
+        }));
+    }
+    if opts.with_notes {
+        writers.push(Box::new(Notes {
+            client: client.clone(),
+            compact: opts.json_compact,
+        }));
+    }
+    if let Some(dir) = &opts.write_tag_wiki {
+        writers.push(Box::new(TagWiki {
+            client: client.clone(),
+            dir: dir.clone(),
+            local: Filesystem {
+                modes: opts.modes(),
+            },
+            compact: opts.json_compact,
+        }));
+    }
+    writers
+}
+
+/// The writers asked for that need nothing but the post itself, for
+/// --rebuild-metadata. The raw JSON isn't among them, since it's what the
+/// others are rebuilt from.
+pub fn offline_writers(opts: &Opts) -> Vec<Box<dyn MetadataWriter>> {
+    let mut writers: Vec<Box<dyn MetadataWriter>> = vec![Box::new(Sidecar {
+        compact: opts.json_compact,
+    })];
+    if opts.write_sources {
+        writers.push(Box::new(Sources));
+    }
+    if opts.write_tags_txt {
+        writers.push(Box::new(TagText {
+            case: opts.tag_case,
+            space: opts.tag_space,
+        }));
+    }
+    writers
+}
+
+// A search with no results comes back as an object rather than a list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Found<T> {
+    Some(Vec<T>),
+    None {},
+}
+
+// Fetches a list from the API, like a post's comments, a page at a time
+// until one comes back short.
+async fn fetch_list<T: DeserializeOwned>(
+    client: &Client,
+    url: &Url,
+) -> Result<Vec<T>, reqwest::Error> {
+    let mut list = Vec::new();
+    let mut page = 1;
+    loop {
+        let found = client
+            .get(page_of(url, page).as_str())
+            .await?
+            .error_for_status()?
+            .json::<Found<T>>()
+            .await?;
+        let entries = match found {
+            Found::Some(entries) => entries,
+            Found::None {} => Vec::new(),
+        };
+        let short = entries.len() < LIST_LIMIT;
+        list.extend(entries);
+        if short {
+            return Ok(list);
+        }
+        page += 1;
+    }
+}
+
+fn page_of(url: &Url, page: u32) -> Url {
+    let mut url = url.clone();
+    url.query_pairs_mut()
+        .append_pair("limit", &LIST_LIMIT.to_string())
+        .append_pair("page", &page.to_string());
+    url
+}
+
+fn to_json(value: &impl Serialize, compact: bool) -> Vec<u8> {
+    let json = if compact {
+        serde_json::to_vec(value)
+    } else {
+        serde_json::to_vec_pretty(value)
+    };
+    json.unwrap()
+}
+
+/// The post's metadata as JSON, in the metadata directory.
+pub struct Sidecar {
+    pub compact: bool,
+}
+
+impl MetadataWriter for Sidecar {
+    fn name(&self) -> &'static str {
+        "metadata"
+    }
+
+    fn required(&self) -> bool {
+        true
+    }
+
+    fn write<'a>(&'a self, storage: &'a dyn StorageBackend, post: &'a Post) -> Pending<'a, ()> {
+        storage.write_metadata(
+            post.tags_path.as_ref().unwrap(),
+            to_json(post, self.compact),
+        )
+    }
+}
+
+// The untouched API object, next to the JSON metadata, so that fields this
+// version doesn't know about aren't lost.
+struct Raw;
+
+impl MetadataWriter for Raw {
+    fn name(&self) -> &'static str {
+        "raw metadata"
+    }
+
+    fn write<'a>(&'a self, storage: &'a dyn StorageBackend, post: &'a Post) -> Pending<'a, ()> {
+        Box::pin(async move {
+            let Some(raw) = &post.raw else {
+                return Ok(());
+            };
+            let path = post
+                .tags_path
+                .as_ref()
+                .unwrap()
+                .with_extension(&RAW_SUFFIX[1..]);
+            storage
+                .write_metadata(&path, raw.get().as_bytes().to_vec())
+                .await
+        })
+    }
+}
+
+// One URL per line, next to the JSON metadata. Posts without sources don't get
+// a file at all.
+struct Sources;
+
+impl MetadataWriter for Sources {
+    fn name(&self) -> &'static str {
+        "sources"
+    }
+
+    fn write<'a>(&'a self, storage: &'a dyn StorageBackend, post: &'a Post) -> Pending<'a, ()> {
+        Box::pin(async move {
+            if post.sources.is_empty() {
+                return Ok(());
+            }
+            let path = post.tags_path.as_ref().unwrap().with_extension("source");
+            let contents = post.sources.join("\n") + "\n";
+            storage.write(&path, contents.into_bytes()).await
+        })
+    }
+}
+
+// Tags separated by commas on one line, next to the file and named after it,
+// the way tools for training image models expect captions.
+struct TagText {
+    case: TagCase,
+    space: TagSpace,
+}
+
+impl TagText {
+    fn format(&self, tag: &str) -> String {
+        let tag = match self.case {
+            TagCase::Original => tag.to_owned(),
+            TagCase::Lower => tag.to_lowercase(),
+        };
+        match self.space {
+            TagSpace::Underscore => tag,
+            TagSpace::Space => tag.replace('_', " "),
+        }
+    }
+}
+
+impl MetadataWriter for TagText {
+    fn name(&self) -> &'static str {
+        "tag file"
+    }
+
+    fn write<'a>(&'a self, storage: &'a dyn StorageBackend, post: &'a Post) -> Pending<'a, ()> {
+        Box::pin(async move {
+            let path = post.file_path.as_ref().unwrap().with_extension("txt");
+            let tags = &post.tags;
+            let line = [
+                &tags.artist,
+                &tags.character,
+                &tags.copyright,
+                &tags.species,
+                &tags.general,
+                &tags.lore,
+                &tags.meta,
+            ]
+            .into_iter()
+            .flatten()
+            .map(|tag| self.format(tag))
+            .collect::<Vec<_>>()
+            .join(", ");
+            storage.write(&path, (line + "\n").into_bytes()).await
+        })
+    }
+}
+
+// The post's comments, oldest first, as <md5>.comments.json next to the JSON
+// metadata. That's one more request per post, so it's only done when asked.
+struct Comments {
+    client: Client,
+    compact: bool,
+}
+
+impl MetadataWriter for Comments {
+    fn name(&self) -> &'static str {
+        "comments"
+    }
+
+    fn write<'a>(&'a self, storage: &'a dyn StorageBackend, post: &'a Post) -> Pending<'a, ()> {
+        Box::pin(async move {
+            let id = post.id.to_string();
+            let url = Url::parse_with_params(
+                "https://e621.net/comments.json",
+                &[
+                    ("group_by", "comment"),
+                    ("search[post_id]", id.as_str()),
+                    ("search[order]", "id_asc"),
+                ],
+            )
+            .expect("the comments URL is always valid");
+            // Kept as they come, whatever fields they have.
+            let comments: Vec<Value> = fetch_list(&self.client, &url).await?;
+            let path = post
+                .tags_path
+                .as_ref()
+                .unwrap()
+                .with_extension(&COMMENTS_SUFFIX[1..]);
+            storage
+                .write_metadata(&path, to_json(&comments, self.compact))
+                .await
+        })
+    }
+}
+
+/// A note on a post: text placed over part of the image, usually a
+/// translation. The box is in pixels of the full-size file.
+#[derive(Serialize, Deserialize)]
+struct Note {
+    id: u64,
+    x: i64,
+    y: i64,
+    width: u64,
+    height: u64,
+    body: String,
+    // Notes that were deleted are still listed, as inactive.
+    #[serde(default = "active", skip_serializing)]
+    is_active: bool,
+}
+
+fn active() -> bool {
+    true
+}
+
+// The post's notes, as <md5>.notes.json next to the JSON metadata. Posts
+// without any get an empty list, so it's clear they were looked for.
+struct Notes {
+    client: Client,
+    compact: bool,
+}
+
+impl MetadataWriter for Notes {
+    fn name(&self) -> &'static str {
+        "notes"
+    }
+
+    fn write<'a>(&'a self, storage: &'a dyn StorageBackend, post: &'a Post) -> Pending<'a, ()> {
+        Box::pin(async move {
+            let id = post.id.to_string();
+            let url = Url::parse_with_params(
+                "https://e621.net/notes.json",
+                &[("search[post_id]", id.as_str())],
+            )
+            .expect("the notes URL is always valid");
+            let mut notes: Vec<Note> = fetch_list(&self.client, &url).await?;
+            notes.retain(|note| note.is_active);
+            notes.sort_by_key(|note| note.id);
+            let path = post
+                .tags_path
+                .as_ref()
+                .unwrap()
+                .with_extension(&NOTES_SUFFIX[1..]);
+            storage
+                .write_metadata(&path, to_json(&notes, self.compact))
+                .await
+        })
+    }
+}
+
+// The wiki page of each of the post's tags, in the --write-tag-wiki directory,
+// as <tag>.json and, for tags that have one, <tag>.md with its text. Tags
+// share pages, so each is only looked up once: a tag without a page still gets
+// a .json saying so, and a tag with a .json, from this run or an earlier one,
+// isn't looked up again.
+struct TagWiki {
+    client: Client,
+    dir: PathBuf,
+    local: Filesystem,
+    compact: bool,
+}
+
+#[derive(Serialize)]
+struct WikiEntry<'a> {
+    tag: &'a str,
+    // As the API gave it, or None if the tag has no page.
+    page: Option<Value>,
+}
+
+impl MetadataWriter for TagWiki {
+    fn name(&self) -> &'static str {
+        "tag wiki"
+    }
+
+    fn write<'a>(&'a self, _: &'a dyn StorageBackend, post: &'a Post) -> Pending<'a, ()> {
+        Box::pin(async move {
+            for tag in post.tags.all() {
+                // Tags can have dots in them, so the extensions are added
+                // rather than set.
+                let name = sanitize(tag);
+                let path = self.dir.join(format!("{}.json", name));
+                if path.exists() {
+                    continue;
+                }
+                let url = Url::parse_with_params(
+                    "https://e621.net/wiki_pages.json",
+                    &[("search[title]", tag.as_str()), ("limit", "1")],
+                )
+                .expect("the wiki URL is always valid");
+                let pages: Vec<Value> = fetch_list(&self.client, &url).await?;
+                let page = pages
+                    .into_iter()
+                    .find(|page| page["title"].as_str() == Some(tag.as_str()));
+                if let Some(body) = page.as_ref().and_then(|page| page["body"].as_str()) {
+                    let markdown = format!("# {}\n\n{}\n", tag.replace('_', " "), body.trim());
+                    self.local
+                        .write(
+                            &self.dir.join(format!("{}.md", name)),
+                            markdown.into_bytes(),
+                        )
+                        .await?;
+                }
+                // Written last, since it's what marks the tag as done.
+                let entry = WikiEntry { tag, page };
+                self.local
+                    .write(&path, to_json(&entry, self.compact))
+                    .await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn asks_for_whole_pages() {
+        let url = Url::parse("https://e621.net/notes.json?search[post_id]=1").unwrap();
+        assert_eq!(
+            page_of(&url, 2).as_str(),
+            "https://e621.net/notes.json?search[post_id]=1&limit=320&page=2"
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_at_a_short_page() {
+        let url = Url::parse(&testutil::serve(b"{\"notes\":[]}").await).unwrap();
+        let notes: Vec<Note> = fetch_list(&testutil::client(), &url).await.unwrap();
+        assert!(notes.is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::client::Client;
+use crate::summary::Summary;
+use crate::units::format_size;
+use clap::ValueEnum;
+use log::{error, info};
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum NotifyOn {
+    /// After every run
+    #[default]
+    Always,
+    /// Only after runs that ended in an error, or where any post failed
+    Failure,
+}
+
+/// Whether a run that ended like this should be told about.
+pub fn wanted(on: NotifyOn, summary: &Summary, error: Option<&str>) -> bool {
+    on == NotifyOn::Always || error.is_some() || !summary.failures.is_empty()
+}
+
+/// How the run ended: "done", "stopped" or "failed".
+pub fn status(summary: &Summary, error: Option<&str>) -> &'static str {
+    match (error, summary.stopped) {
+        (Some(_), _) => "failed",
+        (None, Some(_)) => "stopped",
+        (None, None) => "done",
+    }
+}
+
+/// The outcome of the run, as JSON. `content` and `text` are a one-line
+/// summary for Discord and Slack webhooks, which show those fields.
+fn outcome(summary: &Summary, error: Option<&str>) -> Value {
+    let status = status(summary, error);
+    let mut message = format!(
+        "monosodium {}: {} downloaded ({}), {} failed",
+        status,
+        summary.downloaded,
+        format_size(summary.bytes),
+        summary.failures.len()
+    );
+    if let Some(error) = error {
+        message += &format!(": {}", error);
+    }
+    json!({
+        "content": message,
+        "text": message,
+        "status": status,
+        "error": error,
+        "stopped": summary.stopped.map(|reason| reason.to_string()),
+        "pages": summary.pages,
+        "posts_seen": summary.posts_seen,
+        "already_present": summary.already_present,
+        "downloaded": summary.downloaded,
+        "bytes": summary.bytes,
+        "failed": summary.failures.len(),
+        "failed_ids": summary.failures.iter().map(|failure| failure.id).collect::<Vec<_>>(),
+        "excluded": summary.excluded_total(),
+        "elapsed_seconds": summary.elapsed().as_secs(),
+    })
+}
+
+/// Runs `command` through the shell to say how the run went. The outcome is
+/// in `MONOSODIUM_*` environment variables, and as JSON on standard input.
+/// The command's own failure is logged, but doesn't change the outcome.
+pub async fn run_command(command: &str, summary: &Summary, error: Option<&str>) {
+    #[cfg(unix)]
+    let mut shell = Command::new("sh");
+    #[cfg(unix)]
+    shell.arg("-c");
+    #[cfg(not(unix))]
+    let mut shell = Command::new("cmd");
+    #[cfg(not(unix))]
+    shell.arg("/C");
+
+    shell
+        .arg(command)
+        .env("MONOSODIUM_STATUS", status(summary, error))
+        .env("MONOSODIUM_ERROR", error.unwrap_or_default())
+        .env("MONOSODIUM_PAGES", summary.pages.to_string())
+        .env("MONOSODIUM_DOWNLOADED", summary.downloaded.to_string())
+        .env("MONOSODIUM_BYTES", summary.bytes.to_string())
+        .env("MONOSODIUM_FAILED", summary.failures.len().to_string())
+        .env(
+            "MONOSODIUM_ELAPSED_SECONDS",
+            summary.elapsed().as_secs().to_string(),
+        )
+        .stdin(Stdio::piped());
+
+    info!("Running --notify command");
+    let input = outcome(summary, error).to_string();
+    let result = async {
+        let mut child = shell.spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A command that doesn't read its input is fine too.
+            let _ = stdin.write_all(input.as_bytes()).await;
+        }
+        child.wait().await
+    }
+    .await;
+    match result {
+        Ok(exit) if exit.success() => {}
+        Ok(exit) => error!("The --notify command failed ({})", exit),
+        Err(e) => error!("Could not run the --notify command: {}", e),
+    }
+}
+
+/// POSTs the outcome of the run, as JSON, to `url`.
+pub async fn post_webhook(client: &Client, url: &Url, summary: &Summary, error: Option<&str>) {
+    info!("Posting the run summary to the webhook");
+    let body = outcome(summary, error);
+    match client.post_json(url.as_str(), &body).await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => error!("The webhook refused the summary ({})", response.status()),
+        Err(e) => error!("Could not post to the webhook: {}", e),
+    }
+}
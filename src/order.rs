@@ -0,0 +1,87 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::error::MonosodiumError;
+use crate::pages::Page;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Reads a list of post ids for --download-order-file, one per line. Anything
+/// after a tab is ignored, so either format of --list-only can be replayed as
+/// it is, and blank lines and lines starting with `#` are skipped. Anything
+/// else that isn't an id, or an id given twice, is an error, since the order
+/// would otherwise quietly come out different from what was asked for.
+pub fn read_order_file(path: &Path) -> Result<Vec<u64>, MonosodiumError> {
+    let invalid = |line: usize, problem: String| {
+        MonosodiumError::InvalidOptions(format!("{}, line {}: {}", path.display(), line, problem))
+    };
+    let mut ids = Vec::new();
+    let mut seen = HashSet::new();
+    for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim_end();
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let field = line.split('\t').next().unwrap().trim();
+        let id = field
+            .parse::<u64>()
+            .map_err(|_| invalid(i + 1, format!("{:?} isn't a post id", field)))?;
+        if !seen.insert(id) {
+            return Err(invalid(i + 1, format!("post {} is listed twice", id)));
+        }
+        ids.push(id);
+    }
+    if ids.is_empty() {
+        return Err(MonosodiumError::InvalidOptions(format!(
+            "{} doesn't list any posts",
+            path.display()
+        )));
+    }
+    Ok(ids)
+}
+
+/// Puts the posts of every page onto one, in the order of `order`, followed by
+/// those it doesn't list (which the filters then turn away). Returns that page,
+/// unless there were no posts at all, and the ids in `order` that none of the
+/// pages had.
+pub fn reorder(pages: Vec<Page>, order: &[u64]) -> (Option<Page>, Vec<u64>) {
+    let position: HashMap<u64, usize> = order.iter().enumerate().map(|(i, &id)| (id, i)).collect();
+    let mut pages = pages.into_iter();
+    let Some(mut page) = pages.next() else {
+        return (None, order.to_vec());
+    };
+    for other in pages {
+        page.response.posts.extend(other.response.posts);
+    }
+    page.number = 1;
+    // Stable, so unlisted posts keep the order they came in.
+    page.response
+        .posts
+        .sort_by_key(|post| position.get(&post.id).copied().unwrap_or(usize::MAX));
+    let found: HashSet<u64> = page.response.posts.iter().map(|post| post.id).collect();
+    let missing = order
+        .iter()
+        .copied()
+        .filter(|id| !found.contains(id))
+        .collect();
+    (Some(page), missing)
+}
@@ -0,0 +1,827 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::client::{is_outage, Client};
+use crate::error::MonosodiumError;
+use crate::feed::Feed;
+use crate::profile::Phase;
+use crate::shutdown::Shutdown;
+use crate::users;
+use crate::{ApiResponse, Post};
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use std::fs::{create_dir_all, read_to_string, write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+
+/// How many pages of metadata may wait in line for the downloader, unless
+/// --parallel-pages says otherwise, and the most it may say.
+pub const DEFAULT_PREFETCH_PAGES: u32 = 1;
+pub const MAX_PREFETCH_PAGES: u32 = 16;
+
+/// The most posts e621 will put on one page.
+pub const MAX_PER_PAGE: u32 = 320;
+
+// How many more times a page is asked for when e621 says to slow down, and how
+// long to wait before the first of them; the wait doubles each time.
+const THROTTLED_RETRIES: u32 = 3;
+const THROTTLED_BACKOFF: Duration = Duration::from_secs(10);
+
+// How many times in a row a page is asked for from the API before falling back
+// to --fallback-feed, and how long to wait between those tries.
+const FALLBACK_AFTER: u32 = 3;
+const FALLBACK_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+// Where the posts to archive come from.
+#[derive(Clone, Debug)]
+pub enum Source {
+    Favorites(u32),
+    // The favorites of whoever the client is logged in as, by username.
+    MyFavorites(String),
+    Search(String),
+    // A set, which is searched for by its short name.
+    Set { id: u64, shortname: String },
+}
+
+impl Source {
+    fn page_url(&self, page: usize, per_page: u32) -> String {
+        match self {
+            Source::Favorites(user_id) => favorites_url(Some(*user_id), page, per_page),
+            Source::MyFavorites(_) => favorites_url(None, page, per_page),
+            Source::Search(tags) => search_url(tags, page, per_page),
+            Source::Set { shortname, .. } => {
+                search_url(&format!("set:{}", shortname), page, per_page)
+            }
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Source::Favorites(_) | Source::MyFavorites(_) => "favorites",
+            Source::Search(_) => "search results",
+            Source::Set { .. } => "set",
+        }
+    }
+
+    /// Whether posts are listed newest upload first, as searches are unless
+    /// they have an `order:` tag. Favorites go by when they were favorited.
+    fn is_newest_first(&self) -> bool {
+        match self {
+            Source::Favorites(_) | Source::MyFavorites(_) => false,
+            Source::Search(tags) => !tags
+                .split_whitespace()
+                .any(|tag| tag.starts_with("order:") && tag != "order:id_desc"),
+            Source::Set { .. } => true,
+        }
+    }
+
+    /// Identifies the source across runs, so that saved progress is never
+    /// applied to a different one.
+    pub fn key(&self) -> String {
+        match self {
+            Source::Favorites(user_id) => format!("favorites:{}", user_id),
+            Source::MyFavorites(username) => format!("my-favorites:{}", username),
+            Source::Search(tags) => format!("search:{}", tags),
+            Source::Set { id, .. } => format!("set:{}", id),
+        }
+    }
+}
+
+/// A page of posts, and which page it was.
+pub struct Page {
+    pub number: usize,
+    pub response: ApiResponse,
+}
+
+// Without a user id, e621 gives the favorites of the user who is logged in.
+fn favorites_url(user_id: Option<u32>, page: usize, per_page: u32) -> String {
+    match user_id {
+        Some(user_id) => format!(
+            "https://e621.net/favorites.json?user_id={}&page={}&limit={}",
+            user_id, page, per_page
+        ),
+        None => format!(
+            "https://e621.net/favorites.json?page={}&limit={}",
+            page, per_page
+        ),
+    }
+}
+
+fn search_url(tags: &str, page: usize, per_page: u32) -> String {
+    let page = page.to_string();
+    let per_page = per_page.to_string();
+    Url::parse_with_params(
+        "https://e621.net/posts.json",
+        &[
+            ("tags", tags),
+            ("page", page.as_str()),
+            ("limit", per_page.as_str()),
+        ],
+    )
+    .expect("the search URL is always valid")
+    .into()
+}
+
+/// What to do about posts that move back onto a page after it was fetched,
+/// because posts before them were removed in the meantime, for --page-drift.
+/// Since the next page is fetched as it is by then, they'd be on neither.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PageDrift {
+    /// Don't check
+    #[default]
+    Ignore,
+    /// Fetch each page again before the next, and warn about posts that moved onto it
+    Warn,
+    /// Fetch each page again before the next, and archive posts that moved onto it too
+    Rescan,
+}
+
+/// How big the pages are, and where to stop walking them, short of running
+/// out of them.
+#[derive(Clone)]
+pub struct Paging {
+    /// How many posts to ask for on each page.
+    pub per_page: u32,
+    /// No more than this many pages are fetched.
+    pub max_pages: Option<usize>,
+    /// None are fetched after the first reaching back before this, where
+    /// posts come newest first.
+    pub since: Option<SystemTime>,
+    /// Likewise for the page with any of these favorites, the newest when
+    /// the last run started, for --since-run.
+    pub favorited_since: Option<Vec<u64>>,
+    /// How many pages may be fetched ahead of the one being downloaded.
+    pub ahead: u32,
+    pub drift: PageDrift,
+}
+
+/// Page responses saved on disk, for re-runs that fetch the same pages again,
+/// such as while trying out filters. Each is named after the MD5 of its URL,
+/// and of who was logged in, since what e621 sends depends on that.
+pub struct PageCache {
+    dir: PathBuf,
+    ttl: Duration,
+    refresh: bool,
+    login: Option<String>,
+}
+
+impl PageCache {
+    /// With `refresh`, saved pages are never read, only replaced. `login` is
+    /// the username the pages are fetched as, if any.
+    pub fn new(
+        dir: PathBuf,
+        ttl: Duration,
+        refresh: bool,
+        login: Option<String>,
+    ) -> std::io::Result<PageCache> {
+        create_dir_all(&dir)?;
+        Ok(PageCache {
+            dir,
+            ttl,
+            refresh,
+            login,
+        })
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        let key = match &self.login {
+            Some(username) => md5::compute(format!("{} {}", username, url)),
+            None => md5::compute(url),
+        };
+        self.dir.join(format!("{:x}.json", key))
+    }
+
+    // The saved response for `url`, if it's younger than the TTL.
+    fn get(&self, url: &str) -> Option<String> {
+        if self.refresh {
+            return None;
+        }
+        let path = self.path(url);
+        let age = path.metadata().ok()?.modified().ok()?.elapsed().ok()?;
+        (age < self.ttl)
+            .then(|| read_to_string(path).ok())
+            .flatten()
+    }
+
+    fn put(&self, url: &str, body: &str) {
+        if let Err(e) = write(self.path(url), body) {
+            warn!("Could not cache {}: {}", url, e);
+        }
+    }
+}
+
+pub async fn fetch_page(
+    client: &Client,
+    source: &Source,
+    page: usize,
+    per_page: u32,
+    cache: Option<&PageCache>,
+) -> Result<ApiResponse, MonosodiumError> {
+    let url = source.page_url(page, per_page);
+    if let Some(body) = cache.and_then(|cache| cache.get(&url)) {
+        match serde_json::from_str(&body) {
+            Ok(response) => {
+                debug!("Using cached {}", url);
+                return Ok(response);
+            }
+            Err(e) => warn!("Ignoring unreadable cached {}: {}", url, e),
+        }
+    }
+    let body = client
+        .profile()
+        .time(Phase::Pages, request_page(client, source, &url, page))
+        .await?;
+    let response = serde_json::from_str(&body).map_err(std::io::Error::from)?;
+    if let Some(cache) = cache {
+        cache.put(&url, &body);
+    }
+    Ok(response)
+}
+
+// Asks for a page until e621 gives it or gives a reason not to.
+async fn request_page(
+    client: &Client,
+    source: &Source,
+    url: &str,
+    page: usize,
+) -> Result<String, MonosodiumError> {
+    let mut attempt = 0;
+    loop {
+        let response = client.get(url).await?;
+        let status = response.status();
+        if let (StatusCode::NOT_FOUND, Source::Favorites(user_id)) = (status, source) {
+            return Err(no_such_user(*user_id));
+        }
+        let failed = response.error_for_status_ref().err();
+        let body = response.text().await?;
+        let Some(reason) = refusal(&body) else {
+            match failed {
+                Some(e) => return Err(e.into()),
+                None => return Ok(body),
+            }
+        };
+        match classify(status, &reason) {
+            Refusal::Throttled if attempt < THROTTLED_RETRIES => {
+                let backoff = THROTTLED_BACKOFF * 2u32.pow(attempt);
+                attempt += 1;
+                warn!(
+                    "e621 asked us to slow down ({}); trying page {} again in {}",
+                    reason,
+                    page,
+                    humantime::format_duration(backoff)
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            Refusal::BadQuery => {
+                return Err(MonosodiumError::InvalidQuery(format!(
+                    "e621 won't run this search: {}",
+                    reason
+                )))
+            }
+            Refusal::NeedsLogin if client.is_logged_in() => {
+                return Err(MonosodiumError::InvalidOptions(format!(
+                    "e621 turned down the login: {}; check --username and --api-key",
+                    reason
+                )))
+            }
+            Refusal::NeedsLogin => {
+                return Err(MonosodiumError::InvalidOptions(format!(
+                    "e621 needs a login for these {}: {}; pass --username and --api-key",
+                    source.describe(),
+                    reason
+                )))
+            }
+            _ => {
+                return Err(MonosodiumError::Refused {
+                    status: status.as_u16(),
+                    reason,
+                })
+            }
+        }
+    }
+}
+
+// What e621 sends in place of a page when it turns the request down, whatever
+// the status; older endpoints call the reason the message.
+#[derive(Deserialize)]
+struct ErrorResponse {
+    success: bool,
+    reason: Option<String>,
+    message: Option<String>,
+}
+
+// Why e621 turned a request down, if the body says it did.
+fn refusal(body: &str) -> Option<String> {
+    let error: ErrorResponse = serde_json::from_str(body).ok()?;
+    if error.success {
+        return None;
+    }
+    Some(
+        error
+            .reason
+            .or(error.message)
+            .unwrap_or_else(|| "no reason given".to_owned()),
+    )
+}
+
+// What can be done about a refusal.
+enum Refusal {
+    // Asking again later should work.
+    Throttled,
+    // The tags themselves are the problem, so asking again won't help.
+    BadQuery,
+    // Only some users may see this.
+    NeedsLogin,
+    Other,
+}
+
+// What e621 says when it won't run a search as asked, whatever the status.
+// Other refusals mention tags and searches too, so these are as e621 words
+// them.
+const BAD_QUERY_REASONS: &[&str] = &[
+    "you cannot search for more than",
+    "you cannot go beyond page",
+    "timed out running your query",
+    "invalid tag",
+];
+
+fn classify(status: StatusCode, reason: &str) -> Refusal {
+    let reason = reason.to_lowercase();
+    let says = |phrases: &[&str]| phrases.iter().any(|phrase| reason.contains(phrase));
+    if status == StatusCode::TOO_MANY_REQUESTS || says(&["rate limit", "throttle", "too many"]) {
+        Refusal::Throttled
+    } else if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+        || says(&[
+            "log in",
+            "login",
+            "logged in",
+            "api key",
+            "access denied",
+            "privilege",
+        ])
+    {
+        Refusal::NeedsLogin
+    } else if status == StatusCode::UNPROCESSABLE_ENTITY || says(BAD_QUERY_REASONS) {
+        Refusal::BadQuery
+    } else {
+        Refusal::Other
+    }
+}
+
+// Where pages can come from besides the API.
+struct Elsewhere {
+    cache: Option<Arc<PageCache>>,
+    fallback: Option<Feed>,
+}
+
+// Walks the pages ahead of the downloader, so that the next page is already on
+// hand when the current one finishes. The channel's capacity bounds how far
+// ahead we get, and `paging` how far we go.
+async fn prefetch_pages(
+    client: Client,
+    source: Source,
+    first_page: usize,
+    paging: Paging,
+    elsewhere: Elsewhere,
+    shutdown: Shutdown,
+    pages: mpsc::Sender<Result<Page, MonosodiumError>>,
+) {
+    let Elsewhere { cache, fallback } = elsewhere;
+    let Paging {
+        per_page,
+        max_pages,
+        since,
+        favorited_since,
+        drift,
+        ..
+    } = paging;
+    let last_page = max_pages.map_or(usize::MAX, |max| first_page.saturating_add(max) - 1);
+    // Once the API has failed enough times in a row, the rest of the pages
+    // come from the feed.
+    let mut feed = None;
+    let mut failures = 0;
+    // The last page sent, and the ids of its posts, for --page-drift.
+    let mut previous: Option<(usize, Vec<u64>)> = None;
+    for page in first_page..=last_page {
+        if shutdown.reason().is_some() {
+            break;
+        }
+
+        // The feed doesn't have numbered pages to look at again.
+        let moved = match (&previous, feed) {
+            (Some((number, ids)), None) if drift != PageDrift::Ignore => {
+                moved_onto(&client, &source, *number, ids, per_page, drift).await
+            }
+            _ => Vec::new(),
+        };
+
+        info!("Checking {} page {:2}", source.describe(), page);
+
+        let response = loop {
+            if let Some(feed) = feed {
+                break Feed::fetch_page(feed, &client, page).await;
+            }
+            let response = fetch_page(&client, &source, page, per_page, cache.as_deref()).await;
+            match (&response, &fallback) {
+                (Err(e), Some(fallback)) if is_unreachable(e) => {
+                    failures += 1;
+                    if failures < FALLBACK_AFTER {
+                        warn!(
+                            "Could not fetch page {} ({}); trying again in {}",
+                            page,
+                            e,
+                            humantime::format_duration(FALLBACK_RETRY_DELAY)
+                        );
+                        tokio::time::sleep(FALLBACK_RETRY_DELAY).await;
+                    } else {
+                        warn!(
+                            "The API failed {} times in a row ({}); reading the rest of the {} from --fallback-feed",
+                            failures,
+                            e,
+                            source.describe()
+                        );
+                        feed = Some(fallback);
+                    }
+                }
+                _ => {
+                    failures = 0;
+                    break response;
+                }
+            }
+        };
+        let last = match &response {
+            Ok(response) => response.posts.is_empty(),
+            Err(_) => false,
+        };
+        if last && moved.is_empty() {
+            if let (1, Source::Favorites(user_id)) = (page, &source) {
+                if let Some(e) = check_user(&client, *user_id).await {
+                    let _ = pages.send(Err(e)).await;
+                }
+            }
+            break;
+        }
+        // Posts that moved back go with the next page, or if there isn't one,
+        // as the page they moved onto, again.
+        let (page, response) = match response {
+            Ok(mut response) if !moved.is_empty() => {
+                response.posts.splice(0..0, moved);
+                let page = match (last, &previous) {
+                    (true, Some((number, _))) => *number,
+                    _ => page,
+                };
+                (page, Ok(response))
+            }
+            response => (page, response),
+        };
+
+        let failed = response.is_err();
+        let reached_since = match (&response, since) {
+            (Ok(response), Some(since)) => is_past(&source, &response.posts, since),
+            _ => false,
+        };
+        let reached_favorited_since = match (&response, &favorited_since) {
+            (Ok(response), Some(newest)) => {
+                response.posts.iter().any(|post| newest.contains(&post.id))
+            }
+            _ => false,
+        };
+        if let Ok(response) = &response {
+            previous = Some((page, response.posts.iter().map(|post| post.id).collect()));
+        }
+        let response = response.map(|response| Page {
+            number: page,
+            response,
+        });
+        // If the downloader has hung up, nobody wants the rest.
+        if pages.send(response).await.is_err() || failed || last {
+            break;
+        }
+        if reached_since {
+            info!("Reached posts from before --since, so that's the last page");
+            break;
+        }
+        if reached_favorited_since {
+            info!("Reached the favorites that were newest when the last run started, so that's the last page");
+            break;
+        }
+    }
+}
+
+// Whether a page of `posts` goes back before `since`, so no later page has
+// anything newer. Out of upload order, a newer post may come after any number
+// of older ones, so there's no telling, and every page is read.
+fn is_past(source: &Source, posts: &[Post], since: SystemTime) -> bool {
+    source.is_newest_first() && posts.iter().any(|post| post.is_older_than(since))
+}
+
+// Fetches page `number` again, straight from the API, and picks out the posts
+// moved back onto it from the next page, because posts before them were
+// removed, which would otherwise be skipped.
+async fn moved_onto(
+    client: &Client,
+    source: &Source,
+    number: usize,
+    ids: &[u64],
+    per_page: u32,
+    drift: PageDrift,
+) -> Vec<Post> {
+    let response = match fetch_page(client, source, number, per_page, None).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!(
+                "Could not check page {} again for moved posts: {}",
+                number, e
+            );
+            return Vec::new();
+        }
+    };
+    let moved = moved_back(response.posts, ids);
+    if moved.is_empty() {
+        return moved;
+    }
+    let ids: Vec<String> = moved.iter().map(|post| post.id.to_string()).collect();
+    match drift {
+        PageDrift::Rescan => {
+            info!(
+                "The {} changed during the run, moving posts {} back onto page {}; archiving them too",
+                source.describe(),
+                ids.join(", "),
+                number
+            );
+            moved
+        }
+        _ => {
+            warn!(
+                "The {} changed during the run, moving posts {} back onto page {}, so they're skipped; run again to pick them up",
+                source.describe(),
+                ids.join(", "),
+                number
+            );
+            Vec::new()
+        }
+    }
+}
+
+// The posts after the last one still there from `ids`, the page as it was.
+// New posts before it were added since, and only pushed the rest forward.
+// With none of them left, any of the posts could have moved back.
+fn moved_back(mut posts: Vec<Post>, ids: &[u64]) -> Vec<Post> {
+    let anchor = posts.iter().rposition(|post| ids.contains(&post.id));
+    let moved = posts.split_off(anchor.map_or(0, |anchor| anchor + 1));
+    moved
+        .into_iter()
+        .filter(|post| !ids.contains(&post.id))
+        .collect()
+}
+
+// Tells a user with no favorites from a user who isn't there, most likely
+// because --user-id has a typo, which is an error rather than nothing to do.
+async fn check_user(client: &Client, user_id: u32) -> Option<MonosodiumError> {
+    match users::exists(client, user_id).await {
+        Ok(true) => {
+            info!("User {} hasn't favorited anything", user_id);
+            None
+        }
+        Ok(false) => Some(no_such_user(user_id)),
+        Err(e) => {
+            warn!("Could not check that user {} exists: {}", user_id, e);
+            None
+        }
+    }
+}
+
+fn no_such_user(user_id: u32) -> MonosodiumError {
+    MonosodiumError::InvalidOptions(format!(
+        "there's no e621 user with the id {}; check --user-id",
+        user_id
+    ))
+}
+
+// Whether the API seems to be out of reach, rather than turning down the
+// request.
+fn is_unreachable(e: &MonosodiumError) -> bool {
+    match e {
+        MonosodiumError::Http(e) => e.status().is_none_or(is_outage),
+        _ => false,
+    }
+}
+
+/// The pages of posts to work through, either still arriving from the server
+/// or already read into memory.
+pub enum Pages {
+    Live(mpsc::Receiver<Result<Page, MonosodiumError>>),
+    Buffered(std::vec::IntoIter<Page>),
+    // Pages already read, then the rest as they come.
+    ReadAhead(
+        std::vec::IntoIter<Page>,
+        mpsc::Receiver<Result<Page, MonosodiumError>>,
+    ),
+}
+
+impl Pages {
+    /// Starts fetching pages from `source` in the background, beginning with
+    /// `first_page`, and stopping where `paging` says.
+    pub fn fetch(
+        client: &Client,
+        source: Source,
+        first_page: usize,
+        paging: Paging,
+        cache: Option<Arc<PageCache>>,
+        fallback: Option<Feed>,
+        shutdown: &Shutdown,
+    ) -> Pages {
+        let (sender, pages) = mpsc::channel(paging.ahead.max(1) as usize);
+        tokio::spawn(prefetch_pages(
+            client.clone(),
+            source,
+            first_page,
+            paging,
+            Elsewhere { cache, fallback },
+            shutdown.clone(),
+            sender,
+        ));
+        Pages::Live(pages)
+    }
+
+    pub async fn next(&mut self) -> Option<Result<Page, MonosodiumError>> {
+        match self {
+            Pages::Live(pages) => pages.recv().await,
+            Pages::Buffered(pages) => pages.next().map(Ok),
+            Pages::ReadAhead(read, rest) => match read.next() {
+                Some(page) => Some(Ok(page)),
+                None => rest.recv().await,
+            },
+        }
+    }
+
+    /// Puts `read`, taken from these pages, back in front of them.
+    pub fn unread(self, read: Vec<Page>) -> Pages {
+        match self {
+            Pages::Live(rest) => Pages::ReadAhead(read.into_iter(), rest),
+            Pages::Buffered(rest) => {
+                Pages::Buffered(read.into_iter().chain(rest).collect::<Vec<_>>().into_iter())
+            }
+            Pages::ReadAhead(more, rest) => {
+                let read: Vec<Page> = read.into_iter().chain(more).collect();
+                Pages::ReadAhead(read.into_iter(), rest)
+            }
+        }
+    }
+
+    /// Reads all of the remaining pages into memory.
+    pub async fn buffer(mut self) -> Result<Vec<Page>, MonosodiumError> {
+        let mut pages = Vec::new();
+        while let Some(page) = self.next().await {
+            pages.push(page?);
+        }
+        Ok(pages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn knows_which_sources_are_newest_first() {
+        assert!(Source::Search("fox".to_owned()).is_newest_first());
+        assert!(Source::Search("fox order:id_desc".to_owned()).is_newest_first());
+        assert!(!Source::Search("fox order:score".to_owned()).is_newest_first());
+        assert!(!Source::Favorites(1).is_newest_first());
+        assert!(!Source::MyFavorites("someone".to_owned()).is_newest_first());
+    }
+
+    fn page(ids: &[u64]) -> Vec<Post> {
+        ids.iter()
+            .map(|&id| {
+                let mut post = crate::testutil::post(b"a picture");
+                post.id = id;
+                post
+            })
+            .collect()
+    }
+
+    fn ids(posts: &[Post]) -> Vec<u64> {
+        posts.iter().map(|post| post.id).collect()
+    }
+
+    #[test]
+    fn reads_every_page_of_favorites_for_since() {
+        let since = humantime::parse_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        let old = page(&[3, 2]);
+        let mut newer = page(&[1]);
+        newer[0].created_at = "2024-06-01T00:00:00.000-05:00".to_owned();
+        let favorites = Source::Favorites(1);
+        assert!(!is_past(&favorites, &old, since));
+        assert!(!is_past(&favorites, &newer, since));
+        // Newest first, the first old post is the end.
+        let search = Source::Search("fox".to_owned());
+        assert!(is_past(&search, &old, since));
+        assert!(!is_past(&search, &newer, since));
+    }
+
+    fn numbered(number: usize) -> Page {
+        Page {
+            number,
+            response: ApiResponse {
+                posts: page(&[number as u64]),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn puts_pages_read_back_in_front() {
+        let (sender, receiver) = mpsc::channel(4);
+        sender.send(Ok(numbered(3))).await.unwrap();
+        drop(sender);
+        let mut pages = Pages::Live(receiver).unread(vec![numbered(1), numbered(2)]);
+        let mut numbers = Vec::new();
+        while let Some(page) = pages.next().await {
+            numbers.push(page.unwrap().number);
+        }
+        assert_eq!(numbers, [1, 2, 3]);
+    }
+
+    #[test]
+    fn finds_posts_moved_back() {
+        // 8 was removed, so 5 moved back from the next page.
+        assert_eq!(ids(&moved_back(page(&[9, 7, 6, 5]), &[9, 8, 7, 6])), [5]);
+    }
+
+    #[test]
+    fn ignores_posts_added_before() {
+        // 10 was added, pushing 6 onto the next page.
+        assert!(moved_back(page(&[10, 9, 8, 7]), &[9, 8, 7, 6]).is_empty());
+        // Added and removed both.
+        assert_eq!(
+            ids(&moved_back(page(&[10, 9, 7, 6, 5]), &[9, 8, 7, 6])),
+            [5]
+        );
+    }
+
+    #[test]
+    fn keeps_pages_apart_by_login() {
+        let scratch = crate::testutil::Scratch::new("page-cache");
+        let dir = scratch.path().to_owned();
+        let hour = Duration::from_secs(3600);
+        let anonymous = PageCache::new(dir.clone(), hour, false, None).unwrap();
+        let someone = PageCache::new(dir, hour, false, Some("someone".to_owned())).unwrap();
+        anonymous.put("https://e621.net/posts.json?page=1", "{}");
+        assert_eq!(
+            anonymous
+                .get("https://e621.net/posts.json?page=1")
+                .as_deref(),
+            Some("{}")
+        );
+        assert_eq!(someone.get("https://e621.net/posts.json?page=1"), None);
+    }
+
+    fn classified(status: u16, body: &str) -> Refusal {
+        let reason = refusal(body).unwrap();
+        classify(StatusCode::from_u16(status).unwrap(), &reason)
+    }
+
+    #[test]
+    fn knows_a_search_e621_wont_run() {
+        let too_many =
+            r#"{"success":false,"reason":"You cannot search for more than 40 tags at a time"}"#;
+        assert!(matches!(classified(422, too_many), Refusal::BadQuery));
+        assert!(matches!(classified(400, too_many), Refusal::BadQuery));
+        let too_deep = r#"{"success":false,"message":"You cannot go beyond page 750. Try narrowing your search terms, or upgrade your account to go beyond page 750"}"#;
+        assert!(matches!(classified(410, too_deep), Refusal::BadQuery));
+    }
+
+    #[test]
+    fn doesnt_blame_the_search_for_anything_mentioning_tags() {
+        let down = r#"{"success":false,"reason":"Tag search is temporarily unavailable"}"#;
+        assert!(matches!(classified(503, down), Refusal::Other));
+        let locked = r#"{"success":false,"reason":"Access denied: tag changes are locked"}"#;
+        assert!(matches!(classified(403, locked), Refusal::NeedsLogin));
+        let busy = r#"{"success":false,"reason":"Rate limit exceeded for tag searches"}"#;
+        assert!(matches!(classified(500, busy), Refusal::Throttled));
+    }
+}
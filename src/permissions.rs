@@ -0,0 +1,76 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::io;
+use std::path::Path;
+
+/// Permission bits for files and directories written into the archive, from
+/// --file-mode and --dir-mode. Left to the umask when not given, and ignored
+/// where there's no such thing, like on Windows.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Modes {
+    pub file: Option<u32>,
+    pub dir: Option<u32>,
+}
+
+impl Modes {
+    /// Creates `dir` and any parents that are missing, giving each one
+    /// created the directory mode.
+    pub fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let missing: Vec<&Path> = dir.ancestors().take_while(|dir| !dir.exists()).collect();
+        std::fs::create_dir_all(dir)?;
+        if let Some(mode) = self.dir {
+            for dir in missing.iter().filter(|dir| !dir.as_os_str().is_empty()) {
+                set_mode(dir, mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives a newly written file the file mode.
+    pub fn apply_to_file(&self, path: &Path) -> io::Result<()> {
+        match self.file {
+            Some(mode) => set_mode(path, mode),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Parses an octal mode like `644` or `0o2775`.
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{:?} isn't an octal mode like 644 or 2775", s)),
+    }
+}
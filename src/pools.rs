@@ -0,0 +1,125 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::client::Client;
+use crate::error::MonosodiumError;
+use crate::Post;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+// How many pools to ask for at once; e621 takes a list of ids in one search.
+const POOLS_PER_REQUEST: usize = 100;
+
+#[derive(Deserialize)]
+struct Pool {
+    id: u64,
+    name: String,
+    post_ids: Vec<u64>,
+}
+
+// A search with no results comes back as an object rather than a list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Found {
+    Pools(Vec<Pool>),
+    None {},
+}
+
+/// Where a post comes in the pool it's filed under, for --layout by-pool.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PoolPlace {
+    pub id: u64,
+    pub name: String,
+    /// Counting from 1, in the pool's reading order.
+    pub position: usize,
+    /// How many posts the pool has.
+    pub length: usize,
+}
+
+/// The pools seen so far in a run, looked up as posts in them come along.
+#[derive(Default)]
+pub struct Pools {
+    known: Mutex<HashMap<u64, Pool>>,
+    // Listed by a post, but e621 didn't return them, most likely because
+    // they've been deleted; they aren't asked for again.
+    missing: Mutex<BTreeSet<u64>>,
+}
+
+impl Pools {
+    /// Looks up any pools that `posts` are in and that haven't been seen yet.
+    pub async fn fetch(&self, client: &Client, posts: &[Post]) -> Result<(), MonosodiumError> {
+        let wanted: Vec<u64> = {
+            let known = self.known.lock().unwrap();
+            let missing = self.missing.lock().unwrap();
+            posts
+                .iter()
+                .flat_map(|post| &post.pools)
+                .filter(|id| !known.contains_key(id) && !missing.contains(id))
+                .copied()
+                .collect::<BTreeSet<u64>>()
+                .into_iter()
+                .collect()
+        };
+        for ids in wanted.chunks(POOLS_PER_REQUEST) {
+            info!("Looking up {} pools", ids.len());
+            let list = ids.iter().map(u64::to_string).collect::<Vec<_>>().join(",");
+            let url = format!(
+                "https://e621.net/pools.json?search[id]={}&limit={}",
+                list,
+                ids.len()
+            );
+            let found = match client.get(&url).await?.error_for_status()?.json().await? {
+                Found::Pools(pools) => pools,
+                Found::None {} => Vec::new(),
+            };
+            let mut known = self.known.lock().unwrap();
+            for pool in found {
+                known.insert(pool.id, pool);
+            }
+            let mut missing = self.missing.lock().unwrap();
+            missing.extend(ids.iter().filter(|id| !known.contains_key(id)));
+        }
+        Ok(())
+    }
+
+    /// Where `post` is filed: in the pool with the lowest id of those it's in,
+    /// which is the one made first, and so usually the main series rather
+    /// than a collection it was added to later. None if it isn't in a pool
+    /// that's been looked up.
+    pub fn place(&self, post: &Post) -> Option<PoolPlace> {
+        let known = self.known.lock().unwrap();
+        let mut ids: Vec<u64> = post.pools.clone();
+        ids.sort_unstable();
+        ids.into_iter().find_map(|id| {
+            let pool = known.get(&id)?;
+            let index = pool.post_ids.iter().position(|&p| p == post.id)?;
+            Some(PoolPlace {
+                id,
+                name: pool.name.clone(),
+                position: index + 1,
+                length: pool.post_ids.len(),
+            })
+        })
+    }
+}
@@ -0,0 +1,174 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use crate::units::format_size;
+use reqwest::Url;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What a run spends its time on, as far as --profile tells them apart.
+#[derive(Clone, Copy)]
+pub enum Phase {
+    Pages,
+    Downloads,
+    Writes,
+    RateLimit,
+}
+
+const PHASES: [(Phase, &str); 4] = [
+    (Phase::Pages, "Fetching pages"),
+    (Phase::Downloads, "Downloading files"),
+    (Phase::Writes, "Writing files and metadata"),
+    (Phase::RateLimit, "Waiting on the rate limit"),
+];
+
+/// Time spent in each phase, added to from any task. When it's off, timing
+/// something costs one branch.
+#[derive(Default)]
+pub struct Profile {
+    enabled: bool,
+    nanos: [AtomicU64; 4],
+    counts: [AtomicU64; 4],
+    hosts: Mutex<BTreeMap<String, Host>>,
+}
+
+// The files downloaded from one host.
+#[derive(Default)]
+struct Host {
+    files: u64,
+    bytes: u64,
+    time: Duration,
+    slowest: Option<(Duration, String)>,
+}
+
+impl Profile {
+    pub fn new(enabled: bool) -> Profile {
+        Profile {
+            enabled,
+            ..Profile::default()
+        }
+    }
+
+    /// Runs `work`, adding how long it took to `phase`.
+    pub async fn time<T>(&self, phase: Phase, work: impl Future<Output = T>) -> T {
+        if !self.enabled {
+            return work.await;
+        }
+        let started = Instant::now();
+        let output = work.await;
+        let nanos = started.elapsed().as_nanos() as u64;
+        self.nanos[phase as usize].fetch_add(nanos, Ordering::Relaxed);
+        self.counts[phase as usize].fetch_add(1, Ordering::Relaxed);
+        output
+    }
+
+    /// Adds a file of `bytes` from `url`, which took `elapsed` to download,
+    /// to the breakdown by host.
+    pub fn record_download(&self, url: &str, bytes: u64, elapsed: Duration) {
+        if !self.enabled {
+            return;
+        }
+        let Some(host) = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_owned))
+        else {
+            return;
+        };
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = hosts.entry(host).or_default();
+        host.files += 1;
+        host.bytes += bytes;
+        host.time += elapsed;
+        if host
+            .slowest
+            .as_ref()
+            .is_none_or(|(slowest, _)| elapsed > *slowest)
+        {
+            host.slowest = Some((elapsed, url.to_owned()));
+        }
+    }
+
+    fn spent(&self, phase: Phase) -> Duration {
+        Duration::from_nanos(self.nanos[phase as usize].load(Ordering::Relaxed))
+    }
+
+    /// The breakdown printed at the end of a run that took `elapsed`.
+    pub fn report(&self, elapsed: Duration) -> String {
+        let mut report = format!("Time spent, of {} in all:\n", format_time(elapsed));
+        for (phase, label) in PHASES {
+            let spent = self.spent(phase);
+            let share = 100.0 * spent.as_secs_f64() / elapsed.as_secs_f64().max(f64::EPSILON);
+            let count = self.counts[phase as usize].load(Ordering::Relaxed);
+            let _ = writeln!(
+                report,
+                "  {:<28}{:>10}  {:>5.1}%  ({} times)",
+                format!("{}:", label),
+                format_time(spent),
+                share,
+                count
+            );
+        }
+        report.push_str("Fetching pages includes its waits on the rate limit.\n");
+
+        let hosts = self.hosts.lock().unwrap();
+        if !hosts.is_empty() {
+            report.push_str("Downloads by host, not counting waits:\n");
+        }
+        for (name, host) in hosts.iter() {
+            let rate = host.bytes as f64 / host.time.as_secs_f64().max(f64::EPSILON);
+            let _ = writeln!(
+                report,
+                "  {}: {} files, {}, at {}/s",
+                name,
+                host.files,
+                format_size(host.bytes),
+                format_size(rate as u64)
+            );
+            if let Some((time, url)) = &host.slowest {
+                let _ = writeln!(report, "    slowest: {} in {}", url, format_time(*time));
+            }
+        }
+
+        // Requests are spaced out one --api-delay apart, so a run that's
+        // mostly waiting can only go faster with a shorter delay.
+        let waiting = self.spent(Phase::RateLimit);
+        if waiting * 2 > elapsed {
+            report.push_str(
+                "Most of the run was spent waiting between requests, which --api-delay sets.\n",
+            );
+        } else if self.spent(Phase::Writes) * 2 > elapsed {
+            report.push_str("Most of the run was spent writing, so the disk is what's slow.\n");
+        }
+        report
+    }
+}
+
+fn format_time(time: Duration) -> String {
+    if time < Duration::from_secs(60) {
+        format!("{:.1}s", time.as_secs_f64())
+    } else {
+        humantime::format_duration(Duration::from_secs(time.as_secs())).to_string()
+    }
+}
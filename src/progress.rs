@@ -0,0 +1,66 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::color::{self, Style};
+use std::io::{stderr, IsTerminal, Write};
+
+const WIDTH: usize = 40;
+
+/// A progress bar on stderr, drawn only when stderr is a terminal so that
+/// captured logs stay clean.
+pub struct Progress {
+    total: usize,
+    done: usize,
+    enabled: bool,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Progress {
+        Progress {
+            total,
+            done: 0,
+            enabled: stderr().is_terminal(),
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.done += 1;
+        if !self.enabled {
+            return;
+        }
+        let filled = WIDTH * self.done / self.total.max(1);
+        let _ = write!(
+            stderr(),
+            "\r[{}{}] {}/{}",
+            color::err(Style::Good, "#".repeat(filled)),
+            " ".repeat(WIDTH - filled),
+            self.done,
+            self.total
+        );
+    }
+
+    pub fn finish(&self) {
+        if self.enabled {
+            let _ = writeln!(stderr());
+        }
+    }
+}
@@ -0,0 +1,232 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::client::Client;
+use crate::error::MonosodiumError;
+use crate::pages::{fetch_page, Source, MAX_PER_PAGE};
+use log::info;
+use reqwest::Url;
+use serde::Deserialize;
+use std::fmt;
+
+// e621 won't page past this by page number.
+const MAX_PAGE: usize = 750;
+
+// How many standard errors either side of the estimate the bounds are, for
+// 95% confidence.
+const Z_95: f64 = 1.96;
+
+/// How many posts a source has, as far as could be found out quickly.
+#[derive(Clone, Copy, Debug)]
+pub enum Count {
+    /// What e621 says, for a user's favorites or a single tag.
+    Reported(u64),
+    /// Found by looking for the last page.
+    Counted(u64),
+    /// More pages than e621 will show; there are at least this many.
+    AtLeast(u64),
+}
+
+impl Count {
+    pub fn posts(self) -> u64 {
+        match self {
+            Count::Reported(n) | Count::Counted(n) | Count::AtLeast(n) => n,
+        }
+    }
+}
+
+impl fmt::Display for Count {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Count::Reported(n) => write!(f, "{} (as e621 counts them)", n),
+            Count::Counted(n) => write!(f, "{} (counted by page)", n),
+            Count::AtLeast(n) => write!(f, "at least {} (more than e621 will page through)", n),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct User {
+    favorite_count: u64,
+}
+
+#[derive(Deserialize)]
+struct Tag {
+    name: String,
+    post_count: u64,
+}
+
+// A search with no results comes back as an object rather than a list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Tags {
+    Found(Vec<Tag>),
+    None {},
+}
+
+/// Counts the posts in `source`, given how many are on its first page of
+/// [`MAX_PER_PAGE`]. e621's own count is used where it has one; otherwise the
+/// last page is found by bisection, which takes a handful of requests.
+pub async fn count(
+    client: &Client,
+    source: &Source,
+    first_page: usize,
+) -> Result<Count, MonosodiumError> {
+    let per_page = MAX_PER_PAGE as usize;
+    if first_page < per_page {
+        return Ok(Count::Counted(first_page as u64));
+    }
+    match source {
+        Source::Favorites(user_id) => {
+            let url = format!("https://e621.net/users/{}.json", user_id);
+            let user = client
+                .get(&url)
+                .await?
+                .error_for_status()?
+                .json::<User>()
+                .await?;
+            return Ok(Count::Reported(user.favorite_count));
+        }
+        Source::Search(tags) if is_plain_tag(tags) => {
+            let url = Url::parse_with_params(
+                "https://e621.net/tags.json",
+                &[("search[name]", tags.as_str())],
+            )
+            .expect("the tags URL is always valid");
+            let found = client
+                .get(url.as_str())
+                .await?
+                .error_for_status()?
+                .json::<Tags>()
+                .await?;
+            if let Tags::Found(found) = found {
+                if let Some(tag) = found.into_iter().find(|tag| &tag.name == tags) {
+                    return Ok(Count::Reported(tag.post_count));
+                }
+            }
+        }
+        _ => {}
+    }
+
+    // The first page is full, and `empty`, once found, is past the end.
+    let mut full = 1;
+    let mut empty = None;
+    while empty.is_none() {
+        let page = (full * 2).min(MAX_PAGE);
+        if page == full {
+            return Ok(Count::AtLeast((MAX_PAGE * per_page) as u64));
+        }
+        match page_len(client, source, page).await? {
+            n if n == per_page => full = page,
+            0 => empty = Some(page),
+            n => return Ok(Count::Counted(((page - 1) * per_page + n) as u64)),
+        }
+    }
+    let mut empty = empty.unwrap();
+    while empty - full > 1 {
+        let page = (full + empty) / 2;
+        match page_len(client, source, page).await? {
+            n if n == per_page => full = page,
+            0 => empty = page,
+            n => return Ok(Count::Counted(((page - 1) * per_page + n) as u64)),
+        }
+    }
+    Ok(Count::Counted((full * per_page) as u64))
+}
+
+async fn page_len(client: &Client, source: &Source, page: usize) -> Result<usize, MonosodiumError> {
+    info!("Counting {}: page {}", source.describe(), page);
+    let response = fetch_page(client, source, page, MAX_PER_PAGE, None).await?;
+    Ok(response.posts.len())
+}
+
+// A lone tag that e621 keeps a count for: not a metatag, wildcard, negation
+// or `~` tag.
+fn is_plain_tag(tags: &str) -> bool {
+    !tags.is_empty()
+        && !tags.contains(char::is_whitespace)
+        && !tags.contains([':', '*'])
+        && !tags.starts_with(['-', '~'])
+}
+
+/// An estimate, between two bounds.
+#[derive(Clone, Copy, Debug)]
+pub struct Range {
+    pub low: u64,
+    pub estimate: u64,
+    pub high: u64,
+}
+
+/// How much a whole run would download, scaled up from a sample of its posts.
+pub struct Projection {
+    pub files: Range,
+    pub bytes: Range,
+}
+
+/// Scales `sample` up to `total` posts. Each entry is the size of the file a
+/// sampled post would download, or `None` if it wouldn't download one. The
+/// bounds are 95% confidence intervals, as if the sample had been drawn at
+/// random, and narrow to nothing as the sample approaches the total.
+pub fn project(sample: &[Option<u64>], total: u64) -> Projection {
+    let downloads: Vec<f64> = sample
+        .iter()
+        .map(|size| if size.is_some() { 1.0 } else { 0.0 })
+        .collect();
+    let bytes: Vec<f64> = sample.iter().map(|size| size.unwrap_or(0) as f64).collect();
+    let mut files = scale(&downloads, total);
+    files.high = files.high.min(total.max(sample.len() as u64));
+    Projection {
+        files,
+        bytes: scale(&bytes, total),
+    }
+}
+
+fn scale(values: &[f64], total: u64) -> Range {
+    let n = values.len() as f64;
+    let total = (total as f64).max(n);
+    if values.is_empty() {
+        return Range {
+            low: 0,
+            estimate: 0,
+            high: 0,
+        };
+    }
+    let mean = values.iter().sum::<f64>() / n;
+    let variance = if n > 1.0 {
+        values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0)
+    } else {
+        0.0
+    };
+    // Less uncertain the more of the posts were sampled.
+    let correction = if total > 1.0 {
+        ((total - n) / (total - 1.0)).max(0.0)
+    } else {
+        0.0
+    };
+    let margin = Z_95 * (variance / n * correction).sqrt();
+    let round = |x: f64| (x * total).max(0.0).round() as u64;
+    Range {
+        low: round(mean - margin),
+        estimate: round(mean),
+        high: round(mean + margin),
+    }
+}
@@ -0,0 +1,160 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::doctor::{Finding, Problem};
+use crate::{move_file, Post};
+use serde::Serialize;
+use std::fs::{read_dir, remove_file, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Why a file was quarantined, saved next to it as `<file>.json`.
+#[derive(Serialize)]
+struct Record<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_id: Option<u64>,
+    original_path: &'a Path,
+    expected_md5: &'a str,
+    actual_md5: &'a str,
+    /// What the file looks like from its first few bytes, which is often
+    /// telling: an HTML error page saved in place of an image, say.
+    content_type: &'static str,
+    expected_content_type: &'static str,
+    size: u64,
+    quarantined_at: String,
+}
+
+/// Moves a file that failed its MD5 check into `dir`, rather than letting it
+/// be overwritten, with a note of what was wrong. Files that are missing or
+/// unreadable have nothing to keep, so are left be; `None` means nothing was
+/// moved.
+pub fn quarantine(dir: &Path, post: &Post, finding: &Finding) -> std::io::Result<Option<PathBuf>> {
+    let Problem::Mismatch { actual } = &finding.problem else {
+        return Ok(None);
+    };
+    let expected = Expected {
+        post_id: Some(post.id),
+        md5: &post.file.md5,
+        ext: &post.file.ext,
+    };
+    quarantine_file(dir, &finding.path, expected, actual).map(Some)
+}
+
+/// What a quarantined file should have been.
+pub struct Expected<'a> {
+    /// The post it belongs to, if that's known.
+    pub post_id: Option<u64>,
+    pub md5: &'a str,
+    pub ext: &'a str,
+}
+
+/// Moves the file at `path`, which hashes to `actual_md5` but should have been
+/// `expected`, into `dir`, returning where it went.
+pub fn quarantine_file(
+    dir: &Path,
+    path: &Path,
+    expected: Expected,
+    actual_md5: &str,
+) -> std::io::Result<PathBuf> {
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let destination = match expected.post_id {
+        Some(id) => dir.join(format!("{}-{}-{}", id, stamp, name)),
+        None => dir.join(format!("{}-{}", stamp, name)),
+    };
+
+    let record = Record {
+        post_id: expected.post_id,
+        original_path: path,
+        expected_md5: expected.md5,
+        actual_md5,
+        content_type: sniff(path),
+        expected_content_type: content_type(expected.ext),
+        size: path.metadata()?.len(),
+        quarantined_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+    };
+    move_file(path, &destination)?;
+    let note = File::create(format!("{}.json", destination.display()))?;
+    serde_json::to_writer_pretty(note, &record)?;
+    Ok(destination)
+}
+
+/// Empties the quarantine, returning how many files were in it.
+pub fn clear(dir: &Path) -> std::io::Result<usize> {
+    let mut cleared = 0;
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            if path.extension().is_none_or(|ext| ext != "json") {
+                cleared += 1;
+            }
+            remove_file(path)?;
+        }
+    }
+    Ok(cleared)
+}
+
+fn content_type(ext: &str) -> &'static str {
+    match ext {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webm" => "video/webm",
+        "mp4" => "video/mp4",
+        "swf" => "application/x-shockwave-flash",
+        _ => "application/octet-stream",
+    }
+}
+
+fn sniff(path: &Path) -> &'static str {
+    let mut head = [0u8; 16];
+    let read = File::open(path).and_then(|mut file| file.read(&mut head));
+    let head = match read {
+        Ok(n) => &head[..n],
+        Err(_) => return "unknown",
+    };
+    if head.is_empty() {
+        "empty"
+    } else if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        "image/jpeg"
+    } else if head.starts_with(b"\x89PNG") {
+        "image/png"
+    } else if head.starts_with(b"GIF8") {
+        "image/gif"
+    } else if head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        "video/webm"
+    } else if head.get(4..8) == Some(b"ftyp") {
+        "video/mp4"
+    } else if head.starts_with(b"FWS") || head.starts_with(b"CWS") {
+        "application/x-shockwave-flash"
+    } else if head.trim_ascii_start().starts_with(b"<") {
+        "text/html"
+    } else if head.trim_ascii_start().starts_with(b"{") {
+        "application/json"
+    } else {
+        "application/octet-stream"
+    }
+}
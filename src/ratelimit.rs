@@ -0,0 +1,130 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use log::debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio::time::{sleep_until, Instant};
+
+// A quota window longer than this is more likely a header misread than a real
+// one, and is cut down to it.
+const MAX_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Spaces out every request made by the process, no matter which task makes
+/// it. Waiters are served in order, one per interval. With jitter, each gap
+/// is moved earlier or later by a random amount up to the jitter, so requests
+/// don't tick like a clock; on average they are still one interval apart.
+/// When the server says how much of its quota is left, requests are spread
+/// out over what's left of it instead, if that's slower.
+pub struct RateLimiter {
+    interval: Duration,
+    jitter: Duration,
+    state: Mutex<State>,
+    // Kept apart from the state, whose lock is held by whoever is waiting
+    // their turn.
+    quota: std::sync::Mutex<Quota>,
+}
+
+struct State {
+    next: Instant,
+    rng: u64,
+}
+
+// The least gap the server's quota allows, until its window ends.
+#[derive(Clone, Copy)]
+struct Quota {
+    gap: Duration,
+    until: Instant,
+    exhausted: bool,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration, jitter: Duration) -> RateLimiter {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_nanos() as u64);
+        RateLimiter {
+            interval,
+            jitter: jitter.min(interval),
+            state: Mutex::new(State {
+                next: Instant::now(),
+                // Xorshift gets stuck on zero.
+                rng: seed | 1,
+            }),
+            quota: std::sync::Mutex::new(Quota {
+                gap: Duration::ZERO,
+                until: Instant::now(),
+                exhausted: false,
+            }),
+        }
+    }
+
+    pub async fn wait(&self) {
+        let mut state = self.state.lock().await;
+        let quota = *self.quota.lock().unwrap();
+        // With nothing left, nothing more until the window is over.
+        if quota.exhausted {
+            state.next = state.next.max(quota.until);
+        }
+        sleep_until(state.next).await;
+        let mut gap = self.next_gap(&mut state.rng);
+        if Instant::now() < quota.until {
+            gap = gap.max(quota.gap);
+        }
+        state.next = Instant::now() + gap;
+    }
+
+    /// Notes that the server allows `remaining` more requests in the next
+    /// `window`.
+    pub fn observe(&self, remaining: u64, window: Duration) {
+        let window = window.min(MAX_WINDOW);
+        let gap = match u32::try_from(remaining) {
+            Ok(0) => window,
+            Ok(remaining) => window / remaining,
+            Err(_) => Duration::ZERO,
+        };
+        if gap > self.interval {
+            debug!(
+                "{} requests left in the next {:?}, so waiting at least {:?} between them",
+                remaining, window, gap
+            );
+        }
+        *self.quota.lock().unwrap() = Quota {
+            gap,
+            until: Instant::now() + window,
+            exhausted: remaining == 0,
+        };
+    }
+
+    fn next_gap(&self, rng: &mut u64) -> Duration {
+        if self.jitter.is_zero() {
+            return self.interval;
+        }
+        *rng ^= *rng << 13;
+        *rng ^= *rng >> 7;
+        *rng ^= *rng << 17;
+        // Somewhere in interval - jitter ..= interval + jitter.
+        let span = self.jitter.as_micros() as u64 * 2;
+        let offset = Duration::from_micros(*rng % (span + 1));
+        self.interval - self.jitter + offset
+    }
+}
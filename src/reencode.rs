@@ -0,0 +1,150 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fs::{remove_file, rename};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// What videos are transcoded to, for --reencode-video.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoPreset {
+    /// H.264 and AAC in MP4, which plays almost anywhere
+    H264,
+    /// H.265 and AAC in MP4, about half the size of H.264 for the same quality
+    H265,
+    /// VP9 and Opus in WebM, smaller than H.264 and free of patents
+    Vp9,
+}
+
+impl VideoPreset {
+    fn ext(&self) -> &'static str {
+        match self {
+            VideoPreset::H264 | VideoPreset::H265 => "mp4",
+            VideoPreset::Vp9 => "webm",
+        }
+    }
+
+    fn codecs(&self) -> (&'static str, &'static str) {
+        match self {
+            VideoPreset::H264 => ("libx264", "aac"),
+            VideoPreset::H265 => ("libx265", "aac"),
+            VideoPreset::Vp9 => ("libvpx-vp9", "libopus"),
+        }
+    }
+
+    // The quality settings. Each CRF is around its encoder's usual choice
+    // for good quality; lower is better, and bigger.
+    fn args(&self) -> &'static [&'static str] {
+        match self {
+            VideoPreset::H264 => &[
+                "-preset",
+                "medium",
+                "-crf",
+                "23",
+                "-pix_fmt",
+                "yuv420p",
+                "-b:a",
+                "128k",
+                "-movflags",
+                "+faststart",
+            ],
+            VideoPreset::H265 => &[
+                "-preset",
+                "medium",
+                "-crf",
+                "28",
+                "-tag:v",
+                "hvc1",
+                "-b:a",
+                "128k",
+                "-movflags",
+                "+faststart",
+            ],
+            VideoPreset::Vp9 => &["-crf", "33", "-b:v", "0", "-b:a", "96k"],
+        }
+    }
+
+    /// What a video with this extension, of this many bytes on e621, is
+    /// saved as.
+    pub fn apply(&self, ext: &str, size: u64) -> Reencoded {
+        let (video_codec, audio_codec) = self.codecs();
+        Reencoded {
+            preset: *self,
+            ext: self.ext().to_owned(),
+            video_codec: video_codec.to_owned(),
+            audio_codec: audio_codec.to_owned(),
+            original_ext: ext.to_owned(),
+            original_size: size,
+            original_path: None,
+        }
+    }
+}
+
+/// How a video was transcoded, for --reencode-video, and what it was on e621.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Reencoded {
+    pub preset: VideoPreset,
+    pub ext: String,
+    pub video_codec: String,
+    pub audio_codec: String,
+    pub original_ext: String,
+    pub original_size: u64,
+    /// Where the file as downloaded was kept, for --keep-original-video.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_path: Option<PathBuf>,
+}
+
+/// Transcodes the video downloaded to `path` as `reencoded` says, in place,
+/// moving the download to its `original_path` if it has one.
+pub async fn reencode(path: &Path, reencoded: &Reencoded) -> std::io::Result<()> {
+    // ffmpeg picks the container from the extension, so it has to stay last.
+    let transcoded = path.with_extension(Path::new("reencoding").with_extension(&reencoded.ext));
+    let output = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-i"])
+        .arg(path)
+        .args([
+            "-c:v",
+            &reencoded.video_codec,
+            "-c:a",
+            &reencoded.audio_codec,
+        ])
+        .args(reencoded.preset.args())
+        .arg(&transcoded)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    if !output.status.success() {
+        let _ = remove_file(&transcoded);
+        return Err(std::io::Error::other(format!(
+            "ffmpeg failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    if let Some(original_path) = &reencoded.original_path {
+        rename(path, original_path)?;
+    }
+    rename(&transcoded, path)
+}
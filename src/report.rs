@@ -0,0 +1,186 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::summary::Summary;
+use crate::units::format_size;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+const TOP_ARTISTS: usize = 10;
+
+pub fn write_report(path: &Path, summary: &Summary) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(render(summary).as_bytes())
+}
+
+fn render(summary: &Summary) -> String {
+    // Writing to a String can't fail, so the results are ignored throughout.
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# Monosodium Run Report\n");
+    let _ = writeln!(out, "    {}\n", command_line());
+
+    let _ = writeln!(out, "## Totals\n");
+    let _ = writeln!(out, "| | |");
+    let _ = writeln!(out, "|---|---:|");
+    let _ = writeln!(out, "| Pages checked | {} |", summary.pages);
+    let _ = writeln!(out, "| Posts seen | {} |", summary.posts_seen);
+    let _ = writeln!(
+        out,
+        "| Excluded by filters | {} |",
+        summary.excluded_total()
+    );
+    let _ = writeln!(out, "| Already archived | {} |", summary.already_present);
+    let _ = writeln!(out, "| Downloaded | {} |", summary.downloaded);
+    if summary.deduplicated > 0 {
+        let _ = writeln!(out, "| Linked to a copy | {} |", summary.deduplicated);
+    }
+    if summary.linked_from_library > 0 {
+        let _ = writeln!(
+            out,
+            "| Linked from the library | {} |",
+            summary.linked_from_library
+        );
+    }
+    if summary.metadata_refreshed > 0 {
+        let _ = writeln!(
+            out,
+            "| Metadata refreshed | {} |",
+            summary.metadata_refreshed
+        );
+    }
+    let _ = writeln!(out, "| Failed | {} |", summary.failures.len());
+    let _ = writeln!(out, "| Downloaded size | {} |", format_size(summary.bytes));
+    let _ = writeln!(out, "| Elapsed | {} |", format_elapsed(summary.elapsed()));
+    if let Some(reason) = summary.stopped {
+        let _ = writeln!(out, "| Stopped early | {} |", reason);
+    }
+    let _ = writeln!(out);
+
+    if !summary.excluded.is_empty() {
+        let _ = writeln!(out, "## Exclusions\n");
+        let _ = writeln!(out, "| Reason | Posts |");
+        let _ = writeln!(out, "|---|---:|");
+        for (reason, count) in &summary.excluded {
+            let _ = writeln!(out, "| {} | {} |", reason, count);
+        }
+        let _ = writeln!(out);
+    }
+
+    if !summary.sample.is_empty() {
+        let _ = writeln!(out, "## Balanced Sample\n");
+        let _ = writeln!(out, "| Tag | Posts |");
+        let _ = writeln!(out, "|---|---:|");
+        for (tag, count) in &summary.sample {
+            let _ = writeln!(out, "| {} | {} |", escape(tag), count);
+        }
+        let _ = writeln!(out);
+    }
+
+    if !summary.ratings.is_empty() {
+        let _ = writeln!(out, "## Ratings\n");
+        let _ = writeln!(out, "| Rating | Downloaded |");
+        let _ = writeln!(out, "|---|---:|");
+        for (rating, count) in &summary.ratings {
+            let _ = writeln!(out, "| {} | {} |", rating_name(rating), count);
+        }
+        let _ = writeln!(out);
+    }
+
+    let top_artists = summary.top_artists(TOP_ARTISTS);
+    if !top_artists.is_empty() {
+        let _ = writeln!(out, "## Top Artists\n");
+        let _ = writeln!(out, "| Artist | Downloaded |");
+        let _ = writeln!(out, "|---|---:|");
+        for (artist, count) in top_artists {
+            let _ = writeln!(out, "| {} | {} |", escape(artist), count);
+        }
+        let _ = writeln!(out);
+    }
+
+    if !summary.failures.is_empty() {
+        let _ = writeln!(out, "## Failures\n");
+        let _ = writeln!(out, "| Post | URL | Reason |");
+        let _ = writeln!(out, "|---|---|---|");
+        for failure in &summary.failures {
+            let _ = writeln!(
+                out,
+                "| {} | {} | {} |",
+                failure.id,
+                failure.url.as_deref().unwrap_or(""),
+                escape(&failure.reason)
+            );
+        }
+        let _ = writeln!(out);
+    }
+
+    if !summary.missing_urls.is_empty() {
+        let _ = writeln!(out, "## No File to Download\n");
+        for id in &summary.missing_urls {
+            let _ = writeln!(out, "- [{id}](https://e621.net/posts/{id})");
+        }
+        let _ = writeln!(out);
+    }
+
+    out
+}
+
+// The command as it was typed, give or take shell quoting, so that the run
+// can be repeated.
+fn command_line() -> String {
+    std::env::args()
+        .map(|arg| {
+            if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '\'') {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            } else {
+                arg
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn rating_name(rating: &str) -> &str {
+    match rating {
+        "s" => "safe",
+        "q" => "questionable",
+        "e" => "explicit",
+        other => other,
+    }
+}
+
+fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
+// Keeps table cells intact.
+fn escape(text: &str) -> String {
+    text.replace('|', r"\|").replace('\n', " ")
+}
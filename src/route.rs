@@ -0,0 +1,112 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::Post;
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Files posts with a particular tag under a different output directory.
+#[derive(Clone, Debug)]
+pub struct Route {
+    tag: String,
+    directory: PathBuf,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((tag, directory)) if !tag.trim().is_empty() && !directory.is_empty() => {
+                Ok(Route {
+                    tag: tag.trim().to_lowercase(),
+                    directory: PathBuf::from(directory),
+                })
+            }
+            _ => Err(format!("expected TAG=DIRECTORY, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum RouteMode {
+    /// The first matching route decides where a post goes
+    #[default]
+    First,
+    /// Every matching route gets the post: the first gets the file, the
+    /// rest get links to it
+    All,
+}
+
+/// How one place in the archive refers to a file kept in another.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LinkKind {
+    /// A symbolic link, or a copy on systems without them
+    #[default]
+    Symlink,
+    /// A hard link, which only works within one filesystem
+    Hardlink,
+}
+
+/// Decides which output directories a post belongs in.
+pub struct Router {
+    default: PathBuf,
+    routes: Vec<Route>,
+    mode: RouteMode,
+}
+
+impl Router {
+    pub fn new(default: &Path, routes: &[Route], mode: RouteMode) -> Router {
+        Router {
+            default: default.to_owned(),
+            routes: routes.to_vec(),
+            mode,
+        }
+    }
+
+    /// Every output directory a route might send posts to, default included.
+    pub fn directories(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(self.default.as_path())
+            .chain(self.routes.iter().map(|route| route.directory.as_path()))
+    }
+
+    /// The directories a post goes in, in the order the routes were given.
+    /// There is always at least one, and the first is where the file itself
+    /// is saved.
+    pub fn roots(&self, post: &Post) -> Vec<&Path> {
+        let mut matches = self
+            .routes
+            .iter()
+            .filter(|route| post.tags.all().any(|tag| *tag == route.tag))
+            .map(|route| route.directory.as_path());
+        let roots: Vec<&Path> = match self.mode {
+            RouteMode::First => matches.next().into_iter().collect(),
+            RouteMode::All => matches.collect(),
+        };
+        if roots.is_empty() {
+            vec![self.default.as_path()]
+        } else {
+            roots
+        }
+    }
+}
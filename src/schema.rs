@@ -0,0 +1,93 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::client::Client;
+use crate::Post;
+use log::{info, warn};
+use serde_json::Value;
+
+// The smallest request that returns a whole post.
+const PROBE_URL: &str = "https://e621.net/posts.json?limit=1";
+
+// The fields a post is parsed from, as paths into its JSON.
+const EXPECTED_FIELDS: &[&str] = &[
+    "id",
+    "created_at",
+    "updated_at",
+    "file/width",
+    "file/height",
+    "file/ext",
+    "file/size",
+    "file/md5",
+    "file/url",
+    "tags/general",
+    "tags/species",
+    "tags/character",
+    "tags/copyright",
+    "tags/artist",
+    "tags/invalid",
+    "tags/lore",
+    "tags/meta",
+    "rating",
+    "flags/pending",
+    "flags/flagged",
+    "flags/deleted",
+];
+
+/// Fetches a single post and checks that it still looks the way the rest of
+/// monosodium expects, returning what doesn't. If it can't be fetched, there's
+/// nothing to say: the run itself will report that soon enough.
+pub async fn check(client: &Client) -> Vec<String> {
+    info!("Checking the API still looks as expected");
+    let body = match fetch(client).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Could not check the API: {}", e);
+            return Vec::new();
+        }
+    };
+    let Some(post) = body.get("posts").and_then(|posts| posts.get(0)) else {
+        return vec!["the response has no \"posts\" list".to_owned()];
+    };
+
+    let mut problems: Vec<String> = EXPECTED_FIELDS
+        .iter()
+        .filter(|field| post.pointer(&format!("/{}", field)).is_none())
+        .map(|field| format!("posts have no \"{}\"", field.replace('/', ".")))
+        .collect();
+    // Present but of the wrong type, say.
+    if problems.is_empty() {
+        if let Err(e) = serde_json::from_value::<Post>(post.clone()) {
+            problems.push(format!("posts can't be read: {}", e));
+        }
+    }
+    problems
+}
+
+async fn fetch(client: &Client) -> Result<Value, reqwest::Error> {
+    client
+        .get(PROBE_URL)
+        .await?
+        .error_for_status()?
+        .json::<Value>()
+        .await
+}
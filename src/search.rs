@@ -0,0 +1,140 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::error::MonosodiumError;
+use crate::Post;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+
+/// e621 won't search on more tags than this at once.
+pub const DEFAULT_TAG_LIMIT: usize = 40;
+/// Nor more than this for someone logged in.
+pub const LOGGED_IN_TAG_LIMIT: usize = 50;
+
+/// How a `--tags` query is carried out. As many tags as the server allows are
+/// sent with the search; any plain tags beyond that are checked against each
+/// post as it comes back, which gives the same results as long as every tag
+/// has to match.
+#[derive(Clone, Debug)]
+pub struct Query {
+    pub server: String,
+    pub local_include: Vec<String>,
+    pub local_exclude: Vec<String>,
+    /// For --expand-implications, the tags that imply each of the local ones,
+    /// which count as having it.
+    pub narrower: HashMap<String, HashSet<String>>,
+}
+
+impl Query {
+    pub fn plan(tags: &str, limit: usize) -> Result<Query, MonosodiumError> {
+        let tags: Vec<String> = tags.split_whitespace().map(str::to_lowercase).collect();
+        // A `-` on its own, usually from a stray space, would exclude nothing
+        // locally, and e621 takes it as a tag of its own.
+        if tags.iter().any(|tag| tag == "-") {
+            return Err(MonosodiumError::InvalidQuery(
+                "--tags has a `-` with no tag after it".to_owned(),
+            ));
+        }
+        if tags.len() <= limit {
+            return Ok(Query {
+                server: tags.join(" "),
+                local_include: Vec::new(),
+                local_exclude: Vec::new(),
+                narrower: HashMap::new(),
+            });
+        }
+
+        // Metatags like `rating:s`, "or" tags like `~fox` and wildcards only
+        // mean something to the server, so they have to be sent along.
+        let (server_only, local): (Vec<String>, Vec<String>) = tags
+            .into_iter()
+            .partition(|tag| tag.contains(':') || tag.contains('*') || tag.starts_with('~'));
+        if server_only.len() > limit {
+            return Err(MonosodiumError::InvalidQuery(format!(
+                "--tags has {} metatags, wildcards and ~tags, but e621 only allows {} tags in a search",
+                server_only.len(),
+                limit
+            )));
+        }
+
+        let room = limit - server_only.len();
+        let (sent, kept) = local.split_at(room.min(local.len()));
+        warn!(
+            "--tags has {} tags, more than the limit of {}; the other {} will be checked locally",
+            server_only.len() + sent.len() + kept.len(),
+            limit,
+            kept.len()
+        );
+
+        let mut server = server_only;
+        server.extend_from_slice(sent);
+        let (local_exclude, local_include): (Vec<String>, Vec<String>) =
+            kept.iter().cloned().partition(|tag| tag.starts_with('-'));
+        Ok(Query {
+            server: server.join(" "),
+            local_include,
+            local_exclude: local_exclude
+                .into_iter()
+                .map(|tag| tag[1..].to_string())
+                .collect(),
+            narrower: HashMap::new(),
+        })
+    }
+
+    /// Whether a post has all of the included tags that weren't sent to the
+    /// server, and none of the excluded ones.
+    pub fn matches_locally(&self, post: &Post) -> bool {
+        let has = |wanted: &String| {
+            let narrower = self.narrower.get(wanted);
+            post.tags
+                .all()
+                .any(|tag| tag == wanted || narrower.is_some_and(|narrower| narrower.contains(tag)))
+        };
+        self.local_include.iter().all(has) && !self.local_exclude.iter().any(has)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_everything_under_the_limit() {
+        let query = Query::plan("Fox -comic rating:s", 3).unwrap();
+        assert_eq!(query.server, "fox -comic rating:s");
+        assert!(query.local_include.is_empty() && query.local_exclude.is_empty());
+    }
+
+    #[test]
+    fn checks_plain_tags_over_the_limit_locally() {
+        let query = Query::plan("rating:s fox cat -comic -sketch", 2).unwrap();
+        assert_eq!(query.server, "rating:s fox");
+        assert_eq!(query.local_include, ["cat"]);
+        assert_eq!(query.local_exclude, ["comic", "sketch"]);
+    }
+
+    #[test]
+    fn refuses_a_lone_minus() {
+        assert!(Query::plan("fox - comic", 40).is_err());
+        assert!(Query::plan("fox cat dog -", 2).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::checksum::md5_file;
+use crate::client::Client;
+use crate::color::{self, Style};
+use crate::error::MonosodiumError;
+use crate::metadata::{MetadataWriter, Sidecar};
+use crate::storage::Filesystem;
+use crate::{archive_post, ensure_writable, ApiResponse, Opts, Post};
+use std::fs::{remove_dir_all, File};
+use std::io::BufReader;
+use std::path::Path;
+
+// The smallest safe post there is, which is quick to download and fine to
+// have on screen anywhere.
+const SAMPLE_URL: &str = "https://e621.net/posts.json?tags=rating:s+order:filesize_asc&limit=1";
+
+// Needs a login, and says whether the one given works.
+const LOGIN_URL: &str = "https://e621.net/favorites.json?limit=1";
+
+// Tallies the checks, printing each as it finishes.
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    failed: usize,
+}
+
+impl Report {
+    fn pass(&mut self, step: &str, detail: impl AsRef<str>) {
+        self.passed += 1;
+        println!(
+            "  {}    {}: {}",
+            color::out(Style::Good, "ok"),
+            step,
+            detail.as_ref()
+        );
+    }
+
+    fn fail(&mut self, step: &str, detail: impl AsRef<str>) {
+        self.failed += 1;
+        println!(
+            "  {}  {}: {}",
+            color::out(Style::Bad, "FAIL"),
+            step,
+            detail.as_ref()
+        );
+    }
+
+    fn skip(&self, step: &str, detail: &str) {
+        println!(
+            "  {}  {}: {}",
+            color::out(Style::Notice, "skip"),
+            step,
+            detail
+        );
+    }
+
+    // Reports `result`, and passes on what it has, if anything.
+    fn check<T, E: std::fmt::Display>(
+        &mut self,
+        step: &str,
+        result: Result<T, E>,
+        detail: impl FnOnce(&T) -> String,
+    ) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.pass(step, detail(&value));
+                Some(value)
+            }
+            Err(e) => {
+                self.fail(step, e.to_string());
+                None
+            }
+        }
+    }
+}
+
+/// Goes through a run from start to finish with one small post, in a
+/// directory of its own that's removed afterwards, saying how each step went:
+/// for --selftest. Gives an error if any step failed.
+pub async fn run(opts: &Opts, client: &Client) -> Result<(), MonosodiumError> {
+    let dir = std::env::temp_dir().join(format!("monosodium-selftest-{}", std::process::id()));
+    println!("Testing monosodium in {}:", dir.display());
+    let mut report = Report::default();
+    steps(opts, client, &dir, &mut report).await;
+    if dir.exists() {
+        report.check("clean up", remove_dir_all(&dir), |_| "removed".to_owned());
+    }
+
+    println!(
+        "{} of {} checks passed.",
+        report.passed,
+        report.passed + report.failed
+    );
+    match report.failed {
+        0 => Ok(()),
+        failed => Err(MonosodiumError::SelfTestFailed { failed }),
+    }
+}
+
+async fn steps(opts: &Opts, client: &Client, dir: &Path, report: &mut Report) {
+    let writable = ensure_writable(dir, opts.modes());
+    if report
+        .check("directory", writable, |_| "can be written to".to_owned())
+        .is_none()
+    {
+        return;
+    }
+
+    match &opts.username {
+        None => report.skip("login", "no --username given"),
+        Some(name) => match client.get(LOGIN_URL).await {
+            Ok(response) if response.status().is_success() => {
+                report.pass("login", format!("e621 accepts the API key for {}", name))
+            }
+            Ok(response) => report.fail(
+                "login",
+                format!(
+                    "e621 turned down --username and --api-key ({})",
+                    response.status()
+                ),
+            ),
+            Err(e) => report.fail("login", e.to_string()),
+        },
+    }
+
+    let Some(mut post) = report.check("API", fetch_sample(client).await, |post| {
+        format!("found post {} ({} bytes)", post.id, post.file.size)
+    }) else {
+        return;
+    };
+    post.file_path = Some(dir.join(format!("{}.{}", post.file.md5, post.file.ext)));
+    post.tags_path = Some(dir.join(format!("{}.json", post.file.md5)));
+    let storage = Filesystem {
+        modes: opts.modes(),
+    };
+
+    let downloaded = archive_post(client, &storage, &post, opts).await;
+    if report
+        .check("download", downloaded, |_| "saved the file".to_owned())
+        .is_some()
+    {
+        let md5 = md5_file(post.file_path.as_ref().unwrap());
+        match md5 {
+            Ok(md5) if md5 == post.file.md5 => report.pass("MD5", "matches e621's"),
+            // --strip-metadata changes files, and notes what they hash to.
+            Ok(md5) if post.is_saved_md5(&md5) => {
+                report.pass("MD5", "matches the one it was saved with")
+            }
+            Ok(md5) => report.fail("MD5", format!("{} rather than {}", md5, post.file.md5)),
+            Err(e) => report.fail("MD5", e.to_string()),
+        }
+    }
+
+    let sidecar = Sidecar {
+        compact: opts.json_compact,
+    };
+    let written = sidecar.write(&storage, &post).await;
+    if report
+        .check("metadata", written, |_| "wrote the sidecar".to_owned())
+        .is_some()
+    {
+        let read = read_sidecar(post.tags_path.as_ref().unwrap());
+        match read {
+            Ok(read) if read.id == post.id && read.file.md5 == post.file.md5 => {
+                report.pass("metadata", "reads back the same")
+            }
+            Ok(_) => report.fail("metadata", "reads back as a different post"),
+            Err(e) => report.fail("metadata", e.to_string()),
+        }
+    }
+}
+
+async fn fetch_sample(client: &Client) -> Result<Post, MonosodiumError> {
+    let body = client
+        .get(SAMPLE_URL)
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let response: ApiResponse = serde_json::from_str(&body).map_err(std::io::Error::from)?;
+    response
+        .posts
+        .into_iter()
+        .next()
+        .ok_or_else(|| MonosodiumError::InvalidQuery("e621 didn't send any posts".to_owned()))
+}
+
+fn read_sidecar(path: &Path) -> std::io::Result<Post> {
+    Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+}
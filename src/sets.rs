@@ -0,0 +1,93 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::client::Client;
+use crate::error::MonosodiumError;
+use crate::Post;
+use log::info;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// A set on e621: a collection of posts someone put together, in their own
+/// order, for --set.
+#[derive(Deserialize)]
+pub struct PostSet {
+    pub id: u64,
+    pub name: String,
+    pub shortname: String,
+    post_ids: Vec<u64>,
+}
+
+/// Where a post comes in the set being archived, for --set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SetPlace {
+    pub id: u64,
+    pub name: String,
+    /// Counting from 1, in the set's own order.
+    pub position: usize,
+    /// How many posts the set has.
+    pub length: usize,
+}
+
+impl PostSet {
+    /// Looks up the set with this id. Private sets can only be seen by whoever
+    /// made them, so need them to be logged in.
+    pub async fn fetch(client: &Client, id: u64) -> Result<PostSet, MonosodiumError> {
+        info!("Looking up set {}", id);
+        let url = format!("https://e621.net/post_sets/{}.json", id);
+        let response = client.get(&url).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(MonosodiumError::InvalidOptions(format!(
+                "there's no e621 set with the id {}",
+                id
+            ))),
+            StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED if client.is_logged_in() => {
+                Err(MonosodiumError::InvalidOptions(format!(
+                    "set {} is private, and only its owner can see it",
+                    id
+                )))
+            }
+            StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => {
+                Err(MonosodiumError::InvalidOptions(format!(
+                    "set {} is private; pass the --username and --api-key of its owner",
+                    id
+                )))
+            }
+            _ => Ok(response.error_for_status()?.json().await?),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.post_ids.len()
+    }
+
+    /// Where `post` comes in the set, or None if it isn't in it.
+    pub fn place(&self, post: &Post) -> Option<SetPlace> {
+        let index = self.post_ids.iter().position(|&id| id == post.id)?;
+        Some(SetPlace {
+            id: self.id,
+            name: self.name.clone(),
+            position: index + 1,
+            length: self.post_ids.len(),
+        })
+    }
+}
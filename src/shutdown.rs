@@ -0,0 +1,93 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use log::warn;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Interrupted,
+    TimeBudget,
+    PageLimit,
+    LowDiskSpace,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Interrupted => write!(f, "interrupted"),
+            StopReason::TimeBudget => write!(f, "time budget reached"),
+            StopReason::PageLimit => write!(f, "page limit reached"),
+            StopReason::LowDiskSpace => write!(f, "low on disk space"),
+        }
+    }
+}
+
+/// Asks the run to wind down: no new work is started, but whatever is in
+/// flight is allowed to finish. The first reason given sticks.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    reason: Arc<OnceLock<StopReason>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Stops the run on Ctrl-C, and once `max_duration` has passed. A second
+    /// Ctrl-C exits immediately.
+    pub fn listen(&self, max_duration: Option<Duration>) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                warn!("Interrupted, finishing in-flight work. Press Ctrl-C again to quit now.");
+                shutdown.trigger(StopReason::Interrupted);
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    std::process::exit(130);
+                }
+            }
+        });
+
+        if let Some(max_duration) = max_duration {
+            let shutdown = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(max_duration).await;
+                warn!(
+                    "Time budget of {} used up, finishing in-flight work",
+                    humantime::format_duration(max_duration)
+                );
+                shutdown.trigger(StopReason::TimeBudget);
+            });
+        }
+    }
+
+    pub fn trigger(&self, reason: StopReason) {
+        let _ = self.reason.set(reason);
+    }
+
+    pub fn reason(&self) -> Option<StopReason> {
+        self.reason.get().copied()
+    }
+}
@@ -0,0 +1,302 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::manifest::{self, WriteStrategy};
+use crate::ApiResponse;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// How many post ids from the last page to remember.
+const SAMPLE_SIZE: usize = 8;
+
+// The share of remembered ids that must still be on their page for it to be
+// trusted.
+const MIN_OVERLAP: f64 = 0.5;
+
+/// How often a run makes its progress durable: every so many pages, like `5`,
+/// or once so long has passed, like `10m`, at the end of the page that's
+/// being worked on.
+#[derive(Clone, Copy, Debug)]
+pub enum CheckpointInterval {
+    Pages(usize),
+    Every(Duration),
+}
+
+impl FromStr for CheckpointInterval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(pages) = s.parse::<usize>() {
+            return match pages {
+                0 => Err("expected at least one page".to_owned()),
+                pages => Ok(CheckpointInterval::Pages(pages)),
+            };
+        }
+        humantime::parse_duration(s)
+            .map(CheckpointInterval::Every)
+            .map_err(|_| {
+                format!(
+                    "expected a number of pages like 5 or a duration like 10m, got {:?}",
+                    s
+                )
+            })
+    }
+}
+
+/// Keeps count of the pages finished since the last checkpoint.
+pub struct Checkpoints {
+    interval: CheckpointInterval,
+    pages: usize,
+    last: Instant,
+}
+
+impl Checkpoints {
+    pub fn new(interval: CheckpointInterval) -> Checkpoints {
+        Checkpoints {
+            interval,
+            pages: 0,
+            last: Instant::now(),
+        }
+    }
+
+    /// Counts another page, and says whether it's time to checkpoint.
+    pub fn due(&mut self) -> bool {
+        self.pages += 1;
+        match self.interval {
+            CheckpointInterval::Pages(pages) => self.pages >= pages,
+            CheckpointInterval::Every(interval) => self.last.elapsed() >= interval,
+        }
+    }
+
+    pub fn saved(&mut self) {
+        self.pages = 0;
+        self.last = Instant::now();
+    }
+
+    /// Whether any pages have been finished since the last checkpoint.
+    pub fn pending(&self) -> bool {
+        self.pages > 0
+    }
+}
+
+/// How far the last run got, so that an interrupted run can carry on from
+/// there rather than starting over.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunState {
+    /// Which favorites or search this is about.
+    pub source: String,
+    /// The last page whose posts were all dealt with.
+    pub page: usize,
+    /// How many posts there were to a page.
+    pub per_page: u32,
+    /// The last post on that page.
+    pub anchor: u64,
+    /// Some of the other posts on that page.
+    pub sample: Vec<u64>,
+}
+
+impl RunState {
+    pub fn new(source: String, page: usize, per_page: u32, response: &ApiResponse) -> RunState {
+        let ids: Vec<u64> = response.posts.iter().map(|post| post.id).collect();
+        let step = (ids.len() / SAMPLE_SIZE).max(1);
+        RunState {
+            source,
+            page,
+            per_page,
+            anchor: ids.last().copied().unwrap_or_default(),
+            sample: ids
+                .iter()
+                .step_by(step)
+                .take(SAMPLE_SIZE)
+                .copied()
+                .collect(),
+        }
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Option<RunState>> {
+        manifest::load(path)
+    }
+
+    pub fn save(&self, path: &Path, strategy: WriteStrategy) -> std::io::Result<()> {
+        manifest::save(path, self, strategy)
+    }
+
+    /// Removes the saved state, and its backup, once the run is finished.
+    pub fn clear(path: &Path) -> std::io::Result<()> {
+        manifest::remove(path)
+    }
+
+    /// Whether the pages were the same size as `per_page`. With any other
+    /// size, the page saved starts somewhere else, though its posts could
+    /// still look enough like the ones saved to pass [`still_matches`].
+    ///
+    /// [`still_matches`]: RunState::still_matches
+    pub fn is_per_page(&self, per_page: u32) -> bool {
+        self.per_page == per_page
+    }
+
+    /// Whether the page, fetched again now, still looks like it did when the
+    /// state was saved. If favorites were added or removed in the meantime,
+    /// the posts will have moved to other pages, and carrying on from here
+    /// could miss some.
+    pub fn still_matches(&self, response: &ApiResponse) -> bool {
+        let ids: Vec<u64> = response.posts.iter().map(|post| post.id).collect();
+        if !ids.contains(&self.anchor) {
+            return false;
+        }
+        let found = self.sample.iter().filter(|id| ids.contains(id)).count();
+        found as f64 >= self.sample.len() as f64 * MIN_OVERLAP
+    }
+}
+
+/// The posts dealt with since progress was last saved, for
+/// `--partial-page-recovery`, so that a run resumed after a crash partway
+/// through a page can trust them as archived rather than check each again.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostsDone {
+    /// Which favorites or search this is about.
+    pub source: String,
+    pub posts: BTreeSet<u64>,
+}
+
+impl PostsDone {
+    pub fn new(source: String) -> PostsDone {
+        PostsDone {
+            source,
+            posts: BTreeSet::new(),
+        }
+    }
+
+    /// The posts saved as done for `source`, or none if they were saved for
+    /// something else.
+    pub fn load(path: &Path, source: &str) -> std::io::Result<PostsDone> {
+        Ok(match manifest::load::<PostsDone>(path)? {
+            Some(done) if done.source == source => done,
+            _ => PostsDone::new(source.to_owned()),
+        })
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.posts.contains(&id)
+    }
+
+    /// Adds the posts, and saves straight away if any were new.
+    pub fn record(
+        &mut self,
+        ids: impl IntoIterator<Item = u64>,
+        path: &Path,
+        strategy: WriteStrategy,
+    ) -> std::io::Result<()> {
+        let before = self.posts.len();
+        self.posts.extend(ids);
+        match self.posts.len() > before {
+            true => manifest::save(path, self, strategy),
+            false => Ok(()),
+        }
+    }
+
+    /// Forgets the posts, once progress past them has been saved.
+    pub fn clear(&mut self, path: &Path) -> std::io::Result<()> {
+        self.posts.clear();
+        RunState::clear(path)
+    }
+}
+
+/// The newest favorites when the last run that finished started, for
+/// --since-run. Favorites are listed newest favorite first, so anything
+/// listed before these was favorited since. Taken at the start, not the end,
+/// so that favorites added while it ran are new to the next one, and a few of
+/// them in case the newest is unfavorited in the meantime.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SinceRun {
+    pub newest: Vec<u64>,
+}
+
+impl SinceRun {
+    /// How many of the newest favorites are kept.
+    pub const KEPT: usize = 5;
+
+    /// The posts saved in `path`, if a run has saved any yet.
+    pub fn load(path: &Path) -> std::io::Result<Option<Vec<u64>>> {
+        let Some(anchor) = manifest::load::<SinceRun>(path)? else {
+            return Ok(None);
+        };
+        Ok(Some(anchor.newest).filter(|newest| !newest.is_empty()))
+    }
+
+    /// Saves `newest` in `path`, always atomically, since a run that finds
+    /// the file cut short has to go through everything again.
+    pub fn save(path: &Path, newest: &[u64]) -> std::io::Result<()> {
+        let anchor = SinceRun {
+            newest: newest.iter().copied().take(SinceRun::KEPT).collect(),
+        };
+        manifest::save(path, &anchor, WriteStrategy::Atomic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{post, Scratch};
+
+    fn page(ids: impl IntoIterator<Item = u64>) -> ApiResponse {
+        ApiResponse {
+            posts: ids
+                .into_iter()
+                .map(|id| {
+                    let mut post = post(b"a picture");
+                    post.id = id;
+                    post
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn matches_the_same_page_fetched_again() {
+        let state = RunState::new("favorites:1".to_owned(), 5, 300, &page(100..400));
+        assert!(state.is_per_page(300));
+        assert!(state.still_matches(&page(100..400)));
+        // Its last post moved onto the next page.
+        assert!(!state.still_matches(&page(98..398)));
+        assert!(!state.still_matches(&page(400..700)));
+    }
+
+    #[test]
+    fn keeps_the_newest_favorites() {
+        let scratch = Scratch::new("since-run");
+        let path = scratch.path().join("since-run.json");
+        assert_eq!(SinceRun::load(&path).unwrap(), None);
+        SinceRun::save(&path, &[9, 8, 7, 6, 5, 4, 3]).unwrap();
+        assert_eq!(SinceRun::load(&path).unwrap(), Some(vec![9, 8, 7, 6, 5]));
+    }
+
+    #[test]
+    fn refuses_another_page_size() {
+        let state = RunState::new("favorites:1".to_owned(), 5, 300, &page(100..400));
+        assert!(!state.is_per_page(320));
+    }
+}
@@ -0,0 +1,296 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::error::MonosodiumError;
+use crate::permissions::Modes;
+#[cfg(feature = "s3")]
+use crate::s3::Bucket;
+use crate::tarzst::TarZst;
+use crate::zip::ZipArchive;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Condvar, Mutex, OnceLock};
+
+/// The future returned by every [`StorageBackend`] operation.
+pub type Pending<'a, T> = Pin<Box<dyn Future<Output = Result<T, MonosodiumError>> + Send + 'a>>;
+
+/// Where archived files and metadata end up. Posts' paths are always worked
+/// out as if on disk, under the output directory; backends that aren't the
+/// disk map them to their own names with [`entry_name`].
+pub trait StorageBackend: Send + Sync {
+    fn exists<'a>(&'a self, path: &'a Path) -> Pending<'a, bool>;
+
+    fn write<'a>(&'a self, path: &'a Path, contents: Vec<u8>) -> Pending<'a, ()>;
+
+    /// Writes a JSON sidecar, for backends that treat them differently.
+    fn write_metadata<'a>(&'a self, path: &'a Path, json: Vec<u8>) -> Pending<'a, ()> {
+        self.write(path, json)
+    }
+
+    /// Makes everything written so far durable; called after every page.
+    fn flush(&self) -> Result<(), MonosodiumError> {
+        Ok(())
+    }
+
+    /// Whether paths are real files, that can be linked, hashed and so on.
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+// Set once, before anything is opened, by --max-open-files.
+static OPEN_FILES: OnceLock<OpenFiles> = OnceLock::new();
+
+/// Lets at most `max` files be open at once: those kept open for the whole
+/// run, like the journal and archives, and those opened along the way, to
+/// write posts, save manifests and their backups, or hash files.
+pub fn limit_open_files(max: usize) {
+    OPEN_FILES.get_or_init(|| OpenFiles::new(max));
+}
+
+// Runs `open`, which should be done with the file by the time it returns, once
+// there's one to spare under --max-open-files. It blocks, but never for long,
+// since whatever holds the others lets them go without waiting on anything.
+pub fn with_open_file<T>(open: impl FnOnce() -> T) -> T {
+    let _open = OPEN_FILES.get().map(OpenFiles::wait);
+    open()
+}
+
+/// Counts a file kept open from now until the returned guard is dropped
+/// against --max-open-files.
+pub fn hold_open_file() -> io::Result<Option<OpenFile<'static>>> {
+    OPEN_FILES.get().map(OpenFiles::hold).transpose()
+}
+
+struct OpenFiles {
+    max: usize,
+    open: Mutex<usize>,
+    closed: Condvar,
+}
+
+/// One of the files allowed by --max-open-files, given back when dropped.
+pub struct OpenFile<'a> {
+    files: &'a OpenFiles,
+}
+
+impl OpenFiles {
+    fn new(max: usize) -> OpenFiles {
+        OpenFiles {
+            max,
+            open: Mutex::new(0),
+            closed: Condvar::new(),
+        }
+    }
+
+    fn wait(&self) -> OpenFile<'_> {
+        let open = self.open.lock().unwrap();
+        let mut open = self
+            .closed
+            .wait_while(open, |open| *open >= self.max)
+            .unwrap();
+        *open += 1;
+        OpenFile { files: self }
+    }
+
+    // Files kept open are opened before any others, and never wait, as
+    // nothing would give them one while they do; they have to leave one to
+    // spare, or everything else would wait forever.
+    fn hold(&self) -> io::Result<OpenFile<'_>> {
+        let mut open = self.open.lock().unwrap();
+        if *open + 1 >= self.max {
+            return Err(io::Error::other(format!(
+                "--max-open-files {} is too few for the files kept open all run and one more",
+                self.max
+            )));
+        }
+        *open += 1;
+        Ok(OpenFile { files: self })
+    }
+}
+
+impl Drop for OpenFile<'_> {
+    fn drop(&mut self) {
+        *self.files.open.lock().unwrap() -= 1;
+        self.files.closed.notify_one();
+    }
+}
+
+/// The soft limit on open files, if the platform says what it is.
+#[cfg(target_os = "linux")]
+pub fn open_file_limit() -> Option<u64> {
+    // Columns are the name, the soft limit, the hard limit and the units.
+    let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+    let line = limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?;
+    line["Max open files".len()..]
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_file_limit() -> Option<u64> {
+    None
+}
+
+/// Plain files on disk, the default.
+#[derive(Default)]
+pub struct Filesystem {
+    pub modes: Modes,
+}
+
+impl StorageBackend for Filesystem {
+    fn exists<'a>(&'a self, path: &'a Path) -> Pending<'a, bool> {
+        Box::pin(async move { Ok(path.exists()) })
+    }
+
+    fn write<'a>(&'a self, path: &'a Path, contents: Vec<u8>) -> Pending<'a, ()> {
+        Box::pin(async move {
+            with_open_file(|| {
+                if let Some(parent) = path.parent() {
+                    self.modes.create_dir_all(parent)?;
+                }
+                File::create(path)?.write_all(&contents)?;
+                self.modes.apply_to_file(path)?;
+                Ok(())
+            })
+        })
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+/// Picks the backend asked for on the command line.
+#[cfg_attr(not(feature = "s3"), allow(unused_variables))]
+pub fn open(
+    s3: Option<&str>,
+    zip: Option<&Path>,
+    archive: Option<&Path>,
+    root: &Path,
+    modes: Modes,
+) -> Result<Box<dyn StorageBackend>, MonosodiumError> {
+    if let Some(zip) = zip {
+        return Ok(Box::new(ZipArchive::open(zip, root)?));
+    }
+    if let Some(archive) = archive {
+        return Ok(Box::new(TarZst::open(archive, root)?));
+    }
+    match s3 {
+        None => Ok(Box::new(Filesystem { modes })),
+        #[cfg(feature = "s3")]
+        Some(spec) => Ok(Box::new(Bucket::from_env(spec, root)?)),
+        #[cfg(not(feature = "s3"))]
+        Some(_) => Err(MonosodiumError::InvalidOptions(
+            "this build of monosodium can't use --s3; rebuild it with `--features s3`".to_owned(),
+        )),
+    }
+}
+
+/// The path relative to the output directory, with forward slashes whatever
+/// the platform.
+pub fn entry_name(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    relative
+        .components()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(segment) => Some(segment.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::Scratch;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // How many of `threads` opening a file at once are ever open together.
+    fn most_open(files: &OpenFiles, threads: usize) -> usize {
+        let (open, most) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    let _file = files.wait();
+                    let now = open.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(2));
+                    open.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        most.into_inner()
+    }
+
+    // Not through the limit for the whole process, which every other test
+    // would then be under too.
+    #[test]
+    fn never_has_more_open_than_the_limit() {
+        let files = OpenFiles::new(3);
+        assert_eq!(most_open(&files, 32), 3);
+        assert_eq!(*files.open.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn files_kept_open_leave_fewer_for_the_rest() {
+        let files = OpenFiles::new(3);
+        let journal = files.hold().unwrap();
+        assert_eq!(most_open(&files, 32), 2);
+        let archive = files.hold().unwrap();
+        assert_eq!(most_open(&files, 32), 1);
+        assert!(files.hold().is_err());
+        drop((journal, archive));
+        assert_eq!(most_open(&files, 32), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn writes_files_however_many_at_once() {
+        let scratch = Scratch::new("storage");
+        let storage = Arc::new(Filesystem::default());
+        let writes: Vec<_> = (0..64)
+            .map(|i| {
+                let storage = storage.clone();
+                let path = scratch.path().join(format!("{}/{}.png", i % 4, i));
+                tokio::spawn(async move { storage.write(&path, vec![i as u8; 1000]).await })
+            })
+            .collect();
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+        for i in 0..64 {
+            let path = scratch.path().join(format!("{}/{}.png", i % 4, i));
+            assert!(storage.exists(&path).await.unwrap());
+            assert_eq!(std::fs::read(path).unwrap(), vec![i as u8; 1000]);
+        }
+    }
+}
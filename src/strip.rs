@@ -0,0 +1,122 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Removes embedded metadata, like camera EXIF and GPS positions, from a JPEG
+/// or PNG file. Segments and chunks are dropped whole, so the image data is
+/// copied through untouched. Returns the new file and what was removed, or
+/// `None` if there was nothing to remove or the format isn't one this knows.
+pub fn strip(ext: &str, bytes: &[u8]) -> Option<(Vec<u8>, Vec<&'static str>)> {
+    let (stripped, mut removed) = match ext {
+        "jpg" | "jpeg" => strip_jpeg(bytes)?,
+        "png" => strip_png(bytes)?,
+        _ => return None,
+    };
+    removed.sort_unstable();
+    removed.dedup();
+    (!removed.is_empty()).then_some((stripped, removed))
+}
+
+fn strip_jpeg(bytes: &[u8]) -> Option<(Vec<u8>, Vec<&'static str>)> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut removed = Vec::new();
+    out.extend_from_slice(&bytes[..2]);
+    let mut i = 2;
+    loop {
+        if *bytes.get(i)? != 0xFF {
+            return None;
+        }
+        let marker = *bytes.get(i + 1)?;
+        match marker {
+            // Padding before a marker.
+            0xFF => {
+                i += 1;
+                continue;
+            }
+            // Markers without a length.
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&bytes[i..i + 2]);
+                i += 2;
+                continue;
+            }
+            // Start of scan, after which it's all image data; or the end.
+            0xDA | 0xD9 => {
+                out.extend_from_slice(&bytes[i..]);
+                return Some((out, removed));
+            }
+            _ => {}
+        }
+        let length = u16::from_be_bytes([*bytes.get(i + 2)?, *bytes.get(i + 3)?]) as usize;
+        if length < 2 {
+            return None;
+        }
+        let segment = bytes.get(i..i + 2 + length)?;
+        let payload = &segment[4..];
+        let metadata = match marker {
+            0xE1 if payload.starts_with(b"Exif\0") => Some("EXIF"),
+            0xE1 if payload.starts_with(b"http://ns.adobe.com/xap/") => Some("XMP"),
+            0xED => Some("IPTC"),
+            0xFE => Some("comment"),
+            _ => None,
+        };
+        match metadata {
+            Some(name) => removed.push(name),
+            None => out.extend_from_slice(segment),
+        }
+        i += segment.len();
+    }
+}
+
+fn strip_png(bytes: &[u8]) -> Option<(Vec<u8>, Vec<&'static str>)> {
+    if !bytes.starts_with(PNG_SIGNATURE) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut removed = Vec::new();
+    out.extend_from_slice(PNG_SIGNATURE);
+    let mut i = PNG_SIGNATURE.len();
+    while i < bytes.len() {
+        let length = u32::from_be_bytes(bytes.get(i..i + 4)?.try_into().ok()?) as usize;
+        // Length, type, data and CRC.
+        let chunk = bytes.get(i..i + 12 + length)?;
+        let metadata = match &chunk[4..8] {
+            b"eXIf" => Some("EXIF"),
+            b"tEXt" | b"zTXt" | b"iTXt" => Some("text"),
+            b"tIME" => Some("timestamp"),
+            _ => None,
+        };
+        match metadata {
+            Some(name) => removed.push(name),
+            None => out.extend_from_slice(chunk),
+        }
+        i += chunk.len();
+        if &chunk[4..8] == b"IEND" {
+            out.extend_from_slice(&bytes[i..]);
+            break;
+        }
+    }
+    Some((out, removed))
+}
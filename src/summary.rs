@@ -0,0 +1,230 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::layout::NOT_ARTISTS;
+use crate::shutdown::StopReason;
+use crate::Post;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// A percentage limit isn't applied until this many downloads have been tried,
+// so that one early failure doesn't end the run.
+const MIN_ATTEMPTS_FOR_PERCENT: usize = 20;
+
+/// How many failed downloads a run puts up with: a count, like `50`, or a
+/// share of the downloads tried so far, like `10%`.
+#[derive(Clone, Copy, Debug)]
+pub enum FailureLimit {
+    Count(usize),
+    Percent(f64),
+}
+
+impl FromStr for FailureLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || {
+            format!(
+                "expected a count like 50 or a percentage like 10%, got {:?}",
+                s
+            )
+        };
+        match s.trim().strip_suffix('%') {
+            Some(percent) => percent
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|percent| (0.0..=100.0).contains(percent))
+                .map(FailureLimit::Percent)
+                .ok_or_else(expected),
+            None => s
+                .trim()
+                .parse()
+                .map(FailureLimit::Count)
+                .map_err(|_| expected()),
+        }
+    }
+}
+
+/// A tally of everything a run did, kept as it goes.
+pub struct Summary {
+    started: Instant,
+    pub pages: usize,
+    pub posts_seen: usize,
+    pub already_present: usize,
+    pub downloaded: usize,
+    /// Linked to an identical file already in the archive, for --dedupe.
+    pub deduplicated: usize,
+    /// Hard linked from another collection, for --hardlink-from.
+    pub linked_from_library: usize,
+    /// Sidecars rewritten for --only-updated-since.
+    pub metadata_refreshed: usize,
+    pub bytes: u64,
+    pub ratings: BTreeMap<String, usize>,
+    pub artists: HashMap<String, usize>,
+    pub failures: Vec<Failure>,
+    pub excluded: BTreeMap<&'static str, usize>,
+    pub stopped: Option<StopReason>,
+    /// Posts picked per tag, for a --balance-tag run.
+    pub sample: BTreeMap<String, usize>,
+    /// Posts that weren't deleted but had no file URL, for --fail-on-missing-url.
+    pub missing_urls: Vec<u64>,
+    /// What became of each post, in the order they were dealt with, if
+    /// asked for with `track_outcomes`.
+    pub outcomes: Option<Vec<(u64, Outcome)>>,
+}
+
+/// What became of one post, for --json-output.
+pub enum Outcome {
+    Excluded(&'static str),
+    AlreadyPresent,
+    /// Deleted, or without a file to download.
+    Unavailable,
+    Downloaded,
+    /// Linked to an identical file already in the archive, for --dedupe.
+    Deduplicated,
+    /// Hard linked from another collection, for --hardlink-from.
+    LinkedFromLibrary,
+    Failed(String),
+}
+
+pub struct Failure {
+    pub id: u64,
+    pub url: Option<String>,
+    pub reason: String,
+}
+
+impl Summary {
+    pub fn new() -> Summary {
+        Summary {
+            started: Instant::now(),
+            pages: 0,
+            posts_seen: 0,
+            already_present: 0,
+            downloaded: 0,
+            deduplicated: 0,
+            metadata_refreshed: 0,
+            linked_from_library: 0,
+            bytes: 0,
+            ratings: BTreeMap::new(),
+            artists: HashMap::new(),
+            failures: Vec::new(),
+            excluded: BTreeMap::new(),
+            stopped: None,
+            sample: BTreeMap::new(),
+            missing_urls: Vec::new(),
+            outcomes: None,
+        }
+    }
+
+    /// Keeps a note of what became of each post from here on.
+    pub fn track_outcomes(&mut self) {
+        self.outcomes = Some(Vec::new());
+    }
+
+    pub fn record_outcome(&mut self, post: &Post, outcome: Outcome) {
+        if let Some(outcomes) = &mut self.outcomes {
+            outcomes.push((post.id, outcome));
+        }
+    }
+
+    pub fn record_download(&mut self, post: &Post) {
+        self.record_outcome(post, Outcome::Downloaded);
+        self.downloaded += 1;
+        self.bytes += post.file.size as u64;
+        *self.ratings.entry(post.rating.clone()).or_default() += 1;
+        let artists = post.tags.artist.iter();
+        for artist in artists.filter(|artist| !NOT_ARTISTS.contains(&artist.as_str())) {
+            *self.artists.entry(artist.clone()).or_default() += 1;
+        }
+    }
+
+    pub fn record_failure(&mut self, post: &Post, reason: String) {
+        self.record_outcome(post, Outcome::Failed(reason.clone()));
+        self.failures.push(Failure {
+            id: post.id,
+            url: post.file.url.clone(),
+            reason,
+        });
+    }
+
+    pub fn record_exclusion(&mut self, post: &Post, reason: &'static str) {
+        self.record_outcome(post, Outcome::Excluded(reason));
+        *self.excluded.entry(reason).or_default() += 1;
+    }
+
+    /// Downloads tried so far, whether they worked or not.
+    pub fn attempted(&self) -> usize {
+        self.downloaded + self.deduplicated + self.linked_from_library + self.failures.len()
+    }
+
+    /// Whether more downloads have failed than `limit` allows.
+    pub fn exceeds(&self, limit: FailureLimit) -> bool {
+        let failed = self.failures.len();
+        match limit {
+            FailureLimit::Count(max) => failed > max,
+            FailureLimit::Percent(max) => {
+                let attempted = self.attempted();
+                attempted >= MIN_ATTEMPTS_FOR_PERCENT
+                    && failed as f64 > attempted as f64 * max / 100.0
+            }
+        }
+    }
+
+    pub fn excluded_total(&self) -> usize {
+        self.excluded.values().sum()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The most downloaded artists, busiest first. Ties go alphabetically so
+    /// the order doesn't change from one rendering to the next.
+    pub fn top_artists(&self, n: usize) -> Vec<(&str, usize)> {
+        let mut artists: Vec<(&str, usize)> = self
+            .artists
+            .iter()
+            .map(|(artist, count)| (artist.as_str(), *count))
+            .collect();
+        artists.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        artists.truncate(n);
+        artists
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::post;
+
+    #[test]
+    fn leaves_placeholders_out_of_the_artists() {
+        let mut post = post(b"a picture");
+        post.tags.artist = vec!["conditional_dnp".to_owned(), "someone".to_owned()];
+        let mut summary = Summary::new();
+        summary.record_download(&post);
+        assert_eq!(summary.artists.len(), 1);
+        assert_eq!(summary.artists.get("someone"), Some(&1));
+    }
+}
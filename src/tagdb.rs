@@ -0,0 +1,108 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::Tags;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+
+// Alias chains longer than this are taken to be a loop.
+const MAX_ALIAS_DEPTH: usize = 16;
+
+/// Alias and implication rules from a --tag-db file, like:
+///
+/// ```json
+/// {
+///   "aliases": { "kitty": "cat" },
+///   "implications": { "cat": ["felid"], "felid": ["mammal"] }
+/// }
+/// ```
+#[derive(Deserialize, Default)]
+pub struct TagDb {
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+    #[serde(default)]
+    implications: HashMap<String, Vec<String>>,
+}
+
+/// A post's tags as the tag database sees them, saved in its sidecar next to
+/// the tags from e621.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResolvedTags {
+    /// Every tag, with aliases replaced by what they stand for.
+    pub canonical: Vec<String>,
+    /// Tags implied by those, that the post didn't already have.
+    pub implied: Vec<String>,
+}
+
+impl TagDb {
+    pub fn load(path: &Path) -> std::io::Result<TagDb> {
+        let db: TagDb = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        // Tags from the API are normalized, so the rules have to be too.
+        let nfc = |tag: &str| tag.nfc().collect::<String>();
+        Ok(TagDb {
+            aliases: db
+                .aliases
+                .iter()
+                .map(|(alias, tag)| (nfc(alias), nfc(tag)))
+                .collect(),
+            implications: db
+                .implications
+                .iter()
+                .map(|(tag, implied)| (nfc(tag), implied.iter().map(|tag| nfc(tag)).collect()))
+                .collect(),
+        })
+    }
+
+    fn canonical<'a>(&'a self, mut tag: &'a str) -> &'a str {
+        for _ in 0..MAX_ALIAS_DEPTH {
+            match self.aliases.get(tag) {
+                Some(target) => tag = target,
+                None => break,
+            }
+        }
+        tag
+    }
+
+    /// Applies the aliases to every tag, then follows the implications from
+    /// those, and their implications in turn.
+    pub fn resolve(&self, tags: &Tags) -> ResolvedTags {
+        let canonical: BTreeSet<&str> = tags.all().map(|tag| self.canonical(tag)).collect();
+        let mut implied = BTreeSet::new();
+        let mut pending: Vec<&str> = canonical.iter().copied().collect();
+        while let Some(tag) = pending.pop() {
+            for implication in self.implications.get(tag).into_iter().flatten() {
+                let implication = self.canonical(implication);
+                if !canonical.contains(implication) && implied.insert(implication) {
+                    pending.push(implication);
+                }
+            }
+        }
+        ResolvedTags {
+            canonical: canonical.into_iter().map(str::to_owned).collect(),
+            implied: implied.into_iter().map(str::to_owned).collect(),
+        }
+    }
+}
@@ -0,0 +1,463 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+use crate::error::MonosodiumError;
+use crate::storage::{entry_name, hold_open_file, OpenFile, Pending, StorageBackend};
+use log::{error, warn};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+
+const BLOCK: usize = 512;
+
+// A zstd frame with no content size or checksum and a 128 KiB window, which
+// is as large as a raw block may be.
+const FRAME_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const FRAME_HEADER: [u8; 2] = [0x00, 0x38];
+const RAW_BLOCK: usize = 128 * 1024;
+
+// The largest size a ustar header has room for; bigger ones go in a pax
+// header as well.
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+struct State {
+    file: File,
+    list: File,
+    names: HashSet<String>,
+    // Where the archive's end marker starts, and so where the next entry goes.
+    end: u64,
+    dirty: bool,
+    // The archive and the list, under --max-open-files.
+    _open: [Option<OpenFile<'static>>; 2],
+}
+
+enum Op {
+    Contains(String, oneshot::Sender<bool>),
+    Append(String, Vec<u8>, oneshot::Sender<io::Result<()>>),
+    // Flushing isn't async, so it waits on a plain channel.
+    Finish(mpsc::Sender<io::Result<()>>),
+}
+
+/// A single tar archive holding everything, compressed with zstd as it's
+/// written. Each entry is its own zstd frame, so entries are compressed as
+/// they're downloaded, off the async threads, and then handed to one thread
+/// that owns the archive and writes them one at a time; it's never read
+/// back. Which entries it has is kept alongside in `<FILE>.entries`, with
+/// where each one ends; an interrupted archive is cut back to the last entry
+/// listed there and added to.
+pub struct TarZst {
+    root: PathBuf,
+    compress: bool,
+    ops: Option<Sender<Op>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl TarZst {
+    pub fn open(path: &Path, root: &Path) -> Result<TarZst, MonosodiumError> {
+        let open = [hold_open_file()?, hold_open_file()?];
+        let list_path = entries_path(path);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let length = file.metadata()?.len();
+        let listed = match fs::read_to_string(&list_path) {
+            Ok(listed) => listed,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        if length > 0 && listed.is_empty() {
+            return Err(MonosodiumError::InvalidOptions(format!(
+                "can't add to {}: {} is missing, so what's in it isn't known",
+                path.display(),
+                list_path.display()
+            )));
+        }
+
+        // Entries past the end of the file were listed but never made it to
+        // disk; anything after the last one listed is the end marker, or an
+        // entry that was cut off.
+        let mut names = HashSet::new();
+        let mut kept = String::new();
+        let mut end = 0;
+        for line in listed.lines() {
+            let Some((offset, name)) = line.split_once('\t') else {
+                continue;
+            };
+            match offset.parse::<u64>() {
+                Ok(offset) if offset <= length => {
+                    end = end.max(offset);
+                    names.insert(name.to_owned());
+                    kept.push_str(line);
+                    kept.push('\n');
+                }
+                _ => break,
+            }
+        }
+        file.set_len(end)?;
+        fs::write(&list_path, kept)?;
+        let list = OpenOptions::new().append(true).open(&list_path)?;
+
+        let compress = zstd_available();
+        if !compress {
+            warn!(
+                "zstd isn't on the PATH, so {} is written without compression",
+                path.display()
+            );
+        }
+        let state = State {
+            file,
+            list,
+            names,
+            end,
+            dirty: false,
+            _open: open,
+        };
+        let (ops, received) = channel();
+        let marker = frame(vec![0; 2 * BLOCK], compress)?;
+        let path = path.to_owned();
+        let writer = thread::spawn(move || state.write_entries(received, marker, path));
+        Ok(TarZst {
+            root: root.to_owned(),
+            compress,
+            ops: Some(ops),
+            writer: Some(writer),
+        })
+    }
+
+    fn send(&self, op: Op) {
+        // The writer only stops once the sender is dropped, below.
+        let _ = self.ops.as_ref().unwrap().send(op);
+    }
+}
+
+impl StorageBackend for TarZst {
+    fn exists<'a>(&'a self, path: &'a Path) -> Pending<'a, bool> {
+        let name = entry_name(&self.root, path);
+        Box::pin(async move {
+            let (reply, result) = oneshot::channel();
+            self.send(Op::Contains(name, reply));
+            Ok(result.await.map_err(|_| stopped())?)
+        })
+    }
+
+    fn write<'a>(&'a self, path: &'a Path, contents: Vec<u8>) -> Pending<'a, ()> {
+        let name = entry_name(&self.root, path);
+        let compress = self.compress;
+        Box::pin(async move {
+            let framed = tokio::task::spawn_blocking({
+                let name = name.clone();
+                move || frame(tar_entry(&name, contents), compress)
+            });
+            let framed = framed.await.map_err(io::Error::other)??;
+            let (reply, result) = oneshot::channel();
+            self.send(Op::Append(name, framed, reply));
+            Ok(result.await.unwrap_or_else(|_| Err(stopped()))?)
+        })
+    }
+
+    fn flush(&self) -> Result<(), MonosodiumError> {
+        let (reply, result) = channel();
+        self.send(Op::Finish(reply));
+        Ok(result.recv().unwrap_or_else(|_| Err(stopped()))?)
+    }
+}
+
+impl Drop for TarZst {
+    // The writer finishes the archive once everything queued is written.
+    fn drop(&mut self) {
+        drop(self.ops.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "the archive's writer has stopped",
+    )
+}
+
+impl State {
+    fn write_entries(mut self, ops: Receiver<Op>, marker: Vec<u8>, path: PathBuf) {
+        for op in ops {
+            match op {
+                Op::Contains(name, reply) => {
+                    let _ = reply.send(self.names.contains(&name));
+                }
+                Op::Append(name, frame, reply) => {
+                    let _ = reply.send(self.append(name, &frame));
+                }
+                Op::Finish(reply) => {
+                    let _ = reply.send(self.finish(&marker));
+                }
+            }
+        }
+        if let Err(e) = self.finish(&marker) {
+            error!("Could not finish archive {:?}: {}", path, e);
+        }
+    }
+
+    fn append(&mut self, name: String, frame: &[u8]) -> io::Result<()> {
+        // Over the end marker, if there is one.
+        self.file.set_len(self.end)?;
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(frame)?;
+        self.end += frame.len() as u64;
+        writeln!(self.list, "{}\t{}", self.end, name)?;
+        self.names.insert(name);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn finish(&mut self, marker: &[u8]) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.file.set_len(self.end)?;
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(marker)?;
+        self.file.sync_data()?;
+        self.list.sync_data()?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+fn entries_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".entries");
+    PathBuf::from(name)
+}
+
+fn zstd_available() -> bool {
+    matches!(
+        Command::new("zstd")
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status(),
+        Ok(status) if status.success()
+    )
+}
+
+// One file as a tar entry: a pax header first if the name or size doesn't
+// fit in a ustar header, then the header, the contents, and padding out to
+// a whole block.
+fn tar_entry(name: &str, contents: Vec<u8>) -> Vec<u8> {
+    let size = contents.len() as u64;
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let mut entry = Vec::with_capacity(contents.len() + 3 * BLOCK);
+
+    let mut records = String::new();
+    if name.len() > 100 {
+        records.push_str(&pax_record("path", name));
+    }
+    if size > USTAR_MAX_SIZE {
+        records.push_str(&pax_record("size", &size.to_string()));
+    }
+    if !records.is_empty() {
+        entry.extend_from_slice(&header("././@PaxHeader", records.len() as u64, mtime, b'x'));
+        entry.extend_from_slice(records.as_bytes());
+        pad(&mut entry);
+    }
+
+    entry.extend_from_slice(&header(name, size, mtime, b'0'));
+    entry.extend(contents);
+    pad(&mut entry);
+    entry
+}
+
+fn header(name: &str, size: u64, mtime: u64, kind: u8) -> [u8; BLOCK] {
+    let mut header = [0; BLOCK];
+    // Cut at a character boundary; the pax header has the whole name.
+    let mut cut = name.len().min(100);
+    while !name.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    header[..cut].copy_from_slice(&name.as_bytes()[..cut]);
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size.min(USTAR_MAX_SIZE));
+    octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    octal(&mut header[148..155], u64::from(sum));
+    header
+}
+
+// Zero-padded octal, leaving room for the NUL at the end of the field.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    let digits = &digits.as_bytes()[digits.len() - (field.len() - 1)..];
+    field[..digits.len()].copy_from_slice(digits);
+    field[digits.len()] = 0;
+}
+
+// A pax record is "<length> <key>=<value>\n", the length counting itself.
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + 1;
+    while length != rest + length.to_string().len() {
+        length = rest + length.to_string().len();
+    }
+    format!("{} {}={}\n", length, key, value)
+}
+
+fn pad(entry: &mut Vec<u8>) {
+    let over = entry.len() % BLOCK;
+    if over != 0 {
+        entry.resize(entry.len() + BLOCK - over, 0);
+    }
+}
+
+fn frame(data: Vec<u8>, compress: bool) -> io::Result<Vec<u8>> {
+    if compress {
+        zstd(data)
+    } else {
+        Ok(raw_frame(&data))
+    }
+}
+
+// A zstd frame of raw blocks, which any zstd decoder reads back but which
+// isn't any smaller.
+fn raw_frame(data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + data.len() / RAW_BLOCK * 3 + 9);
+    frame.extend_from_slice(&FRAME_MAGIC);
+    frame.extend_from_slice(&FRAME_HEADER);
+    let mut blocks = data.chunks(RAW_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        frame.extend_from_slice(&[1, 0, 0]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = u32::from(blocks.peek().is_none());
+        // Raw blocks are type 0, so only the size and the last-block bit.
+        let block_header = (block.len() as u32) << 3 | last;
+        frame.extend_from_slice(&block_header.to_le_bytes()[..3]);
+        frame.extend_from_slice(block);
+    }
+    frame
+}
+
+fn zstd(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut child = Command::new("zstd")
+        .args(["-q", "-c"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // Written from another thread, so that zstd's output is read while its
+    // input is still going in.
+    let writer = thread::spawn(move || stdin.write_all(&data));
+    let output = child.wait_with_output()?;
+    writer.join().unwrap()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "zstd failed with {}",
+            output.status
+        )));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::Scratch;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn writes_entries_from_many_tasks() {
+        let scratch = Scratch::new("tarzst-tasks");
+        let path = scratch.path().join("archive.tar.zst");
+        let archive = std::sync::Arc::new(TarZst::open(&path, scratch.path()).unwrap());
+        let tasks: Vec<_> = (0..16)
+            .map(|i| {
+                let archive = archive.clone();
+                let root = scratch.path().to_owned();
+                tokio::spawn(async move {
+                    let path = root.join(format!("{}.png", i));
+                    archive.write(&path, vec![i as u8; 700]).await.unwrap();
+                    assert!(archive.exists(&path).await.unwrap());
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert!(!archive
+            .exists(&scratch.path().join("16.png"))
+            .await
+            .unwrap());
+        archive.flush().unwrap();
+        drop(archive);
+
+        let listed = fs::read_to_string(entries_path(&path)).unwrap();
+        assert_eq!(listed.lines().count(), 16);
+        let end: u64 = listed
+            .lines()
+            .last()
+            .unwrap()
+            .split('\t')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > end);
+    }
+
+    #[tokio::test]
+    async fn adds_to_an_interrupted_archive() {
+        let scratch = Scratch::new("tarzst-reopen");
+        let path = scratch.path().join("archive.tar.zst");
+        let first = scratch.path().join("first.png");
+        let archive = TarZst::open(&path, scratch.path()).unwrap();
+        archive.write(&first, b"first".to_vec()).await.unwrap();
+        drop(archive);
+
+        let archive = TarZst::open(&path, scratch.path()).unwrap();
+        assert!(archive.exists(&first).await.unwrap());
+        archive
+            .write(&scratch.path().join("second.png"), b"second".to_vec())
+            .await
+            .unwrap();
+        drop(archive);
+        let listed = fs::read_to_string(entries_path(&path)).unwrap();
+        assert_eq!(listed.lines().count(), 2);
+    }
+}
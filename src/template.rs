@@ -0,0 +1,294 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::layout::{primary_artist, rating_directory, sanitize, year_and_month};
+use crate::Post;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// What a `{field}` in a template can stand for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Id,
+    Md5,
+    Ext,
+    Rating,
+    Artist,
+    Copyright,
+    Character,
+    Species,
+    Year,
+    Month,
+    Day,
+    Pool,
+    PoolPosition,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("id", Field::Id),
+    ("md5", Field::Md5),
+    ("ext", Field::Ext),
+    ("rating", Field::Rating),
+    ("artist", Field::Artist),
+    ("copyright", Field::Copyright),
+    ("character", Field::Character),
+    ("species", Field::Species),
+    ("year", Field::Year),
+    ("month", Field::Month),
+    ("day", Field::Day),
+    ("pool", Field::Pool),
+    ("pool_position", Field::PoolPosition),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+/// Where each post's file goes under the output directory, for
+/// --output-layout-template, written as a path with `{field}`s in it like
+/// `{rating}/{artist}/{year}/{md5}.{ext}`. Every segment is made safe to use
+/// once it's filled in, and one that comes out empty is left out, which is
+/// what lets fields with nothing to say disappear.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathTemplate {
+    segments: Vec<Vec<Part>>,
+}
+
+impl PathTemplate {
+    /// The file's path relative to the output directory.
+    pub fn expand(&self, post: &Post) -> PathBuf {
+        self.segments
+            .iter()
+            .map(|segment| {
+                segment
+                    .iter()
+                    .map(|part| match part {
+                        Part::Text(text) => text.clone(),
+                        Part::Field(field) => value(*field, post),
+                    })
+                    .collect::<String>()
+            })
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| sanitize(&segment))
+            .collect()
+    }
+
+    /// Whether the template needs to know which pool a post is in.
+    pub fn uses_pools(&self) -> bool {
+        self.segments
+            .iter()
+            .flatten()
+            .any(|part| matches!(part, Part::Field(Field::Pool | Field::PoolPosition)))
+    }
+}
+
+// What a field comes out as for a post. Those with nothing to say fall back to
+// unknown_<field>, except for the parts of a date past the year, and the
+// pool, which come out empty, so a post outside a pool has no directory for
+// it, and one with an unreadable date is filed under unknown_date alone.
+fn value(field: Field, post: &Post) -> String {
+    let first = |tags: &[String], category: &str| {
+        tags.first()
+            .cloned()
+            .unwrap_or_else(|| format!("unknown_{}", category))
+    };
+    let date = year_and_month(&post.created_at);
+    match field {
+        Field::Id => post.id.to_string(),
+        Field::Md5 => post.file.md5.clone(),
+        Field::Ext => post.ext().to_owned(),
+        Field::Rating => rating_directory(&post.rating).to_owned(),
+        Field::Artist => primary_artist(post).to_owned(),
+        Field::Copyright => first(&post.tags.copyright, "copyright"),
+        Field::Character => first(&post.tags.character, "character"),
+        Field::Species => first(&post.tags.species, "species"),
+        Field::Year => date.map_or("unknown_date", |(year, _)| year).to_owned(),
+        Field::Month => date.map_or("", |(_, month)| month).to_owned(),
+        Field::Day => date
+            .and_then(|_| post.created_at.get(8..10))
+            .filter(|day| day.chars().all(|c| c.is_ascii_digit()))
+            .unwrap_or_default()
+            .to_owned(),
+        Field::Pool => post
+            .pool
+            .as_ref()
+            .map(|pool| pool.name.clone())
+            .unwrap_or_default(),
+        // Padded so that they sort in reading order.
+        Field::PoolPosition => post
+            .pool
+            .as_ref()
+            .map(|pool| {
+                let width = pool.length.to_string().len().max(3);
+                format!("{:0width$}", pool.position, width = width)
+            })
+            .unwrap_or_default(),
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let segments = s
+            .split('/')
+            .map(|segment| match segment {
+                "" => Err(format!(
+                    "{:?} has an empty directory in it; separate directories with a single /",
+                    s
+                )),
+                segment => parse_segment(segment),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let file_name = segments.last().expect("split always gives one segment");
+        let unique = file_name
+            .iter()
+            .any(|part| matches!(part, Part::Field(Field::Md5 | Field::Id)));
+        if !unique {
+            return Err(format!(
+                "the file name in {:?} needs {{md5}} or {{id}} in it, or posts would overwrite each other",
+                s
+            ));
+        }
+        Ok(PathTemplate { segments })
+    }
+}
+
+fn parse_segment(segment: &str) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut rest = segment;
+    while !rest.is_empty() {
+        match rest.find(['{', '}']) {
+            Some(at) if rest[at..].starts_with('}') => {
+                return Err(format!("{:?} has a }} without a {{", segment));
+            }
+            Some(at) => {
+                if at > 0 {
+                    parts.push(Part::Text(rest[..at].to_owned()));
+                }
+                let Some(end) = rest[at..].find('}') else {
+                    return Err(format!("{:?} has a {{ without a }}", segment));
+                };
+                let name = &rest[at + 1..at + end];
+                let Some(&(_, field)) = FIELDS.iter().find(|(known, _)| *known == name) else {
+                    let known: Vec<&str> = FIELDS.iter().map(|(name, _)| *name).collect();
+                    return Err(format!(
+                        "there's no {{{}}}; the fields are {}",
+                        name,
+                        known.join(", ")
+                    ));
+                };
+                parts.push(Part::Field(field));
+                rest = &rest[at + end + 1..];
+            }
+            None => {
+                parts.push(Part::Text(rest.to_owned()));
+                rest = "";
+            }
+        }
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pools::PoolPlace;
+    use crate::testutil::post;
+
+    fn expand(template: &str, post: &Post) -> PathBuf {
+        template.parse::<PathTemplate>().unwrap().expand(post)
+    }
+
+    #[test]
+    fn fills_in_fields_around_text() {
+        let mut post = post(b"contents");
+        post.tags.artist = vec!["conditional_dnp".to_owned(), "fox".to_owned()];
+        let md5 = post.file.md5.clone();
+        assert_eq!(
+            expand("{rating}/{artist}/{year}-{month}-{day}/{md5}.{ext}", &post),
+            PathBuf::from(format!("safe/fox/2023-01-01/{}.png", md5))
+        );
+        assert_eq!(
+            expand("{species}/{character}/{id}.{ext}", &post),
+            PathBuf::from("unknown_species/unknown_character/1234.png")
+        );
+    }
+
+    #[test]
+    fn refuses_templates_it_cant_read() {
+        for (template, error) in [
+            ("{artst}/{id}", "there's no {artst}"),
+            ("{artist}/{id", "has a { without a }"),
+            ("artist}/{id}", "has a } without a {"),
+            ("{artist}//{id}", "has an empty directory in it"),
+            ("{md5}/{artist}", "needs {md5} or {id} in it"),
+        ] {
+            let err = template.parse::<PathTemplate>().unwrap_err();
+            assert!(err.contains(error), "{}: {}", template, err);
+        }
+    }
+
+    #[test]
+    fn keeps_tags_from_leaving_their_directory() {
+        let mut post = post(b"contents");
+        post.tags.artist = vec!["..".to_owned()];
+        post.tags.copyright = vec!["../../etc".to_owned()];
+        post.tags.character = vec!["c:\\windows".to_owned()];
+        assert_eq!(
+            expand("{artist}/{copyright}/{character}/{id}.{ext}", &post),
+            PathBuf::from("_/.._.._etc/c__windows/1234.png")
+        );
+    }
+
+    #[test]
+    fn leaves_out_directories_that_come_out_empty() {
+        let mut post = post(b"contents");
+        let template = "{pool}/{pool_position}_{id}.{ext}";
+        assert_eq!(expand(template, &post), PathBuf::from("_1234.png"));
+
+        post.pool = Some(PoolPlace {
+            id: 7,
+            name: "comic".to_owned(),
+            position: 4,
+            length: 12,
+        });
+        assert_eq!(expand(template, &post), PathBuf::from("comic/004_1234.png"));
+
+        post.created_at = "recently".to_owned();
+        assert_eq!(
+            expand("{year}/{month}/{day}/{id}.{ext}", &post),
+            PathBuf::from("unknown_date/1234.png")
+        );
+    }
+
+    #[test]
+    fn knows_when_it_needs_pools() {
+        assert!("{pool}/{id}".parse::<PathTemplate>().unwrap().uses_pools());
+        assert!(!"{artist}/{id}"
+            .parse::<PathTemplate>()
+            .unwrap()
+            .uses_pools());
+    }
+}
@@ -0,0 +1,155 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// What the tests have in common: somewhere to write files, a storage backend
+// that keeps everything in memory, a server that always says the same, and a
+// post to archive.
+
+use crate::breaker::CircuitBreaker;
+use crate::client::Client;
+use crate::error::MonosodiumError;
+use crate::ratelimit::RateLimiter;
+use crate::storage::{Pending, StorageBackend};
+use crate::Post;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// A directory of its own for a test, removed again afterwards.
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    pub fn new(name: &str) -> Scratch {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "monosodium-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Scratch(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Keeps whatever's written, so it can be read back.
+#[derive(Default)]
+pub struct Memory {
+    files: Mutex<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl Memory {
+    pub fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+}
+
+impl StorageBackend for Memory {
+    fn exists<'a>(&'a self, path: &'a Path) -> Pending<'a, bool> {
+        Box::pin(async move { Ok(self.files.lock().unwrap().contains_key(path)) })
+    }
+
+    fn write<'a>(&'a self, path: &'a Path, contents: Vec<u8>) -> Pending<'a, ()> {
+        Box::pin(async move {
+            self.files.lock().unwrap().insert(path.to_owned(), contents);
+            Ok::<_, MonosodiumError>(())
+        })
+    }
+}
+
+/// Answers every request with a 200 and `body`, and returns a URL to ask.
+pub async fn serve(body: &'static [u8]) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                // Up to the end of the headers; none of the requests have a body.
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match socket.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => request.extend_from_slice(&buffer[..read]),
+                    }
+                }
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = socket.write_all(head.as_bytes()).await;
+                let _ = socket.write_all(body).await;
+            });
+        }
+    });
+    format!("http://{}/file", address)
+}
+
+/// A post whose file is `contents`, to be saved as out/1234.png.
+pub fn post(contents: &[u8]) -> Post {
+    serde_json::from_value(json!({
+        "id": 1234,
+        "created_at": "2023-01-01T00:00:00.000-05:00",
+        "updated_at": "2023-01-01T00:00:00.000-05:00",
+        "file": {
+            "width": 1,
+            "height": 1,
+            "ext": "png",
+            "size": contents.len(),
+            "md5": format!("{:x}", md5::compute(contents)),
+            "url": null,
+        },
+        "tags": {
+            "general": [], "species": [], "character": [], "copyright": [],
+            "artist": [], "invalid": [], "lore": [], "meta": [],
+        },
+        "rating": "s",
+        "flags": { "pending": false, "flagged": false, "deleted": false },
+        "file_path": "out/1234.png",
+        "tags_path": "out/metadata/1234.json",
+    }))
+    .unwrap()
+}
+
+/// A client that doesn't wait between requests.
+pub fn client() -> Client {
+    Client::new(
+        reqwest::Client::new(),
+        RateLimiter::new(Duration::ZERO, Duration::ZERO),
+        CircuitBreaker::new(5, Duration::from_secs(1)),
+    )
+}
@@ -0,0 +1,174 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::client::Client;
+use crate::error::MonosodiumError;
+use crate::lookup_post;
+use crate::storage::StorageBackend;
+use log::{info, warn};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::path::Path;
+
+#[derive(Deserialize)]
+struct User {
+    id: u32,
+    name: String,
+}
+
+// A search with no results comes back as an object rather than a list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Users {
+    Found(Vec<User>),
+    None {},
+}
+
+/// Finds the numeric id of the user called `name`. Ids never change, so once
+/// found, they're remembered in `cache_path` and not looked up again.
+pub async fn lookup(
+    client: &Client,
+    name: &str,
+    cache_path: &Path,
+) -> Result<u32, MonosodiumError> {
+    // e621 names are case-insensitive, with underscores for spaces.
+    let name = name.trim().replace(' ', "_").to_lowercase();
+    let mut cache = load(cache_path)?;
+    if let Some(&id) = cache.get(&name) {
+        return Ok(id);
+    }
+
+    info!("Looking up the user id of {}", name);
+    let url = Url::parse_with_params(
+        "https://e621.net/users.json",
+        &[("search[name_matches]", name.as_str())],
+    )
+    .expect("the users URL is always valid");
+    let users = client
+        .get(url.as_str())
+        .await?
+        .error_for_status()?
+        .json::<Users>()
+        .await?;
+    let found = match users {
+        Users::Found(users) => users
+            .into_iter()
+            .find(|user| user.name.to_lowercase() == name),
+        Users::None {} => None,
+    };
+    let Some(user) = found else {
+        return Err(MonosodiumError::InvalidOptions(format!(
+            "there's no e621 user called {:?}",
+            name
+        )));
+    };
+
+    cache.insert(name, user.id);
+    if let Err(e) = save(cache_path, &cache) {
+        warn!("Could not save user id cache {:?}: {}", cache_path, e);
+    }
+    Ok(user.id)
+}
+
+/// Whether there's an e621 user with this id at all. Favorites of one that
+/// doesn't exist come back as an empty page, just like those of a user who
+/// hasn't favorited anything.
+pub async fn exists(client: &Client, id: u32) -> Result<bool, MonosodiumError> {
+    let url = format!("https://e621.net/users/{}.json", id);
+    let response = client.get(&url).await?;
+    match response.status() {
+        StatusCode::NOT_FOUND => Ok(false),
+        _ => response
+            .error_for_status()
+            .map(|_| true)
+            .map_err(Into::into),
+    }
+}
+
+/// Saves the public profile of the user with this id as `user.json` in
+/// `directory`, just as e621 gives it, and their avatar next to it as
+/// `avatar.<ext>`, for --archive-avatar-and-profile. An avatar is a post, so a
+/// user without one, or whose avatar post has no file to give, just has the
+/// profile saved.
+pub async fn archive_profile(
+    client: &Client,
+    storage: &dyn StorageBackend,
+    directory: &Path,
+    id: u32,
+) -> Result<(), MonosodiumError> {
+    info!("Saving the profile of user {}", id);
+    let url = format!("https://e621.net/users/{}.json", id);
+    let profile = client
+        .get(&url)
+        .await?
+        .error_for_status()?
+        .json::<serde_json::Value>()
+        .await?;
+    let json = serde_json::to_vec_pretty(&profile).map_err(std::io::Error::from)?;
+    storage.write(&directory.join("user.json"), json).await?;
+
+    let Some(avatar_id) = profile.get("avatar_id").and_then(|id| id.as_u64()) else {
+        info!("User {} has no avatar", id);
+        return Ok(());
+    };
+    let post = match lookup_post(client, avatar_id).await {
+        Ok(post) => post,
+        Err(e) => {
+            warn!(
+                "Could not look up post {}, the avatar of user {}: {}",
+                avatar_id, id, e
+            );
+            return Ok(());
+        }
+    };
+    let Some(file_url) = &post.file.url else {
+        warn!(
+            "Post {}, the avatar of user {}, has no file to download",
+            avatar_id, id
+        );
+        return Ok(());
+    };
+    let bytes = client
+        .get(file_url)
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let path = directory.join(format!("avatar.{}", post.file.ext));
+    storage.write(&path, bytes.into()).await
+}
+
+fn load(cache_path: &Path) -> std::io::Result<BTreeMap<String, u32>> {
+    match File::open(cache_path) {
+        Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn save(cache_path: &Path, cache: &BTreeMap<String, u32>) -> std::io::Result<()> {
+    serde_json::to_writer_pretty(File::create(cache_path)?, cache)?;
+    Ok(())
+}
@@ -0,0 +1,96 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::Post;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Another version of a post's file that was saved instead of it, because of
+/// --prefer-extension.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Variant {
+    pub url: String,
+    pub ext: String,
+}
+
+/// Picks the version of the post's file to save, going through `preferred`
+/// extensions in order: the full file if it has the extension, or else the
+/// tallest of its samples and alternates that does. `None` means the full
+/// file, which is also what's saved when nothing has any of the extensions.
+pub fn choose(post: &Post, preferred: &[String]) -> Option<Variant> {
+    let Some(sample) = &post.sample else {
+        return None;
+    };
+    let mut candidates = Vec::new();
+    collect_urls(sample, 0, &mut candidates);
+    // Tallest first; the sort is stable, so ties keep the API's order.
+    candidates.sort_by_key(|(_, height)| std::cmp::Reverse(*height));
+    for wanted in preferred {
+        if post.file.ext.eq_ignore_ascii_case(wanted) {
+            return None;
+        }
+        let found = candidates
+            .iter()
+            .find(|(url, _)| extension(url).is_some_and(|ext| ext.eq_ignore_ascii_case(wanted)));
+        if let Some((url, _)) = found {
+            return Some(Variant {
+                url: url.clone(),
+                ext: wanted.to_ascii_lowercase(),
+            });
+        }
+    }
+    None
+}
+
+// Every URL anywhere under `value`, with the height given beside it or
+// further up. The layout of alternates has changed before, so this doesn't
+// count on any particular one.
+fn collect_urls(value: &Value, height: u64, urls: &mut Vec<(String, u64)>) {
+    match value {
+        Value::String(s) if s.starts_with("https://") || s.starts_with("http://") => {
+            urls.push((s.clone(), height));
+        }
+        Value::Array(values) => {
+            for value in values {
+                collect_urls(value, height, urls);
+            }
+        }
+        Value::Object(fields) => {
+            let height = fields
+                .get("height")
+                .and_then(Value::as_u64)
+                .unwrap_or(height);
+            for value in fields.values() {
+                collect_urls(value, height, urls);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn extension(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let name = url.path_segments()?.next_back()?;
+    let (_, ext) = name.rsplit_once('.')?;
+    Some(ext.to_owned())
+}
@@ -0,0 +1,632 @@
+// MIT License
+//
+// Copyright (c) 2021-2023 Tilton Raccoon <tilton@tiltonraccoon.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::error::MonosodiumError;
+use crate::storage::{entry_name, hold_open_file, OpenFile, Pending, StorageBackend};
+use log::{error, warn};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const ZIP64_END: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+const END: u32 = 0x06054b50;
+
+// Zip64, since archives of a whole collection are easily over 4 GiB.
+const VERSION: u16 = 45;
+const UTF8_NAMES: u16 = 1 << 11;
+const STORED: u16 = 0;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+struct Entry {
+    name: String,
+    flags: u16,
+    method: u16,
+    time: u16,
+    date: u16,
+    crc: u32,
+    compressed_size: u64,
+    size: u64,
+    offset: u64,
+}
+
+struct State {
+    file: File,
+    entries: Vec<Entry>,
+    // The latest entry for each name; a name written twice leaves the older
+    // copy in the file, unlisted.
+    names: HashMap<String, usize>,
+    // Where the central directory starts, and so where the next entry goes.
+    end: u64,
+    dirty: bool,
+    // The archive, under --max-open-files.
+    _open: Option<OpenFile<'static>>,
+}
+
+/// A single zip archive holding everything. Entries are stored without
+/// compression, since images and videos are compressed already. An existing
+/// archive is added to: new entries go over its central directory, which is
+/// written out again when the archive is closed. One never closed, because
+/// the run adding to it was cut short, has its entries listed again from
+/// their own headers when it's next opened.
+pub struct ZipArchive {
+    path: PathBuf,
+    root: PathBuf,
+    state: Mutex<State>,
+}
+
+impl ZipArchive {
+    pub fn open(path: &Path, root: &Path) -> Result<ZipArchive, MonosodiumError> {
+        let open = hold_open_file()?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let length = file.metadata()?.len();
+        // Brought back from the entries' headers, so the directory needs writing.
+        let mut recovered = false;
+        let (entries, end) = if length == 0 {
+            (Vec::new(), 0)
+        } else {
+            match read_central_directory(&mut file, length) {
+                Ok(found) => found,
+                Err(e) => match recover_entries(&mut file, length)? {
+                    Some(found) => {
+                        warn!(
+                            "Zip archive {} wasn't finished by the run before; \
+                             found {} entries in it again",
+                            path.display(),
+                            found.0.len()
+                        );
+                        recovered = true;
+                        found
+                    }
+                    None => {
+                        return Err(MonosodiumError::InvalidOptions(format!(
+                            "can't add to zip archive {}: {}",
+                            path.display(),
+                            e
+                        )))
+                    }
+                },
+            }
+        };
+        let names = entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (entry.name.clone(), i))
+            .collect();
+        Ok(ZipArchive {
+            path: path.to_owned(),
+            root: root.to_owned(),
+            state: Mutex::new(State {
+                file,
+                entries,
+                names,
+                end,
+                dirty: recovered,
+                _open: open,
+            }),
+        })
+    }
+}
+
+impl StorageBackend for ZipArchive {
+    fn exists<'a>(&'a self, path: &'a Path) -> Pending<'a, bool> {
+        let name = entry_name(&self.root, path);
+        Box::pin(async move { Ok(self.state.lock().unwrap().names.contains_key(&name)) })
+    }
+
+    fn write<'a>(&'a self, path: &'a Path, contents: Vec<u8>) -> Pending<'a, ()> {
+        let name = entry_name(&self.root, path);
+        Box::pin(async move { Ok(self.state.lock().unwrap().append(name, &contents)?) })
+    }
+
+    // Only the entries; the central directory goes where the next entry
+    // would, so writing it any sooner would have it written over.
+    fn flush(&self) -> Result<(), MonosodiumError> {
+        Ok(self.state.lock().unwrap().file.sync_data()?)
+    }
+}
+
+impl Drop for ZipArchive {
+    fn drop(&mut self) {
+        let written = self.state.get_mut().unwrap().write_central_directory();
+        if let Err(e) = written {
+            error!("Could not finish zip archive {:?}: {}", self.path, e);
+        }
+    }
+}
+
+impl State {
+    fn append(&mut self, name: String, contents: &[u8]) -> io::Result<()> {
+        let (time, date) = dos_timestamp(SystemTime::now());
+        let crc = crc32(contents);
+        let size = contents.len() as u64;
+
+        let mut header = Vec::with_capacity(50 + name.len());
+        put_u32(&mut header, LOCAL_HEADER);
+        put_u16(&mut header, VERSION);
+        put_u16(&mut header, UTF8_NAMES);
+        put_u16(&mut header, STORED);
+        put_u16(&mut header, time);
+        put_u16(&mut header, date);
+        put_u32(&mut header, crc);
+        put_u32(&mut header, u32::MAX);
+        put_u32(&mut header, u32::MAX);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, 20);
+        header.extend_from_slice(name.as_bytes());
+        put_u16(&mut header, 1);
+        put_u16(&mut header, 16);
+        put_u64(&mut header, size);
+        put_u64(&mut header, size);
+
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&header)?;
+        self.file.write_all(contents)?;
+
+        self.names.insert(name.clone(), self.entries.len());
+        self.entries.push(Entry {
+            name,
+            flags: UTF8_NAMES,
+            method: STORED,
+            time,
+            date,
+            crc,
+            compressed_size: size,
+            size,
+            offset: self.end,
+        });
+        self.end += header.len() as u64 + size;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn write_central_directory(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let mut directory = Vec::new();
+        let mut count = 0u64;
+        for (i, entry) in self.entries.iter().enumerate() {
+            if self.names[&entry.name] != i {
+                continue;
+            }
+            count += 1;
+            put_u32(&mut directory, CENTRAL_HEADER);
+            put_u16(&mut directory, VERSION);
+            put_u16(&mut directory, VERSION);
+            put_u16(&mut directory, entry.flags);
+            put_u16(&mut directory, entry.method);
+            put_u16(&mut directory, entry.time);
+            put_u16(&mut directory, entry.date);
+            put_u32(&mut directory, entry.crc);
+            put_u32(&mut directory, u32::MAX);
+            put_u32(&mut directory, u32::MAX);
+            put_u16(&mut directory, entry.name.len() as u16);
+            put_u16(&mut directory, 28);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u32(&mut directory, 0);
+            put_u32(&mut directory, u32::MAX);
+            directory.extend_from_slice(entry.name.as_bytes());
+            put_u16(&mut directory, 1);
+            put_u16(&mut directory, 24);
+            put_u64(&mut directory, entry.size);
+            put_u64(&mut directory, entry.compressed_size);
+            put_u64(&mut directory, entry.offset);
+        }
+
+        let zip64_end = self.end + directory.len() as u64;
+        put_u32(&mut directory, ZIP64_END);
+        put_u64(&mut directory, 44);
+        put_u16(&mut directory, VERSION);
+        put_u16(&mut directory, VERSION);
+        put_u32(&mut directory, 0);
+        put_u32(&mut directory, 0);
+        put_u64(&mut directory, count);
+        put_u64(&mut directory, count);
+        put_u64(&mut directory, zip64_end - self.end);
+        put_u64(&mut directory, self.end);
+
+        put_u32(&mut directory, ZIP64_LOCATOR);
+        put_u32(&mut directory, 0);
+        put_u64(&mut directory, zip64_end);
+        put_u32(&mut directory, 1);
+
+        put_u32(&mut directory, END);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, u16::MAX);
+        put_u16(&mut directory, u16::MAX);
+        put_u32(&mut directory, u32::MAX);
+        put_u32(&mut directory, u32::MAX);
+        put_u16(&mut directory, 0);
+
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&directory)?;
+        self.file.set_len(self.end + directory.len() as u64)?;
+        self.file.sync_data()?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+// Returns the entries, and where the central directory starts.
+fn read_central_directory(file: &mut File, length: u64) -> io::Result<(Vec<Entry>, u64)> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_owned());
+
+    // Without an archive comment, the end record is the last 22 bytes.
+    if length < 22 {
+        return Err(invalid("it's too short to be a zip archive"));
+    }
+    let end = read_at(file, length - 22, 22)?;
+    if u32_at(&end, 0) != END {
+        return Err(invalid("it isn't a zip archive, or it has a comment"));
+    }
+    let mut count = u16_at(&end, 10) as u64;
+    let mut size = u32_at(&end, 12) as u64;
+    let mut offset = u32_at(&end, 16) as u64;
+    if length >= 42 {
+        let locator = read_at(file, length - 42, 20)?;
+        if u32_at(&locator, 0) == ZIP64_LOCATOR {
+            let record = read_at(file, u64_at(&locator, 8), 56)?;
+            if u32_at(&record, 0) != ZIP64_END {
+                return Err(invalid("its zip64 end record is missing"));
+            }
+            count = u64_at(&record, 32);
+            size = u64_at(&record, 40);
+            offset = u64_at(&record, 48);
+        }
+    }
+    if offset + size > length {
+        return Err(invalid("its central directory is cut off"));
+    }
+
+    let directory = read_at(file, offset, size as usize)?;
+    let mut entries = Vec::new();
+    let mut header = directory.as_slice();
+    for _ in 0..count {
+        if header.len() < 46 || u32_at(header, 0) != CENTRAL_HEADER {
+            return Err(invalid("its central directory is damaged"));
+        }
+        let name_length = u16_at(header, 28) as usize;
+        let extra_length = u16_at(header, 30) as usize;
+        let comment_length = u16_at(header, 32) as usize;
+        let length = 46 + name_length + extra_length + comment_length;
+        if header.len() < length {
+            return Err(invalid("its central directory is damaged"));
+        }
+        let mut entry = Entry {
+            name: String::from_utf8_lossy(&header[46..46 + name_length]).into_owned(),
+            flags: u16_at(header, 8),
+            method: u16_at(header, 10),
+            time: u16_at(header, 12),
+            date: u16_at(header, 14),
+            crc: u32_at(header, 16),
+            compressed_size: u32_at(header, 20) as u64,
+            size: u32_at(header, 24) as u64,
+            offset: u32_at(header, 42) as u64,
+        };
+
+        read_zip64(
+            &header[46 + name_length..46 + name_length + extra_length],
+            [
+                &mut entry.size,
+                &mut entry.compressed_size,
+                &mut entry.offset,
+            ],
+        );
+
+        entries.push(entry);
+        header = &header[length..];
+    }
+    Ok((entries, offset))
+}
+
+// Lists the entries from their own headers, for an archive whose central
+// directory never got written. Stops at the first that isn't whole, which is
+// where the next one goes. None for a file that doesn't start with an entry.
+fn recover_entries(file: &mut File, length: u64) -> io::Result<Option<(Vec<Entry>, u64)>> {
+    if length < 4 || u32_at(&read_at(file, 0, 4)?, 0) != LOCAL_HEADER {
+        return Ok(None);
+    }
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + 30 <= length {
+        let header = read_at(file, offset, 30)?;
+        let flags = u16_at(&header, 6);
+        // Sizes that come after the data can't be found without it.
+        if u32_at(&header, 0) != LOCAL_HEADER || flags & 0x08 != 0 {
+            break;
+        }
+        let name_length = u16_at(&header, 26) as u64;
+        let extra_length = u16_at(&header, 28) as u64;
+        let start = offset + 30 + name_length + extra_length;
+        if start > length {
+            break;
+        }
+        let rest = read_at(file, offset + 30, (name_length + extra_length) as usize)?;
+        let mut size = u32_at(&header, 22) as u64;
+        let mut compressed_size = u32_at(&header, 18) as u64;
+        read_zip64(
+            &rest[name_length as usize..],
+            [&mut size, &mut compressed_size],
+        );
+        if start + compressed_size > length {
+            break;
+        }
+        // Written, but maybe not all of it made it to the disk.
+        let method = u16_at(&header, 8);
+        let crc = u32_at(&header, 14);
+        if method == STORED && crc32(&read_at(file, start, compressed_size as usize)?) != crc {
+            break;
+        }
+        entries.push(Entry {
+            name: String::from_utf8_lossy(&rest[..name_length as usize]).into_owned(),
+            flags,
+            method,
+            time: u16_at(&header, 10),
+            date: u16_at(&header, 12),
+            crc,
+            compressed_size,
+            size,
+            offset,
+        });
+        offset = start + compressed_size;
+    }
+    Ok(Some((entries, offset)))
+}
+
+// Fields too big for the header are in the zip64 extra field, in the order
+// given, for only those that are.
+fn read_zip64<const N: usize>(mut extra: &[u8], values: [&mut u64; N]) {
+    while extra.len() >= 4 {
+        let id = u16_at(extra, 0);
+        let field_length = (u16_at(extra, 2) as usize).min(extra.len() - 4);
+        if id == 1 {
+            let mut field = &extra[4..4 + field_length];
+            for value in values {
+                if *value == u32::MAX as u64 && field.len() >= 8 {
+                    *value = u64_at(field, 0);
+                    field = &field[8..];
+                }
+            }
+            return;
+        }
+        extra = &extra[4 + field_length..];
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0; length];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+// MS-DOS time and date, in UTC, which is as good as any without a time zone.
+fn dos_timestamp(time: SystemTime) -> (u16, u16) {
+    let stamp = humantime::format_rfc3339_seconds(time).to_string();
+    let field = |range: std::ops::Range<usize>| stamp[range].parse::<u16>().unwrap_or(0);
+    let time = (field(11..13) << 11) | (field(14..16) << 5) | (field(17..19) / 2);
+    let date = (field(0..4).saturating_sub(1980) << 9) | (field(5..7) << 5) | field(8..10);
+    (time, date)
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::Scratch;
+
+    // Everything listed in the archive's central directory, read back.
+    fn read_back(path: &Path) -> HashMap<String, Vec<u8>> {
+        let mut file = File::open(path).unwrap();
+        let length = file.metadata().unwrap().len();
+        let (entries, _) = read_central_directory(&mut file, length).unwrap();
+        entries
+            .into_iter()
+            .map(|entry| {
+                let header = read_at(&mut file, entry.offset, 30).unwrap();
+                let start =
+                    entry.offset + 30 + u16_at(&header, 26) as u64 + u16_at(&header, 28) as u64;
+                let contents = read_at(&mut file, start, entry.size as usize).unwrap();
+                assert_eq!(crc32(&contents), entry.crc);
+                (entry.name, contents)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reads_back_what_was_written() {
+        let scratch = Scratch::new("zip");
+        let root = scratch.path().join("out");
+        let zip = scratch.path().join("out.zip");
+        let archive = ZipArchive::open(&zip, &root).unwrap();
+        let post = root.join("1234.png");
+        let sidecar = root.join("metadata/1234.json");
+        archive
+            .write(&post, b"not really a png".to_vec())
+            .await
+            .unwrap();
+        archive
+            .write_metadata(&sidecar, b"{}".to_vec())
+            .await
+            .unwrap();
+        assert!(archive.exists(&post).await.unwrap());
+        assert!(!archive.exists(&root.join("5678.png")).await.unwrap());
+        drop(archive);
+
+        let contents = read_back(&zip);
+        assert_eq!(contents.len(), 2);
+        assert_eq!(contents["1234.png"], b"not really a png");
+        assert_eq!(contents["metadata/1234.json"], b"{}");
+    }
+
+    #[tokio::test]
+    async fn adds_to_an_existing_archive() {
+        let scratch = Scratch::new("zip");
+        let root = scratch.path().join("out");
+        let zip = scratch.path().join("out.zip");
+        let archive = ZipArchive::open(&zip, &root).unwrap();
+        archive
+            .write(&root.join("1.png"), b"one".to_vec())
+            .await
+            .unwrap();
+        drop(archive);
+
+        let archive = ZipArchive::open(&zip, &root).unwrap();
+        assert!(archive.exists(&root.join("1.png")).await.unwrap());
+        archive
+            .write(&root.join("2.png"), b"two".to_vec())
+            .await
+            .unwrap();
+        archive
+            .write(&root.join("1.png"), b"uno".to_vec())
+            .await
+            .unwrap();
+        drop(archive);
+
+        let contents = read_back(&zip);
+        assert_eq!(contents.len(), 2);
+        assert_eq!(contents["1.png"], b"uno");
+        assert_eq!(contents["2.png"], b"two");
+    }
+
+    #[tokio::test]
+    async fn survives_a_run_that_never_closed_it() {
+        let scratch = Scratch::new("zip");
+        let root = scratch.path().join("out");
+        let zip = scratch.path().join("out.zip");
+        let archive = ZipArchive::open(&zip, &root).unwrap();
+        archive
+            .write(&root.join("1.png"), b"one".to_vec())
+            .await
+            .unwrap();
+        drop(archive);
+
+        // A crash: entries written over the directory, which never comes back,
+        // and the last of them only half there.
+        let archive = ZipArchive::open(&zip, &root).unwrap();
+        archive
+            .write(&root.join("2.png"), b"two".to_vec())
+            .await
+            .unwrap();
+        archive.flush().unwrap();
+        // Longer than the directory it went over, so that it ends the file.
+        let three = vec![3; 1000];
+        archive
+            .write(&root.join("3.png"), three.clone())
+            .await
+            .unwrap();
+        std::mem::forget(archive);
+        let length = std::fs::metadata(&zip).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&zip)
+            .unwrap()
+            .set_len(length - 2)
+            .unwrap();
+
+        let archive = ZipArchive::open(&zip, &root).unwrap();
+        assert!(archive.exists(&root.join("1.png")).await.unwrap());
+        assert!(archive.exists(&root.join("2.png")).await.unwrap());
+        assert!(!archive.exists(&root.join("3.png")).await.unwrap());
+        archive
+            .write(&root.join("3.png"), three.clone())
+            .await
+            .unwrap();
+        drop(archive);
+
+        let contents = read_back(&zip);
+        assert_eq!(contents.len(), 3);
+        assert_eq!(contents["3.png"], three);
+    }
+
+    #[test]
+    fn refuses_what_isnt_a_zip() {
+        let scratch = Scratch::new("zip");
+        let zip = scratch.path().join("out.zip");
+        std::fs::write(&zip, b"nothing like a zip archive").unwrap();
+        assert!(ZipArchive::open(&zip, scratch.path()).is_err());
+    }
+}